@@ -0,0 +1,300 @@
+use anyhow::Result;
+
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// reference: https://www.nesdev.org/wiki/APU_DMC
+// Timer periods in CPU cycles
+pub const DMC_RATE_NTSC: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+pub const DMC_RATE_PAL: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+/// The delta modulation channel plays 1-bit delta encoded samples from CPU memory.
+/// It cannot read memory on its own: whenever its sample buffer is empty it raises
+/// a DMA request, and the CPU halts to fetch the next byte with `fill_buffer`.
+#[derive(Debug, Clone)]
+pub struct Dmc {
+    pub region: Region,
+    pub irq_enabled: bool,
+    pub irq_flag: bool,
+    pub looped: bool,
+    pub timer_period: u16,
+    pub timer: u16,
+    /// 7-bit output level
+    pub output_level: u8,
+    /// $4012: sample address = $C000 + A * 64
+    pub sample_address: u16,
+    /// $4013: sample length = L * 16 + 1 bytes
+    pub sample_length: u16,
+    pub current_address: u16,
+    pub bytes_remaining: u16,
+    pub sample_buffer: Option<u8>,
+    pub shift_register: u8,
+    pub bits_remaining: u8,
+    pub silence: bool,
+}
+
+impl Dmc {
+    pub fn new(region: Region) -> Self {
+        Self {
+            region,
+            irq_enabled: false,
+            irq_flag: false,
+            looped: false,
+            timer_period: Self::rate_table(region)[0],
+            timer: 0,
+            output_level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+
+    #[inline]
+    fn rate_table(region: Region) -> &'static [u16; 16] {
+        match region {
+            Region::Ntsc => &DMC_RATE_NTSC,
+            Region::Pal => &DMC_RATE_PAL,
+        }
+    }
+
+    /// $4010: IL-- RRRR
+    pub fn write_control(&mut self, value: u8) {
+        self.irq_enabled = value & 0x80 != 0;
+        self.looped = value & 0x40 != 0;
+        self.timer_period = Self::rate_table(self.region)[(value & 0x0f) as usize];
+        if !self.irq_enabled {
+            self.irq_flag = false;
+        }
+    }
+
+    /// $4011: -DDD DDDD, direct load of the output level
+    pub fn write_output_level(&mut self, value: u8) {
+        self.output_level = value & 0x7f;
+    }
+
+    /// $4012: AAAA AAAA
+    pub fn write_sample_address(&mut self, value: u8) {
+        self.sample_address = 0xC000 | ((value as u16) << 6);
+    }
+
+    /// $4013: LLLL LLLL
+    pub fn write_sample_length(&mut self, value: u8) {
+        self.sample_length = ((value as u16) << 4) | 1;
+    }
+
+    /// $4015 bit 4: start the sample if it is not already playing, or stop it
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    #[inline]
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    /// The address the memory reader wants to fetch, if the sample buffer is empty
+    #[inline]
+    #[must_use]
+    pub fn dma_request(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    /// Complete a DMA request with the byte the CPU fetched
+    pub fn fill_buffer(&mut self, value: u8) {
+        self.sample_buffer = Some(value);
+        // The address wraps around to $8000 rather than $0000
+        self.current_address = if self.current_address == 0xFFFF {
+            0x8000
+        } else {
+            self.current_address + 1
+        };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looped {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    /// Clocked on every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+
+        if !self.silence {
+            // Each bit moves the level up or down by 2, clamped to 0..=127
+            if self.shift_register & 1 == 1 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.silence = false;
+                    self.shift_register = sample;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+}
+
+impl Savestate for Dmc {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.irq_flag);
+        state.write_bool(self.looped);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+        state.write_u8(self.output_level);
+        state.write_u16(self.sample_address);
+        state.write_u16(self.sample_length);
+        state.write_u16(self.current_address);
+        state.write_u16(self.bytes_remaining);
+        state.write_option_u8(self.sample_buffer);
+        state.write_u8(self.shift_register);
+        state.write_u8(self.bits_remaining);
+        state.write_bool(self.silence);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.irq_enabled = state.read_bool()?;
+        self.irq_flag = state.read_bool()?;
+        self.looped = state.read_bool()?;
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.output_level = state.read_u8()?;
+        self.sample_address = state.read_u16()?;
+        self.sample_length = state.read_u16()?;
+        self.current_address = state.read_u16()?;
+        self.bytes_remaining = state.read_u16()?;
+        self.sample_buffer = state.read_option_u8()?;
+        self.shift_register = state.read_u8()?;
+        self.bits_remaining = state.read_u8()?;
+        self.silence = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::apu::Apu;
+    use crate::cpu::TestHarness;
+    use crate::mem::Mem;
+
+    #[test]
+    fn test_dma_steals_cpu_cycles() {
+        let mut harness = TestHarness::new().with_program(
+            0xc000,
+            &[
+                0xa9, 0x0f, // LDA #$0F
+                0x8d, 0x10, 0x40, // STA $4010
+                0xa9, 0x00, // LDA #$00
+                0x8d, 0x13, 0x40, // STA $4013
+                0xa9, 0x10, // LDA #$10
+                0x8d, 0x15, 0x40, // STA $4015
+                0xea, // NOP
+            ],
+        );
+        harness.run(7).unwrap();
+        // 3 x LDA/STA pairs of 2 + 4 cycles, plus the NOP
+        let expected_cycles = 3 * 6 + 2;
+
+        // The single sample byte at $C000 is fetched right after $4015 enables the channel
+        let cpu = &harness.cpu;
+        assert_eq!(cpu.apu.dmc.sample_buffer, Some(0xa9));
+        assert!(!cpu.apu.dmc.is_active());
+        // STA ends with a write cycle, so the DMA only halts the CPU for 3 cycles
+        assert_eq!(cpu.cycles, expected_cycles + 3);
+    }
+
+    #[test]
+    fn test_irq() {
+        // IRQ handler at $D000
+        let mut harness = TestHarness::new()
+            .with_program(0xc000, &[0xea, 0xea, 0xea, 0xea])
+            .with_memory(0xfffe, &[0x00, 0xd0])
+            .with_memory(0xd000, &[0xea]);
+        let cpu = &mut harness.cpu;
+        cpu.mem_write(0x4010, 0x8f).unwrap(); // IRQ enabled, fastest rate
+        cpu.mem_write(0x4013, 0x00).unwrap(); // 1 byte sample
+        cpu.mem_write(0x4015, 0x10).unwrap();
+
+        harness.run(1).unwrap();
+        assert!(harness.cpu.apu.irq());
+        assert_eq!(harness.cpu.mem_read(0x4015).unwrap() & 0x80, 0x80);
+
+        // The IRQ is taken before the next instruction
+        harness.run(1).unwrap();
+        assert!(harness.cpu.registers.interrupt_disabled);
+        assert_eq!(harness.cpu.registers.pc, 0xd001);
+
+        // Writing $4015 acknowledges the interrupt
+        harness.cpu.mem_write(0x4015, 0x00).unwrap();
+        assert!(!harness.cpu.apu.irq());
+    }
+
+    #[test]
+    fn test_output_unit() {
+        let mut apu = Apu::default();
+        apu.write_register(0x4011, 0x40);
+        apu.write_register(0x4010, 0x0f);
+        apu.write_register(0x4013, 0x00);
+        apu.write_register(0x4015, 0x10);
+        apu.dmc.fill_buffer(0xff);
+
+        // Drain the empty output cycle, then every bit of $FF raises the level by 2
+        for _ in 0..(8 * 54) {
+            apu.clock();
+        }
+        let level = apu.dmc.output();
+        for _ in 0..(8 * 54) {
+            apu.clock();
+        }
+        assert_eq!(apu.dmc.output(), level + 16);
+    }
+}
@@ -0,0 +1,81 @@
+use anyhow::Result;
+
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// reference: https://www.nesdev.org/wiki/APU_Envelope
+
+/// Volume envelope shared by the pulse and noise channels.
+/// It either outputs a constant volume or a decaying saw from 15 down to 0.
+#[derive(Debug, Clone, Default)]
+pub struct Envelope {
+    pub start: bool,
+    pub looped: bool,
+    pub constant_volume: bool,
+    /// Constant volume, or the reload value of the divider
+    pub volume: u8,
+    pub divider: u8,
+    pub decay: u8,
+}
+
+impl Envelope {
+    /// Write the lower 6 bits of $4000/$4004/$400C: --LC VVVV
+    #[inline]
+    pub fn write(&mut self, value: u8) {
+        self.looped = value & 0x20 != 0;
+        self.constant_volume = value & 0x10 != 0;
+        self.volume = value & 0x0f;
+    }
+
+    /// Clocked by the frame counter on every quarter frame
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+            return;
+        }
+
+        if self.divider > 0 {
+            self.divider -= 1;
+            return;
+        }
+
+        self.divider = self.volume;
+        if self.decay > 0 {
+            self.decay -= 1;
+        } else if self.looped {
+            self.decay = 15;
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn output(&self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+impl Savestate for Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.start);
+        state.write_bool(self.looped);
+        state.write_bool(self.constant_volume);
+        state.write_u8(self.volume);
+        state.write_u8(self.divider);
+        state.write_u8(self.decay);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.start = state.read_bool()?;
+        self.looped = state.read_bool()?;
+        self.constant_volume = state.read_bool()?;
+        self.volume = state.read_u8()?;
+        self.divider = state.read_u8()?;
+        self.decay = state.read_u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,336 @@
+use anyhow::Result;
+
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// reference: https://www.nesdev.org/wiki/APU_Frame_Counter
+// Sequencer steps in CPU cycles since the sequencer was reset
+const STEPS_NTSC: [u32; 6] = [7457, 14913, 22371, 29828, 29829, 29830];
+const STEPS_NTSC_5_STEP: [u32; 6] = [7457, 14913, 22371, 29829, 37281, 37282];
+const STEPS_PAL: [u32; 6] = [8313, 16627, 24939, 33252, 33253, 33254];
+const STEPS_PAL_5_STEP: [u32; 6] = [8313, 16627, 24939, 33253, 41565, 41566];
+
+/// What the frame counter clocks on a given CPU cycle
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrameStep {
+    None,
+    /// Envelopes and the triangle's linear counter
+    Quarter,
+    /// Quarter frame units plus length counters and sweep units
+    Half,
+}
+
+/// The frame counter drives the low frequency units of every channel and,
+/// in 4-step mode, raises the frame IRQ once per sequence.
+#[derive(Debug, Clone)]
+pub struct FrameCounter {
+    pub region: Region,
+    pub five_step: bool,
+    pub irq_inhibit: bool,
+    pub irq_flag: bool,
+    /// CPU cycles since the sequencer was last reset
+    pub cycle: u32,
+    /// A $4017 write only resets the sequencer after a delay of 3 or 4 CPU cycles
+    pending_write: Option<u8>,
+    write_delay: u8,
+}
+
+impl FrameCounter {
+    pub fn new(region: Region) -> Self {
+        Self {
+            region,
+            five_step: false,
+            irq_inhibit: false,
+            irq_flag: false,
+            cycle: 0,
+            pending_write: None,
+            write_delay: 0,
+        }
+    }
+
+    #[inline]
+    fn steps(&self) -> &'static [u32; 6] {
+        match (self.region, self.five_step) {
+            (Region::Ntsc, false) => &STEPS_NTSC,
+            (Region::Ntsc, true) => &STEPS_NTSC_5_STEP,
+            (Region::Pal, false) => &STEPS_PAL,
+            (Region::Pal, true) => &STEPS_PAL_5_STEP,
+        }
+    }
+
+    /// $4017: MI-- ----
+    /// The inhibit flag takes effect immediately, the mode change and sequencer reset
+    /// happen 3 CPU cycles later if the write lands on an even cycle, 4 on an odd one.
+    pub fn write(&mut self, value: u8, odd_cycle: bool) {
+        self.irq_inhibit = value & 0x40 != 0;
+        if self.irq_inhibit {
+            self.irq_flag = false;
+        }
+        self.pending_write = Some(value);
+        self.write_delay = if odd_cycle { 4 } else { 3 };
+    }
+
+    /// Clocked on every CPU cycle
+    pub fn clock(&mut self) -> FrameStep {
+        if let Some(value) = self.pending_write {
+            self.write_delay -= 1;
+            if self.write_delay == 0 {
+                self.pending_write = None;
+                self.five_step = value & 0x80 != 0;
+                self.cycle = 0;
+                // Switching to 5-step mode clocks every unit immediately
+                if self.five_step {
+                    return FrameStep::Half;
+                }
+                return FrameStep::None;
+            }
+        }
+
+        self.cycle += 1;
+        let steps = self.steps();
+        let step = match steps.iter().position(|&s| s == self.cycle) {
+            Some(step) => step,
+            None => return FrameStep::None,
+        };
+
+        if !self.five_step && step >= 3 && !self.irq_inhibit {
+            self.irq_flag = true;
+        }
+
+        match step {
+            0 | 2 => FrameStep::Quarter,
+            1 | 4 => FrameStep::Half,
+            5 => {
+                self.cycle = 0;
+                FrameStep::None
+            }
+            _ => FrameStep::None,
+        }
+    }
+}
+
+impl Savestate for FrameCounter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.five_step);
+        state.write_bool(self.irq_inhibit);
+        state.write_bool(self.irq_flag);
+        state.write_u32(self.cycle);
+        state.write_option_u8(self.pending_write);
+        state.write_u8(self.write_delay);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.five_step = state.read_bool()?;
+        self.irq_inhibit = state.read_bool()?;
+        self.irq_flag = state.read_bool()?;
+        self.cycle = state.read_u32()?;
+        self.pending_write = state.read_option_u8()?;
+        self.write_delay = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::apu::Apu;
+    use crate::cpu::{StopReason, TestHarness};
+    use crate::mem::Mem;
+
+    // The expectations follow the documentation of blargg's apu_test len_ctr, len_table,
+    // irq_flag and jitter tests. The ROMs themselves aren't run.
+
+    fn clock_apu(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles {
+            apu.clock();
+        }
+    }
+
+    #[test]
+    fn test_length_counter_clocks() {
+        let mut apu = Apu::default();
+        apu.write_register(0x4017, 0x00);
+        apu.write_register(0x4015, 0x01);
+        apu.write_register(0x4000, 0x00);
+        apu.write_register(0x4003, 0x18); // length index 3 = 2
+        clock_apu(&mut apu, 3 + 14912);
+        assert_eq!(apu.pulse1.length_counter.counter, 2);
+        clock_apu(&mut apu, 1);
+        assert_eq!(apu.pulse1.length_counter.counter, 1);
+        clock_apu(&mut apu, 29829 - 14913);
+        assert_eq!(apu.read_status() & 0x01, 0x00);
+    }
+
+    #[test]
+    fn test_halted_length_counter() {
+        let mut apu = Apu::default();
+        apu.write_register(0x4017, 0x00);
+        apu.write_register(0x4015, 0x01);
+        apu.write_register(0x4000, 0x20);
+        apu.write_register(0x4003, 0x18);
+        clock_apu(&mut apu, 2 * 29830);
+        assert_eq!(apu.read_status() & 0x01, 0x01);
+    }
+
+    #[test]
+    fn test_five_step_mode_clocks_immediately() {
+        let mut apu = Apu::default();
+        apu.write_register(0x4015, 0x0f);
+        apu.write_register(0x4003, 0x08); // length index 1 = 254
+        apu.write_register(0x400F, 0x00); // length index 0 = 10
+        apu.write_register(0x4017, 0x80);
+        clock_apu(&mut apu, 3);
+        assert_eq!(apu.pulse1.length_counter.counter, 253);
+        assert_eq!(apu.noise.length_counter.counter, 9);
+    }
+
+    #[test]
+    fn test_irq_flag() {
+        let mut apu = Apu::default();
+        apu.write_register(0x4017, 0x00);
+        clock_apu(&mut apu, 3 + 29827);
+        assert!(!apu.irq());
+        clock_apu(&mut apu, 1);
+        assert!(apu.irq());
+        // Reading $4015 acknowledges the frame interrupt
+        assert_eq!(apu.read_status() & 0x40, 0x40);
+        assert!(!apu.irq());
+
+        // The flag is raised over three consecutive cycles
+        clock_apu(&mut apu, 1);
+        assert!(apu.irq());
+    }
+
+    #[test]
+    fn test_irq_inhibit() {
+        let mut apu = Apu::default();
+        apu.write_register(0x4017, 0x00);
+        clock_apu(&mut apu, 3 + 29828);
+        assert!(apu.irq());
+        // Setting the inhibit flag clears the flag and keeps it clear
+        apu.write_register(0x4017, 0x40);
+        assert!(!apu.irq());
+        clock_apu(&mut apu, 2 * 29830);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_five_step_mode_has_no_irq() {
+        let mut apu = Apu::default();
+        apu.write_register(0x4017, 0x80);
+        clock_apu(&mut apu, 2 * 37282);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_write_jitter() {
+        fn cycles_until_irq(odd: bool) -> u32 {
+            let mut apu = Apu::default();
+            if odd {
+                apu.clock();
+            }
+            apu.write_register(0x4017, 0x00);
+            let mut cycles = 0;
+            while !apu.irq() {
+                apu.clock();
+                cycles += 1;
+            }
+            cycles
+        }
+        assert_eq!(cycles_until_irq(false), 3 + 29828);
+        assert_eq!(cycles_until_irq(true), 4 + 29828);
+    }
+
+    #[test]
+    fn test_irq_wired_to_cpu() {
+        let mut harness = TestHarness::new()
+            .with_program(
+                0xc000,
+                &[
+                    0x58, // CLI
+                    0x4c, 0x01, 0xc0, // JMP $C001
+                ],
+            )
+            .with_memory(0xfffe, &[0x00, 0xd0])
+            .with_memory(0xd000, &[0xea]);
+        harness.cpu.mem_write(0x4017, 0x00).unwrap();
+
+        // NOP in the handler, then BRK stops the run
+        assert_eq!(harness.run(20000).unwrap(), StopReason::Break);
+        let cpu = &harness.cpu;
+        assert_eq!(cpu.registers.pc, 0xd001);
+        assert!(cpu.registers.interrupt_disabled);
+        assert!(cpu.cycles > 29828);
+        assert!(cpu.apu.irq());
+    }
+
+    /// Source for a delay of exactly `cycles` CPU cycles, at least 5 of them
+    fn delay_source(cycles: u32) -> String {
+        // Each pass of the outer loop takes 1281 cycles, the whole loop one more
+        let outer = (cycles - 5) / 1281;
+        let mut rest = cycles;
+        let mut source = String::new();
+        if outer > 0 {
+            source.push_str(&format!(
+                "LDY #{}\nouter: LDX #$FF\ninner: DEX\nBNE inner\nDEY\nBNE outer\n",
+                outer
+            ));
+            rest -= outer * 1281 + 1;
+        }
+        if rest % 2 == 1 {
+            source.push_str("BIT $00\n");
+            rest -= 3;
+        }
+        source.push_str(&"NOP\n".repeat((rest / 2) as usize));
+        source
+    }
+
+    /// Write $4017 through the CPU on an odd or even cycle, wait `delay` cycles and
+    /// return the status read by an `LDA $4015`
+    fn status_after(odd: bool, delay: u32) -> u8 {
+        // BIT shifts the write by three cycles
+        let source = format!(
+            ".org $0600\n{}LDA #$00\nSTA $4017\ndelay:\n{}read: LDA $4015\nBRK\n",
+            if odd { "" } else { "BIT $00\n" },
+            delay_source(delay)
+        );
+        let mut harness = TestHarness::new()
+            .with_source(&source)
+            .unwrap()
+            .with_registers(|r| r.interrupt_disabled = true);
+        let delay_start = harness.symbol("delay").unwrap();
+        let read = harness.symbol("read").unwrap();
+        harness.run_until(delay_start, 10).unwrap();
+        // STA writes on its last cycle, one cycle before the delay starts
+        assert_eq!((harness.cpu.apu.cycle - 1) % 2 == 1, odd);
+        let start = harness.cpu.cycles;
+        harness.run_until(read, 100_000).unwrap();
+        assert_eq!(harness.cpu.cycles - start, delay as u64);
+        assert_eq!(harness.run(10).unwrap(), StopReason::Break);
+        harness.cpu.registers.a
+    }
+
+    #[test]
+    fn test_irq_flag_read_by_cpu() {
+        // The APU is clocked once after the write and three times before LDA reads on
+        // its last cycle, so the flag shows up after 3 + 29828 - 4 cycles, one more when odd
+        for (odd, edge) in [(false, 29827), (true, 29828)] {
+            assert_eq!(status_after(odd, edge - 1) & 0x40, 0x00, "odd {}", odd);
+            assert_eq!(status_after(odd, edge) & 0x40, 0x40, "odd {}", odd);
+            assert_eq!(status_after(odd, edge + 2) & 0x40, 0x40, "odd {}", odd);
+        }
+    }
+
+    #[test]
+    fn test_store_to_status_keeps_irq() {
+        // A store to $4015 doesn't read it first, so the frame interrupt stays pending
+        let mut harness = TestHarness::new()
+            .with_source(".org $0600\nLDA #$00\nSTA $4015\nBRK\n")
+            .unwrap()
+            .with_registers(|r| r.interrupt_disabled = true);
+        harness.cpu.mem_write(0x4017, 0x00).unwrap();
+        clock_apu(&mut harness.cpu.apu, 3 + 29828);
+        assert!(harness.cpu.apu.irq());
+        assert_eq!(harness.run(10).unwrap(), StopReason::Break);
+        assert!(harness.cpu.apu.irq());
+    }
+}
@@ -0,0 +1,65 @@
+use anyhow::Result;
+
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// reference: https://www.nesdev.org/wiki/APU_Length_Counter
+pub const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, //
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/// The length counter automatically silences a channel once its count reaches zero.
+/// It is clocked by the frame counter on every half frame.
+#[derive(Debug, Clone, Default)]
+pub struct LengthCounter {
+    pub enabled: bool,
+    pub halt: bool,
+    pub counter: u8,
+}
+
+impl LengthCounter {
+    #[inline]
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        // Clearing the enable bit in $4015 immediately zeroes the counter
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    /// Load the counter from the 5-bit index written to the upper bits of the channel's last register
+    #[inline]
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(index & 0x1f) as usize];
+        }
+    }
+
+    #[inline]
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_active(&self) -> bool {
+        self.counter > 0
+    }
+}
+
+impl Savestate for LengthCounter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.halt);
+        state.write_u8(self.counter);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.enabled = state.read_bool()?;
+        self.halt = state.read_bool()?;
+        self.counter = state.read_u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+
+pub const CHANNEL_COUNT: usize = 6;
+
+/// Every sound source that reaches the mixer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+    /// Cartridge expansion audio, mixed in linearly
+    Expansion,
+}
+
+impl Channel {
+    pub const ALL: [Channel; CHANNEL_COUNT] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Triangle,
+        Channel::Noise,
+        Channel::Dmc,
+        Channel::Expansion,
+    ];
+
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "dmc",
+            Channel::Expansion => "expansion",
+        }
+    }
+}
+
+impl FromStr for Channel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Channel::ALL
+            .into_iter()
+            .find(|channel| channel.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| anyhow!("unknown audio channel '{}'", s))
+    }
+}
+
+// reference: https://www.nesdev.org/wiki/APU_Mixer
+
+/// The NES mixes its channels through resistor networks rather than summing them,
+/// which makes the output nonlinear. The two lookup tables below are the standard
+/// approximation of those networks: one for the pulse pair and one for
+/// triangle, noise and DMC. The result is in the range 0.0..=1.0.
+#[derive(Debug, Clone)]
+pub struct Mixer {
+    pulse_table: [f32; 31],
+    tnd_table: [f32; 203],
+    muted: [bool; CHANNEL_COUNT],
+    soloed: [bool; CHANNEL_COUNT],
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        let mut pulse_table = [0f32; 31];
+        for (n, v) in pulse_table.iter_mut().enumerate().skip(1) {
+            *v = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+        let mut tnd_table = [0f32; 203];
+        for (n, v) in tnd_table.iter_mut().enumerate().skip(1) {
+            *v = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+        Self {
+            pulse_table,
+            tnd_table,
+            muted: [false; CHANNEL_COUNT],
+            soloed: [false; CHANNEL_COUNT],
+        }
+    }
+}
+
+impl Mixer {
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel as usize] = muted;
+    }
+
+    /// While any channel is soloed, only soloed channels are heard
+    pub fn set_soloed(&mut self, channel: Channel, soloed: bool) {
+        self.soloed[channel as usize] = soloed;
+    }
+
+    #[must_use]
+    pub fn is_audible(&self, channel: Channel) -> bool {
+        if self.soloed.iter().any(|&soloed| soloed) {
+            return self.soloed[channel as usize];
+        }
+        !self.muted[channel as usize]
+    }
+
+    #[inline]
+    fn gate(&self, channel: Channel, level: u8) -> u8 {
+        if self.is_audible(channel) {
+            level
+        } else {
+            0
+        }
+    }
+
+    /// Mix the 4-bit channel outputs and the 7-bit DMC level into a single sample
+    #[must_use]
+    pub fn mix(&self, pulse1: u8, pulse2: u8, triangle: u8, noise: u8, dmc: u8) -> f32 {
+        let pulse1 = self.gate(Channel::Pulse1, pulse1);
+        let pulse2 = self.gate(Channel::Pulse2, pulse2);
+        let triangle = self.gate(Channel::Triangle, triangle);
+        let noise = self.gate(Channel::Noise, noise);
+        let dmc = self.gate(Channel::Dmc, dmc);
+
+        let pulse_out = self.pulse_table[(pulse1 + pulse2) as usize];
+        let tnd_index = 3 * triangle as usize + 2 * noise as usize + dmc as usize;
+        let tnd_out = self.tnd_table[tnd_index];
+        pulse_out + tnd_out
+    }
+
+    /// The level a single channel would produce if it was the only one playing,
+    /// ignoring mute and solo
+    #[must_use]
+    pub fn mix_channel(&self, channel: Channel, level: u8) -> f32 {
+        match channel {
+            Channel::Pulse1 | Channel::Pulse2 => self.pulse_table[level as usize],
+            Channel::Triangle => self.tnd_table[3 * level as usize],
+            Channel::Noise => self.tnd_table[2 * level as usize],
+            Channel::Dmc => self.tnd_table[level as usize],
+            // Expansion audio does not go through the resistor networks
+            Channel::Expansion => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{nes_with_program, PULSE_TONE_PROGRAM};
+
+    #[test]
+    fn test_lookup_tables() {
+        let mixer = Mixer::default();
+        assert_eq!(mixer.mix(0, 0, 0, 0, 0), 0.0);
+        assert!((mixer.mix(15, 15, 0, 0, 0) - 0.2575).abs() < 0.001);
+        assert!((mixer.mix(0, 0, 15, 15, 0) - 0.3857).abs() < 0.001);
+        assert!((mixer.mix(0, 0, 15, 15, 127) - 0.7415).abs() < 0.001);
+        // Nonlinear: two channels together are quieter than twice one channel
+        assert!(mixer.mix(15, 15, 0, 0, 0) < 2.0 * mixer.mix(15, 0, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_channel_names() {
+        assert_eq!("Pulse1".parse::<Channel>().unwrap(), Channel::Pulse1);
+        assert!("square".parse::<Channel>().is_err());
+    }
+
+    #[test]
+    fn test_solo_and_mute() {
+        let mut nes = nes_with_program(&PULSE_TONE_PROGRAM);
+        nes.set_audio_output(true);
+        nes.cpu.apu.mixer.set_soloed(Channel::Triangle, true);
+        nes.run_frame().unwrap();
+        nes.run_frame().unwrap();
+        let samples = nes.audio_samples().unwrap();
+        assert!(samples[samples.len() / 2..].iter().all(|&s| s.abs() < 1e-3));
+
+        nes.cpu.apu.mixer.set_soloed(Channel::Triangle, false);
+        nes.run_frame().unwrap();
+        assert!(nes.audio_samples().unwrap().iter().any(|&s| s.abs() > 0.01));
+
+        nes.cpu.apu.mixer.set_muted(Channel::Pulse1, true);
+        assert!(!nes.cpu.apu.mixer.is_audible(Channel::Pulse1));
+    }
+}
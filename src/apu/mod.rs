@@ -0,0 +1,298 @@
+mod dmc;
+mod envelope;
+mod frame_counter;
+mod length_counter;
+mod mixer;
+mod noise;
+mod pulse;
+mod triangle;
+
+pub use dmc::*;
+pub use envelope::*;
+pub use frame_counter::*;
+pub use length_counter::*;
+pub use mixer::*;
+pub use noise::*;
+pub use pulse::*;
+pub use triangle::*;
+
+use anyhow::Result;
+
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// # APU
+/// The APU sits at $4000-$4017 in the CPU's address space and generates sound from
+/// its channels. Every call to `clock` advances it by one CPU cycle and appends one
+/// mixed sample to the sample stream, so the stream runs at the CPU clock rate
+/// (~1.79 MHz on NTSC) and has to be resampled before it reaches the host.
+/// reference: https://www.nesdev.org/wiki/APU
+#[derive(Debug, Clone)]
+pub struct Apu {
+    pub region: Region,
+    pub pulse1: Pulse,
+    pub pulse2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: Dmc,
+    pub frame_counter: FrameCounter,
+    pub mixer: Mixer,
+    /// Output of cartridge expansion audio, set by mappers with their own sound hardware
+    pub expansion: f32,
+    /// Number of CPU cycles the APU has been clocked for
+    pub cycle: u64,
+    samples_enabled: bool,
+    samples: Vec<f32>,
+    stems_enabled: bool,
+    stem_samples: [Vec<f32>; CHANNEL_COUNT],
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new(Region::default())
+    }
+}
+
+impl Apu {
+    pub fn new(region: Region) -> Self {
+        Self {
+            region,
+            pulse1: Pulse::new(PulseChannel::One),
+            pulse2: Pulse::new(PulseChannel::Two),
+            triangle: Triangle::default(),
+            noise: Noise::new(region),
+            dmc: Dmc::new(region),
+            frame_counter: FrameCounter::new(region),
+            mixer: Mixer::default(),
+            expansion: 0.0,
+            cycle: 0,
+            samples_enabled: false,
+            samples: Vec::new(),
+            stems_enabled: false,
+            stem_samples: Default::default(),
+        }
+    }
+
+    /// Return every channel to its power-up state, keeping the mixer and sample settings
+    pub fn power_on(&mut self) {
+        let mut apu = Self::new(self.region);
+        apu.mixer = std::mem::take(&mut self.mixer);
+        apu.samples_enabled = self.samples_enabled;
+        apu.samples = std::mem::take(&mut self.samples);
+        apu.stems_enabled = self.stems_enabled;
+        apu.stem_samples = std::mem::take(&mut self.stem_samples);
+        *self = apu;
+    }
+
+    pub fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4000 => self.pulse1.write_control(value),
+            0x4001 => self.pulse1.write_sweep(value),
+            0x4002 => self.pulse1.write_timer_lo(value),
+            0x4003 => self.pulse1.write_timer_hi(value),
+            0x4004 => self.pulse2.write_control(value),
+            0x4005 => self.pulse2.write_sweep(value),
+            0x4006 => self.pulse2.write_timer_lo(value),
+            0x4007 => self.pulse2.write_timer_hi(value),
+            0x4008 => self.triangle.write_linear_counter(value),
+            0x400A => self.triangle.write_timer_lo(value),
+            0x400B => self.triangle.write_timer_hi(value),
+            0x400C => self.noise.write_control(value),
+            0x400E => self.noise.write_period(value),
+            0x400F => self.noise.write_length(value),
+            0x4010 => self.dmc.write_control(value),
+            0x4011 => self.dmc.write_output_level(value),
+            0x4012 => self.dmc.write_sample_address(value),
+            0x4013 => self.dmc.write_sample_length(value),
+            0x4015 => self.write_status(value),
+            0x4017 => self.frame_counter.write(value, self.cycle % 2 == 1),
+            _ => {}
+        }
+    }
+
+    /// $4015 write: ---D NT21, enables or silences each channel
+    fn write_status(&mut self, value: u8) {
+        self.pulse1.length_counter.set_enabled(value & 0x01 != 0);
+        self.pulse2.length_counter.set_enabled(value & 0x02 != 0);
+        self.triangle.length_counter.set_enabled(value & 0x04 != 0);
+        self.noise.length_counter.set_enabled(value & 0x08 != 0);
+        self.dmc.set_enabled(value & 0x10 != 0);
+    }
+
+    /// $4015 read: IF-D NT21, reports which channels are still running and both interrupts.
+    /// Reading clears the frame interrupt flag.
+    pub fn read_status(&mut self) -> u8 {
+        let status = (self.pulse1.length_counter.is_active() as u8)
+            | ((self.pulse2.length_counter.is_active() as u8) << 1)
+            | ((self.triangle.length_counter.is_active() as u8) << 2)
+            | ((self.noise.length_counter.is_active() as u8) << 3)
+            | ((self.dmc.is_active() as u8) << 4)
+            | ((self.frame_counter.irq_flag as u8) << 6)
+            | ((self.dmc.irq_flag as u8) << 7);
+        self.frame_counter.irq_flag = false;
+        status
+    }
+
+    /// State of the APU's IRQ output, which is wired to the CPU's IRQ line
+    #[inline]
+    #[must_use]
+    pub fn irq(&self) -> bool {
+        self.frame_counter.irq_flag || self.dmc.irq_flag
+    }
+
+    /// Advance the APU by one CPU cycle
+    pub fn clock(&mut self) {
+        match self.frame_counter.clock() {
+            FrameStep::Quarter => self.clock_quarter_frame(),
+            FrameStep::Half => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            FrameStep::None => {}
+        }
+
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        // The pulse timers are clocked on every APU cycle, which is every second CPU cycle
+        if self.cycle % 2 == 1 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.cycle += 1;
+        if self.samples_enabled {
+            self.samples.push(self.output());
+        }
+        if self.stems_enabled {
+            for channel in Channel::ALL {
+                let sample = self.channel_output(channel);
+                self.stem_samples[channel as usize].push(sample);
+            }
+        }
+    }
+
+    /// Envelopes and the triangle's linear counter
+    pub fn clock_quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.triangle.clock_linear_counter();
+        self.noise.envelope.clock();
+    }
+
+    /// Length counters and sweep units
+    pub fn clock_half_frame(&mut self) {
+        self.pulse1.length_counter.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.length_counter.clock();
+        self.pulse2.clock_sweep();
+        self.triangle.length_counter.clock();
+        self.noise.length_counter.clock();
+    }
+
+    /// The current mixed output level, with muted channels left out
+    #[must_use]
+    pub fn output(&self) -> f32 {
+        let mut output = self.mixer.mix(
+            self.pulse1.output(),
+            self.pulse2.output(),
+            self.triangle.output(),
+            self.noise.output(),
+            self.dmc.output(),
+        );
+        if self.mixer.is_audible(Channel::Expansion) {
+            output += self.expansion;
+        }
+        output
+    }
+
+    /// The current output level of a single channel on its own
+    #[must_use]
+    pub fn channel_output(&self, channel: Channel) -> f32 {
+        match channel {
+            Channel::Pulse1 => self.mixer.mix_channel(channel, self.pulse1.output()),
+            Channel::Pulse2 => self.mixer.mix_channel(channel, self.pulse2.output()),
+            Channel::Triangle => self.mixer.mix_channel(channel, self.triangle.output()),
+            Channel::Noise => self.mixer.mix_channel(channel, self.noise.output()),
+            Channel::Dmc => self.mixer.mix_channel(channel, self.dmc.output()),
+            Channel::Expansion => self.expansion,
+        }
+    }
+
+    /// Produce the mixed sample stream, one sample per CPU cycle. Off by default, since
+    /// nobody would take the samples and they would pile up at the CPU clock rate.
+    pub fn set_samples_enabled(&mut self, enabled: bool) {
+        self.samples_enabled = enabled;
+        if !enabled {
+            self.samples = Vec::new();
+        }
+    }
+
+    /// Take the samples produced since the last call, one per CPU cycle
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    /// Also produce a separate sample stream per channel. Stems ignore mute and solo.
+    pub fn set_stems_enabled(&mut self, enabled: bool) {
+        self.stems_enabled = enabled;
+        if !enabled {
+            self.stem_samples = Default::default();
+        }
+    }
+
+    /// Take the per-channel samples produced since the last call, indexed by `Channel`
+    pub fn take_stem_samples(&mut self) -> [Vec<f32>; CHANNEL_COUNT] {
+        std::mem::take(&mut self.stem_samples)
+    }
+}
+
+impl Savestate for Apu {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.pulse1.save_state(state);
+        self.pulse2.save_state(state);
+        self.triangle.save_state(state);
+        self.noise.save_state(state);
+        self.dmc.save_state(state);
+        self.frame_counter.save_state(state);
+        state.write_f32(self.expansion);
+        state.write_u64(self.cycle);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.pulse1.load_state(state)?;
+        self.pulse2.load_state(state)?;
+        self.triangle.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.dmc.load_state(state)?;
+        self.frame_counter.load_state(state)?;
+        self.expansion = state.read_f32()?;
+        self.cycle = state.read_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_stream() {
+        let mut apu = Apu::default();
+        apu.set_samples_enabled(true);
+        for _ in 0..64 {
+            apu.clock();
+        }
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), 64);
+        assert!(apu.take_samples().is_empty());
+    }
+
+    #[test]
+    fn test_samples_are_opt_in() {
+        let mut apu = Apu::default();
+        for _ in 0..64 {
+            apu.clock();
+        }
+        assert!(apu.take_samples().is_empty());
+    }
+}
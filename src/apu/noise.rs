@@ -0,0 +1,146 @@
+use anyhow::Result;
+
+use crate::apu::envelope::Envelope;
+use crate::apu::length_counter::LengthCounter;
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// reference: https://www.nesdev.org/wiki/APU_Noise
+// Timer periods in CPU cycles
+pub const NOISE_PERIOD_NTSC: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+pub const NOISE_PERIOD_PAL: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+/// Pseudo-random noise generated by a 15-bit linear feedback shift register.
+#[derive(Debug, Clone)]
+pub struct Noise {
+    pub region: Region,
+    /// Mode flag: feedback from bit 6 instead of bit 1, which gives a short 93-step
+    /// (or 31-step) metallic sequence instead of the 32767-step one
+    pub mode: bool,
+    pub shift_register: u16,
+    pub timer_period: u16,
+    pub timer: u16,
+    pub envelope: Envelope,
+    pub length_counter: LengthCounter,
+}
+
+impl Noise {
+    pub fn new(region: Region) -> Self {
+        Self {
+            region,
+            mode: false,
+            // On power-up, the shift register is loaded with the value 1
+            shift_register: 1,
+            timer_period: NOISE_PERIOD_NTSC[0],
+            timer: 0,
+            envelope: Envelope::default(),
+            length_counter: LengthCounter::default(),
+        }
+    }
+
+    /// $400C: --LC VVVV
+    pub fn write_control(&mut self, value: u8) {
+        self.length_counter.halt = value & 0x20 != 0;
+        self.envelope.write(value);
+    }
+
+    /// $400E: M--- PPPP
+    pub fn write_period(&mut self, value: u8) {
+        self.mode = value & 0x80 != 0;
+        let table = match self.region {
+            Region::Ntsc => &NOISE_PERIOD_NTSC,
+            Region::Pal => &NOISE_PERIOD_PAL,
+        };
+        self.timer_period = table[(value & 0x0f) as usize];
+    }
+
+    /// $400F: LLLL L---
+    pub fn write_length(&mut self, value: u8) {
+        self.length_counter.load(value >> 3);
+        self.envelope.start = true;
+    }
+
+    /// Clocked on every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            self.clock_shift_register();
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_shift_register(&mut self) {
+        let tap = if self.mode { 6 } else { 1 };
+        let feedback = (self.shift_register & 1) ^ ((self.shift_register >> tap) & 1);
+        self.shift_register >>= 1;
+        self.shift_register |= feedback << 14;
+    }
+
+    #[must_use]
+    pub fn output(&self) -> u8 {
+        if !self.length_counter.is_active() || self.shift_register & 1 == 1 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+}
+
+impl Savestate for Noise {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.mode);
+        state.write_u16(self.shift_register);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+        self.envelope.save_state(state);
+        self.length_counter.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.mode = state.read_bool()?;
+        self.shift_register = state.read_u16()?;
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.envelope.load_state(state)?;
+        self.length_counter.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lfsr_modes() {
+        fn sequence_length(mode: bool) -> usize {
+            let mut noise = Noise::new(Region::Ntsc);
+            noise.mode = mode;
+            noise.timer_period = 1;
+            let start = noise.shift_register;
+            let mut steps = 0;
+            loop {
+                noise.clock_timer();
+                steps += 1;
+                if noise.shift_register == start {
+                    return steps;
+                }
+            }
+        }
+        assert_eq!(sequence_length(false), 32767);
+        assert_eq!(sequence_length(true), 93);
+    }
+
+    #[test]
+    fn test_period_tables() {
+        let mut ntsc = Noise::new(Region::Ntsc);
+        let mut pal = Noise::new(Region::Pal);
+        ntsc.write_period(0x0f);
+        pal.write_period(0x0f);
+        assert_eq!(ntsc.timer_period, 4068);
+        assert_eq!(pal.timer_period, 3778);
+    }
+}
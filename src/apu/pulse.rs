@@ -0,0 +1,189 @@
+use anyhow::Result;
+
+use crate::apu::envelope::Envelope;
+use crate::apu::length_counter::LengthCounter;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// reference: https://www.nesdev.org/wiki/APU_Pulse
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0], // 12.5%
+    [0, 1, 1, 0, 0, 0, 0, 0], // 25%
+    [0, 1, 1, 1, 1, 0, 0, 0], // 50%
+    [1, 0, 0, 1, 1, 1, 1, 1], // 25% negated
+];
+
+/// The two pulse channels only differ in how the sweep unit negates the period:
+/// pulse 1 uses ones' complement, pulse 2 uses two's complement.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PulseChannel {
+    One,
+    Two,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Sweep {
+    pub enabled: bool,
+    pub period: u8,
+    pub negate: bool,
+    pub shift: u8,
+    pub reload: bool,
+    pub divider: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct Pulse {
+    pub channel: PulseChannel,
+    pub duty: u8,
+    pub duty_step: u8,
+    pub timer_period: u16,
+    pub timer: u16,
+    pub sweep: Sweep,
+    pub envelope: Envelope,
+    pub length_counter: LengthCounter,
+}
+
+impl Pulse {
+    pub fn new(channel: PulseChannel) -> Self {
+        Self {
+            channel,
+            duty: 0,
+            duty_step: 0,
+            timer_period: 0,
+            timer: 0,
+            sweep: Sweep::default(),
+            envelope: Envelope::default(),
+            length_counter: LengthCounter::default(),
+        }
+    }
+
+    /// $4000/$4004: DDLC VVVV
+    pub fn write_control(&mut self, value: u8) {
+        self.duty = value >> 6;
+        self.length_counter.halt = value & 0x20 != 0;
+        self.envelope.write(value);
+    }
+
+    /// $4001/$4005: EPPP NSSS
+    pub fn write_sweep(&mut self, value: u8) {
+        self.sweep.enabled = value & 0x80 != 0;
+        self.sweep.period = (value >> 4) & 0x07;
+        self.sweep.negate = value & 0x08 != 0;
+        self.sweep.shift = value & 0x07;
+        self.sweep.reload = true;
+    }
+
+    /// $4002/$4006: timer low 8 bits
+    pub fn write_timer_lo(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0x0700) | value as u16;
+    }
+
+    /// $4003/$4007: LLLL LHHH
+    pub fn write_timer_hi(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0x00ff) | (((value & 0x07) as u16) << 8);
+        self.length_counter.load(value >> 3);
+        // The sequencer is restarted at the first value of the current sequence
+        self.duty_step = 0;
+        self.envelope.start = true;
+    }
+
+    /// Clocked on every APU cycle (every second CPU cycle)
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.duty_step = (self.duty_step + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// The period the sweep unit is continuously computing, even when disabled
+    #[must_use]
+    pub fn sweep_target_period(&self) -> u16 {
+        let change = self.timer_period >> self.sweep.shift;
+        if self.sweep.negate {
+            match self.channel {
+                PulseChannel::One => self.timer_period.wrapping_sub(change).wrapping_sub(1),
+                PulseChannel::Two => self.timer_period.wrapping_sub(change),
+            }
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    #[inline]
+    fn is_sweep_muting(&self) -> bool {
+        self.timer_period < 8 || (!self.sweep.negate && self.sweep_target_period() > 0x7ff)
+    }
+
+    /// Clocked by the frame counter on every half frame
+    pub fn clock_sweep(&mut self) {
+        if self.sweep.divider == 0
+            && self.sweep.enabled
+            && self.sweep.shift > 0
+            && !self.is_sweep_muting()
+        {
+            self.timer_period = self.sweep_target_period();
+        }
+
+        if self.sweep.divider == 0 || self.sweep.reload {
+            self.sweep.divider = self.sweep.period;
+            self.sweep.reload = false;
+        } else {
+            self.sweep.divider -= 1;
+        }
+    }
+
+    #[must_use]
+    pub fn output(&self) -> u8 {
+        if !self.length_counter.is_active()
+            || self.is_sweep_muting()
+            || DUTY_TABLE[self.duty as usize][self.duty_step as usize] == 0
+        {
+            return 0;
+        }
+        self.envelope.output()
+    }
+}
+
+impl Savestate for Sweep {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u8(self.period);
+        state.write_bool(self.negate);
+        state.write_u8(self.shift);
+        state.write_bool(self.reload);
+        state.write_u8(self.divider);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.enabled = state.read_bool()?;
+        self.period = state.read_u8()?;
+        self.negate = state.read_bool()?;
+        self.shift = state.read_u8()?;
+        self.reload = state.read_bool()?;
+        self.divider = state.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Pulse {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.duty);
+        state.write_u8(self.duty_step);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+        self.sweep.save_state(state);
+        self.envelope.save_state(state);
+        self.length_counter.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.duty = state.read_u8()?;
+        self.duty_step = state.read_u8()?;
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.sweep.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.length_counter.load_state(state)
+    }
+}
@@ -0,0 +1,155 @@
+use anyhow::Result;
+
+use crate::apu::length_counter::LengthCounter;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// reference: https://www.nesdev.org/wiki/APU_Triangle
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, //
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+/// The triangle channel has no volume control. Its sequencer is gated by both the
+/// length counter and the linear counter, a second, finer-grained duration counter.
+#[derive(Debug, Clone, Default)]
+pub struct Triangle {
+    /// Control flag, also halts the length counter
+    pub control: bool,
+    pub linear_reload_value: u8,
+    pub linear_reload: bool,
+    pub linear_counter: u8,
+    pub timer_period: u16,
+    pub timer: u16,
+    pub step: u8,
+    pub length_counter: LengthCounter,
+}
+
+impl Triangle {
+    /// $4008: CRRR RRRR
+    pub fn write_linear_counter(&mut self, value: u8) {
+        self.control = value & 0x80 != 0;
+        self.length_counter.halt = self.control;
+        self.linear_reload_value = value & 0x7f;
+    }
+
+    /// $400A: timer low 8 bits
+    pub fn write_timer_lo(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0x0700) | value as u16;
+    }
+
+    /// $400B: LLLL LHHH
+    pub fn write_timer_hi(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0x00ff) | (((value & 0x07) as u16) << 8);
+        self.length_counter.load(value >> 3);
+        self.linear_reload = true;
+    }
+
+    /// Periods below 2 produce frequencies above 20kHz that real hardware smooths into a
+    /// constant level. Rather than alias them into audible noise the sequencer is halted,
+    /// which silences the channel without the pop of dropping to zero.
+    #[inline]
+    #[must_use]
+    pub fn is_ultrasonic(&self) -> bool {
+        self.timer_period < 2
+    }
+
+    /// Clocked on every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length_counter.is_active() && self.linear_counter > 0 && !self.is_ultrasonic() {
+                self.step = (self.step + 1) & 0x1f;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// Clocked by the frame counter on every quarter frame
+    pub fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    /// Silencing the triangle only stops the sequencer, so the output holds its last value
+    #[inline]
+    #[must_use]
+    pub fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.step as usize]
+    }
+}
+
+impl Savestate for Triangle {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.control);
+        state.write_u8(self.linear_reload_value);
+        state.write_bool(self.linear_reload);
+        state.write_u8(self.linear_counter);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+        state.write_u8(self.step);
+        self.length_counter.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.control = state.read_bool()?;
+        self.linear_reload_value = state.read_u8()?;
+        self.linear_reload = state.read_bool()?;
+        self.linear_counter = state.read_u8()?;
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.step = state.read_u8()?;
+        self.length_counter.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::apu::Apu;
+
+    #[test]
+    fn test_ultrasonic_period_halts_sequencer() {
+        let mut apu = Apu::default();
+        apu.write_register(0x4015, 0x04);
+        apu.write_register(0x4008, 0xff); // control set, linear counter reload 127
+        apu.write_register(0x400A, 0x01); // timer period 1
+        apu.write_register(0x400B, 0x08);
+        apu.clock_quarter_frame();
+
+        let step = apu.triangle.step;
+        for _ in 0..100 {
+            apu.clock();
+        }
+        // The sequencer is halted rather than aliasing
+        assert_eq!(apu.triangle.step, step);
+
+        apu.write_register(0x400A, 0x02);
+        for _ in 0..100 {
+            apu.clock();
+        }
+        assert_ne!(apu.triangle.step, step);
+    }
+
+    #[test]
+    fn test_linear_counter() {
+        let mut apu = Apu::default();
+        apu.write_register(0x4015, 0x04);
+        apu.write_register(0x4008, 0x02); // control clear, linear counter reload 2
+        apu.write_register(0x400A, 0x10);
+        apu.write_register(0x400B, 0x08);
+
+        apu.clock_quarter_frame();
+        assert_eq!(apu.triangle.linear_counter, 2);
+        apu.clock_quarter_frame();
+        apu.clock_quarter_frame();
+        assert_eq!(apu.triangle.linear_counter, 0);
+        assert_eq!(apu.read_status() & 0x04, 0x04);
+    }
+}
@@ -0,0 +1,79 @@
+use std::f64::consts::PI;
+
+// reference: https://www.nesdev.org/wiki/APU_Mixer
+// The console's audio path ends in two first-order high-pass filters and one
+// first-order low-pass filter, which remove the DC offset of the mixer and
+// soften the harshest edges.
+
+#[derive(Debug, Clone)]
+pub struct HighPassFilter {
+    alpha: f32,
+    prev_input: f32,
+    prev_output: f32,
+}
+
+impl HighPassFilter {
+    pub fn new(cutoff: f64, sample_rate: f64) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        Self {
+            alpha: (rc / (rc + dt)) as f32,
+            prev_input: 0.0,
+            prev_output: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        let output = self.alpha * (self.prev_output + input - self.prev_input);
+        self.prev_input = input;
+        self.prev_output = output;
+        output
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LowPassFilter {
+    alpha: f32,
+    prev_output: f32,
+}
+
+impl LowPassFilter {
+    pub fn new(cutoff: f64, sample_rate: f64) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        Self {
+            alpha: (dt / (rc + dt)) as f32,
+            prev_output: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        self.prev_output += self.alpha * (input - self.prev_output);
+        self.prev_output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_high_pass_removes_dc() {
+        let mut high_pass = HighPassFilter::new(90.0, 44_100.0);
+        let mut out = 0.0;
+        for _ in 0..44_100 {
+            out = high_pass.process(1.0);
+        }
+        assert!(out.abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_low_pass_keeps_dc() {
+        let mut low_pass = LowPassFilter::new(14_000.0, 44_100.0);
+        let mut out = 0.0;
+        for _ in 0..100 {
+            out = low_pass.process(1.0);
+        }
+        assert!((out - 1.0).abs() < 1e-3);
+    }
+}
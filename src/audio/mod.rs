@@ -0,0 +1,152 @@
+mod blip;
+mod filter;
+mod recorder;
+mod wav;
+
+pub use blip::*;
+pub use filter::*;
+pub use recorder::*;
+pub use wav::*;
+
+/// Largest relative change of the resampling ratio a frontend can request.
+/// Half a percent is far below what is audible as a pitch change.
+pub const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+/// Turns the APU's per-CPU-cycle sample stream into audio at the host's sample rate.
+/// The stream is band-limited and resampled first, then run through the same
+/// high-pass and low-pass filters as the console's own output stage.
+#[derive(Debug, Clone)]
+pub struct AudioPipeline {
+    sample_rate: u32,
+    blip: BlipBuffer,
+    high_pass_90: HighPassFilter,
+    high_pass_440: HighPassFilter,
+    low_pass_14k: LowPassFilter,
+    resampled: Vec<f32>,
+    output: Vec<f32>,
+}
+
+impl AudioPipeline {
+    pub fn new(clock_rate: f64, sample_rate: u32) -> Self {
+        let rate = sample_rate as f64;
+        Self {
+            sample_rate,
+            blip: BlipBuffer::new(clock_rate, rate),
+            high_pass_90: HighPassFilter::new(90.0, rate),
+            high_pass_440: HighPassFilter::new(440.0, rate),
+            low_pass_14k: LowPassFilter::new(14_000.0, rate),
+            resampled: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Feed samples produced at the CPU clock rate
+    pub fn push_samples(&mut self, samples: &[f32]) {
+        for &sample in samples {
+            self.blip.push(sample);
+        }
+
+        self.blip.read_samples(&mut self.resampled);
+        for sample in self.resampled.drain(..) {
+            let sample = self.high_pass_90.process(sample);
+            let sample = self.high_pass_440.process(sample);
+            let sample = self.low_pass_14k.process(sample);
+            self.output.push(sample);
+        }
+    }
+
+    /// Nudge the resampling ratio, clamped to +/- `MAX_RATE_ADJUSTMENT`.
+    /// Positive values produce more samples per emulated second.
+    pub fn set_rate_adjustment(&mut self, adjustment: f64) {
+        self.blip
+            .set_adjustment(adjustment.clamp(-MAX_RATE_ADJUSTMENT, MAX_RATE_ADJUSTMENT));
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn rate_adjustment(&self) -> f64 {
+        self.blip.adjustment()
+    }
+
+    /// Dynamic rate control: steer the host's audio buffer towards half full.
+    /// An emptier buffer speeds up sample production slightly and a fuller one slows
+    /// it down, so the buffer never under- or overflows and no samples are dropped.
+    /// reference: https://github.com/libretro/docs/blob/master/archive/ratecontrol.pdf
+    pub fn adjust_for_fill_level(&mut self, buffered: usize, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        let fill = buffered.min(capacity) as f64 / capacity as f64;
+        self.set_rate_adjustment(MAX_RATE_ADJUSTMENT * (1.0 - 2.0 * fill));
+    }
+
+    /// Take the samples produced at the host rate since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::region::Region;
+    use crate::test_util::{nes_with_program, PULSE_TONE_PROGRAM};
+
+    const NTSC_CLOCK_RATE: f64 = Region::Ntsc.cpu_clock_rate();
+
+    #[test]
+    fn test_resample_rate() {
+        // One NTSC frame worth of CPU cycles
+        for sample_rate in [44_100, 48_000] {
+            let mut pipeline = AudioPipeline::new(NTSC_CLOCK_RATE, sample_rate);
+            pipeline.push_samples(&vec![0.25; 29781]);
+            let expected = 29781.0 * sample_rate as f64 / NTSC_CLOCK_RATE;
+            assert!((pipeline.take_samples().len() as f64 - expected).abs() <= 1.0);
+        }
+    }
+
+    #[test]
+    fn test_dynamic_rate_control() {
+        fn produced(fill: usize) -> usize {
+            let mut pipeline = AudioPipeline::new(NTSC_CLOCK_RATE, 44_100);
+            pipeline.adjust_for_fill_level(fill, 4096);
+            pipeline.push_samples(&vec![0.0; 178977]);
+            pipeline.take_samples().len()
+        }
+        // An empty buffer asks for more samples, a full one for fewer
+        assert!(produced(0) > produced(2048));
+        assert!(produced(4096) < produced(2048));
+        assert!((produced(0) - produced(2048)) as f64 <= 4410.0 * MAX_RATE_ADJUSTMENT + 1.0);
+    }
+
+    #[test]
+    fn test_rate_adjustment_is_clamped() {
+        let mut pipeline = AudioPipeline::new(NTSC_CLOCK_RATE, 44_100);
+        pipeline.set_rate_adjustment(1.0);
+        assert_eq!(pipeline.rate_adjustment(), MAX_RATE_ADJUSTMENT);
+    }
+
+    #[test]
+    fn test_live_output_is_opt_in() {
+        // Without a consumer nothing is buffered
+        let mut nes = nes_with_program(&PULSE_TONE_PROGRAM);
+        nes.run_frame().unwrap();
+        assert!(nes.cpu.apu.take_samples().is_empty());
+        assert!(nes.audio_samples().is_err());
+
+        nes.set_audio_output(true);
+        nes.run_frame().unwrap();
+        assert!(nes.audio_samples().unwrap().len() > 700);
+        nes.set_audio_output(false);
+        nes.run_frame().unwrap();
+        assert!(nes.cpu.apu.take_samples().is_empty());
+    }
+}
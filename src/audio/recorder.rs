@@ -0,0 +1,137 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::apu::{Channel, CHANNEL_COUNT};
+use crate::audio::{AudioPipeline, WavWriter};
+
+struct Track {
+    pipeline: AudioPipeline,
+    writer: WavWriter<BufWriter<File>>,
+}
+
+impl Track {
+    fn create(path: &Path, clock_rate: f64, sample_rate: u32) -> Result<Self> {
+        Ok(Self {
+            pipeline: AudioPipeline::new(clock_rate, sample_rate),
+            writer: WavWriter::create(path, sample_rate)?,
+        })
+    }
+
+    fn record(&mut self, samples: &[f32]) -> Result<()> {
+        self.pipeline.push_samples(samples);
+        self.writer.write_samples(&self.pipeline.take_samples())
+    }
+}
+
+/// Records the mixed APU output to a WAV file, and optionally every channel to its own stem.
+/// Each track has its own resampler, so the recording is unaffected by the rate
+/// adjustments a frontend makes to the live output.
+pub struct AudioRecorder {
+    mix: Track,
+    stems: Vec<(Channel, Track)>,
+}
+
+impl AudioRecorder {
+    pub fn create(path: &Path, clock_rate: f64, sample_rate: u32, stems: bool) -> Result<Self> {
+        let mix = Track::create(path, clock_rate, sample_rate)?;
+        let stems = if stems {
+            Channel::ALL
+                .into_iter()
+                .map(|channel| {
+                    let path = Self::stem_path(path, channel);
+                    Ok((channel, Track::create(&path, clock_rate, sample_rate)?))
+                })
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            Vec::new()
+        };
+        Ok(Self { mix, stems })
+    }
+
+    /// `song.wav` records the pulse 1 stem to `song.pulse1.wav`
+    #[must_use]
+    pub fn stem_path(path: &Path, channel: Channel) -> PathBuf {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        path.with_file_name(format!("{}.{}.wav", stem, channel.name()))
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn records_stems(&self) -> bool {
+        !self.stems.is_empty()
+    }
+
+    /// Record samples at the CPU clock rate
+    pub fn record(&mut self, mix: &[f32], stems: &[Vec<f32>; CHANNEL_COUNT]) -> Result<()> {
+        self.mix.record(mix)?;
+        for (channel, track) in self.stems.iter_mut() {
+            track.record(&stems[*channel as usize])?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        self.mix.writer.finish()?;
+        for (_, track) in self.stems {
+            track.writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use super::*;
+    use crate::test_util::{nes_with_program, PULSE_TONE_PROGRAM};
+
+    fn wav_data(path: &Path) -> Vec<i16> {
+        let bytes = fs::read(path).unwrap();
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(&bytes[8..12], b"WAVE");
+        assert_eq!(
+            LittleEndian::read_u32(&bytes[4..8]) as usize,
+            bytes.len() - 8
+        );
+        let data_size = LittleEndian::read_u32(&bytes[40..44]) as usize;
+        assert_eq!(data_size, bytes.len() - 44);
+        bytes[44..].chunks(2).map(LittleEndian::read_i16).collect()
+    }
+
+    #[test]
+    fn test_recording_with_stems() {
+        let dir = std::env::temp_dir().join(format!("nes-wav-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("song.wav");
+
+        let mut nes = nes_with_program(&PULSE_TONE_PROGRAM);
+        nes.start_recording(&path, true).unwrap();
+        for _ in 0..10 {
+            assert!(nes.run_frame().unwrap());
+        }
+        nes.stop_recording().unwrap();
+
+        let mix = wav_data(&path);
+        // 10 frames at 60Hz is a sixth of a second
+        assert!((mix.len() as i64 - 44_100 / 6).abs() < 100);
+        assert!(mix.iter().any(|&s| s.abs() > 1000));
+
+        let pulse1 = wav_data(&AudioRecorder::stem_path(&path, Channel::Pulse1));
+        let triangle = wav_data(&AudioRecorder::stem_path(&path, Channel::Triangle));
+        assert_eq!(pulse1.len(), mix.len());
+        assert!(pulse1.iter().any(|&s| s.abs() > 1000));
+        // The idle triangle only contributes a DC offset that the high-pass filters remove
+        assert!(triangle[triangle.len() / 2..].iter().all(|&s| s.abs() < 10));
+        for channel in Channel::ALL {
+            assert!(AudioRecorder::stem_path(&path, channel).exists());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use byteorder::{LittleEndian, WriteBytesExt};
+
+const HEADER_SIZE: u32 = 44;
+/// The RIFF size field is 32 bits and counts everything after itself
+const MAX_DATA_SIZE: u64 = u32::MAX as u64 - (HEADER_SIZE as u64 - 8);
+
+/// Writes mono 16-bit PCM WAV files.
+/// The RIFF and data chunk sizes are only known at the end, so they are patched in by `finish`.
+/// reference: http://soundfile.sapp.org/doc/WaveFormat/
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    samples_written: u64,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create(path: &Path, sample_rate: u32) -> Result<Self> {
+        let file = File::create(path)?;
+        Self::new(BufWriter::new(file), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, sample_rate: u32) -> Result<Self> {
+        let channels = 1u16;
+        let bits_per_sample = 16u16;
+        let block_align = channels * bits_per_sample / 8;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_u32::<LittleEndian>(HEADER_SIZE - 8)?;
+        writer.write_all(b"WAVE")?;
+
+        writer.write_all(b"fmt ")?;
+        writer.write_u32::<LittleEndian>(16)?;
+        writer.write_u16::<LittleEndian>(1)?; // PCM
+        writer.write_u16::<LittleEndian>(channels)?;
+        writer.write_u32::<LittleEndian>(sample_rate)?;
+        writer.write_u32::<LittleEndian>(sample_rate * block_align as u32)?;
+        writer.write_u16::<LittleEndian>(block_align)?;
+        writer.write_u16::<LittleEndian>(bits_per_sample)?;
+
+        writer.write_all(b"data")?;
+        writer.write_u32::<LittleEndian>(0)?;
+
+        Ok(Self {
+            writer,
+            samples_written: 0,
+        })
+    }
+
+    /// Write samples in the range -1.0..=1.0, anything outside is clipped
+    pub fn write_samples(&mut self, samples: &[f32]) -> Result<()> {
+        let samples_written = self.samples_written + samples.len() as u64;
+        if samples_written * 2 > MAX_DATA_SIZE {
+            bail!("the recording is over the 4 GiB a WAV file can hold");
+        }
+        for &sample in samples {
+            let pcm = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.writer.write_i16::<LittleEndian>(pcm)?;
+        }
+        self.samples_written = samples_written;
+        Ok(())
+    }
+
+    /// Patch the chunk sizes and flush the file
+    pub fn finish(mut self) -> Result<W> {
+        // write_samples keeps the size within the limit
+        let data_size = (self.samples_written * 2) as u32;
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer
+            .write_u32::<LittleEndian>(HEADER_SIZE - 8 + data_size)?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_u32::<LittleEndian>(data_size)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_size_limit() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44_100).unwrap();
+        wav.samples_written = MAX_DATA_SIZE / 2 - 1;
+        wav.write_samples(&[0.0]).unwrap();
+        assert!(wav.write_samples(&[0.0]).is_err());
+        assert_eq!(wav.samples_written, MAX_DATA_SIZE / 2);
+
+        let bytes = wav.finish().unwrap().into_inner();
+        assert_eq!(&bytes[4..8], &(u32::MAX - 1).to_le_bytes());
+        assert_eq!(&bytes[40..44], &(u32::MAX - 37).to_le_bytes());
+    }
+}
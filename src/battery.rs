@@ -0,0 +1,220 @@
+use std::fmt::Debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// Frames between flushes of dirty save memory, about one second
+pub const DEFAULT_FLUSH_INTERVAL: u64 = 60;
+
+/// Memory on the cartridge that keeps its contents with the power off,
+/// like battery-backed PRG RAM or a serial EEPROM on the mapper.
+pub trait SaveMemory: Debug {
+    fn save_data(&self) -> Vec<u8>;
+    /// Replace the contents, which counts as a write for `take_dirty`
+    fn load_save_data(&mut self, data: &[u8]);
+    /// Whether it was written to since the last call
+    fn take_dirty(&mut self) -> bool;
+}
+
+/// The `.sav` file a cartridge's save memory is persisted to
+#[derive(Debug, Clone)]
+pub struct SaveFile {
+    pub path: PathBuf,
+    /// Dirty save memory is written back at most once every this many frames
+    pub flush_interval: u64,
+    frames_since_flush: u64,
+}
+
+impl SaveFile {
+    /// The save file for a ROM: the same path with a `.sav` extension
+    pub fn for_rom(rom: &Path) -> Self {
+        Self {
+            path: rom.with_extension("sav"),
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            frames_since_flush: 0,
+        }
+    }
+
+    /// Fill the save memory from the file, if there is one yet
+    pub fn load(&self, memory: &mut dyn SaveMemory) -> Result<()> {
+        if !self.path.exists() {
+            return Ok(());
+        }
+        let data = fs::read(&self.path)
+            .with_context(|| format!("failed to read save file {}", self.path.display()))?;
+        memory.load_save_data(&data);
+        // Nothing to write back until the game changes it
+        memory.take_dirty();
+        Ok(())
+    }
+
+    /// Called once per frame, writes the save memory back if it changed and the interval passed
+    pub fn tick(&mut self, memory: &mut dyn SaveMemory) -> Result<()> {
+        self.frames_since_flush += 1;
+        if self.frames_since_flush >= self.flush_interval {
+            self.flush(memory)?;
+        }
+        Ok(())
+    }
+
+    /// Write the save memory back if it changed
+    pub fn flush(&mut self, memory: &mut dyn SaveMemory) -> Result<()> {
+        self.frames_since_flush = 0;
+        if memory.take_dirty() {
+            fs::write(&self.path, memory.save_data())
+                .with_context(|| format!("failed to write save file {}", self.path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constant::PRG_RAM_SIZE;
+    use crate::nes::NesEmulator;
+    use crate::test_util::create_test_rom;
+
+    /// A battery-backed ROM that stores $42 to $6000, in its own directory
+    fn battery_rom(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nes-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("game.nes");
+        let mut rom = create_test_rom(&[
+            0xa9, 0x42, // LDA #$42
+            0x8d, 0x00, 0x60, // STA $6000
+            0x4c, 0x05, 0x80, // JMP $8005
+        ]);
+        rom[6] |= 0x02;
+        fs::write(&rom_path, &rom).unwrap();
+        rom_path
+    }
+
+    /// Save memory on the mapper instead of PRG RAM, like a serial EEPROM
+    #[derive(Debug)]
+    struct TestEeprom {
+        data: Vec<u8>,
+        dirty: bool,
+    }
+
+    impl SaveMemory for TestEeprom {
+        fn save_data(&self) -> Vec<u8> {
+            self.data.clone()
+        }
+
+        fn load_save_data(&mut self, data: &[u8]) {
+            let len = data.len().min(self.data.len());
+            self.data[..len].copy_from_slice(&data[..len]);
+            self.dirty = true;
+        }
+
+        fn take_dirty(&mut self) -> bool {
+            std::mem::take(&mut self.dirty)
+        }
+    }
+
+    /// Stand in for the game writing to the EEPROM
+    fn write_eeprom(nes: &mut NesEmulator, data: &[u8]) {
+        nes.cpu.eeprom.as_mut().unwrap().load_save_data(data);
+    }
+
+    #[test]
+    fn test_load_and_flush() {
+        let rom_path = battery_rom("battery");
+        let sav_path = rom_path.with_extension("sav");
+        let mut save = vec![0u8; PRG_RAM_SIZE];
+        save[1] = 0x99;
+        fs::write(&sav_path, &save).unwrap();
+
+        // The save is loaded on boot and written back periodically once dirty
+        let mut nes = NesEmulator::default();
+        nes.load_rom(&rom_path).unwrap();
+        assert_eq!(nes.cpu.mapper[0x6001], 0x99);
+        nes.save_file.as_mut().unwrap().flush_interval = 2;
+        nes.run_frame().unwrap();
+        assert_eq!(fs::read(&sav_path).unwrap()[0], 0x00);
+        nes.run_frame().unwrap();
+        let saved = fs::read(&sav_path).unwrap();
+        assert_eq!(
+            (saved.len(), saved[0], saved[1]),
+            (PRG_RAM_SIZE, 0x42, 0x99)
+        );
+        fs::remove_dir_all(rom_path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_disabled() {
+        let rom_path = battery_rom("battery-off");
+        let mut nes = NesEmulator::default();
+        nes.set_battery_saves(false);
+        nes.load_rom(&rom_path).unwrap();
+        nes.run_frame().unwrap();
+        nes.flush_save().unwrap();
+        assert!(!rom_path.with_extension("sav").exists());
+        fs::remove_dir_all(rom_path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_restored_state_is_saved() {
+        let rom_path = battery_rom("battery-state");
+        let sav_path = rom_path.with_extension("sav");
+        let mut nes = NesEmulator::default();
+        nes.load_rom(&rom_path).unwrap();
+        let before_write = nes.save_state().unwrap();
+        nes.run_frame().unwrap();
+        nes.flush_save().unwrap();
+        assert_eq!(fs::read(&sav_path).unwrap()[0], 0x42);
+
+        // Going back to before the game wrote $42 reaches the file too
+        nes.load_state(&before_write).unwrap();
+        nes.flush_save().unwrap();
+        assert_eq!(fs::read(&sav_path).unwrap()[0], 0x00);
+        fs::remove_dir_all(rom_path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_eeprom() {
+        let rom_path = battery_rom("battery-eeprom");
+        let sav_path = rom_path.with_extension("sav");
+        fs::write(&sav_path, [1, 2, 3, 4]).unwrap();
+
+        // The EEPROM takes the place of PRG RAM on boot
+        let mut nes = NesEmulator::default();
+        nes.cpu.eeprom = Some(Box::new(TestEeprom {
+            data: vec![0; 4],
+            dirty: false,
+        }));
+        nes.load_rom(&rom_path).unwrap();
+        assert_eq!(nes.save_memory().save_data(), [1, 2, 3, 4]);
+        assert_eq!(nes.cpu.mapper[0x6000], 0x00);
+
+        // Flushed periodically, and the game's PRG RAM write of $42 never reaches the file
+        nes.save_file.as_mut().unwrap().flush_interval = 1;
+        write_eeprom(&mut nes, &[5, 6, 7, 8]);
+        nes.run_frame().unwrap();
+        assert_eq!(fs::read(&sav_path).unwrap(), [5, 6, 7, 8]);
+
+        // Flushed on exit
+        nes.save_file.as_mut().unwrap().flush_interval = 1000;
+        write_eeprom(&mut nes, &[9, 9, 9, 9]);
+        nes.run_frame().unwrap();
+        assert_eq!(fs::read(&sav_path).unwrap(), [5, 6, 7, 8]);
+        nes.flush_save().unwrap();
+        assert_eq!(fs::read(&sav_path).unwrap(), [9, 9, 9, 9]);
+
+        // Part of savestates, and a restore reaches the file
+        let state = nes.save_state().unwrap();
+        write_eeprom(&mut nes, &[7, 7, 7, 7]);
+        nes.flush_save().unwrap();
+        nes.load_state(&state).unwrap();
+        assert_eq!(nes.save_memory().save_data(), [9, 9, 9, 9]);
+        nes.flush_save().unwrap();
+        assert_eq!(fs::read(&sav_path).unwrap(), [9, 9, 9, 9]);
+
+        // A state from a cartridge without one doesn't load
+        nes.cpu.eeprom = None;
+        assert!(nes.load_state(&state).is_err());
+        fs::remove_dir_all(rom_path.parent().unwrap()).unwrap();
+    }
+}
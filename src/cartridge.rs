@@ -0,0 +1,160 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use crate::cpu::{disassemble, DisassembledInstruction};
+
+pub const INES_HEADER_SIZE: usize = 16;
+pub const PRG_ROM_BANK_SIZE: usize = 16 * 1024;
+pub const CHR_ROM_BANK_SIZE: usize = 8 * 1024;
+const TRAINER_SIZE: usize = 512;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+}
+
+/// A cartridge image in the iNES format
+/// reference: https://www.nesdev.org/wiki/INES
+#[derive(Debug, Clone)]
+pub struct Cartridge {
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    pub mapper: u16,
+    pub mirroring: Mirroring,
+    /// Battery-backed PRG RAM at $6000-$7FFF
+    pub battery: bool,
+    pub nes2: bool,
+    /// NES 2.0 default expansion device, 0 when unspecified
+    pub expansion_device: u8,
+}
+
+impl Cartridge {
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < INES_HEADER_SIZE || &data[0..4] != b"NES\x1a" {
+            bail!("not an iNES file");
+        }
+        let header = &data[..INES_HEADER_SIZE];
+        let flags6 = header[6];
+        let flags7 = header[7];
+        let nes2 = flags7 & 0x0c == 0x08;
+
+        let mut mapper = ((flags7 & 0xf0) | (flags6 >> 4)) as u16;
+        let mut prg_banks = header[4] as usize;
+        let mut chr_banks = header[5] as usize;
+        let mut expansion_device = 0;
+        if nes2 {
+            mapper |= ((header[8] & 0x0f) as u16) << 8;
+            prg_banks |= ((header[9] & 0x0f) as usize) << 8;
+            chr_banks |= ((header[9] >> 4) as usize) << 8;
+            expansion_device = header[15] & 0x3f;
+        }
+
+        let mirroring = if flags6 & 0x08 != 0 {
+            Mirroring::FourScreen
+        } else if flags6 & 0x01 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+
+        let mut offset = INES_HEADER_SIZE;
+        if flags6 & 0x04 != 0 {
+            offset += TRAINER_SIZE;
+        }
+        let prg_size = prg_banks * PRG_ROM_BANK_SIZE;
+        let chr_size = chr_banks * CHR_ROM_BANK_SIZE;
+        if data.len() < offset + prg_size + chr_size {
+            bail!("iNES file is truncated");
+        }
+        let prg_rom = data[offset..offset + prg_size].to_vec();
+        let chr_rom = data[offset + prg_size..offset + prg_size + chr_size].to_vec();
+
+        Ok(Self {
+            prg_rom,
+            chr_rom,
+            mapper,
+            mirroring,
+            battery: flags6 & 0x02 != 0,
+            nes2,
+            expansion_device,
+        })
+    }
+
+    /// Disassemble the PRG ROM. NROM's one or two 16KB banks end at $FFFF,
+    /// larger ROMs are shown bank by bank at $8000.
+    #[must_use]
+    pub fn disassemble(&self) -> Vec<DisassembledInstruction> {
+        if self.prg_rom.len() <= 2 * PRG_ROM_BANK_SIZE {
+            let origin = (0x10000 - self.prg_rom.len()) as u16;
+            return disassemble(&self.prg_rom, origin);
+        }
+        self.prg_rom
+            .chunks(PRG_ROM_BANK_SIZE)
+            .flat_map(|bank| disassemble(bank, 0x8000))
+            .collect()
+    }
+
+    /// Offset in PRG ROM of `address` in the layout of `disassemble`, for an instruction at
+    /// `offset`. Larger ROMs only know the bank at $8000-$BFFF, the one being disassembled.
+    #[must_use]
+    pub fn prg_rom_offset(&self, address: u16, offset: usize) -> Option<usize> {
+        let relative = (address as usize).checked_sub(0x8000)?;
+        if self.prg_rom.len() <= 2 * PRG_ROM_BANK_SIZE {
+            return Some(relative % self.prg_rom.len());
+        }
+        (relative < PRG_ROM_BANK_SIZE)
+            .then(|| offset / PRG_ROM_BANK_SIZE * PRG_ROM_BANK_SIZE + relative)
+    }
+
+    /// MD5 of the PRG and CHR ROM without the header, as used by FCEUX to identify a ROM
+    #[must_use]
+    pub fn checksum(&self) -> [u8; 16] {
+        let mut context = md5::Context::new();
+        context.consume(&self.prg_rom);
+        context.consume(&self.chr_rom);
+        context.compute().0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nes::NesEmulator;
+    use crate::test_util::create_test_rom;
+
+    #[test]
+    fn test_ines_header() {
+        let mut rom = create_test_rom(&[0xea]);
+        rom[6] = 0x13; // mapper 1, battery, vertical mirroring
+        let cartridge = Cartridge::from_bytes(&rom).unwrap();
+        assert_eq!(cartridge.mapper, 1);
+        assert!(cartridge.battery);
+        assert_eq!(cartridge.mirroring, Mirroring::Vertical);
+        assert_eq!(cartridge.prg_rom.len(), 0x4000);
+        assert_eq!(cartridge.chr_rom.len(), 0x2000);
+
+        assert!(Cartridge::from_bytes(&rom[..100]).is_err());
+        // Only NROM is supported
+        let mut nes = NesEmulator::default();
+        assert!(nes.insert_cartridge(cartridge).is_err());
+    }
+
+    #[test]
+    fn test_disassemble_prg_rom() {
+        // A 16KB PRG ends at $FFFF
+        let cartridge = Cartridge::from_bytes(&create_test_rom(&[0xea])).unwrap();
+        let lines = cartridge.disassemble();
+        assert_eq!(
+            (lines[0].address, lines[0].text()),
+            (0xc000, "NOP".to_string())
+        );
+    }
+}
@@ -1,12 +1,137 @@
+use std::path::PathBuf;
+
 use structopt::StructOpt;
 
+use crate::apu::Channel;
+use crate::input::InputDeviceKind;
+use crate::util::parse_hex_u16;
+
 #[derive(StructOpt)]
 pub struct Cli {
     /// The path to the file to read
     #[structopt(parse(from_os_str))]
-    pub path: std::path::PathBuf,
+    pub path: PathBuf,
+
+    /// Print the disassembly of the ROM's PRG and exit. Files without an iNES
+    /// header are disassembled as a raw PRG bank loaded at --asm-origin.
+    #[structopt(long)]
+    pub print_asm: bool,
+
+    /// Load address of a raw PRG bank for --print-asm
+    #[structopt(long, default_value = "8000", parse(try_from_str = parse_hex_u16))]
+    pub asm_origin: u16,
+
+    /// Run the path as a test ROM reporting through $6000 and exit with its result code.
+    /// A directory runs every ROM in it and prints a summary. --frames is the timeout.
+    /// Only NROM (mapper 0) ROMs load; the summary says why a ROM didn't run or finish.
+    #[structopt(long)]
+    pub test_rom: bool,
+
+    /// Run the path as a raw binary 6502 functional test on a flat RAM bus until it
+    /// traps in a loop to itself, and exit with an error unless it traps at --success-pc
+    #[structopt(long)]
+    pub functional_test: bool,
+
+    /// Address the raw binary of --functional-test is loaded at
+    #[structopt(long, default_value = "0000", parse(try_from_str = parse_hex_u16))]
+    pub load_address: u16,
+
+    /// Address --functional-test starts executing at
+    #[structopt(long, default_value = "0400", parse(try_from_str = parse_hex_u16))]
+    pub start_pc: u16,
+
+    /// Address --functional-test traps at when every test passed. The default is the prebuilt
+    /// 6502_functional_test.bin's, which needs decimal mode: build the suite with
+    /// disable_decimal = 1 and pass the address of its `success` label
+    #[structopt(long, default_value = "3469", parse(try_from_str = parse_hex_u16))]
+    pub success_pc: u16,
+
+    /// Instructions --functional-test runs before giving up
+    #[structopt(long, default_value = "100000000")]
+    pub max_instructions: u64,
+
+    /// Run the SingleStepTests (ProcessorTests nes6502) JSON files of the directory
+    /// given as the path on a flat RAM bus, and report the results of every opcode
+    #[structopt(long)]
+    pub single_step_tests: bool,
+
+    /// Also compare the bus activity of every cycle in --single-step-tests
+    #[structopt(long)]
+    pub check_cycles: bool,
+
+    /// Write a trace of every executed instruction in the nestest log format
+    #[structopt(long, parse(from_os_str))]
+    pub trace: Option<PathBuf>,
+
+    /// Run nestest in automation mode from $C000 and compare its trace against
+    /// this golden log, reporting the first line that differs
+    #[structopt(long, parse(from_os_str))]
+    pub nestest: Option<PathBuf>,
+
+    /// Print every executed instruction
+    #[structopt(long)]
+    pub verbose: bool,
+
+    /// Load labels from a ca65 .dbg, FCEUX .nl or Mesen .mlb file, can be given several
+    /// times. Without it the symbol files next to the ROM are loaded.
+    #[structopt(long, number_of_values = 1, parse(from_os_str))]
+    pub symbols: Vec<PathBuf>,
+
+    /// Load the ROM and open the interactive debugger on stdin instead of running it
+    #[structopt(long)]
+    pub debug: bool,
+
+    /// Load the ROM and let a GDB remote protocol client on this local port drive it
+    #[structopt(long)]
+    pub gdb: Option<u16>,
+
+    /// Number of frames to run without a frontend
+    #[structopt(long, default_value = "600")]
+    pub frames: u64,
+
+    /// Record the mixed audio to a WAV file
+    #[structopt(long, parse(from_os_str))]
+    pub record_wav: Option<PathBuf>,
+
+    /// Also record every channel to its own WAV file next to the mix
+    #[structopt(long)]
+    pub stems: bool,
+
+    /// Output sample rate of the audio
+    #[structopt(long, default_value = "44100")]
+    pub sample_rate: u32,
+
+    /// Mute a channel: pulse1, pulse2, triangle, noise, dmc or expansion
+    #[structopt(long, number_of_values = 1)]
+    pub mute: Vec<Channel>,
+
+    /// Solo a channel, every channel that is not soloed is muted
+    #[structopt(long, number_of_values = 1)]
+    pub solo: Vec<Channel>,
+
+    /// Connect input devices: controllers, four-score, hori, zapper, arkanoid, power-pad
+    /// or keyboard. Overrides the ROM's NES 2.0 default expansion device. The zapper
+    /// never senses light yet, because the PPU doesn't render a picture.
+    #[structopt(long)]
+    pub input: Option<InputDeviceKind>,
+
+    /// Record the controller input of every frame from power on to an FCEUX .fm2 movie
+    #[structopt(long, parse(from_os_str))]
+    pub record_movie: Option<PathBuf>,
+
+    /// Play an FCEUX .fm2 movie back from power on, running until it ends
+    #[structopt(long, parse(from_os_str), conflicts_with = "record-movie")]
+    pub play_movie: Option<PathBuf>,
+
+    /// Continue from a savestate instead of power on
+    #[structopt(long, parse(from_os_str))]
+    pub load_state: Option<PathBuf>,
+
+    /// Write a savestate of the machine on exit
+    #[structopt(long, parse(from_os_str))]
+    pub save_state: Option<PathBuf>,
 
+    /// Don't load or write the .sav file of battery-backed cartridges
     #[structopt(long)]
-    #[allow(unused)]
-    print_asm: bool, // Future feature
+    pub no_battery_save: bool,
 }
@@ -10,14 +10,20 @@ How to calculate the maximum memory?
 **/
 pub const MEMORY_MAX: usize = 1 << 16; // 0xFFFF
 
-#[allow(unused)]
 pub const ADDRESS_NMI: u16 = 0xFFFA;
 #[allow(unused)]
 pub const PC_ADDRESS_RESET: u16 = 0xFFFC;
 pub const PRG_ROM_ADDRESS: u16 = 0x8000;
+// $6000-$7FFF: cartridge PRG RAM, often battery-backed
+pub const PRG_RAM_ADDRESS: u16 = 0x6000;
+pub const PRG_RAM_SIZE: usize = 0x2000;
 pub const ADDRESS_BRK: u16 = 0xFFFE;
+// IRQ shares its vector with BRK
+pub const ADDRESS_IRQ: u16 = 0xFFFE;
 pub const ADDRESS_TEST_PROGRAM: u16 = 0xC000;
 pub const NEGATIVE_FLAG: u8 = 0x80;
+// Only exists in the copy of the status pushed by PHP and BRK
+pub const BREAK_FLAG: u8 = 0x10;
 // $0100–$01FF: The page containing the stack, which can be located anywhere here,
 // but typically starts at $01FF
 pub const SP_BASE_ADDRESS: u16 = 0x0100;
@@ -39,7 +39,13 @@ pub const IND: AddressingMode = Indirect;
 impl Cpu6502 {
     // reference: https://skilldrick.github.io/easy6502/#addressing
     // return value: (address, value, program counter step)
-    pub fn decode_addressing_mode(&self, mode: AddressingMode) -> Result<(Option<u16>, u16, u16)> {
+    // The value at the address is only read with `read`: stores and jumps don't read it,
+    // and reads of registers like $4015 or $4017 have side effects.
+    pub fn decode_addressing_mode(
+        &mut self,
+        mode: AddressingMode,
+        read: bool,
+    ) -> Result<(Option<u16>, u16, u16)> {
         let ptr = self.registers.pc.wrapping_add(1);
         Ok(match mode {
             IMM | REL => {
@@ -48,63 +54,126 @@ impl Cpu6502 {
             }
             ZP => {
                 let addr = self.mem_read(ptr)?.into();
-                let v = self.mem_read(addr)?;
+                let v = self.read_operand(addr, read)?;
                 (Some(addr), v as u16, 1)
             }
             ZPX => {
                 let pos = self.mem_read(ptr)?;
                 let addr = pos.wrapping_add(self.registers.x) as u16;
-                let v = self.mem_read(ptr)?;
+                let v = self.read_operand(addr, read)?;
                 (Some(addr), v as u16, 1)
             }
             ZPY => {
                 let pos = self.mem_read(ptr)?;
                 let addr = pos.wrapping_add(self.registers.y) as u16;
-                let v = self.mem_read(ptr)?;
+                let v = self.read_operand(addr, read)?;
                 (Some(addr), v as u16, 1)
             }
             ABS => {
                 let addr = self.mem_read_u16(ptr)?;
-                let v = self.mem_read(addr)?;
+                let v = self.read_operand(addr, read)?;
                 (Some(addr), v as u16, 2)
             }
             ABX => {
                 let base = self.mem_read_u16(ptr)?;
                 let addr = base.wrapping_add(self.registers.x as u16);
-                let v = self.mem_read(ptr)?;
+                let v = self.read_operand(addr, read)?;
                 (Some(addr), v as u16, 2)
             }
             ABY => {
                 let base = self.mem_read_u16(ptr)?;
                 let addr = base.wrapping_add(self.registers.y as u16);
-                let v = self.mem_read(ptr)?;
+                let v = self.read_operand(addr, read)?;
                 (Some(addr), v as u16, 2)
             }
+            // The pointer's high byte is read from the same page, $10FF reads $10FF and $1000
             IND => {
                 let addr = self.mem_read_u16(ptr)?;
-                let jmp_ptr = self.mem_read_u16(addr)?;
+                let low = self.mem_read(addr)?;
+                let high = self.mem_read((addr & 0xff00) | (addr.wrapping_add(1) & 0x00ff))?;
+                let jmp_ptr = u16::from_le_bytes([low, high]);
                 (Some(jmp_ptr), 0xDEAD, 2)
             }
             // Indexed Indirect: Read base value from program counter, before dreferencing
             // add x and use value of register x as the address
             IZX => {
                 let pos = self.mem_read(ptr)?;
-                let ptr = pos.wrapping_add(self.registers.x) as u16;
-                let addr = self.mem_read_u16(ptr)?;
-                let v = self.mem_read(ptr)?;
+                let ptr = pos.wrapping_add(self.registers.x);
+                let addr = self.mem_read_u16_zero_page(ptr)?;
+                let v = self.read_operand(addr, read)?;
                 (Some(addr), v as u16, 1)
             }
             // Indirect Indexed: read base value from program counter, dereferenece,
             // add y and return
             IZY => {
-                let base = self.mem_read(ptr)?.into();
-                let deref_base = self.mem_read_u16(base)?;
+                let base = self.mem_read(ptr)?;
+                let deref_base = self.mem_read_u16_zero_page(base)?;
                 let addr = deref_base.wrapping_add(self.registers.y as u16);
-                let v = self.mem_read(ptr)?;
+                let v = self.read_operand(addr, read)?;
                 (Some(addr), v as u16, 1)
             }
-            ACC => (None, self.registers.a as u16, 1),
+            ACC => (None, self.registers.a as u16, 0),
             IMP => (None, 0xDEAD, 0),
         })
     }
+
+    fn read_operand(&mut self, addr: u16, read: bool) -> Result<u8> {
+        match read {
+            true => self.mem_read(addr),
+            false => Ok(0),
+        }
+    }
+
+    /// Pointers in the zero page wrap around from $FF to $00 for their high byte
+    fn mem_read_u16_zero_page(&mut self, ptr: u8) -> Result<u16> {
+        let low = self.mem_read(ptr as u16)?;
+        let high = self.mem_read(ptr.wrapping_add(1) as u16)?;
+        Ok(u16::from_le_bytes([low, high]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::run_to_break;
+
+    #[test]
+    fn test_indexed_reads_use_effective_address() {
+        let harness = run_to_break(
+            ".org $0600\nLDX #1\nLDY #2\nLDA $10,X\nSTA $20\nLDA $0310,X\nSTA $21\nLDA $0310,Y\nSTA $22\nLDX $10,Y\nBRK",
+            &[(0x0011, &[0xa1, 0xa2]), (0x0311, &[0xb1, 0xb2])],
+        );
+        assert_eq!(&harness.cpu.mapper[0x20..0x23], &[0xa1, 0xb1, 0xb2]);
+        assert_eq!(harness.cpu.registers.x, 0xa2);
+    }
+
+    #[test]
+    fn test_indirect_reads_wrap_in_zero_page() {
+        // Both pointers sit at $FF and take their high byte from $00
+        let harness = run_to_break(
+            ".org $0600\nLDX #1\nLDY #2\nLDA ($FE,X)\nSTA $20\nLDA ($FF),Y\nSTA $21\nBRK",
+            &[
+                (0x0000, &[0x03]),
+                (0x00ff, &[0x10]),
+                (0x0310, &[0xc0, 0xc1, 0xc2]),
+            ],
+        );
+        assert_eq!(&harness.cpu.mapper[0x20..0x22], &[0xc0, 0xc2]);
+    }
+
+    #[test]
+    fn test_jmp_indirect_page_wrap() {
+        // The high byte of the pointer at $02FF comes from $0200, not $0300
+        let harness = run_to_break(
+            ".org $0600\nJMP ($02FF)\n.org $0700\nBRK",
+            &[(0x0200, &[0x07]), (0x02ff, &[0x00]), (0x0300, &[0x08])],
+        );
+        assert_eq!(harness.cpu.registers.pc, 0x0700);
+    }
+
+    #[test]
+    fn test_accumulator_has_no_operand() {
+        // ASL A is one byte long, the ORA after it runs
+        let harness = run_to_break(".org $0600\nLDA #$40\nASL A\nORA #$01\nBRK", &[]);
+        assert_eq!(harness.cpu.registers.a, 0x81);
+    }
 }
@@ -0,0 +1,646 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::constant::MEMORY_MAX;
+use crate::cpu::address::AddressingMode::{self, *};
+use crate::cpu::disassembler::{instruction_length, is_official_opcode};
+use crate::cpu::opcode::OPCODE_TABLE;
+
+/// Output of the assembler: one contiguous block of bytes and the symbols defined by the source
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Assembly {
+    /// Address of the first byte, set by the first `.org` (default $0000)
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    /// Labels and constants, local labels as `global@local`
+    pub symbols: BTreeMap<String, u16>,
+}
+
+impl Assembly {
+    /// Copy the bytes into a 64KB address space at their origin, e.g. to patch a ROM in memory.
+    /// The assembler keeps them inside it, so this can't run past the end.
+    pub fn apply(&self, memory: &mut [u8; MEMORY_MAX]) {
+        let start = self.origin as usize;
+        memory[start..start + self.bytes.len()].copy_from_slice(&self.bytes);
+    }
+
+    #[must_use]
+    pub fn symbol(&self, name: &str) -> Option<u16> {
+        self.symbols.get(name).copied()
+    }
+}
+
+/// Assemble 6502 source in standard syntax.
+///
+/// ```text
+///         .org $C000
+/// COUNT = 5
+/// start:  LDX #COUNT
+/// @loop:  DEX
+///         BNE @loop
+///         JMP (vector)
+/// vector: .word start
+/// text:   .byte "hi", $00, <start, >start
+/// ```
+pub fn assemble(source: &str) -> Result<Assembly> {
+    let mut assembler = Assembler::default();
+    for pass in [Pass::Measure, Pass::Emit] {
+        assembler.pass = pass;
+        assembler.pc = 0;
+        assembler.origin = None;
+        assembler.bytes.clear();
+        assembler.scope.clear();
+        for (number, line) in source.lines().enumerate() {
+            assembler
+                .line(number, line)
+                .with_context(|| format!("line {}: {}", number + 1, line.trim()))?;
+        }
+    }
+    Ok(Assembly {
+        origin: assembler.origin.unwrap_or_default(),
+        bytes: assembler.bytes,
+        symbols: assembler.symbols,
+    })
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+enum Pass {
+    /// Define every label, forward references are still unknown
+    #[default]
+    Measure,
+    /// Every symbol is known, emit the bytes
+    Emit,
+}
+
+#[derive(Debug, Default)]
+struct Assembler {
+    pass: Pass,
+    pc: u16,
+    origin: Option<u16>,
+    bytes: Vec<u8>,
+    symbols: BTreeMap<String, u16>,
+    /// The last global label, owner of the local labels that follow
+    scope: String,
+    /// Addressing mode picked for each instruction line in the first pass,
+    /// so zero page vs absolute can't change the layout in the second one
+    modes: HashMap<usize, AddressingMode>,
+}
+
+/// Remove a trailing comment, keeping semicolons inside quotes
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = None;
+    for (i, c) in line.char_indices() {
+        match (c, quoted) {
+            ('"' | '\'', None) => quoted = Some(c),
+            (c, Some(q)) if c == q => quoted = None,
+            (';', None) => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Split directive arguments on commas outside of quotes
+fn split_args(args: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = None;
+    let mut start = 0;
+    for (i, c) in args.char_indices() {
+        match (c, quoted) {
+            ('"', None) => quoted = Some(c),
+            (c, Some(q)) if c == q => quoted = None,
+            (',', None) => {
+                parts.push(args[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(args[start..].trim());
+    parts.retain(|part| !part.is_empty());
+    parts
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '@')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl Assembler {
+    fn line(&mut self, number: usize, line: &str) -> Result<()> {
+        let mut line = strip_comment(line).trim();
+
+        // label:
+        if let Some((label, rest)) = line.split_once(':') {
+            let label = label.trim();
+            if is_identifier(label) && !rest.starts_with(':') {
+                let name = self.qualify(label);
+                if !label.starts_with('@') {
+                    self.scope = label.to_string();
+                }
+                self.define(name, self.pc)?;
+                line = rest.trim();
+            }
+        }
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        // NAME = expr
+        if let Some((name, expr)) = line.split_once('=') {
+            let name = name.trim();
+            if is_identifier(name) {
+                if let Some(value) = self.eval(expr)? {
+                    self.define(self.qualify(name), value as u16)?;
+                }
+                return Ok(());
+            }
+        }
+
+        let (keyword, args) = match line.split_once(char::is_whitespace) {
+            Some((keyword, args)) => (keyword, args.trim()),
+            None => (line, ""),
+        };
+        if let Some(directive) = keyword.strip_prefix('.') {
+            return self.directive(&directive.to_ascii_lowercase(), args);
+        }
+        self.instruction(number, &keyword.to_ascii_uppercase(), args)
+    }
+
+    fn qualify(&self, name: &str) -> String {
+        if name.starts_with('@') {
+            format!("{}{}", self.scope, name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    fn define(&mut self, name: String, value: u16) -> Result<()> {
+        match (self.pass, self.symbols.get(&name)) {
+            (Pass::Measure, Some(_)) => bail!("'{}' is already defined", name),
+            _ => {
+                self.symbols.insert(name, value);
+                Ok(())
+            }
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<()> {
+        let origin = *self.origin.get_or_insert(self.pc);
+        let Some(offset) = self.pc.checked_sub(origin) else {
+            bail!("${:04X} is before the origin ${:04X}", self.pc, origin);
+        };
+        let offset = offset as usize;
+        if offset < self.bytes.len() {
+            bail!("${:04X} overlaps code that was already assembled", self.pc);
+        }
+        if self.pc as usize + bytes.len() > MEMORY_MAX {
+            bail!("${:04X} runs past the end of memory at $FFFF", self.pc);
+        }
+        self.bytes.resize(offset, 0);
+        self.bytes.extend_from_slice(bytes);
+        self.pc = self.pc.wrapping_add(bytes.len() as u16);
+        Ok(())
+    }
+
+    /// Evaluate an expression, None while it refers to a symbol that isn't defined yet
+    fn eval(&self, expr: &str) -> Result<Option<i64>> {
+        let mut parser = ExprParser {
+            assembler: self,
+            input: expr.trim().as_bytes(),
+            pos: 0,
+        };
+        let value = parser.parse()?;
+        if value.is_none() && self.pass == Pass::Emit {
+            bail!("undefined symbol in '{}'", expr.trim());
+        }
+        Ok(value)
+    }
+
+    fn directive(&mut self, directive: &str, args: &str) -> Result<()> {
+        match directive {
+            "org" => {
+                let Some(address) = self.eval(args)? else {
+                    bail!(".org needs a known address");
+                };
+                self.pc = address as u16;
+            }
+            "byte" | "db" => {
+                for arg in split_args(args) {
+                    if let Some(text) = arg.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+                        self.emit(text.as_bytes())?;
+                        continue;
+                    }
+                    let value = self.eval(arg)?.unwrap_or_default();
+                    if !(-128..=255).contains(&value) {
+                        bail!("{} does not fit in a byte", value);
+                    }
+                    self.emit(&[value as u8])?;
+                }
+            }
+            "word" | "dw" => {
+                for arg in split_args(args) {
+                    let value = self.eval(arg)?.unwrap_or_default();
+                    self.emit(&(value as u16).to_le_bytes())?;
+                }
+            }
+            _ => bail!("unknown directive .{}", directive),
+        }
+        Ok(())
+    }
+
+    /// The opcode of `mnemonic` in `mode`, preferring the documented one
+    fn opcode(mnemonic: &str, mode: AddressingMode) -> Option<u8> {
+        let matches = |&opcode: &u8| {
+            let (operation, op_mode, ..) = OPCODE_TABLE[opcode as usize];
+            op_mode == mode && format!("{:?}", operation) == mnemonic
+        };
+        (0..=255u8)
+            .filter(matches)
+            .min_by_key(|&opcode| !is_official_opcode(opcode))
+    }
+
+    fn instruction(&mut self, number: usize, mnemonic: &str, operand: &str) -> Result<()> {
+        if !OPCODE_TABLE
+            .iter()
+            .any(|(operation, ..)| format!("{:?}", operation) == mnemonic)
+        {
+            bail!("unknown instruction {}", mnemonic);
+        }
+        let upper = operand.to_ascii_uppercase();
+        // Candidate modes for the operand syntax, the zero page one first
+        let (modes, expr): (&[AddressingMode], &str) = if operand.is_empty() {
+            (&[Implicit, Accumulator], "")
+        } else if upper == "A" {
+            (&[Accumulator], "")
+        } else if let Some(expr) = operand.strip_prefix('#') {
+            (&[Immediate], expr)
+        } else if upper.starts_with('(') && upper.ends_with(",X)") {
+            (&[IndirectX], &operand[1..operand.len() - 3])
+        } else if upper.starts_with('(') && upper.ends_with("),Y") {
+            (&[IndirectY], &operand[1..operand.len() - 3])
+        } else if upper.starts_with('(') && upper.ends_with(')') {
+            (&[Indirect], &operand[1..operand.len() - 1])
+        } else if upper.ends_with(",X") {
+            (&[ZeroPageX, AbsoluteX], &operand[..operand.len() - 2])
+        } else if upper.ends_with(",Y") {
+            (&[ZeroPageY, AbsoluteY], &operand[..operand.len() - 2])
+        } else {
+            (&[Relative, ZeroPage, Absolute], operand)
+        };
+        let value = if expr.is_empty() {
+            Some(0)
+        } else {
+            self.eval(expr)?
+        };
+
+        let mode = match self.modes.get(&number) {
+            Some(&mode) if self.pass == Pass::Emit => mode,
+            _ => {
+                let fits_zero_page = value.is_some_and(|value| (0..=0xff).contains(&value));
+                let mode = modes
+                    .iter()
+                    .copied()
+                    .filter(|&mode| Self::opcode(mnemonic, mode).is_some())
+                    // Unknown or large values can't use zero page when an absolute mode exists
+                    .find(|&mode| {
+                        let zero_page = matches!(mode, ZeroPage | ZeroPageX | ZeroPageY);
+                        !zero_page || fits_zero_page || modes.len() == 1
+                    })
+                    .or_else(|| {
+                        modes
+                            .iter()
+                            .copied()
+                            .find(|&mode| Self::opcode(mnemonic, mode).is_some())
+                    })
+                    .ok_or_else(|| anyhow!("{} does not support this addressing mode", mnemonic))?;
+                self.modes.insert(number, mode);
+                mode
+            }
+        };
+        let opcode = Self::opcode(mnemonic, mode)
+            .ok_or_else(|| anyhow!("{} does not support this addressing mode", mnemonic))?;
+        let value = value.unwrap_or_default();
+
+        let mut bytes = vec![opcode];
+        match mode {
+            Relative => {
+                let offset = value - (self.pc as i64 + 2);
+                if self.pass == Pass::Emit && !(-128..=127).contains(&offset) {
+                    bail!("branch target is {} bytes away, out of range", offset);
+                }
+                bytes.push(offset as u8);
+            }
+            _ => match instruction_length(mode) {
+                2 => {
+                    if self.pass == Pass::Emit && !(-128..=255).contains(&value) {
+                        bail!("{} does not fit in a byte", value);
+                    }
+                    bytes.push(value as u8);
+                }
+                3 => bytes.extend_from_slice(&(value as u16).to_le_bytes()),
+                _ => {}
+            },
+        }
+        self.emit(&bytes)
+    }
+}
+
+/// Recursive descent parser for operand expressions.
+/// Numbers are `$hex`, `%binary`, decimal or `'c'`, `*` is the current address,
+/// `<` and `>` take the low and high byte.
+struct ExprParser<'a> {
+    assembler: &'a Assembler,
+    input: &'a [u8],
+    pos: usize,
+}
+
+type Value = Option<i64>;
+
+impl ExprParser<'_> {
+    fn parse(&mut self) -> Result<Value> {
+        let value = self.binary(0)?;
+        self.skip_whitespace();
+        if self.pos < self.input.len() {
+            bail!(
+                "unexpected '{}' in expression",
+                self.input[self.pos] as char
+            );
+        }
+        Ok(value)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .input
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.input.get(self.pos).copied()
+    }
+
+    /// The binary operator at the current position with its precedence and length
+    fn operator(&mut self) -> Option<(&'static str, usize)> {
+        self.skip_whitespace();
+        let rest = &self.input[self.pos..];
+        const OPERATORS: [(&str, usize); 9] = [
+            ("|", 0),
+            ("^", 1),
+            ("&", 2),
+            ("<<", 3),
+            (">>", 3),
+            ("+", 4),
+            ("-", 4),
+            ("*", 5),
+            ("/", 5),
+        ];
+        OPERATORS
+            .iter()
+            .find(|(op, _)| rest.starts_with(op.as_bytes()))
+            .copied()
+    }
+
+    fn binary(&mut self, min_precedence: usize) -> Result<Value> {
+        let mut left = self.unary()?;
+        while let Some((op, precedence)) = self.operator() {
+            if precedence < min_precedence {
+                break;
+            }
+            self.pos += op.len();
+            let right = self.binary(precedence + 1)?;
+            left = match (left, right) {
+                (Some(l), Some(r)) => Some(match op {
+                    "|" => l | r,
+                    "^" => l ^ r,
+                    "&" => l & r,
+                    "<<" | ">>" => {
+                        let shift = u32::try_from(r).ok().and_then(|r| match op {
+                            "<<" => l.checked_shl(r),
+                            _ => l.checked_shr(r),
+                        });
+                        match shift {
+                            Some(value) => value,
+                            None => bail!("can't shift by {}", r),
+                        }
+                    }
+                    "+" => l.checked_add(r).context("overflow in expression")?,
+                    "-" => l.checked_sub(r).context("overflow in expression")?,
+                    "*" => l.checked_mul(r).context("overflow in expression")?,
+                    _ if r == 0 => bail!("division by zero"),
+                    _ => l.checked_div(r).context("overflow in expression")?,
+                }),
+                _ => None,
+            };
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Value> {
+        match self.peek() {
+            Some(b'-') => {
+                self.pos += 1;
+                self.unary()?
+                    .map(|v| v.checked_neg().context("overflow in expression"))
+                    .transpose()
+            }
+            Some(b'~') => {
+                self.pos += 1;
+                Ok(self.unary()?.map(|v| !v))
+            }
+            Some(b'<') => {
+                self.pos += 1;
+                Ok(self.unary()?.map(|v| v & 0xff))
+            }
+            Some(b'>') => {
+                self.pos += 1;
+                Ok(self.unary()?.map(|v| (v >> 8) & 0xff))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(u8) -> bool) -> &str {
+        let start = self.pos;
+        while self.input.get(self.pos).is_some_and(|&c| f(c)) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.input[start..self.pos]).unwrap_or_default()
+    }
+
+    fn primary(&mut self) -> Result<Value> {
+        let Some(c) = self.peek() else {
+            bail!("missing value in expression");
+        };
+        match c {
+            b'(' => {
+                self.pos += 1;
+                let value = self.binary(0)?;
+                if self.peek() != Some(b')') {
+                    bail!("missing ')'");
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            b'*' => {
+                self.pos += 1;
+                Ok(Some(self.assembler.pc as i64))
+            }
+            b'$' => {
+                self.pos += 1;
+                let digits = self.take_while(|c| c.is_ascii_hexdigit());
+                Ok(Some(i64::from_str_radix(digits, 16)?))
+            }
+            b'%' => {
+                self.pos += 1;
+                let digits = self.take_while(|c| c == b'0' || c == b'1');
+                Ok(Some(i64::from_str_radix(digits, 2)?))
+            }
+            b'\'' => {
+                let value = *self
+                    .input
+                    .get(self.pos + 1)
+                    .ok_or_else(|| anyhow!("unterminated character"))?;
+                if self.input.get(self.pos + 2) != Some(&b'\'') {
+                    bail!("unterminated character");
+                }
+                self.pos += 3;
+                Ok(Some(value as i64))
+            }
+            c if c.is_ascii_digit() => {
+                let digits = self.take_while(|c| c.is_ascii_digit());
+                Ok(Some(digits.parse()?))
+            }
+            c if c.is_ascii_alphabetic() || c == b'_' || c == b'@' => {
+                let name = self
+                    .take_while(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'@')
+                    .to_string();
+                let name = self.assembler.qualify(&name);
+                Ok(self.assembler.symbols.get(&name).map(|&v| v as i64))
+            }
+            c => bail!("unexpected '{}' in expression", c as char),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{disassemble, StopReason, TestHarness};
+
+    const SOURCE: &str = r#"
+            .org $C000
+    COUNT = 5
+    ptr = $20
+    start:  LDX #COUNT      ; comment
+    @loop:  DEX
+            BNE @loop
+            LDA (ptr),Y
+            STA $0200,X
+            LDA data+1
+            ASL
+            JMP (vector)
+    other:  BEQ @loop
+    @loop:  RTS
+    vector: .word start, *
+    data:   .byte "hi", <start, >start, %101, 'A'
+    "#;
+
+    #[test]
+    fn test_instructions_and_directives() {
+        let assembly = assemble(SOURCE).unwrap();
+        assert_eq!(assembly.origin, 0xc000);
+        assert_eq!(
+            assembly.bytes,
+            vec![
+                0xa2, 0x05, // LDX #$05
+                0xca, // DEX
+                0xd0, 0xfd, // BNE $C002
+                0xb1, 0x20, // LDA ($20),Y
+                0x9d, 0x00, 0x02, // STA $0200,X
+                0xad, 0x19, 0xc0, // LDA $C019, forward reference stays absolute
+                0x0a, // ASL A
+                0x6c, 0x14, 0xc0, // JMP ($C014)
+                0xf0, 0x00, // BEQ $C013, the local label of 'other'
+                0x60, // RTS
+                0x00, 0xc0, 0x16, 0xc0, // .word start, *
+                b'h', b'i', 0x00, 0xc0, 0x05, b'A',
+            ]
+        );
+    }
+
+    #[test]
+    fn test_symbols() {
+        let assembly = assemble(SOURCE).unwrap();
+        assert_eq!(assembly.symbol("start@loop"), Some(0xc002));
+        assert_eq!(assembly.symbol("other@loop"), Some(0xc013));
+        assert_eq!(assembly.symbol("COUNT"), Some(5));
+        assert_eq!(assembly.symbol("missing"), None);
+    }
+
+    #[test]
+    fn test_disassembles_to_source() {
+        let assembly = assemble(SOURCE).unwrap();
+        let text: Vec<String> = disassemble(&assembly.bytes[..5], 0xc000)
+            .iter()
+            .map(|line| line.text())
+            .collect();
+        assert_eq!(text, vec!["LDX #$05", "DEX", "BNE $C002"]);
+    }
+
+    #[test]
+    fn test_apply_and_run() {
+        let mut harness = TestHarness::new();
+        assemble(".org $0600\nLDA #$42\nSTA $10\nBRK")
+            .unwrap()
+            .apply(&mut harness.cpu.mapper);
+        harness.cpu.registers.pc = 0x0600;
+        assert_eq!(harness.run(10).unwrap(), StopReason::Break);
+        assert_eq!(harness.cpu.mapper[0x10], 0x42);
+    }
+
+    #[test]
+    fn test_errors() {
+        // Errors point at the line
+        let error = assemble("NOP\nBNE far\n.org $1000\nfar: NOP").unwrap_err();
+        assert!(format!("{:#}", error).starts_with("line 2"));
+        assert!(assemble("LDA ($20)").is_err());
+        assert!(assemble("FOO #1").is_err());
+        assert!(assemble("x: NOP\nx: NOP").is_err());
+    }
+
+    #[test]
+    fn test_shift_out_of_range() {
+        for source in [
+            ".byte 1 << 64",
+            ".byte 1 >> 64",
+            ".byte 1 << -1",
+            ".byte 1 << 99999",
+        ] {
+            assert!(assemble(source).is_err(), "{}", source);
+        }
+        assert!(assemble(".word $4000000000000000 * 4").is_err());
+        assert_eq!(assemble(".byte 1 << 7").unwrap().bytes, vec![0x80]);
+    }
+
+    #[test]
+    fn test_end_of_memory() {
+        // The last byte can be used, the one after it can't
+        let assembly = assemble(".org $FFFE\n.word $1234").unwrap();
+        let mut memory = [0u8; MEMORY_MAX];
+        assembly.apply(&mut memory);
+        assert_eq!(&memory[0xfffe..], &[0x34, 0x12]);
+        assert!(assemble(".org $FFFF\nNOP\nNOP").is_err());
+        assert!(assemble(".org $FFFE\nJMP $8000").is_err());
+        assert!(assemble(".org $8000\nNOP\n.org $7000\nNOP").is_err());
+    }
+}
@@ -1,20 +1,33 @@
 use std::fs::File;
 use std::io::BufReader;
 
-use anyhow::{Error, Result};
+use anyhow::{bail, Error, Result};
 use structopt::StructOpt;
 
+use crate::apu::Apu;
+use crate::battery::SaveMemory;
+use crate::cartridge::{Cartridge, PRG_ROM_BANK_SIZE};
 use crate::cli::Cli;
 use crate::constant::ADDRESS_BRK;
+use crate::constant::ADDRESS_IRQ;
+use crate::constant::ADDRESS_NMI;
 use crate::constant::ADDRESS_TEST_PROGRAM;
 use crate::constant::MEMORY_MAX;
 use crate::constant::NEGATIVE_FLAG;
 use crate::constant::PC_ADDRESS_RESET;
+use crate::constant::PRG_RAM_ADDRESS;
+use crate::constant::PRG_RAM_SIZE;
 use crate::constant::PRG_ROM_ADDRESS;
 use crate::cpu::debugger::CpuDebugger;
+use crate::cpu::disassembler::{disassemble_one, DisassembledInstruction};
 use crate::cpu::instruction::CpuInstruction;
 use crate::cpu::opcode::{Operation, OPCODE_TABLE};
+use crate::cpu::symbols::SymbolTable;
+use crate::cpu::trace::{trace_line, TraceLog};
+use crate::input::InputPorts;
 use crate::mem::Mem;
+use crate::ppu::Ppu;
+use crate::savestate::{Savestate, StateReader, StateWriter};
 use crate::stack::get_sp_offset;
 use crate::stack::Stacked;
 
@@ -22,6 +35,20 @@ use super::CpuRegister;
 
 // reference: https://www.nesdev.org/wiki/CPU_registers
 
+/// The last access the CPU made on its bus
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BusAccess {
+    Read(u16),
+    Write(u16),
+}
+
+/// One access in the bus log, with the value that was read or written
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BusCycle {
+    pub access: BusAccess,
+    pub value: u8,
+}
+
 #[derive(Debug)]
 pub struct Cpu6502 {
     pub debugger: CpuDebugger<u8>,
@@ -31,6 +58,36 @@ pub struct Cpu6502 {
     /// The stack address space is hardwired to memory page $01, i.e. the address range $0100–$01FF (256–511)
     pub mapper: [u8; MEMORY_MAX], // 64KB
     pub instr: Option<CpuInstruction>, // The currently executing instruction
+    /// $2000-$3FFF: PPU registers, $4014: OAM DMA
+    pub ppu: Box<Ppu>,
+    /// $4000-$4017: APU and I/O registers
+    pub apu: Apu,
+    /// $4016/$4017: controller ports
+    pub input: InputPorts,
+    /// Total number of CPU cycles elapsed, including DMA stalls
+    pub cycles: u64,
+    /// Cycles of the instruction in flight that have already been ticked
+    pub instr_cycles: u8,
+    pub last_access: Option<BusAccess>,
+    /// The last value driven on the data bus, seen in bits a device doesn't drive
+    pub open_bus: u8,
+    /// Set by writes to PRG RAM, cleared once it's saved
+    pub prg_ram_dirty: bool,
+    /// Mapper save memory that replaces PRG RAM as the battery save, like a serial EEPROM
+    pub eeprom: Option<Box<dyn SaveMemory>>,
+    /// Log every instruction in the nestest format before it executes
+    pub trace: Option<TraceLog>,
+    /// Treat the whole address space as plain RAM, without mirrors or devices, for CPU test suites.
+    /// The APU isn't clocked, so it never raises an IRQ.
+    pub flat_bus: bool,
+    /// Halt at a BRK instead of executing it, the end of a program for the debugger and test harness
+    pub stop_on_brk: bool,
+    /// Every access made on the bus while set
+    pub bus_log: Option<Vec<BusCycle>>,
+    /// Size of the PRG ROM mapped at $8000-$FFFF, 0 without a cartridge
+    pub prg_rom_size: usize,
+    /// Labels shown in traces and the debugger
+    pub symbols: SymbolTable,
 }
 
 impl Default for Cpu6502 {
@@ -42,50 +99,84 @@ impl Default for Cpu6502 {
             registers: CpuRegister::default(),
             mapper: [0u8; MEMORY_MAX],
             instr: None,
+            ppu: Box::default(),
+            apu: Apu::default(),
+            input: InputPorts::default(),
+            cycles: 0,
+            instr_cycles: 0,
+            last_access: None,
+            open_bus: 0,
+            prg_ram_dirty: false,
+            eeprom: None,
+            trace: None,
+            flat_bus: false,
+            stop_on_brk: true,
+            bus_log: None,
+            prg_rom_size: 0,
+            symbols: SymbolTable::default(),
         }
     }
 }
 
 pub trait Clocked {
-    fn clocked(self: &mut Self) -> Result<bool>;
+    fn clocked(&mut self) -> Result<bool>;
 }
 
 impl Clocked for Cpu6502 {
-    fn clocked(self: &mut Self) -> Result<bool> {
+    fn clocked(&mut self) -> Result<bool> {
+        // The PPU raises an NMI at the start of vblank, it can't be masked
+        if std::mem::take(&mut self.ppu.nmi) {
+            self.interrupt(ADDRESS_NMI)?;
+        }
+        // A flat bus has no APU to raise an IRQ
+        if !self.flat_bus && self.apu.irq() && !self.registers.interrupt_disabled {
+            self.interrupt(ADDRESS_IRQ)?;
+        }
+
+        if let Some(mut trace) = self.trace.take() {
+            let result = trace.write_line(&trace_line(self));
+            self.trace = Some(trace);
+            result?;
+        }
+
         // // load cpu program counter register at $8000
         if let Ok(opcode) = self.mem_read(self.registers.pc) {
-            let (addr, addr_value, num_bytes, mut instr) =
-                self.decode_instruction(opcode as u8).unwrap();
-
-            instr.mode_args = addr_value;
-            instr.write_target = addr;
+            let (num_bytes, instr) = self.decode_instruction(opcode)?;
 
-            if instr.opcode == Operation::BRK {
+            if instr.opcode == Operation::BRK && self.stop_on_brk {
                 self.debugger.debug_instr(self, instr);
                 return Ok(false);
             }
 
-            self.instr = Some(instr);
-
             // Debug the instruction
             self.debugger.debug_instr(self, instr);
 
-            println!("Program counter {:0x?}", self.registers.pc);
+            if self.debugger.verbose {
+                println!("Program counter {:0x?}", self.registers.pc);
+            }
 
             self.registers.pc = self.registers.pc.wrapping_add(num_bytes);
+            let clocks_before = self.clocks_to_pause;
             self.execute_instruction(&instr)?;
 
-            println!("After => Program counter {:0x?}", self.registers.pc);
+            if self.debugger.verbose {
+                println!("After => Program counter {:0x?}", self.registers.pc);
+            }
 
+            // Taken branches add their extra cycles to clocks_to_pause while executing
+            let extra_cycles = self.clocks_to_pause.wrapping_sub(clocks_before);
             self.clocks_to_pause = self.clocks_to_pause.wrapping_add(instr.cycle - 1);
+            let cycles = instr.cycle + extra_cycles;
+            let remaining = cycles.saturating_sub(self.instr_cycles);
+            self.instr_cycles = cycles;
+            self.tick(remaining)?;
             return Ok(true);
         }
-        return Ok(false);
+        Ok(false)
     }
 }
 
 impl Stacked for Cpu6502 {
-    #[must_use]
     #[inline]
     fn push_stack(&mut self, val: u8) -> Result<()> {
         let sp = self.registers.sp;
@@ -95,7 +186,6 @@ impl Stacked for Cpu6502 {
         Ok(())
     }
 
-    #[must_use]
     #[inline]
     fn pop_stack(&mut self) -> Result<u8> {
         // increase the stack pointer by 1, read from the base + offset address
@@ -105,27 +195,169 @@ impl Stacked for Cpu6502 {
 }
 
 impl Mem for Cpu6502 {
-    fn mem_read(&self, addr: u16) -> Result<u8> {
-        match addr {
+    fn mem_read(&mut self, addr: u16) -> Result<u8> {
+        self.last_access = Some(BusAccess::Read(addr));
+        let value = match addr {
+            _ if self.flat_bus => self.mapper[addr as usize],
             0x0000..=0x1fff => {
                 // Mask to zero out the highest two bits in a 16-bit address
                 let mirror_down_addr = addr & 0b00000111_11111111;
-                println!("Read from address {:0x?}", mirror_down_addr);
-                Ok(self.mapper[mirror_down_addr as usize])
+                if self.debugger.verbose {
+                    println!("Read from address {:0x?}", mirror_down_addr);
+                }
+                self.mapper[mirror_down_addr as usize]
+            }
+            0x2000..=0x3fff => {
+                self.catch_up()?;
+                self.ppu.read_register(addr)
+            }
+            0x4015 => {
+                self.catch_up()?;
+                self.apu.read_status()
             }
-            _ => Ok(self.mapper[addr as usize]),
+            // Only D0-D4 are driven by the ports, the rest is left over on the bus.
+            // reference: https://www.nesdev.org/wiki/Open_bus_behavior
+            0x4016 | 0x4017 => {
+                self.catch_up()?;
+                let port = (addr - 0x4016) as usize;
+                (self.open_bus & 0xe0) | self.input.read(port)
+            }
+            _ => self.mapper[addr as usize],
+        };
+        self.open_bus = value;
+        if let Some(log) = self.bus_log.as_mut() {
+            log.push(BusCycle {
+                access: BusAccess::Read(addr),
+                value,
+            });
         }
+        Ok(value)
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) -> Result<()> {
-        self.mapper[addr as usize] = data;
+        self.last_access = Some(BusAccess::Write(addr));
+        self.open_bus = data;
+        if let Some(log) = self.bus_log.as_mut() {
+            log.push(BusCycle {
+                access: BusAccess::Write(addr),
+                value: data,
+            });
+        }
+        match addr {
+            _ if self.flat_bus => self.mapper[addr as usize] = data,
+            0x2000..=0x3fff => {
+                self.catch_up()?;
+                self.ppu.write_register(addr, data)
+            }
+            0x4014 => {
+                self.catch_up()?;
+                self.oam_dma(data)?;
+            }
+            0x4000..=0x4013 | 0x4015 | 0x4017 => {
+                self.catch_up()?;
+                self.apu.write_register(addr, data)
+            }
+            0x4016 => {
+                self.catch_up()?;
+                self.input.write(data)
+            }
+            0x6000..=0x7fff => {
+                self.prg_ram_dirty = true;
+                self.mapper[addr as usize] = data;
+            }
+            _ => self.mapper[addr as usize] = data,
+        }
         Ok(())
     }
 }
 
 impl Cpu6502 {
+    /// Read memory the way the CPU sees it without side effects on the registers of devices
+    #[must_use]
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            _ if self.flat_bus => self.mapper[addr as usize],
+            0x0000..=0x1fff => self.mapper[(addr & 0b00000111_11111111) as usize],
+            0x2000..=0x3fff => self.ppu.peek_register(addr),
+            0x4016 | 0x4017 => (self.open_bus & 0xe0) | self.input.peek((addr - 0x4016) as usize),
+            _ => self.mapper[addr as usize],
+        }
+    }
+
+    /// Write memory without side effects on the registers of devices
+    pub fn poke(&mut self, addr: u16, value: u8) {
+        match addr {
+            _ if self.flat_bus => self.mapper[addr as usize] = value,
+            0x0000..=0x1fff => self.mapper[(addr & 0b00000111_11111111) as usize] = value,
+            _ => self.mapper[addr as usize] = value,
+        }
+    }
+
+    /// Offset in PRG ROM that `addr` maps to with the current banks
+    #[must_use]
+    pub fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if self.flat_bus || self.prg_rom_size == 0 || addr < PRG_ROM_ADDRESS {
+            return None;
+        }
+        Some((addr - PRG_ROM_ADDRESS) as usize % self.prg_rom_size)
+    }
+
+    /// Address the PRG ROM `offset` is mapped at with the current banks. NROM-128 is mirrored,
+    /// the bank at $C000 is the one holding the vectors.
+    #[must_use]
+    pub fn prg_rom_address(&self, offset: usize) -> Option<u16> {
+        if self.flat_bus || offset >= self.prg_rom_size {
+            return None;
+        }
+        Some((0x10000 - self.prg_rom_size + offset) as u16)
+    }
+
+    /// Label of `addr` in the bank mapped there
+    #[must_use]
+    pub fn label(&self, addr: u16) -> Option<String> {
+        if self.symbols.is_empty() {
+            return None;
+        }
+        self.symbols.label(addr, self.prg_rom_offset(addr))
+    }
+
+    /// Address of a label, the one in a mapped bank when it's defined in several
+    #[must_use]
+    pub fn symbol_address(&self, name: &str) -> Option<u16> {
+        let mut fallback = None;
+        for symbol in self.symbols.named(name) {
+            let Some(address) = symbol
+                .address
+                .or_else(|| self.prg_rom_address(symbol.prg_offset?))
+            else {
+                continue;
+            };
+            match (symbol.prg_offset, self.prg_rom_offset(address)) {
+                (Some(offset), Some(mapped)) if offset != mapped => {
+                    fallback = fallback.or(Some(address))
+                }
+                _ => return Some(address),
+            }
+        }
+        fallback
+    }
+
+    /// Disassemble the memory from `start` to `end` inclusive
+    #[must_use]
+    pub fn disassemble(&self, start: u16, end: u16) -> Vec<DisassembledInstruction> {
+        let mut lines = Vec::new();
+        let mut address = start as u32;
+        while address <= end as u32 {
+            let bytes: Vec<u8> = (0..3).map(|i| self.peek((address + i) as u16)).collect();
+            let line = disassemble_one(&bytes, address as u16);
+            address += line.bytes.len() as u32;
+            lines.push(line);
+        }
+        lines
+    }
+
     // memory
-    pub fn read_write_target(&self, write_target: Option<u16>) -> Result<u8> {
+    pub fn read_write_target(&mut self, write_target: Option<u16>) -> Result<u8> {
         Ok(match write_target {
             None => self.registers.a,
             Some(ptr) => self.mem_read(ptr)?,
@@ -183,17 +415,116 @@ impl Cpu6502 {
         self.registers.negative = v & 0b10000000 > 0;
     }
 
-    #[allow(unused)]
+    #[allow(unused, clippy::identity_op)]
     pub fn status_register_byte(&self, is_instruction: bool) -> u8 {
-        let result = ((self.registers.carry      as u8) << 0) |
+        ((self.registers.carry      as u8) << 0) |
             ((self.registers.zero       as u8) << 1) |
             ((self.registers.interrupt_disabled as u8) << 2) |
             ((self.registers.decimal    as u8) << 3) |
             (0                       << 4) | // Break flag
             ((if is_instruction {1} else {0}) << 5) |
             ((self.registers.overflow   as u8) << 6) |
-            ((self.registers.negative   as u8) << 7);
-        return result;
+            ((self.registers.negative   as u8) << 7)
+    }
+
+    /// Run the IRQ/NMI sequence: push the return address and status, then jump through the vector
+    pub fn interrupt(&mut self, vector: u16) -> Result<()> {
+        self.push_stack16(self.registers.pc)?;
+        // The break flag is only set when the status is pushed by BRK/PHP
+        let sr = self.status_register_byte(true);
+        self.push_stack(sr)?;
+        self.registers.interrupt_disabled = true;
+        self.registers.pc = self.mem_read_u16(vector)?;
+        self.tick(7)
+    }
+
+    /// Advance the rest of the console by the given number of CPU cycles.
+    /// The DMC can request a DMA on any of these cycles, which halts the CPU and
+    /// makes the remaining devices run for the stolen cycles as well.
+    pub fn tick(&mut self, cycles: u8) -> Result<()> {
+        if self.flat_bus {
+            self.cycles += cycles as u64;
+            return Ok(());
+        }
+        let mut remaining = cycles as u32;
+        while remaining > 0 {
+            for _ in 0..3 {
+                self.ppu.tick();
+            }
+            self.apu.clock();
+            self.cycles += 1;
+            remaining -= 1;
+
+            if let Some(addr) = self.apu.dmc.dma_request() {
+                remaining += self.dmc_dma(addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Register accesses happen on the last cycle of an instruction, while the
+    /// devices are otherwise only ticked once it has finished. Tick the cycles before
+    /// the access first, so timing sensitive registers like $4017 see the right cycle.
+    fn catch_up(&mut self) -> Result<()> {
+        let Some(instr) = self.instr else {
+            return Ok(());
+        };
+        let target = instr.cycle.saturating_sub(1);
+        if self.instr_cycles < target {
+            let cycles = target - self.instr_cycles;
+            self.instr_cycles = target;
+            self.tick(cycles)?;
+        }
+        Ok(())
+    }
+
+    /// Fetch the next DMC sample byte on behalf of the APU and return how many cycles the CPU was halted.
+    /// reference: https://www.nesdev.org/wiki/DMA#DMC_DMA
+    fn dmc_dma(&mut self, addr: u16) -> Result<u32> {
+        // The CPU can only be halted on a read cycle, so a DMA landing on a write
+        // waits for the next cycle and loses one stall cycle in the process
+        let stall = match self.last_access {
+            Some(BusAccess::Write(_)) => 3,
+            Some(BusAccess::Read(last)) => {
+                // While halted the CPU keeps repeating its last read. For $4016/$4017
+                // the extra read clocks the controller shift register again,
+                // so a button bit gets dropped from the read in progress.
+                if last == 0x4016 || last == 0x4017 {
+                    self.mem_read(last)?;
+                }
+                4
+            }
+            None => 4,
+        };
+
+        let value = self.mem_read(addr)?;
+        self.apu.dmc.fill_buffer(value);
+        Ok(stall)
+    }
+
+    /// Copy a page to OAM through $2004. The CPU is halted for 513 cycles, one more
+    /// to line up with the reads when the DMA starts on an odd cycle.
+    /// reference: https://www.nesdev.org/wiki/DMA#OAM_DMA
+    fn oam_dma(&mut self, page: u8) -> Result<()> {
+        self.tick(1 + (self.cycles % 2) as u8)?;
+        for low in 0..=0xff {
+            let value = self.mem_read(u16::from_be_bytes([page, low]))?;
+            self.ppu.write_oam(value);
+            self.tick(2)?;
+        }
+        Ok(())
+    }
+
+    /// Power cycle the console: clear the internal RAM and every register, then reset
+    pub fn power_on(&mut self) -> Result<()> {
+        self.registers = CpuRegister::default();
+        self.mapper[..0x0800].fill(0);
+        self.clocks_to_pause = 0;
+        self.instr_cycles = 0;
+        self.last_access = None;
+        self.open_bus = 0;
+        self.apu.power_on();
+        self.reset()
     }
 
     pub fn reset(&mut self) -> Result<()> {
@@ -201,13 +532,15 @@ impl Cpu6502 {
 
         self.registers.a = 0;
         self.registers.x = 0;
+        // Interrupts are masked until the program clears the flag itself
+        self.registers.interrupt_disabled = true;
         // // Reset the address of program counter
         self.registers.pc = self.mem_read_u16(PC_ADDRESS_RESET).unwrap();
         Ok(())
     }
 
     #[allow(unused)]
-    pub fn load_program(self: &mut Self, program: Vec<u8>) -> Result<()> {
+    pub fn load_program(&mut self, program: Vec<u8>) -> Result<()> {
         // $8000–$FFFF: ROM and mapper registers ((see MMC1 and UxROM for examples))
         let program_rom_address = PRG_ROM_ADDRESS as usize;
         self.mapper[program_rom_address..(program_rom_address + program.len())]
@@ -228,7 +561,30 @@ impl Cpu6502 {
         Ok(())
     }
 
-    pub fn load_test_program(self: &mut Self, program: Vec<u8>) -> Result<()> {
+    /// Map the cartridge's PRG ROM into $8000-$FFFF. Only NROM is supported:
+    /// a single 16KB bank is mirrored into both halves.
+    pub fn load_cartridge(&mut self, cartridge: &Cartridge) -> Result<()> {
+        if cartridge.mapper != 0 {
+            bail!(
+                "unsupported mapper {}, only NROM (mapper 0) is emulated",
+                cartridge.mapper
+            );
+        }
+        let prg_rom_address = PRG_ROM_ADDRESS as usize;
+        match cartridge.prg_rom.len() {
+            PRG_ROM_BANK_SIZE => {
+                let mirror_address = prg_rom_address + PRG_ROM_BANK_SIZE;
+                self.mapper[prg_rom_address..mirror_address].copy_from_slice(&cartridge.prg_rom);
+                self.mapper[mirror_address..].copy_from_slice(&cartridge.prg_rom);
+            }
+            0x8000 => self.mapper[prg_rom_address..].copy_from_slice(&cartridge.prg_rom),
+            size => bail!("unsupported PRG ROM size {:#x} for NROM", size),
+        }
+        self.prg_rom_size = cartridge.prg_rom.len();
+        Ok(())
+    }
+
+    pub fn load_test_program(&mut self, program: Vec<u8>) -> Result<()> {
         let program_rom_address = PRG_ROM_ADDRESS as usize;
         self.mapper[program_rom_address..(program_rom_address + program.len())]
             .copy_from_slice(&program[..]);
@@ -245,7 +601,7 @@ impl Cpu6502 {
         Ok(())
     }
 
-    pub fn run(self: &mut Self) -> Result<()> {
+    pub fn run(&mut self) -> Result<()> {
         let mut clock_status = true;
         while clock_status && self.registers.pc != ADDRESS_BRK {
             if self.clocks_to_pause > 0 {
@@ -257,7 +613,7 @@ impl Cpu6502 {
     }
 
     #[allow(unused)]
-    pub fn bounded_run(self: &mut Self, steps: usize) -> Result<()> {
+    pub fn bounded_run(&mut self, steps: usize) -> Result<()> {
         for _ in 0..steps {
             let clock_status = self.clocked()?;
             if !clock_status {
@@ -267,39 +623,41 @@ impl Cpu6502 {
         Ok(())
     }
 
-    fn decode_instruction(
-        self: &Self,
-        opcode: u8,
-    ) -> Result<(Option<u16>, u16, u16, CpuInstruction)> {
-        let (opcode, address_mode, cycle, extra_cycle) = &OPCODE_TABLE[opcode as usize];
-        let (addr, addr_value, num_bytes) = self.decode_addressing_mode(*address_mode)?;
-        Ok((
-            addr,
-            addr_value,
-            num_bytes + 1,
-            CpuInstruction {
-                opcode: *opcode,
-                cycle: *cycle,
-                address_mode: *address_mode,
-                extra_cycle: *extra_cycle,
-                write_target: None,
-                mode_args: 0,
-            },
-        ))
+    /// Decode the instruction at the program counter, reading its operand.
+    /// Returns its length and the instruction, which becomes the current one.
+    fn decode_instruction(&mut self, opcode: u8) -> Result<(u16, CpuInstruction)> {
+        let (operation, address_mode, cycle, extra_cycle) = &OPCODE_TABLE[opcode as usize];
+        let mut instr = CpuInstruction {
+            opcode: *operation,
+            cycle: *cycle,
+            address_mode: *address_mode,
+            extra_cycle: *extra_cycle,
+            write_target: None,
+            mode_args: 0,
+        };
+        // The operand is read on this instruction's cycles, catch_up has to know them
+        self.instr = Some(instr);
+        self.instr_cycles = 0;
+        let (addr, addr_value, num_bytes) =
+            self.decode_addressing_mode(*address_mode, operation.reads_operand())?;
+        instr.write_target = addr;
+        instr.mode_args = addr_value;
+        self.instr = Some(instr);
+        Ok((num_bytes + 1, instr))
     }
 
-    fn execute_instruction(self: &mut Self, instruction: &CpuInstruction) -> Result<(), Error> {
+    fn execute_instruction(&mut self, instruction: &CpuInstruction) -> Result<(), Error> {
         macro_rules! execute_opcode {
             ($($opcode:ident),*) => {
                 match instruction.opcode {
                     $(
                         Operation::$opcode => self.$opcode(),
                     )*
-                    _ => unimplemented!()
+                    opcode => bail!("unimplemented instruction {:?}", opcode),
                 }
             };
         }
-        return execute_opcode!(
+        execute_opcode!(
             ADC, AND, ASL, // Axx
             BCC, BCS, BEQ, BIT, BMI, BNE, BPL, BRK, BVC, BVS, // Bxx
             CLC, CLD, CLI, CLV, CMP, CPX, CPY, // Cxx
@@ -313,13 +671,13 @@ impl Cpu6502 {
             PHA, PHP, PLA, PLP, // Pxx
             ROL, ROR, RTI, RTS, // Rxx
             SBC, SEC, SED, SEI, STA, STX, STY, // Sxx
-            TAX, TXA, TAY, TYA, TXS // Txx
-        );
+            TAX, TXA, TAY, TYA, TSX, TXS // Txx
+        )
     }
 
     /// Read image from a provided input path
     #[allow(dead_code)]
-    fn load_image(self: &mut Self) {
+    fn load_image(&mut self) {
         let cli = Cli::from_args();
 
         let f = File::open(cli.path).expect("couldn't open file");
@@ -327,3 +685,126 @@ impl Cpu6502 {
         println!("{}", f.capacity());
     }
 }
+
+/// PRG RAM at $6000-$7FFF
+impl SaveMemory for Cpu6502 {
+    fn save_data(&self) -> Vec<u8> {
+        let start = PRG_RAM_ADDRESS as usize;
+        self.mapper[start..start + PRG_RAM_SIZE].to_vec()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let start = PRG_RAM_ADDRESS as usize;
+        let len = data.len().min(PRG_RAM_SIZE);
+        self.mapper[start..start + len].copy_from_slice(&data[..len]);
+        self.prg_ram_dirty = true;
+    }
+
+    fn take_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.prg_ram_dirty)
+    }
+}
+
+impl Savestate for CpuInstruction {
+    fn save_state(&self, state: &mut StateWriter) {
+        // The operation and addressing mode are stored as the opcode that decodes to them
+        let opcode = OPCODE_TABLE
+            .iter()
+            .position(|&(op, mode, ..)| op == self.opcode && mode == self.address_mode)
+            .unwrap_or_default();
+        state.write_u8(opcode as u8);
+        state.write_u8(self.cycle);
+        state.write_u8(self.extra_cycle);
+        state.write_u16(self.mode_args);
+        state.write_option_u16(self.write_target);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        let (opcode, address_mode, ..) = OPCODE_TABLE[state.read_u8()? as usize];
+        self.opcode = opcode;
+        self.address_mode = address_mode;
+        self.cycle = state.read_u8()?;
+        self.extra_cycle = state.read_u8()?;
+        self.mode_args = state.read_u16()?;
+        self.write_target = state.read_option_u16()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Cpu6502 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.clocks_to_pause);
+        self.registers.save_state(state);
+        state.write_bytes(&self.mapper);
+        state.write_bool(self.instr.is_some());
+        if let Some(instr) = &self.instr {
+            instr.save_state(state);
+        }
+        self.apu.save_state(state);
+        self.input.save_state(state);
+        state.write_u64(self.cycles);
+        state.write_u8(self.instr_cycles);
+        let (kind, addr) = match self.last_access {
+            None => (0, 0),
+            Some(BusAccess::Read(addr)) => (1, addr),
+            Some(BusAccess::Write(addr)) => (2, addr),
+        };
+        state.write_u8(kind);
+        state.write_u16(addr);
+        state.write_u8(self.open_bus);
+        let eeprom = self
+            .eeprom
+            .as_ref()
+            .map(|eeprom| eeprom.save_data())
+            .unwrap_or_default();
+        state.write_u32(eeprom.len() as u32);
+        state.write_bytes(&eeprom);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.clocks_to_pause = state.read_u8()?;
+        self.registers.load_state(state)?;
+        state.read_bytes(&mut self.mapper)?;
+        // The restored PRG RAM has to reach the battery save like any other write
+        self.prg_ram_dirty = true;
+        self.instr = if state.read_bool()? {
+            let (opcode, address_mode, cycle, extra_cycle) = OPCODE_TABLE[0];
+            let mut instr = CpuInstruction {
+                opcode,
+                cycle,
+                address_mode,
+                extra_cycle,
+                mode_args: 0,
+                write_target: None,
+            };
+            instr.load_state(state)?;
+            Some(instr)
+        } else {
+            None
+        };
+        self.apu.load_state(state)?;
+        self.input.load_state(state)?;
+        self.cycles = state.read_u64()?;
+        self.instr_cycles = state.read_u8()?;
+        let (kind, addr) = (state.read_u8()?, state.read_u16()?);
+        self.last_access = match kind {
+            0 => None,
+            1 => Some(BusAccess::Read(addr)),
+            2 => Some(BusAccess::Write(addr)),
+            _ => bail!("invalid bus access in savestate"),
+        };
+        self.open_bus = state.read_u8()?;
+        let mut eeprom = vec![0; state.read_u32()? as usize];
+        state.read_bytes(&mut eeprom)?;
+        match &mut self.eeprom {
+            // Restoring goes through `load_save_data`, so it reaches the battery save too
+            Some(memory) if memory.save_data().len() == eeprom.len() => {
+                memory.load_save_data(&eeprom)
+            }
+            Some(_) => bail!("savestate has a different EEPROM size than the cartridge"),
+            None if !eeprom.is_empty() => bail!("savestate has an EEPROM the cartridge lacks"),
+            None => {}
+        }
+        Ok(())
+    }
+}
@@ -1,13 +1,103 @@
 use std::{
-    fmt::{Binary, Debug},
+    fmt::{self, Binary, Debug},
     marker::PhantomData,
 };
 
-use crate::{cpu::instruction::CpuInstruction, cpu::Cpu6502};
+use anyhow::Result;
+use bitflags::bitflags;
 
-#[derive(Copy, Clone, Debug, Default)]
+use crate::{
+    cpu::instruction::CpuInstruction,
+    cpu::{BusAccess, BusCycle, Cpu6502, Expression, ExpressionContext},
+};
+
+bitflags! {
+    /// Accesses a watchpoint stops on
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct WatchKind: u8 {
+        const READ    = 0b001;
+        const WRITE   = 0b010;
+        const EXECUTE = 0b100;
+    }
+}
+
+/// Stop before the instruction at `address` executes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub address: u16,
+    pub enabled: bool,
+    /// Only stop when this holds
+    pub condition: Option<Expression>,
+    /// Times the address was reached with the condition holding
+    pub hits: u64,
+    /// Hits to let pass before stopping
+    pub ignore: u64,
+}
+
+/// Stop when an address in `start..=end` is accessed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub id: usize,
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+    pub enabled: bool,
+    pub condition: Option<Expression>,
+    pub hits: u64,
+    pub ignore: u64,
+}
+
+/// Why execution stopped
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BreakReason {
+    Breakpoint { id: usize, address: u16 },
+    Execute { id: usize, address: u16 },
+    Access { id: usize, cycle: BusCycle },
+}
+
+impl fmt::Display for BreakReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Breakpoint { id, address } => write!(f, "breakpoint {} at ${:04X}", id, address),
+            Self::Execute { id, address } => {
+                write!(f, "watchpoint {}: execute ${:04X}", id, address)
+            }
+            Self::Access { id, cycle } => match cycle.access {
+                BusAccess::Read(address) => write!(
+                    f,
+                    "watchpoint {}: read ${:04X} = ${:02X}",
+                    id, address, cycle.value
+                ),
+                BusAccess::Write(address) => write!(
+                    f,
+                    "watchpoint {}: write ${:04X} = ${:02X}",
+                    id, address, cycle.value
+                ),
+            },
+        }
+    }
+}
+
+/// Why the debugger gave control back after running the CPU
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DebugStop {
+    /// Ran every instruction it was asked to
+    Done,
+    Break(BreakReason),
+    /// The CPU reached a BRK
+    Halted,
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct CpuDebugger<T: Binary + Debug> {
     _marker_data: PhantomData<T>,
+    /// Print every executed instruction and RAM access
+    pub verbose: bool,
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
+    /// Breakpoints and watchpoints share their ids
+    next_id: usize,
 }
 
 impl<T> CpuDebugger<T>
@@ -15,21 +105,290 @@ where
     T: Binary + Debug,
 {
     #[allow(unused)]
-    pub fn bin(self, value: T) {
+    pub fn bin(&self, value: T) {
         let x = value;
         println!("{x:#b}")
     }
 
     #[allow(unused)]
-    pub fn hex(self, value: T) {
+    pub fn hex(&self, value: T) {
         let x = value;
         println!("{:0x?}", x)
     }
 
-    pub fn debug_instr(self, cpu: &Cpu6502, instr: CpuInstruction) {
+    pub fn debug_instr(&self, cpu: &Cpu6502, instr: CpuInstruction) {
+        if !self.verbose {
+            return;
+        }
         println!(
             "PC: ${:0x?} | OPCODE: 0x{:0x?} | INSTRUCTION: {:?}",
             cpu.registers.pc, instr.opcode, instr
         );
     }
+
+    fn take_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Add a breakpoint and return its id
+    pub fn add_breakpoint(&mut self, address: u16) -> usize {
+        let id = self.take_id();
+        self.breakpoints.push(Breakpoint {
+            id,
+            address,
+            enabled: true,
+            condition: None,
+            hits: 0,
+            ignore: 0,
+        });
+        id
+    }
+
+    /// Add a watchpoint on `start..=end` and return its id
+    pub fn add_watchpoint(&mut self, start: u16, end: u16, kind: WatchKind) -> usize {
+        let id = self.take_id();
+        self.watchpoints.push(Watchpoint {
+            id,
+            start: start.min(end),
+            end: start.max(end),
+            kind,
+            enabled: true,
+            condition: None,
+            hits: 0,
+            ignore: 0,
+        });
+        id
+    }
+
+    /// Remove a breakpoint or watchpoint, false if there is none with that id
+    pub fn remove(&mut self, id: usize) -> bool {
+        let count = self.breakpoints.len() + self.watchpoints.len();
+        self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+        self.watchpoints.retain(|watchpoint| watchpoint.id != id);
+        count != self.breakpoints.len() + self.watchpoints.len()
+    }
+
+    /// Whether accesses have to be logged for the watchpoints to see them
+    #[must_use]
+    pub fn watches_accesses(&self) -> bool {
+        self.watchpoints.iter().any(|watchpoint| {
+            watchpoint.enabled
+                && watchpoint
+                    .kind
+                    .intersects(WatchKind::READ | WatchKind::WRITE)
+        })
+    }
+
+    /// Change the condition of a breakpoint or watchpoint, false if there is none with that id
+    pub fn set_condition(&mut self, id: usize, condition: Option<Expression>) -> bool {
+        if let Some(breakpoint) = self.breakpoints.iter_mut().find(|b| b.id == id) {
+            breakpoint.condition = condition;
+        } else if let Some(watchpoint) = self.watchpoints.iter_mut().find(|w| w.id == id) {
+            watchpoint.condition = condition;
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// Let the next `count` hits of a breakpoint or watchpoint pass, false if there is none
+    /// with that id
+    pub fn set_ignore(&mut self, id: usize, count: u64) -> bool {
+        if let Some(breakpoint) = self.breakpoints.iter_mut().find(|b| b.id == id) {
+            breakpoint.ignore = breakpoint.hits + count;
+        } else if let Some(watchpoint) = self.watchpoints.iter_mut().find(|w| w.id == id) {
+            watchpoint.ignore = watchpoint.hits + count;
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// Check the breakpoints and execute watchpoints before the instruction at PC runs.
+    /// Every point that is reached counts a hit, the first one to stop is returned.
+    pub fn check_execute(&mut self, context: &ExpressionContext) -> Result<Option<BreakReason>> {
+        let pc = context.cpu.registers.pc;
+        let mut reason = None;
+        for breakpoint in &mut self.breakpoints {
+            if !breakpoint.enabled || breakpoint.address != pc {
+                continue;
+            }
+            let condition = breakpoint.condition.as_ref();
+            if hit(condition, &mut breakpoint.hits, breakpoint.ignore, context)? {
+                reason = reason.or(Some(BreakReason::Breakpoint {
+                    id: breakpoint.id,
+                    address: pc,
+                }));
+            }
+        }
+        for watchpoint in &mut self.watchpoints {
+            if !watchpoint.enabled
+                || !watchpoint.kind.contains(WatchKind::EXECUTE)
+                || !(watchpoint.start..=watchpoint.end).contains(&pc)
+            {
+                continue;
+            }
+            let condition = watchpoint.condition.as_ref();
+            if hit(condition, &mut watchpoint.hits, watchpoint.ignore, context)? {
+                reason = reason.or(Some(BreakReason::Execute {
+                    id: watchpoint.id,
+                    address: pc,
+                }));
+            }
+        }
+        Ok(reason)
+    }
+
+    /// Check the read and write watchpoints against the accesses of the last instruction
+    pub fn check_accesses(
+        &mut self,
+        accesses: &[BusCycle],
+        context: &ExpressionContext,
+    ) -> Result<Option<BreakReason>> {
+        let mut reason = None;
+        for cycle in accesses {
+            let (address, kind) = match cycle.access {
+                BusAccess::Read(address) => (address, WatchKind::READ),
+                BusAccess::Write(address) => (address, WatchKind::WRITE),
+            };
+            let context = ExpressionContext {
+                access: Some((address, cycle.value)),
+                ..*context
+            };
+            for watchpoint in &mut self.watchpoints {
+                if !watchpoint.enabled
+                    || !watchpoint.kind.contains(kind)
+                    || !(watchpoint.start..=watchpoint.end).contains(&address)
+                {
+                    continue;
+                }
+                let condition = watchpoint.condition.as_ref();
+                if hit(condition, &mut watchpoint.hits, watchpoint.ignore, &context)? {
+                    reason = reason.or(Some(BreakReason::Access {
+                        id: watchpoint.id,
+                        cycle: *cycle,
+                    }));
+                }
+            }
+        }
+        Ok(reason)
+    }
+}
+
+/// A subroutine call found on the stack
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StackFrame {
+    /// Address of the JSR
+    pub call_site: u16,
+    /// Subroutine it called
+    pub target: u16,
+}
+
+/// The subroutine calls on the stack, innermost first. The stack doesn't tell what its bytes
+/// are, so two bytes count as a return address when they point right after a JSR.
+#[must_use]
+pub fn call_stack(cpu: &Cpu6502) -> Vec<StackFrame> {
+    const OPCODE_JSR: u8 = 0x20;
+    let mut frames = Vec::new();
+    let mut address = 0x0100 + cpu.registers.sp as u16 + 1;
+    while address < 0x01ff {
+        // JSR pushes the address of its last byte
+        let pushed = u16::from_le_bytes([cpu.peek(address), cpu.peek(address + 1)]);
+        let call_site = pushed.wrapping_sub(2);
+        if cpu.peek(call_site) == OPCODE_JSR {
+            let target = u16::from_le_bytes([cpu.peek(pushed.wrapping_sub(1)), cpu.peek(pushed)]);
+            frames.push(StackFrame { call_site, target });
+            address += 2;
+        } else {
+            address += 1;
+        }
+    }
+    frames
+}
+
+/// Count a hit when the condition holds, and stop once the ignored hits are used up
+fn hit(
+    condition: Option<&Expression>,
+    hits: &mut u64,
+    ignore: u64,
+    context: &ExpressionContext,
+) -> Result<bool> {
+    if let Some(condition) = condition {
+        if !condition.is_true(context)? {
+            return Ok(false);
+        }
+    }
+    *hits += 1;
+    Ok(*hits > ignore)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nes::NesEmulator;
+    use crate::test_util::{nes_with_source, STORE_LOOP_SOURCE};
+
+    /// Run until something other than a plain step stops
+    fn run(nes: &mut NesEmulator) -> DebugStop {
+        loop {
+            match nes.debug_step().unwrap() {
+                DebugStop::Done => {}
+                stop => return stop,
+            }
+        }
+    }
+
+    #[test]
+    fn test_breakpoint_condition() {
+        let mut nes = nes_with_source(STORE_LOOP_SOURCE);
+        let debugger = &mut nes.cpu.debugger;
+        let id = debugger.add_breakpoint(0x8005);
+        debugger.set_condition(id, Some(Expression::parse("X == 2").unwrap()));
+        assert_eq!(
+            run(&mut nes),
+            DebugStop::Break(BreakReason::Breakpoint {
+                id,
+                address: 0x8005
+            })
+        );
+        assert_eq!(
+            (nes.cpu.registers.x, nes.cpu.debugger.breakpoints[0].hits),
+            (2, 1)
+        );
+        // Only hits where the condition holds are counted
+        assert_eq!(run(&mut nes), DebugStop::Halted);
+        assert_eq!(nes.cpu.debugger.breakpoints[0].hits, 1);
+    }
+
+    #[test]
+    fn test_ignore_count() {
+        let mut nes = nes_with_source(STORE_LOOP_SOURCE);
+        let debugger = &mut nes.cpu.debugger;
+        let id = debugger.add_breakpoint(0x800b);
+        debugger.set_ignore(id, 1);
+        let stop = DebugStop::Break(BreakReason::Breakpoint {
+            id,
+            address: 0x800b,
+        });
+        assert_eq!(run(&mut nes), stop);
+        assert_eq!(nes.cpu.registers.x, 1);
+        assert_eq!(run(&mut nes), stop);
+        assert_eq!(nes.cpu.registers.x, 2);
+        assert_eq!(nes.cpu.debugger.breakpoints[0].hits, 3);
+    }
+
+    #[test]
+    fn test_watchpoint_condition() {
+        // Every call writes $0300, only the write of 2 stops
+        let mut nes = nes_with_source(STORE_LOOP_SOURCE);
+        let debugger = &mut nes.cpu.debugger;
+        let watch = debugger.add_watchpoint(0x0300, 0x0300, WatchKind::WRITE);
+        debugger.set_condition(watch, Some(Expression::parse("VALUE == 2").unwrap()));
+        let DebugStop::Break(BreakReason::Access { id, cycle }) = run(&mut nes) else {
+            panic!("the watchpoint did not stop");
+        };
+        assert_eq!((id, cycle.value), (watch, 2));
+        assert_eq!(nes.cpu.debugger.watchpoints[0].hits, 1);
+    }
 }
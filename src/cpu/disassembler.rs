@@ -0,0 +1,263 @@
+use std::fmt;
+
+use crate::cpu::address::AddressingMode::{self, *};
+use crate::cpu::opcode::{Operation, OPCODE_TABLE};
+
+/// Number of bytes an instruction takes, opcode included
+#[must_use]
+pub fn instruction_length(mode: AddressingMode) -> u16 {
+    match mode {
+        Implicit | Accumulator => 1,
+        Immediate | ZeroPage | ZeroPageX | ZeroPageY | IndirectX | IndirectY | Relative => 2,
+        Absolute | AbsoluteX | AbsoluteY | Indirect => 3,
+    }
+}
+
+/// Whether an opcode is one of the 151 documented instructions.
+/// The rest are decoded by the CPU too, but are rendered as data.
+#[must_use]
+pub fn is_official_opcode(opcode: u8) -> bool {
+    let (operation, ..) = OPCODE_TABLE[opcode as usize];
+    match operation {
+        Operation::KIL
+        | Operation::ISC
+        | Operation::DCP
+        | Operation::AXS
+        | Operation::LAS
+        | Operation::LAX
+        | Operation::AHX
+        | Operation::SAX
+        | Operation::XAA
+        | Operation::SHX
+        | Operation::RRA
+        | Operation::TAS
+        | Operation::SHY
+        | Operation::ARR
+        | Operation::SRE
+        | Operation::ALR
+        | Operation::RLA
+        | Operation::ANC
+        | Operation::SLO => false,
+        Operation::NOP => opcode == 0xea,
+        Operation::SBC => opcode != 0xeb,
+        _ => true,
+    }
+}
+
+/// Render the operand of an instruction at `address` in standard 6502 syntax
+#[must_use]
+pub fn format_operand(mode: AddressingMode, address: u16, operand: u16) -> String {
+    format_operand_with(mode, address, operand, |_| None)
+}
+
+/// Render the operand like `format_operand`, with the addresses `label` knows by name
+#[must_use]
+pub fn format_operand_with(
+    mode: AddressingMode,
+    address: u16,
+    operand: u16,
+    label: impl Fn(u16) -> Option<String>,
+) -> String {
+    let zero_page = || label(operand).unwrap_or_else(|| format!("${:02X}", operand));
+    let absolute = |target: u16| label(target).unwrap_or_else(|| format!("${:04X}", target));
+    match mode {
+        Implicit => String::new(),
+        Accumulator => "A".to_string(),
+        Immediate => format!("#${:02X}", operand),
+        ZeroPage => zero_page(),
+        ZeroPageX => format!("{},X", zero_page()),
+        ZeroPageY => format!("{},Y", zero_page()),
+        Absolute => absolute(operand),
+        AbsoluteX => format!("{},X", absolute(operand)),
+        AbsoluteY => format!("{},Y", absolute(operand)),
+        Indirect => format!("({})", absolute(operand)),
+        IndirectX => format!("({},X)", zero_page()),
+        IndirectY => format!("({}),Y", zero_page()),
+        Relative => absolute(branch_target(address, operand as u8)),
+    }
+}
+
+/// Destination of a branch at `address` with the given offset
+#[must_use]
+pub fn branch_target(address: u16, offset: u8) -> u16 {
+    address.wrapping_add(2).wrapping_add(offset as i8 as u16)
+}
+
+/// One line of disassembly
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DisassembledInstruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    /// `None` when the bytes are rendered as `.db` data
+    pub operation: Option<Operation>,
+    pub mode: AddressingMode,
+    /// Operand value, 8 or 16 bits depending on the mode
+    pub operand: u16,
+}
+
+impl DisassembledInstruction {
+    /// The instruction in assembler syntax, e.g. `LDA ($20),Y` or `.db $02`
+    #[must_use]
+    pub fn text(&self) -> String {
+        self.text_with(|_| None)
+    }
+
+    /// The instruction in assembler syntax with the addresses `label` knows by name
+    #[must_use]
+    pub fn text_with(&self, label: impl Fn(u16) -> Option<String>) -> String {
+        let Some(operation) = self.operation else {
+            let bytes: Vec<String> = self.bytes.iter().map(|b| format!("${:02X}", b)).collect();
+            return format!(".db {}", bytes.join(", "));
+        };
+        let operand = format_operand_with(self.mode, self.address, self.operand, label);
+        if operand.is_empty() {
+            format!("{:?}", operation)
+        } else {
+            format!("{:?} {}", operation, operand)
+        }
+    }
+
+    /// The line as displayed, with the addresses `label` knows by name
+    #[must_use]
+    pub fn line_with(&self, label: impl Fn(u16) -> Option<String>) -> String {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        format!(
+            "${:04X}  {:<8}  {}",
+            self.address,
+            bytes.join(" "),
+            self.text_with(label)
+        )
+    }
+
+    /// Absolute address the instruction refers to, if it has one
+    #[must_use]
+    pub fn target(&self) -> Option<u16> {
+        self.operation?;
+        match self.mode {
+            Relative => Some(branch_target(self.address, self.operand as u8)),
+            Implicit | Accumulator | Immediate => None,
+            _ => Some(self.operand),
+        }
+    }
+}
+
+impl fmt::Display for DisassembledInstruction {
+    // $C000  B1 20     LDA ($20),Y
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.line_with(|_| None))
+    }
+}
+
+/// Decode the instruction at the start of `bytes`, which sit at `address`.
+/// Unofficial opcodes and instructions cut off by the end of the buffer become a `.db` byte.
+#[must_use]
+pub fn disassemble_one(bytes: &[u8], address: u16) -> DisassembledInstruction {
+    let opcode = bytes[0];
+    let (operation, mode, ..) = OPCODE_TABLE[opcode as usize];
+    let len = instruction_length(mode) as usize;
+    if !is_official_opcode(opcode) || bytes.len() < len {
+        return DisassembledInstruction {
+            address,
+            bytes: vec![opcode],
+            operation: None,
+            mode: Implicit,
+            operand: 0,
+        };
+    }
+    let operand = match len {
+        2 => bytes[1] as u16,
+        3 => u16::from_le_bytes([bytes[1], bytes[2]]),
+        _ => 0,
+    };
+    DisassembledInstruction {
+        address,
+        bytes: bytes[..len].to_vec(),
+        operation: Some(operation),
+        mode,
+        operand,
+    }
+}
+
+/// Disassemble a block of code linearly, e.g. a raw PRG bank loaded at `origin`
+#[must_use]
+pub fn disassemble(bytes: &[u8], origin: u16) -> Vec<DisassembledInstruction> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let address = origin.wrapping_add(offset as u16);
+        let line = disassemble_one(&bytes[offset..], address);
+        offset += line.bytes.len();
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu6502;
+
+    fn text(bytes: &[u8]) -> Vec<String> {
+        disassemble(bytes, 0xc000)
+            .iter()
+            .map(|line| line.text())
+            .collect()
+    }
+
+    #[test]
+    fn test_addressing_modes() {
+        let program = [
+            0xa9, 0x00, // LDA #$00
+            0xb1, 0x20, // LDA ($20),Y
+            0xa1, 0x20, // LDA ($20,X)
+            0xd0, 0xfa, // BNE $C002
+            0x6c, 0x34, 0x12, // JMP ($1234)
+            0x9d, 0x00, 0x02, // STA $0200,X
+            0xb6, 0x10, // LDX $10,Y
+            0x0a, // ASL A
+            0x60, // RTS
+        ];
+        assert_eq!(
+            text(&program),
+            vec![
+                "LDA #$00",
+                "LDA ($20),Y",
+                "LDA ($20,X)",
+                "BNE $C002",
+                "JMP ($1234)",
+                "STA $0200,X",
+                "LDX $10,Y",
+                "ASL A",
+                "RTS",
+            ]
+        );
+        assert_eq!(instruction_length(AddressingMode::AbsoluteX), 3);
+    }
+
+    #[test]
+    fn test_unknown_and_truncated_opcodes() {
+        // KIL, the unofficial LAX and a JSR cut off by the end of the buffer
+        assert_eq!(
+            text(&[0x02, 0xa7, 0x20, 0x00]),
+            vec![".db $02", ".db $A7", ".db $20", "BRK"]
+        );
+        assert_eq!((0..=255).filter(|&op| is_official_opcode(op)).count(), 151);
+    }
+
+    #[test]
+    fn test_listing_and_branch_target() {
+        let lines = disassemble(&[0xb1, 0x20, 0xd0, 0xfc], 0xc002);
+        assert_eq!(lines[0].to_string(), "$C002  B1 20     LDA ($20),Y");
+        assert_eq!(lines[1].target(), Some(0xc002));
+    }
+
+    #[test]
+    fn test_cpu_memory() {
+        // The memory of a running machine, RAM mirrors included
+        let mut cpu = Cpu6502::default();
+        cpu.mapper[0..3].copy_from_slice(&[0x4c, 0x00, 0xc0]);
+        let lines = cpu.disassemble(0x0800, 0x0803);
+        assert_eq!(lines[0].text(), "JMP $C000");
+        assert_eq!(lines.len(), 2);
+    }
+}
@@ -0,0 +1,429 @@
+use std::fmt;
+
+use anyhow::{bail, Result};
+
+use crate::cpu::{Cpu6502, SymbolTable};
+
+/// Everything a condition can look at when a breakpoint or watchpoint hits
+#[derive(Copy, Clone, Debug)]
+pub struct ExpressionContext<'a> {
+    pub cpu: &'a Cpu6502,
+    pub scanline: u16,
+    pub dot: u16,
+    pub frame: u64,
+    /// Address and value of the access that hit a watchpoint
+    pub access: Option<(u16, u8)>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Variable {
+    A,
+    X,
+    Y,
+    Sp,
+    P,
+    Pc,
+    Negative,
+    Overflow,
+    Decimal,
+    InterruptDisabled,
+    Zero,
+    Carry,
+    Scanline,
+    Dot,
+    Frame,
+    Cycles,
+    Value,
+    Address,
+}
+
+const VARIABLES: [(&str, Variable); 18] = [
+    ("a", Variable::A),
+    ("x", Variable::X),
+    ("y", Variable::Y),
+    ("sp", Variable::Sp),
+    ("p", Variable::P),
+    ("pc", Variable::Pc),
+    ("n", Variable::Negative),
+    ("v", Variable::Overflow),
+    ("d", Variable::Decimal),
+    ("i", Variable::InterruptDisabled),
+    ("z", Variable::Zero),
+    ("c", Variable::Carry),
+    ("scanline", Variable::Scanline),
+    ("dot", Variable::Dot),
+    ("frame", Variable::Frame),
+    ("cycles", Variable::Cycles),
+    ("value", Variable::Value),
+    ("address", Variable::Address),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Node {
+    Number(i64),
+    Variable(Variable),
+    /// `[addr]`
+    Byte(Box<Node>),
+    /// `{addr}`, little endian
+    Word(Box<Node>),
+    /// Address of a label, looked up in the bank mapped when evaluated
+    Symbol(String),
+    Unary(&'static str, Box<Node>),
+    Binary(&'static str, Box<Node>, Box<Node>),
+}
+
+/// Binary operators from the loosest to the tightest binding, as in Rust, so bitwise operators
+/// bind tighter than comparisons. Longer operators come first to match them before a prefix.
+const OPERATORS: [(&str, usize); 18] = [
+    ("||", 0),
+    ("&&", 1),
+    ("==", 2),
+    ("!=", 2),
+    ("<=", 2),
+    (">=", 2),
+    ("<<", 6),
+    (">>", 6),
+    ("<", 2),
+    (">", 2),
+    ("|", 3),
+    ("^", 4),
+    ("&", 5),
+    ("+", 7),
+    ("-", 7),
+    ("*", 8),
+    ("/", 8),
+    ("%", 8),
+];
+
+/// A condition for breakpoints and watchpoints, e.g. `X == 2 && [$0300] & $80`.
+///
+/// Values are registers (`A X Y SP P PC`), flags (`N V D I Z C`), `SCANLINE`, `DOT`, `FRAME`,
+/// `CYCLES`, and `VALUE` and `ADDRESS` of the access that hit a watchpoint. `[addr]` reads a
+/// byte and `{addr}` a word. Numbers are `$hex`, `%binary` or decimal, anything but 0 is true.
+/// Other names are labels of the loaded symbols, which can start with `_` or `@`. Labels always
+/// win: one spelled exactly like a built-in value, e.g. `c` or `frame`, takes its place, and the
+/// value is still there in another case, e.g. `C` or `FRAME`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Self> {
+        Self::parse_with_symbols(source, &SymbolTable::default())
+    }
+
+    /// Parse an expression that can refer to the labels in `symbols`
+    pub fn parse_with_symbols(source: &str, symbols: &SymbolTable) -> Result<Self> {
+        let mut parser = Parser {
+            input: source.as_bytes(),
+            pos: 0,
+            symbols,
+        };
+        let root = parser.binary(0)?;
+        if let Some(c) = parser.peek() {
+            bail!("unexpected '{}' in expression", c as char);
+        }
+        Ok(Self {
+            source: source.trim().to_string(),
+            root,
+        })
+    }
+
+    pub fn evaluate(&self, context: &ExpressionContext) -> Result<i64> {
+        evaluate(&self.root, context)
+    }
+
+    /// Whether the expression evaluates to something other than 0
+    pub fn is_true(&self, context: &ExpressionContext) -> Result<bool> {
+        Ok(self.evaluate(context)? != 0)
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn evaluate(node: &Node, context: &ExpressionContext) -> Result<i64> {
+    let cpu = context.cpu;
+    let registers = &cpu.registers;
+    Ok(match node {
+        Node::Number(value) => *value,
+        Node::Variable(variable) => match variable {
+            Variable::A => registers.a as i64,
+            Variable::X => registers.x as i64,
+            Variable::Y => registers.y as i64,
+            Variable::Sp => registers.sp as i64,
+            Variable::P => cpu.status_register_byte(true) as i64,
+            Variable::Pc => registers.pc as i64,
+            Variable::Negative => registers.negative as i64,
+            Variable::Overflow => registers.overflow as i64,
+            Variable::Decimal => registers.decimal as i64,
+            Variable::InterruptDisabled => registers.interrupt_disabled as i64,
+            Variable::Zero => registers.zero as i64,
+            Variable::Carry => registers.carry as i64,
+            Variable::Scanline => context.scanline as i64,
+            Variable::Dot => context.dot as i64,
+            Variable::Frame => context.frame as i64,
+            Variable::Cycles => cpu.cycles as i64,
+            Variable::Value | Variable::Address => {
+                let Some((address, value)) = context.access else {
+                    bail!("VALUE and ADDRESS only exist for watchpoints on reads and writes");
+                };
+                match variable {
+                    Variable::Value => value as i64,
+                    _ => address as i64,
+                }
+            }
+        },
+        Node::Symbol(name) => match cpu.symbol_address(name) {
+            Some(address) => address as i64,
+            None => bail!("label '{}' is not mapped", name),
+        },
+        Node::Byte(address) => cpu.peek(evaluate(address, context)? as u16) as i64,
+        Node::Word(address) => {
+            let address = evaluate(address, context)? as u16;
+            let lo = cpu.peek(address) as i64;
+            let hi = cpu.peek(address.wrapping_add(1)) as i64;
+            hi << 8 | lo
+        }
+        Node::Unary(op, operand) => {
+            let value = evaluate(operand, context)?;
+            match *op {
+                "-" => value.wrapping_neg(),
+                "~" => !value,
+                _ => (value == 0) as i64,
+            }
+        }
+        Node::Binary(op, left, right) => {
+            let l = evaluate(left, context)?;
+            // Short-circuit so `X < 4 && [table + X]` never reads past the table
+            match *op {
+                "&&" if l == 0 => return Ok(0),
+                "||" if l != 0 => return Ok(1),
+                _ => {}
+            }
+            let r = evaluate(right, context)?;
+            match *op {
+                "&&" | "||" => (r != 0) as i64,
+                "|" => l | r,
+                "^" => l ^ r,
+                "&" => l & r,
+                "==" => (l == r) as i64,
+                "!=" => (l != r) as i64,
+                "<=" => (l <= r) as i64,
+                ">=" => (l >= r) as i64,
+                "<" => (l < r) as i64,
+                ">" => (l > r) as i64,
+                "<<" => l.wrapping_shl(r as u32),
+                ">>" => l.wrapping_shr(r as u32),
+                "+" => l.wrapping_add(r),
+                "-" => l.wrapping_sub(r),
+                "*" => l.wrapping_mul(r),
+                _ if r == 0 => bail!("division by zero in '{}'", op),
+                "/" => l.wrapping_div(r),
+                _ => l.wrapping_rem(r),
+            }
+        }
+    })
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    symbols: &'a SymbolTable,
+}
+
+impl Parser<'_> {
+    fn peek(&mut self) -> Option<u8> {
+        while self
+            .input
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+        self.input.get(self.pos).copied()
+    }
+
+    /// The binary operator at the current position with its precedence
+    fn operator(&mut self) -> Option<(&'static str, usize)> {
+        self.peek();
+        let rest = &self.input[self.pos..];
+        OPERATORS
+            .iter()
+            .find(|(op, _)| rest.starts_with(op.as_bytes()))
+            .copied()
+    }
+
+    fn binary(&mut self, min_precedence: usize) -> Result<Node> {
+        let mut left = self.unary()?;
+        while let Some((op, precedence)) = self.operator() {
+            if precedence < min_precedence {
+                break;
+            }
+            self.pos += op.len();
+            let right = self.binary(precedence + 1)?;
+            left = Node::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Node> {
+        let op = match self.peek() {
+            Some(b'-') => "-",
+            Some(b'~') => "~",
+            Some(b'!') if self.input.get(self.pos + 1) != Some(&b'=') => "!",
+            _ => return self.primary(),
+        };
+        self.pos += 1;
+        Ok(Node::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn take_while(&mut self, f: impl Fn(u8) -> bool) -> &str {
+        let start = self.pos;
+        while self.input.get(self.pos).is_some_and(|&c| f(c)) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.input[start..self.pos]).unwrap_or_default()
+    }
+
+    fn closed(&mut self, node: Node, close: u8) -> Result<Node> {
+        if self.peek() != Some(close) {
+            bail!("missing '{}'", close as char);
+        }
+        self.pos += 1;
+        Ok(node)
+    }
+
+    fn primary(&mut self) -> Result<Node> {
+        let Some(c) = self.peek() else {
+            bail!("missing value in expression");
+        };
+        if matches!(c, b'(' | b'[' | b'{') {
+            self.pos += 1;
+            let inner = self.binary(0)?;
+            return match c {
+                b'(' => self.closed(inner, b')'),
+                b'[' => self.closed(Node::Byte(Box::new(inner)), b']'),
+                _ => self.closed(Node::Word(Box::new(inner)), b'}'),
+            };
+        }
+        let (radix, digits) = match c {
+            b'$' => {
+                self.pos += 1;
+                (16, self.take_while(|c| c.is_ascii_hexdigit()))
+            }
+            b'%' => {
+                self.pos += 1;
+                (2, self.take_while(|c| c == b'0' || c == b'1'))
+            }
+            c if c.is_ascii_digit() => (10, self.take_while(|c| c.is_ascii_digit())),
+            c if c.is_ascii_alphabetic() || c == b'_' || c == b'@' => {
+                let name = self
+                    .take_while(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'@')
+                    .to_string();
+                // A loaded label hides the built-in value spelled exactly like it
+                let shadowed = self.symbols.named(&name).next().is_some();
+                if let Some(&(_, variable)) = VARIABLES
+                    .iter()
+                    .find(|(known, _)| !shadowed && name.eq_ignore_ascii_case(known))
+                {
+                    return Ok(Node::Variable(variable));
+                }
+                if self.symbols.named(&name).next().is_none() {
+                    bail!("unknown value '{}' in expression", name);
+                }
+                return Ok(Node::Symbol(name));
+            }
+            c => bail!("unexpected '{}' in expression", c as char),
+        };
+        match i64::from_str_radix(digits, radix) {
+            Ok(value) => Ok(Node::Number(value)),
+            Err(_) => bail!("missing digits in expression"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nes::NesEmulator;
+    use crate::test_util::{nes_with_source, STORE_LOOP_SOURCE};
+
+    /// X = 5, carry set and the word $1234 at $10
+    fn nes() -> NesEmulator {
+        let mut nes = nes_with_source(STORE_LOOP_SOURCE);
+        nes.cpu.registers.x = 0x05;
+        nes.cpu.registers.carry = true;
+        nes.cpu.poke(0x0010, 0x34);
+        nes.cpu.poke(0x0011, 0x12);
+        nes
+    }
+
+    fn evaluate(nes: &NesEmulator, source: &str) -> Result<i64> {
+        Expression::parse(source)?.evaluate(&nes.expression_context())
+    }
+
+    #[test]
+    fn test_operators() {
+        let nes = nes();
+        assert_eq!(evaluate(&nes, "x == 5 && C").unwrap(), 1);
+        assert_eq!(evaluate(&nes, "1 + 2 * 3 << 1").unwrap(), 14);
+        // Bitwise operators bind tighter than comparisons
+        assert_eq!(evaluate(&nes, "[$10] & $30 == $30").unwrap(), 1);
+        assert_eq!(evaluate(&nes, "!Z || -1 > 0").unwrap(), 1);
+        // The right side isn't evaluated when the left decides
+        assert_eq!(evaluate(&nes, "0 && 1 / 0").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_memory_and_machine_state() {
+        let nes = nes();
+        assert_eq!(evaluate(&nes, "{$10}").unwrap(), 0x1234);
+        assert_eq!(evaluate(&nes, "[$0a + X + 1] | %1").unwrap(), 0x35);
+        assert_eq!(
+            evaluate(&nes, "PC == $8000 && FRAME == 0 && SCANLINE >= 0").unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_label_names() {
+        let mut nes = nes();
+        nes.cpu.symbols =
+            SymbolTable::parse_fceux_nl("$0300#_main#\n$0010#c#\n$0011#frame#\n", None).unwrap();
+        let value = |source| {
+            Expression::parse_with_symbols(source, &nes.cpu.symbols)
+                .unwrap()
+                .evaluate(&nes.expression_context())
+                .unwrap()
+        };
+        assert_eq!(value("_main"), 0x0300);
+        // The label `c` hides the carry flag, `C` is still the flag
+        assert_eq!(value("c"), 0x0010);
+        assert_eq!(value("C"), 1);
+        assert_eq!(value("x"), 5);
+        // Longer names follow the same rule
+        assert_eq!(value("frame"), 0x0011);
+        assert_eq!(value("FRAME"), nes.cpu.ppu.frame as i64);
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        for invalid in ["x ==", "[$10", "foo", "1 2", "$"] {
+            assert!(Expression::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_evaluation_errors() {
+        let nes = nes();
+        assert!(evaluate(&nes, "1 % 0").is_err());
+        // VALUE only exists for watchpoints
+        assert!(evaluate(&nes, "VALUE").is_err());
+    }
+}
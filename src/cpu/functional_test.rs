@@ -0,0 +1,235 @@
+use std::fmt;
+
+use anyhow::{bail, Result};
+
+use crate::constant::MEMORY_MAX;
+use crate::cpu::{Clocked, Cpu6502};
+
+// reference: https://github.com/Klaus2m5/6502_65C02_functional_tests
+
+/// How to run one of the raw binary 6502 test suites: where the image goes,
+/// where it starts and the address it traps at when every test passed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FunctionalTest {
+    pub load_address: u16,
+    pub start: u16,
+    pub success: u16,
+    pub max_instructions: u64,
+}
+
+impl Default for FunctionalTest {
+    /// The layout of 6502_functional_test.bin, with the success address of its prebuilt binary.
+    /// That build also tests decimal mode, which the 2A03 doesn't have, so it fails there on this
+    /// CPU: assemble the suite with `disable_decimal = 1` and pass the address of the `success`
+    /// label from that build's listing instead.
+    fn default() -> Self {
+        Self {
+            load_address: 0x0000,
+            start: 0x0400,
+            success: 0x3469,
+            max_instructions: 100_000_000,
+        }
+    }
+}
+
+/// Why a functional test stopped
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FunctionalTestStop {
+    /// An instruction jumped or branched to itself
+    Trap(u16),
+    /// The instruction limit ran out first
+    Limit,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FunctionalTestResult {
+    pub stop: FunctionalTestStop,
+    pub success: u16,
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+impl FunctionalTestResult {
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.stop == FunctionalTestStop::Trap(self.success)
+    }
+}
+
+impl fmt::Display for FunctionalTestResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.stop {
+            FunctionalTestStop::Trap(pc) => write!(f, "trapped at ${:04X}", pc)?,
+            FunctionalTestStop::Limit => write!(f, "no trap")?,
+        }
+        write!(
+            f,
+            " after {} instructions and {} cycles: {}",
+            self.instructions,
+            self.cycles,
+            if self.passed() { "PASS" } else { "FAIL" }
+        )
+    }
+}
+
+impl FunctionalTest {
+    /// Load the binary on a CPU with a flat RAM bus and run it until it traps.
+    /// The suites test BRK and RTI, so BRK goes through its vector here instead of halting.
+    pub fn run(&self, binary: &[u8]) -> Result<FunctionalTestResult> {
+        let start = self.load_address as usize;
+        if start + binary.len() > MEMORY_MAX {
+            bail!(
+                "{} bytes don't fit in memory at ${:04X}",
+                binary.len(),
+                self.load_address
+            );
+        }
+        let mut cpu = Box::<Cpu6502>::default();
+        // No APU on a flat bus, so nothing but BRK interrupts the test
+        cpu.flat_bus = true;
+        cpu.stop_on_brk = false;
+        cpu.mapper[start..start + binary.len()].copy_from_slice(binary);
+        cpu.registers.pc = self.start;
+
+        let mut instructions = 0;
+        let stop = loop {
+            if instructions == self.max_instructions {
+                break FunctionalTestStop::Limit;
+            }
+            let pc = cpu.registers.pc;
+            if !cpu.clocked()? {
+                bail!("the CPU stopped at ${:04X}", pc);
+            }
+            instructions += 1;
+            if cpu.registers.pc == pc {
+                break FunctionalTestStop::Trap(pc);
+            }
+        };
+        Ok(FunctionalTestResult {
+            stop,
+            success: self.success,
+            instructions,
+            cycles: cpu.cycles,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{assemble, Assembly};
+
+    /// Adds `value` to $40 and traps at `success` when the sum is $50
+    fn check_sum(value: u8) -> (Vec<u8>, Assembly) {
+        let source = format!(
+            ".org $0400\nCLC\nLDA #$40\nADC #${:02X}\nCMP #$50\nfail: BNE fail\nsuccess: JMP success\n",
+            value
+        );
+        let assembly = assemble(&source).unwrap();
+        let mut image = [0u8; MEMORY_MAX];
+        assembly.apply(&mut image);
+        (image.to_vec(), assembly)
+    }
+
+    #[test]
+    fn test_cli_defaults() {
+        use crate::cli::Cli;
+        use structopt::StructOpt;
+
+        let cli = Cli::from_iter(["nes", "--functional-test", "6502_functional_test.bin"]);
+        let test = FunctionalTest {
+            load_address: cli.load_address,
+            start: cli.start_pc,
+            success: cli.success_pc,
+            max_instructions: cli.max_instructions,
+        };
+        assert_eq!(test, FunctionalTest::default());
+    }
+
+    #[test]
+    fn test_trap_at_success() {
+        let (binary, assembly) = check_sum(0x10);
+        let test = FunctionalTest {
+            success: assembly.symbol("success").unwrap(),
+            ..FunctionalTest::default()
+        };
+        let result = test.run(&binary).unwrap();
+        assert!(result.passed());
+        assert_eq!(result.stop, FunctionalTestStop::Trap(0x0409));
+        assert_eq!(result.instructions, 6);
+        assert_eq!(
+            result.to_string(),
+            "trapped at $0409 after 6 instructions and 13 cycles: PASS"
+        );
+    }
+
+    #[test]
+    fn test_trap_elsewhere_fails() {
+        let (binary, assembly) = check_sum(0x11);
+        let test = FunctionalTest {
+            success: assembly.symbol("success").unwrap(),
+            ..FunctionalTest::default()
+        };
+        let result = test.run(&binary).unwrap();
+        assert!(!result.passed());
+        assert_eq!(result.stop, FunctionalTestStop::Trap(0x0407));
+    }
+
+    #[test]
+    fn test_load_address_and_stops() {
+        let test = FunctionalTest {
+            load_address: 0x0200,
+            start: 0x0200,
+            max_instructions: 3,
+            ..FunctionalTest::default()
+        };
+        let result = test.run(&[0xea, 0xea, 0xea, 0xea]).unwrap();
+        assert_eq!(result.stop, FunctionalTestStop::Limit);
+        assert!(test.run(&vec![0; 0x10000]).is_err());
+    }
+
+    #[test]
+    fn test_break_and_return() {
+        // Ten BRKs counted by the handler, which checks the pushed B flag and that
+        // BRK set I. Running the padding byte after a BRK would count X twice.
+        let source = "
+            .org $0400
+            LDX #0
+            STX $10
+            loop: BRK
+            INX
+            INX
+            CPX #10
+            BNE loop
+            LDA $10
+            CMP #10
+            fail: BNE fail
+            success: JMP success
+            handler: INC $10
+            STX $11
+            TSX
+            LDA $0101,X
+            AND #$10
+            brk_fail: BEQ brk_fail
+            PHP
+            PLA
+            AND #$04
+            irq_fail: BEQ irq_fail
+            LDX $11
+            RTI
+            .org $FFFE
+            .word handler
+        ";
+        let assembly = assemble(source).unwrap();
+        let mut binary = [0u8; MEMORY_MAX];
+        assembly.apply(&mut binary);
+        let test = FunctionalTest {
+            success: assembly.symbol("success").unwrap(),
+            ..FunctionalTest::default()
+        };
+        let result = test.run(&binary).unwrap();
+        assert_eq!(result.stop, FunctionalTestStop::Trap(test.success));
+        assert!(result.passed());
+        assert!(result.instructions > 100);
+    }
+}
@@ -0,0 +1,363 @@
+use std::fmt;
+
+use crate::cpu::disassembler::disassemble;
+use crate::cpu::harness::ExpectedState;
+use crate::cpu::reference::ReferenceCpu;
+use crate::cpu::{BusAccess, Clocked, Cpu6502};
+
+/// Where fuzzed programs are placed
+pub const FUZZ_ORIGIN: u16 = 0x8000;
+/// Instructions a case may execute, branches can loop back into the program
+const MAX_STEPS: usize = 256;
+
+/// A small xorshift64* generator, so a failing seed reproduces on any machine
+#[derive(Clone, Debug)]
+pub struct FuzzRng(u64);
+
+impl FuzzRng {
+    #[must_use]
+    pub fn new(seed: u64) -> Self {
+        // The state must never be zero
+        Self(seed ^ 0x9e37_79b9_7f4a_7c15 | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+
+    /// A number in 0..n
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// The initial state and program of one differential test
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuzzCase {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    /// NV-BDIZC
+    pub p: u8,
+    /// Seed of the pseudo-random bytes filling all of memory, 0 for zeroes
+    pub fill: u64,
+    /// Bytes poked into memory before the program is loaded at `FUZZ_ORIGIN`
+    pub memory: Vec<(u16, u8)>,
+    pub program: Vec<u8>,
+}
+
+/// Where `Cpu6502` and the reference model first disagreed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuzzDivergence {
+    /// Index of the instruction, counting from 0
+    pub step: usize,
+    pub pc: u16,
+    pub differences: Vec<String>,
+}
+
+impl FuzzCase {
+    /// Random registers and memory, with extra pokes around the zero page and stack, and up to
+    /// `max_instructions` documented instructions with random operands
+    pub fn generate(rng: &mut FuzzRng, max_instructions: usize) -> Self {
+        let mut memory = Vec::new();
+        for page in [0x00, 0x01, rng.next_u8()] {
+            for _ in 0..16 {
+                memory.push((u16::from_le_bytes([rng.next_u8(), page]), rng.next_u8()));
+            }
+        }
+        let mut program = Vec::new();
+        for _ in 0..=rng.below(max_instructions) {
+            let opcode = loop {
+                let opcode = rng.next_u8();
+                if ReferenceCpu::models(opcode) {
+                    break opcode;
+                }
+            };
+            program.push(opcode);
+            for _ in 1..ReferenceCpu::length(opcode).unwrap_or(1) {
+                program.push(rng.next_u8());
+            }
+        }
+        Self {
+            a: rng.next_u8(),
+            x: rng.next_u8(),
+            y: rng.next_u8(),
+            sp: rng.next_u8(),
+            p: rng.next_u8(),
+            fill: rng.next_u64(),
+            memory,
+            program,
+        }
+    }
+
+    /// Byte ranges of the instructions of the program
+    fn instructions(&self) -> Vec<(usize, usize)> {
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        while start < self.program.len() {
+            let length = ReferenceCpu::length(self.program[start]).unwrap_or(1) as usize;
+            let end = (start + length).min(self.program.len());
+            ranges.push((start, end));
+            start = end;
+        }
+        ranges
+    }
+
+    /// Run the program on both CPUs, comparing registers, flags and the memory
+    /// either of them wrote after every instruction. The case ends when the program
+    /// counter leaves the program or reaches an opcode the reference doesn't model.
+    #[must_use]
+    pub fn check(&self) -> Option<FuzzDivergence> {
+        let mut reference = ReferenceCpu::new();
+        if self.fill != 0 {
+            let mut rng = FuzzRng::new(self.fill);
+            reference.memory.fill_with(|| rng.next_u8());
+        }
+        for &(address, value) in &self.memory {
+            reference.memory[address as usize] = value;
+        }
+        let origin = FUZZ_ORIGIN as usize;
+        reference.memory[origin..origin + self.program.len()].copy_from_slice(&self.program);
+        reference.a = self.a;
+        reference.x = self.x;
+        reference.y = self.y;
+        reference.sp = self.sp;
+        reference.p = self.p;
+        reference.pc = FUZZ_ORIGIN;
+
+        let mut cpu = Box::<Cpu6502>::default();
+        cpu.flat_bus = true;
+        cpu.mapper.copy_from_slice(&reference.memory);
+        cpu.registers.a = self.a;
+        cpu.registers.x = self.x;
+        cpu.registers.y = self.y;
+        cpu.registers.sp = self.sp;
+        cpu.registers.pc = FUZZ_ORIGIN;
+        cpu.set_status_register_from_byte(self.p);
+
+        let end = origin + self.program.len();
+        for step in 0..MAX_STEPS {
+            let pc = reference.pc;
+            if !(origin..end).contains(&(pc as usize)) || !reference.step() {
+                return None;
+            }
+            cpu.bus_log = Some(Vec::new());
+            let differences = match cpu.clocked() {
+                Err(error) => vec![format!("error: {:#}", error)],
+                Ok(false) => vec!["the CPU stopped".to_string()],
+                Ok(true) => {
+                    let written = cpu
+                        .bus_log
+                        .take()
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter_map(|cycle| match cycle.access {
+                            BusAccess::Write(address) => Some(address),
+                            BusAccess::Read(_) => None,
+                        });
+                    let mut addresses: Vec<u16> =
+                        reference.writes.iter().copied().chain(written).collect();
+                    addresses.sort_unstable();
+                    addresses.dedup();
+                    let expected = ExpectedState {
+                        a: Some(reference.a),
+                        x: Some(reference.x),
+                        y: Some(reference.y),
+                        pc: Some(reference.pc),
+                        sp: Some(reference.sp),
+                        carry: Some(reference.p & 0x01 != 0),
+                        zero: Some(reference.p & 0x02 != 0),
+                        interrupt_disabled: Some(reference.p & 0x04 != 0),
+                        decimal: Some(reference.p & 0x08 != 0),
+                        overflow: Some(reference.p & 0x40 != 0),
+                        negative: Some(reference.p & 0x80 != 0),
+                        memory: addresses
+                            .into_iter()
+                            .map(|address| (address, vec![reference.memory[address as usize]]))
+                            .collect(),
+                    };
+                    expected.diff(&cpu)
+                }
+            };
+            if !differences.is_empty() {
+                return Some(FuzzDivergence {
+                    step,
+                    pc,
+                    differences,
+                });
+            }
+        }
+        None
+    }
+
+    /// Smaller variants of the case: one instruction or poke less, a register or operand cleared
+    fn simplifications(&self) -> Vec<FuzzCase> {
+        let mut candidates = Vec::new();
+        for (start, end) in self.instructions().into_iter().rev() {
+            let mut case = self.clone();
+            case.program.drain(start..end);
+            candidates.push(case);
+        }
+        for i in (0..self.memory.len()).rev() {
+            let mut case = self.clone();
+            case.memory.remove(i);
+            candidates.push(case);
+        }
+        let registers: [fn(&mut FuzzCase) -> &mut u8; 5] = [
+            |case| &mut case.a,
+            |case| &mut case.x,
+            |case| &mut case.y,
+            |case| &mut case.p,
+            |case| &mut case.sp,
+        ];
+        for register in registers {
+            let mut case = self.clone();
+            *register(&mut case) = 0;
+            candidates.push(case);
+        }
+        candidates.push(FuzzCase {
+            fill: 0,
+            ..self.clone()
+        });
+        for (start, end) in self.instructions() {
+            for i in start + 1..end {
+                let mut case = self.clone();
+                case.program[i] = 0;
+                candidates.push(case);
+            }
+        }
+        candidates.retain(|case| case != self);
+        candidates
+    }
+}
+
+/// Shrink a case while `fails` still holds for it, until no simplification is left
+pub fn minimize(case: &FuzzCase, fails: impl Fn(&FuzzCase) -> bool) -> FuzzCase {
+    let mut case = case.clone();
+    while let Some(smaller) = case
+        .simplifications()
+        .into_iter()
+        .find(|candidate| fails(candidate))
+    {
+        case = smaller;
+    }
+    case
+}
+
+impl fmt::Display for FuzzCase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "A:{:02X} X:{:02X} Y:{:02X} SP:{:02X} P:{:02X} fill:{:#x}",
+            self.a, self.x, self.y, self.sp, self.p, self.fill
+        )?;
+        for (address, value) in &self.memory {
+            writeln!(f, "${:04X} = ${:02X}", address, value)?;
+        }
+        let lines: Vec<String> = disassemble(&self.program, FUZZ_ORIGIN)
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+/// A minimized divergence with the seed of the case it was found from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuzzFailure {
+    pub seed: u64,
+    pub case: FuzzCase,
+    pub divergence: FuzzDivergence,
+}
+
+impl fmt::Display for FuzzFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "case {:#018x} diverges at instruction {} (${:04X}):",
+            self.seed, self.divergence.step, self.divergence.pc
+        )?;
+        for line in &self.divergence.differences {
+            writeln!(f, "  {}", line)?;
+        }
+        write!(f, "{}", self.case)
+    }
+}
+
+/// Check `cases` random cases, each generated from its own seed drawn from `seed`,
+/// and return the first divergence after minimizing it
+#[must_use]
+pub fn fuzz_cpu(seed: u64, cases: usize, max_instructions: usize) -> Option<FuzzFailure> {
+    let mut seeds = FuzzRng::new(seed);
+    (0..cases).find_map(|_| {
+        let seed = seeds.next_u64();
+        let case = FuzzCase::generate(&mut FuzzRng::new(seed), max_instructions);
+        case.check()?;
+        let case = minimize(&case, |case| case.check().is_some());
+        let divergence = case.check()?;
+        Some(FuzzFailure {
+            seed,
+            case,
+            divergence,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::is_official_opcode;
+
+    #[test]
+    fn test_reference_models_official_opcodes() {
+        // Every documented opcode except BRK, which stops the CPU
+        let modeled: Vec<u8> = (0..=255).filter(|&op| ReferenceCpu::models(op)).collect();
+        let official: Vec<u8> = (1..=255).filter(|&op| is_official_opcode(op)).collect();
+        assert_eq!(modeled, official);
+    }
+
+    #[test]
+    fn test_differential_fuzzing() {
+        // NES_FUZZ_CASES and NES_FUZZ_SEED run a longer or different campaign
+        let env = |name: &str, default: u64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        let cases = env("NES_FUZZ_CASES", 200) as usize;
+        let seed = env("NES_FUZZ_SEED", 0x6502);
+        if let Some(failure) = fuzz_cpu(seed, cases, 8) {
+            panic!("{}", failure);
+        }
+    }
+
+    #[test]
+    fn test_minimize() {
+        // Minimizing keeps only what the failure needs
+        let case = FuzzCase {
+            a: 0x01,
+            x: 0x02,
+            y: 0x03,
+            sp: 0xfd,
+            p: 0x24,
+            fill: 0x1234,
+            memory: vec![(0x0010, 0x01), (0x0100, 0x02)],
+            program: vec![0xa9, 0x01, 0xe8, 0x8d, 0x00, 0x02],
+        };
+        let minimized = minimize(&case, |case| case.program.contains(&0xe8));
+        assert_eq!(
+            minimized.to_string(),
+            "A:00 X:00 Y:00 SP:00 P:00 fill:0x0\n$8000  E8        INX"
+        );
+        assert_eq!(minimized.check(), None);
+    }
+}
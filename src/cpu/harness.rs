@@ -0,0 +1,321 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use anyhow::{bail, Context, Result};
+
+use crate::cpu::assembler::assemble;
+use crate::cpu::{Clocked, Cpu6502, CpuRegister};
+
+/// Why a harness run stopped
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// A BRK was fetched
+    Break,
+    /// The program counter reached the marker address
+    Marker,
+    /// The instruction limit ran out first
+    Limit,
+}
+
+/// A bare CPU to run small programs against, set up with a builder:
+///
+/// ```text
+/// let mut harness = TestHarness::new()
+///     .with_source(".org $0600\n LDA #$42\n STA $10")?
+///     .with_registers(|r| r.x = 1);
+/// harness.run(100)?;
+/// harness.verify(&ExpectedState { a: Some(0x42), memory: vec![(0x10, vec![0x42])], ..Default::default() })?;
+/// ```
+#[derive(Debug, Default)]
+pub struct TestHarness {
+    pub cpu: Cpu6502,
+    /// Symbols of the assembled source
+    pub symbols: BTreeMap<String, u16>,
+    /// Instructions executed since the harness was built
+    pub instructions: usize,
+}
+
+impl TestHarness {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Copy a program to `origin` and start executing there
+    #[must_use]
+    pub fn with_program(mut self, origin: u16, program: &[u8]) -> Self {
+        self = self.with_memory(origin, program);
+        self.cpu.registers.pc = origin;
+        self
+    }
+
+    /// Assemble the source and start executing at its first byte
+    pub fn with_source(mut self, source: &str) -> Result<Self> {
+        let assembly = assemble(source)?;
+        self.symbols.extend(assembly.symbols);
+        Ok(self.with_program(assembly.origin, &assembly.bytes))
+    }
+
+    #[must_use]
+    pub fn with_registers(mut self, set: impl FnOnce(&mut CpuRegister)) -> Self {
+        set(&mut self.cpu.registers);
+        self
+    }
+
+    /// Set every flag from a status byte, NV-BDIZC
+    #[must_use]
+    pub fn with_status(mut self, status: u8) -> Self {
+        self.cpu.set_status_register_from_byte(status);
+        self
+    }
+
+    /// Poke bytes into memory without going through the devices on the bus
+    #[must_use]
+    pub fn with_memory(mut self, address: u16, bytes: &[u8]) -> Self {
+        for (i, &byte) in bytes.iter().enumerate() {
+            let address = address.wrapping_add(i as u16);
+            let address = match address {
+                0x0000..=0x1fff => address & 0x07ff,
+                _ => address,
+            };
+            self.cpu.mapper[address as usize] = byte;
+        }
+        self
+    }
+
+    /// Address of a label from the assembled source
+    pub fn symbol(&self, name: &str) -> Result<u16> {
+        self.symbols
+            .get(name)
+            .copied()
+            .with_context(|| format!("no symbol named '{}'", name))
+    }
+
+    fn run_while(&mut self, max_instructions: usize, marker: Option<u16>) -> Result<StopReason> {
+        for _ in 0..max_instructions {
+            if Some(self.cpu.registers.pc) == marker {
+                return Ok(StopReason::Marker);
+            }
+            if !self.cpu.clocked()? {
+                return Ok(StopReason::Break);
+            }
+            self.instructions += 1;
+        }
+        match Some(self.cpu.registers.pc) == marker {
+            true => Ok(StopReason::Marker),
+            false => Ok(StopReason::Limit),
+        }
+    }
+
+    /// Run at most `max_instructions`, stopping early at a BRK
+    pub fn run(&mut self, max_instructions: usize) -> Result<StopReason> {
+        self.run_while(max_instructions, None)
+    }
+
+    /// Run until the program counter reaches `marker`, failing on a BRK or after `max_instructions`
+    pub fn run_until(&mut self, marker: u16, max_instructions: usize) -> Result<()> {
+        match self.run_while(max_instructions, Some(marker))? {
+            StopReason::Marker => Ok(()),
+            reason => bail!(
+                "stopped by {:?} at ${:04X} before reaching ${:04X} after {} instructions",
+                reason,
+                self.cpu.registers.pc,
+                marker,
+                self.instructions
+            ),
+        }
+    }
+
+    /// Compare the CPU against the expected state, the error lists every difference
+    pub fn verify(&self, expected: &ExpectedState) -> Result<()> {
+        let differences = expected.diff(&self.cpu);
+        if differences.is_empty() {
+            return Ok(());
+        }
+        let mut message = format!(
+            "state mismatch after {} instructions at ${:04X}:",
+            self.instructions, self.cpu.registers.pc
+        );
+        for line in differences {
+            let _ = write!(message, "\n  {}", line);
+        }
+        bail!(message)
+    }
+}
+
+/// The state a test expects, fields left as None aren't checked
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExpectedState {
+    pub a: Option<u8>,
+    pub x: Option<u8>,
+    pub y: Option<u8>,
+    pub pc: Option<u16>,
+    pub sp: Option<u8>,
+    pub carry: Option<bool>,
+    pub zero: Option<bool>,
+    pub interrupt_disabled: Option<bool>,
+    pub decimal: Option<bool>,
+    pub overflow: Option<bool>,
+    pub negative: Option<bool>,
+    /// Bytes expected from each address on
+    pub memory: Vec<(u16, Vec<u8>)>,
+}
+
+impl ExpectedState {
+    /// Expect every register and flag to match
+    #[must_use]
+    pub fn registers(registers: &CpuRegister) -> Self {
+        Self {
+            a: Some(registers.a),
+            x: Some(registers.x),
+            y: Some(registers.y),
+            pc: Some(registers.pc),
+            sp: Some(registers.sp),
+            carry: Some(registers.carry),
+            zero: Some(registers.zero),
+            interrupt_disabled: Some(registers.interrupt_disabled),
+            decimal: Some(registers.decimal),
+            overflow: Some(registers.overflow),
+            negative: Some(registers.negative),
+            memory: Vec::new(),
+        }
+    }
+
+    /// One line per field that doesn't match, like `A: expected $05, got $04`
+    #[must_use]
+    pub fn diff(&self, cpu: &Cpu6502) -> Vec<String> {
+        let registers = &cpu.registers;
+        let mut lines = Vec::new();
+        let bytes = [
+            ("A", self.a, registers.a),
+            ("X", self.x, registers.x),
+            ("Y", self.y, registers.y),
+            ("SP", self.sp, registers.sp),
+        ];
+        for (name, expected, actual) in bytes {
+            if let Some(expected) = expected.filter(|&expected| expected != actual) {
+                lines.push(format!(
+                    "{}: expected ${:02X}, got ${:02X}",
+                    name, expected, actual
+                ));
+            }
+        }
+        if let Some(pc) = self.pc.filter(|&pc| pc != registers.pc) {
+            lines.push(format!(
+                "PC: expected ${:04X}, got ${:04X}",
+                pc, registers.pc
+            ));
+        }
+        let flags = [
+            ("C", self.carry, registers.carry),
+            ("Z", self.zero, registers.zero),
+            ("I", self.interrupt_disabled, registers.interrupt_disabled),
+            ("D", self.decimal, registers.decimal),
+            ("V", self.overflow, registers.overflow),
+            ("N", self.negative, registers.negative),
+        ];
+        for (name, expected, actual) in flags {
+            if let Some(expected) = expected.filter(|&expected| expected != actual) {
+                lines.push(format!(
+                    "{}: expected {}, got {}",
+                    name, expected as u8, actual as u8
+                ));
+            }
+        }
+        for (address, bytes) in &self.memory {
+            for (i, &expected) in bytes.iter().enumerate() {
+                let address = address.wrapping_add(i as u16);
+                let actual = cpu.peek(address);
+                if actual != expected {
+                    lines.push(format!(
+                        "${:04X}: expected ${:02X}, got ${:02X}",
+                        address, expected, actual
+                    ));
+                }
+            }
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = r#"
+            .org $0300
+            LDX #$03
+    @loop:  STX $20,Y
+            INY
+            DEX
+            BNE @loop
+    done:   SEC
+            BRK
+    "#;
+
+    /// Stopped at `done`, after storing 3, 2 and 1 from $0021
+    fn stopped_at_done() -> TestHarness {
+        let mut harness = TestHarness::new()
+            .with_source(SOURCE)
+            .unwrap()
+            .with_registers(|registers| registers.y = 0x01)
+            .with_status(0b1000_0000)
+            .with_memory(0x0020, &[0xff; 4]);
+        assert_eq!(harness.cpu.registers.pc, 0x0300);
+        let done = harness.symbol("done").unwrap();
+        harness.run_until(done, 100).unwrap();
+        harness
+    }
+
+    #[test]
+    fn test_run_until_marker() {
+        let harness = stopped_at_done();
+        assert_eq!(harness.instructions, 13);
+        let expected = ExpectedState {
+            x: Some(0),
+            y: Some(0x04),
+            pc: Some(harness.symbol("done").unwrap()),
+            zero: Some(true),
+            carry: Some(false),
+            memory: vec![(0x0020, vec![0xff, 0x03, 0x02, 0x01])],
+            ..Default::default()
+        };
+        harness.verify(&expected).unwrap();
+    }
+
+    #[test]
+    fn test_break_ends_run() {
+        let mut harness = stopped_at_done();
+        assert_eq!(harness.run(100).unwrap(), StopReason::Break);
+        assert_eq!(
+            harness.cpu.registers.pc,
+            harness.symbol("done").unwrap() + 1
+        );
+    }
+
+    #[test]
+    fn test_reports_every_difference() {
+        let mut harness = stopped_at_done();
+        harness.run(100).unwrap();
+        let expected = ExpectedState {
+            a: Some(0x01),
+            carry: Some(false),
+            memory: vec![(0x0021, vec![0x00])],
+            ..ExpectedState::default()
+        };
+        let error = harness.verify(&expected).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "state mismatch after 14 instructions at $0309:\n  A: expected $01, got $00\n  C: expected 0, got 1\n  $0021: expected $00, got $03"
+        );
+    }
+
+    #[test]
+    fn test_instruction_limit() {
+        // Running out of instructions before the marker fails
+        let mut harness = TestHarness::new().with_program(0x8000, &[0x4c, 0x00, 0x80]);
+        assert_eq!(harness.run(5).unwrap(), StopReason::Limit);
+        assert!(harness.run_until(0x9000, 5).is_err());
+        assert!(harness.symbol("missing").is_err());
+    }
+}
@@ -13,11 +13,9 @@ impl Cpu6502 {
         let (x2, o2) = x1.overflowing_add(self.registers.carry as u8);
 
         self.registers.carry = o1 | o2;
-        // Set if sign bit is incorrect
-        let signed_sum =
-            (v as i8 as i16) + (self.registers.a as i8 as i16) + (self.registers.carry as i16);
+        // Set if both operands have the same sign and the result has the other one
+        self.registers.overflow = (v ^ x2) & (self.registers.a ^ x2) & 0x80 != 0;
         self.registers.a = x2;
-        self.registers.overflow = (signed_sum < -128) || (signed_sum > 127);
         self.update_accumulator_flags();
         Ok(())
     }
@@ -43,7 +41,30 @@ impl Cpu6502 {
         // Bit 0 is set to 0 and bit 7 is placed in the carry flag
         self.registers.carry = get_bit(r, 7) > 0;
         self.store_write_target(x, instr.write_target)?;
-        self.update_accumulator_flags();
+        self.update_zero_and_negative_flags(x);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::run_to_break;
+
+    #[test]
+    fn test_adc_overflow_with_carry_in() {
+        // $7F + $00 + carry crosses into the negative range
+        let harness = run_to_break(".org $0600\nSEC\nLDA #$7F\nADC #$00\nBRK", &[]);
+        assert_eq!(harness.cpu.registers.a, 0x80);
+        assert!(harness.cpu.registers.overflow);
+        assert!(!harness.cpu.registers.carry);
+    }
+
+    #[test]
+    fn test_asl_memory_flags_from_result() {
+        // A is non-zero, the shifted byte is zero
+        let harness = run_to_break(".org $0600\nLDA #$01\nASL $10\nBRK", &[(0x0010, &[0x80])]);
+        assert_eq!(harness.cpu.mapper[0x10], 0x00);
+        assert!(harness.cpu.registers.zero);
+        assert!(harness.cpu.registers.carry);
+    }
+}
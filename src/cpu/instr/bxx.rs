@@ -1,6 +1,12 @@
 use anyhow::{Ok, Result};
 
-use crate::{constant::ADDRESS_BRK, cpu::Cpu6502, mem::Mem, stack::Stacked, util::get_bit};
+use crate::{
+    constant::{ADDRESS_BRK, BREAK_FLAG},
+    cpu::Cpu6502,
+    mem::Mem,
+    stack::Stacked,
+    util::get_bit,
+};
 
 impl Cpu6502 {
     fn execute_branch(&mut self) {
@@ -9,7 +15,7 @@ impl Cpu6502 {
         let v = instr.mode_args;
         self.registers.pc = self.registers.pc.wrapping_add((v as i8) as u16);
         // +1 if branch success
-        self.clocks_to_pause += 1;
+        self.clocks_to_pause = self.clocks_to_pause.wrapping_add(1);
         // TODO +2 if crossed page
     }
 
@@ -95,10 +101,12 @@ impl Cpu6502 {
     #[inline]
     #[allow(non_snake_case)]
     pub fn BRK(&mut self) -> Result<()> {
-        let pc = self.registers.pc;
+        // The byte after BRK is padding, the return address skips it
+        let pc = self.registers.pc.wrapping_add(1);
         self.push_stack16(pc)?;
-        let sr = self.status_register_byte(true);
+        let sr = self.status_register_byte(true) | BREAK_FLAG;
         self.push_stack(sr)?;
+        self.registers.interrupt_disabled = true;
         self.registers.pc = self.mem_read_u16(ADDRESS_BRK)?;
         Ok(())
     }
@@ -125,3 +133,26 @@ impl Cpu6502 {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::constant::BREAK_FLAG;
+    use crate::cpu::Clocked;
+    use crate::test_util::run_to_break;
+
+    #[test]
+    fn test_brk_pushes_break_flag() {
+        // The harness stops at the BRK, execute it past its padding byte
+        let mut harness = run_to_break(".org $0600\nCLI\nBRK\n.org $FFFE\n.word $0700", &[]);
+        harness.cpu.stop_on_brk = false;
+        let cycles = harness.cpu.cycles;
+        assert!(harness.cpu.clocked().unwrap());
+        assert_eq!(harness.cpu.cycles - cycles, 7);
+        let sp = harness.cpu.registers.sp as usize;
+        let pushed = &harness.cpu.mapper[0x0101 + sp..0x0104 + sp];
+        assert_eq!(pushed[0] & BREAK_FLAG, BREAK_FLAG);
+        assert_eq!(u16::from_le_bytes([pushed[1], pushed[2]]), 0x0602);
+        assert_eq!(harness.cpu.registers.pc, 0x0700);
+        assert!(harness.cpu.registers.interrupt_disabled);
+    }
+}
@@ -29,7 +29,7 @@ impl Cpu6502 {
         let addr = instr.write_target;
         // Rewrite the new value to the memory location
         self.store_write_target(value, addr)?;
-        self.update_zero_and_negative_flags(self.read_write_target(addr)?);
+        self.update_zero_and_negative_flags(value);
         Ok(())
     }
 }
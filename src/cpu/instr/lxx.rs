@@ -44,7 +44,21 @@ impl Cpu6502 {
         self.registers.carry = get_bit(target_value, 0) > 0;
 
         let result = target_value.wrapping_shr(1);
+        self.store_write_target(result, instr.write_target)?;
         self.update_zero_and_negative_flags(result);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::run_to_break;
+
+    #[test]
+    fn test_lsr_memory_writes_back() {
+        let harness = run_to_break(".org $0600\nLDA #$FF\nLSR $10\nBRK", &[(0x0010, &[0x03])]);
+        assert_eq!(harness.cpu.mapper[0x10], 0x01);
+        assert_eq!(harness.cpu.registers.a, 0xff);
+        assert!(harness.cpu.registers.carry);
+    }
+}
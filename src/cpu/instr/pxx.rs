@@ -1,6 +1,6 @@
 use anyhow::Result;
 
-use crate::{cpu::Cpu6502, stack::Stacked};
+use crate::{constant::BREAK_FLAG, cpu::Cpu6502, stack::Stacked};
 
 impl Cpu6502 {
     /// PHA - Push Accumulator
@@ -15,7 +15,7 @@ impl Cpu6502 {
     #[inline]
     #[allow(non_snake_case)]
     pub fn PHP(&mut self) -> Result<()> {
-        self.push_stack(self.status_register_byte(true))?;
+        self.push_stack(self.status_register_byte(true) | BREAK_FLAG)?;
         Ok(())
     }
 
@@ -35,3 +35,15 @@ impl Cpu6502 {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::constant::BREAK_FLAG;
+    use crate::test_util::run_to_break;
+
+    #[test]
+    fn test_php_pushes_break_flag() {
+        let harness = run_to_break(".org $0600\nPHP\nPLA\nBRK", &[]);
+        assert_eq!(harness.cpu.registers.a & BREAK_FLAG, BREAK_FLAG);
+    }
+}
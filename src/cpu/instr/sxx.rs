@@ -44,11 +44,10 @@ impl Cpu6502 {
         let (v2, o2) = v1.overflowing_sub(neg_carry as u8);
 
         self.registers.carry = !(o1 | o2);
-        // Set if sign bit is incorrect
-        let signed_sum =
-            (mem as i8 as i16) + (self.registers.a as i8 as i16) + (self.registers.carry as i16);
+        // Set if the operands have different signs and the sign of the result differs from A
+        self.registers.overflow =
+            (self.registers.a ^ mem as u8) & (self.registers.a ^ v2) & 0x80 != 0;
         self.registers.a = v2;
-        self.registers.overflow = (signed_sum < -128) || (signed_sum > 127);
         self.update_accumulator_flags();
 
         Ok(())
@@ -75,3 +74,17 @@ impl Cpu6502 {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::run_to_break;
+
+    #[test]
+    fn test_sbc_overflow() {
+        // -128 - 1 wraps to +127
+        let harness = run_to_break(".org $0600\nSEC\nLDA #$80\nSBC #$01\nBRK", &[]);
+        assert_eq!(harness.cpu.registers.a, 0x7f);
+        assert!(harness.cpu.registers.overflow);
+        assert!(harness.cpu.registers.carry);
+    }
+}
@@ -46,4 +46,26 @@ impl Cpu6502 {
         self.registers.sp = self.registers.x;
         Ok(())
     }
+
+    /// TSX: Copies the current contents of the stack register into the X register.
+    #[inline]
+    #[allow(non_snake_case)]
+    pub fn TSX(&mut self) -> Result<()> {
+        self.registers.x = self.registers.sp;
+        self.update_zero_and_negative_flags(self.registers.x);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::run_to_break;
+
+    #[test]
+    fn test_tsx() {
+        let harness = run_to_break(".org $0600\nLDX #$80\nTXS\nLDX #$00\nTSX\nBRK", &[]);
+        assert_eq!(harness.cpu.registers.x, 0x80);
+        assert!(harness.cpu.registers.negative);
+        assert!(!harness.cpu.registers.zero);
+    }
 }
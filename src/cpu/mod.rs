@@ -1,16 +1,35 @@
 mod address;
+mod assembler;
 mod cpu6502;
 mod debugger;
+mod disassembler;
+mod expression;
+mod functional_test;
+mod fuzz;
+mod harness;
 mod instr;
 mod instruction;
 mod opcode;
+mod reference;
 mod register;
+mod single_step;
+mod symbols;
+mod trace;
 
 pub use address::*;
-pub use address::*;
+pub use assembler::*;
 pub use cpu6502::*;
 pub use debugger::*;
+pub use disassembler::*;
+pub use expression::*;
+pub use functional_test::*;
+pub use fuzz::*;
+pub use harness::*;
 pub use instr::*;
 pub use instruction::*;
 pub use opcode::*;
+pub use reference::*;
 pub use register::*;
+pub use single_step::*;
+pub use symbols::*;
+pub use trace::*;
@@ -1,6 +1,7 @@
 use crate::cpu::address::*;
 use crate::cpu::instruction::CycleCount;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Operation {
     ADC,
@@ -83,6 +84,18 @@ pub enum Operation {
 
 use Operation::*;
 
+impl Operation {
+    /// Whether the instruction reads the memory its operand points to. Stores only write
+    /// it and jumps only take the address.
+    #[must_use]
+    pub fn reads_operand(self) -> bool {
+        !matches!(
+            self,
+            STA | STX | STY | JMP | JSR | SAX | AHX | SHX | SHY | TAS
+        )
+    }
+}
+
 pub const OPCODE_TABLE: [(Operation, AddressingMode, CycleCount, CycleCount); 256] =
     // (Operation, addressing mode, clock cycles, extra clock cycles if page boundary crossed)
     [
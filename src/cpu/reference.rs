@@ -0,0 +1,351 @@
+use crate::constant::MEMORY_MAX;
+
+// reference: https://www.masswerk.at/6502/6502_instruction_set.html
+
+/// A deliberately simple model of the documented 6502 instructions, written
+/// without `OPCODE_TABLE` or any of `Cpu6502`'s code so the two can be checked
+/// against each other. Memory is flat, there are no cycles, and like the 2A03
+/// the decimal flag doesn't affect ADC/SBC.
+#[derive(Clone, Debug)]
+pub struct ReferenceCpu {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub pc: u16,
+    /// NV-BDIZC
+    pub p: u8,
+    pub memory: Vec<u8>,
+    /// Addresses written by the last instruction
+    pub writes: Vec<u16>,
+}
+
+const C: u8 = 0x01;
+const Z: u8 = 0x02;
+const I: u8 = 0x04;
+const D: u8 = 0x08;
+const V: u8 = 0x40;
+const N: u8 = 0x80;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Mode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    IndirectX,
+    IndirectY,
+}
+
+/// The documented opcodes follow the `aaabbbcc` pattern: `cc` picks a group,
+/// `aaa` the operation and `bbb` the addressing mode.
+/// reference: https://llx.com/Neil/a2/opcodes.html
+fn group_mode(opcode: u8) -> Option<Mode> {
+    let bbb = (opcode >> 2) & 0x07;
+    let indexed_y = matches!(opcode, 0x96 | 0xb6 | 0xbe);
+    Some(match (opcode & 0x03, bbb) {
+        (0b01, 0) => Mode::IndirectX,
+        (0b01, 1) | (_, 1) => Mode::ZeroPage,
+        (0b01, 2) => Mode::Immediate,
+        (0b01, 3) | (_, 3) => Mode::Absolute,
+        (0b01, 4) => Mode::IndirectY,
+        (_, 5) if indexed_y => Mode::ZeroPageY,
+        (_, 5) => Mode::ZeroPageX,
+        (0b01, 6) => Mode::AbsoluteY,
+        (_, 7) if indexed_y => Mode::AbsoluteY,
+        (_, 7) => Mode::AbsoluteX,
+        (_, 0) => Mode::Immediate,
+        (0b10, 2) => Mode::Accumulator,
+        _ => return None,
+    })
+}
+
+impl ReferenceCpu {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            a: 0,
+            x: 0,
+            y: 0,
+            sp: 0xfd,
+            pc: 0,
+            p: 0x24,
+            memory: vec![0; MEMORY_MAX],
+            writes: Vec::new(),
+        }
+    }
+
+    /// Whether the opcode is one the model executes: every documented one except BRK
+    #[must_use]
+    pub fn models(opcode: u8) -> bool {
+        Self::decode(opcode).is_some()
+    }
+
+    /// Number of bytes of a modeled opcode, operands included
+    #[must_use]
+    pub fn length(opcode: u8) -> Option<u16> {
+        let (_, mode) = Self::decode(opcode)?;
+        Some(match opcode {
+            0x20 | 0x4c | 0x6c => 3,
+            _ if opcode & 0x1f == 0x10 => 2,
+            _ => match mode {
+                Mode::Implied | Mode::Accumulator => 1,
+                Mode::Absolute | Mode::AbsoluteX | Mode::AbsoluteY => 3,
+                _ => 2,
+            },
+        })
+    }
+
+    /// The mnemonic and addressing mode of a modeled opcode
+    fn decode(opcode: u8) -> Option<(&'static str, Mode)> {
+        let implied = match opcode {
+            0x08 => "PHP",
+            0x18 => "CLC",
+            0x20 => "JSR",
+            0x28 => "PLP",
+            0x38 => "SEC",
+            0x40 => "RTI",
+            0x48 => "PHA",
+            0x4c | 0x6c => "JMP",
+            0x58 => "CLI",
+            0x60 => "RTS",
+            0x68 => "PLA",
+            0x78 => "SEI",
+            0x88 => "DEY",
+            0x8a => "TXA",
+            0x98 => "TYA",
+            0x9a => "TXS",
+            0xa8 => "TAY",
+            0xaa => "TAX",
+            0xb8 => "CLV",
+            0xba => "TSX",
+            0xc8 => "INY",
+            0xca => "DEX",
+            0xd8 => "CLD",
+            0xe8 => "INX",
+            0xea => "NOP",
+            0xf8 => "SED",
+            _ if opcode & 0x1f == 0x10 => "branch",
+            _ => "",
+        };
+        if !implied.is_empty() {
+            return Some((implied, Mode::Implied));
+        }
+        let aaa = (opcode >> 5) as usize;
+        let mode = group_mode(opcode)?;
+        let name = match opcode & 0x03 {
+            0b01 => ["ORA", "AND", "EOR", "ADC", "STA", "LDA", "CMP", "SBC"][aaa],
+            0b10 => ["ASL", "ROL", "LSR", "ROR", "STX", "LDX", "DEC", "INC"][aaa],
+            0b00 => ["", "BIT", "", "", "STY", "LDY", "CPY", "CPX"][aaa],
+            _ => "",
+        };
+        let valid = match (name, mode) {
+            ("", _) => false,
+            ("STA", Mode::Immediate) => false,
+            (_, Mode::Immediate) => {
+                opcode & 0x03 == 0b01 || matches!(opcode, 0xa0 | 0xa2 | 0xc0 | 0xe0)
+            }
+            ("STX", Mode::Accumulator) | ("LDX", Mode::Accumulator) => false,
+            ("DEC", Mode::Accumulator) | ("INC", Mode::Accumulator) => false,
+            ("STX", Mode::AbsoluteX | Mode::AbsoluteY) | ("STY", Mode::AbsoluteX) => false,
+            ("BIT", mode) => matches!(mode, Mode::ZeroPage | Mode::Absolute),
+            ("CPX" | "CPY", mode) => {
+                matches!(mode, Mode::Immediate | Mode::ZeroPage | Mode::Absolute)
+            }
+            _ => true,
+        };
+        valid.then_some((name, mode))
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        self.memory[addr as usize] = value;
+        self.writes.push(addr);
+    }
+
+    fn read_u16_zero_page(&self, addr: u8) -> u16 {
+        u16::from_le_bytes([
+            self.read(addr as u16),
+            self.read(addr.wrapping_add(1) as u16),
+        ])
+    }
+
+    fn push(&mut self, value: u8) {
+        self.write(0x0100 | self.sp as u16, value);
+        self.sp = self.sp.wrapping_sub(1);
+    }
+
+    fn pull(&mut self) -> u8 {
+        self.sp = self.sp.wrapping_add(1);
+        self.read(0x0100 | self.sp as u16)
+    }
+
+    fn set_flag(&mut self, flag: u8, on: bool) {
+        if on {
+            self.p |= flag;
+        } else {
+            self.p &= !flag;
+        }
+    }
+
+    fn set_zn(&mut self, value: u8) -> u8 {
+        self.set_flag(Z, value == 0);
+        self.set_flag(N, value & 0x80 != 0);
+        value
+    }
+
+    fn add(&mut self, value: u8) {
+        let sum = self.a as u16 + value as u16 + (self.p & C) as u16;
+        let result = sum as u8;
+        self.set_flag(C, sum > 0xff);
+        self.set_flag(V, (self.a ^ result) & (value ^ result) & 0x80 != 0);
+        self.a = self.set_zn(result);
+    }
+
+    fn compare(&mut self, register: u8, value: u8) {
+        self.set_flag(C, register >= value);
+        self.set_zn(register.wrapping_sub(value));
+    }
+
+    /// Execute one instruction. Returns false, without doing anything, for opcodes it doesn't model.
+    pub fn step(&mut self) -> bool {
+        self.writes.clear();
+        let opcode = self.read(self.pc);
+        let Some((name, mode)) = Self::decode(opcode) else {
+            return false;
+        };
+        let length = Self::length(opcode).unwrap_or(1);
+        let operand8 = self.read(self.pc.wrapping_add(1));
+        let operand16 = u16::from_le_bytes([operand8, self.read(self.pc.wrapping_add(2))]);
+        let next = self.pc.wrapping_add(length);
+        self.pc = next;
+
+        let address = match mode {
+            Mode::Implied | Mode::Accumulator => 0,
+            Mode::Immediate => next.wrapping_sub(1),
+            Mode::ZeroPage => operand8 as u16,
+            Mode::ZeroPageX => operand8.wrapping_add(self.x) as u16,
+            Mode::ZeroPageY => operand8.wrapping_add(self.y) as u16,
+            Mode::Absolute => operand16,
+            Mode::AbsoluteX => operand16.wrapping_add(self.x as u16),
+            Mode::AbsoluteY => operand16.wrapping_add(self.y as u16),
+            Mode::IndirectX => self.read_u16_zero_page(operand8.wrapping_add(self.x)),
+            Mode::IndirectY => self
+                .read_u16_zero_page(operand8)
+                .wrapping_add(self.y as u16),
+        };
+        match name {
+            "ORA" => self.a = self.set_zn(self.a | self.read(address)),
+            "AND" => self.a = self.set_zn(self.a & self.read(address)),
+            "EOR" => self.a = self.set_zn(self.a ^ self.read(address)),
+            "ADC" => self.add(self.read(address)),
+            "SBC" => self.add(!self.read(address)),
+            "CMP" => self.compare(self.a, self.read(address)),
+            "CPX" => self.compare(self.x, self.read(address)),
+            "CPY" => self.compare(self.y, self.read(address)),
+            "LDA" => self.a = self.set_zn(self.read(address)),
+            "LDX" => self.x = self.set_zn(self.read(address)),
+            "LDY" => self.y = self.set_zn(self.read(address)),
+            "STA" => self.write(address, self.a),
+            "STX" => self.write(address, self.x),
+            "STY" => self.write(address, self.y),
+            "BIT" => {
+                let value = self.read(address);
+                self.set_flag(Z, self.a & value == 0);
+                self.set_flag(N, value & 0x80 != 0);
+                self.set_flag(V, value & 0x40 != 0);
+            }
+            "ASL" | "ROL" | "LSR" | "ROR" | "INC" | "DEC" => {
+                let old = match mode {
+                    Mode::Accumulator => self.a,
+                    _ => self.read(address),
+                };
+                let carry = self.p & C;
+                let (result, carry_out) = match name {
+                    "ASL" => (old << 1, old & 0x80 != 0),
+                    "ROL" => (old << 1 | carry, old & 0x80 != 0),
+                    "LSR" => (old >> 1, old & 0x01 != 0),
+                    "ROR" => (old >> 1 | carry << 7, old & 0x01 != 0),
+                    "INC" => (old.wrapping_add(1), carry != 0),
+                    _ => (old.wrapping_sub(1), carry != 0),
+                };
+                self.set_flag(C, carry_out);
+                self.set_zn(result);
+                match mode {
+                    Mode::Accumulator => self.a = result,
+                    _ => self.write(address, result),
+                }
+            }
+            "branch" => {
+                // Bits 7-6 pick the flag, bit 5 the value it's compared with
+                let flag = [N, V, C, Z][(opcode >> 6) as usize];
+                if (self.p & flag != 0) == (opcode & 0x20 != 0) {
+                    self.pc = next.wrapping_add(operand8 as i8 as u16);
+                }
+            }
+            "JMP" if opcode == 0x4c => self.pc = operand16,
+            "JMP" => {
+                // The pointer's high byte is fetched from the same page
+                let high = (operand16 & 0xff00) | (operand16.wrapping_add(1) & 0x00ff);
+                self.pc = u16::from_le_bytes([self.read(operand16), self.read(high)]);
+            }
+            "JSR" => {
+                let ret = next.wrapping_sub(1);
+                self.push((ret >> 8) as u8);
+                self.push(ret as u8);
+                self.pc = operand16;
+            }
+            "RTS" => {
+                let low = self.pull();
+                let high = self.pull();
+                self.pc = u16::from_le_bytes([low, high]).wrapping_add(1);
+            }
+            "RTI" => {
+                self.p = self.pull() & 0xcf | 0x20;
+                let low = self.pull();
+                let high = self.pull();
+                self.pc = u16::from_le_bytes([low, high]);
+            }
+            "PHA" => self.push(self.a),
+            "PHP" => self.push(self.p | 0x30),
+            "PLA" => {
+                let value = self.pull();
+                self.a = self.set_zn(value);
+            }
+            "PLP" => self.p = self.pull() & 0xcf | 0x20,
+            "CLC" => self.set_flag(C, false),
+            "SEC" => self.set_flag(C, true),
+            "CLI" => self.set_flag(I, false),
+            "SEI" => self.set_flag(I, true),
+            "CLD" => self.set_flag(D, false),
+            "SED" => self.set_flag(D, true),
+            "CLV" => self.set_flag(V, false),
+            "TAX" => self.x = self.set_zn(self.a),
+            "TAY" => self.y = self.set_zn(self.a),
+            "TXA" => self.a = self.set_zn(self.x),
+            "TYA" => self.a = self.set_zn(self.y),
+            "TSX" => self.x = self.set_zn(self.sp),
+            "TXS" => self.sp = self.x,
+            "INX" => self.x = self.set_zn(self.x.wrapping_add(1)),
+            "INY" => self.y = self.set_zn(self.y.wrapping_add(1)),
+            "DEX" => self.x = self.set_zn(self.x.wrapping_sub(1)),
+            "DEY" => self.y = self.set_zn(self.y.wrapping_sub(1)),
+            _ => {}
+        }
+        true
+    }
+}
+
+impl Default for ReferenceCpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,4 +1,7 @@
+use anyhow::Result;
+
 use crate::constant::{ADDRESS_TEST_PROGRAM, SP_ADDRESS_RESET};
+use crate::savestate::{Savestate, StateReader, StateWriter};
 
 #[derive(Clone, Debug)]
 pub struct CpuRegister {
@@ -37,3 +40,43 @@ impl Default for CpuRegister {
         }
     }
 }
+
+impl Savestate for CpuRegister {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.a);
+        state.write_u8(self.x);
+        state.write_u8(self.y);
+        state.write_u16(self.pc);
+        state.write_u8(self.sp);
+        let flags = [
+            self.carry,
+            self.zero,
+            self.interrupt_disabled,
+            self.decimal,
+            self.overflow,
+            self.negative,
+        ];
+        state.write_u8(
+            flags
+                .iter()
+                .rev()
+                .fold(0, |bits, &flag| bits << 1 | flag as u8),
+        );
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.a = state.read_u8()?;
+        self.x = state.read_u8()?;
+        self.y = state.read_u8()?;
+        self.pc = state.read_u16()?;
+        self.sp = state.read_u8()?;
+        let flags = state.read_u8()?;
+        self.carry = flags & 0x01 != 0;
+        self.zero = flags & 0x02 != 0;
+        self.interrupt_disabled = flags & 0x04 != 0;
+        self.decimal = flags & 0x08 != 0;
+        self.overflow = flags & 0x10 != 0;
+        self.negative = flags & 0x20 != 0;
+        Ok(())
+    }
+}
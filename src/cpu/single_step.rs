@@ -0,0 +1,299 @@
+use std::fmt::{self, Write};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::cpu::harness::ExpectedState;
+use crate::cpu::{BusAccess, BusCycle, Clocked, Cpu6502};
+
+// reference: https://github.com/SingleStepTests/ProcessorTests (nes6502)
+
+/// Registers and memory before or after a test, as stored in the JSON files
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct SingleStepState {
+    pub pc: u16,
+    pub s: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub ram: Vec<(u16, u8)>,
+}
+
+/// One test vector: a single instruction with its state before and after, and its bus activity
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct SingleStepTest {
+    pub name: String,
+    pub initial: SingleStepState,
+    #[serde(rename = "final")]
+    pub final_state: SingleStepState,
+    /// One `[address, value, "read" | "write"]` entry per cycle
+    pub cycles: Vec<(u16, u8, String)>,
+}
+
+impl SingleStepTest {
+    /// The per-cycle bus activity, in the format of the CPU's bus log
+    pub fn bus_cycles(&self) -> Result<Vec<BusCycle>> {
+        self.cycles
+            .iter()
+            .map(|(address, value, kind)| {
+                let access = match kind.as_str() {
+                    "read" => BusAccess::Read(*address),
+                    "write" => BusAccess::Write(*address),
+                    kind => bail!("unknown bus activity '{}'", kind),
+                };
+                Ok(BusCycle {
+                    access,
+                    value: *value,
+                })
+            })
+            .collect()
+    }
+
+    /// Run the instruction on a CPU with a flat RAM bus and compare the final state.
+    /// The CPU is reused between tests, so only the memory the test touched is cleared.
+    pub fn run(&self, cpu: &mut Cpu6502, check_cycles: bool) -> Result<()> {
+        cpu.flat_bus = true;
+        cpu.stop_on_brk = false;
+        cpu.bus_log = Some(Vec::new());
+        let initial = &self.initial;
+        cpu.registers.pc = initial.pc;
+        cpu.registers.sp = initial.s;
+        cpu.registers.a = initial.a;
+        cpu.registers.x = initial.x;
+        cpu.registers.y = initial.y;
+        cpu.set_status_register_from_byte(initial.p);
+        for &(address, value) in &initial.ram {
+            cpu.mapper[address as usize] = value;
+        }
+
+        let cycles = cpu.cycles;
+        let result = cpu.clocked();
+        let elapsed = cpu.cycles - cycles;
+        let log = cpu.bus_log.take().unwrap_or_default();
+        let differences = self.expected_state().diff(cpu);
+
+        let touched = initial.ram.iter().chain(&self.final_state.ram);
+        for &(address, _) in touched {
+            cpu.mapper[address as usize] = 0;
+        }
+        for cycle in &log {
+            if let BusAccess::Write(address) = cycle.access {
+                cpu.mapper[address as usize] = 0;
+            }
+        }
+
+        result.with_context(|| format!("{}: the instruction did not execute", self.name))?;
+        let mut message = String::new();
+        for line in differences {
+            let _ = write!(message, "\n  {}", line);
+        }
+        if check_cycles {
+            let expected = self.bus_cycles()?;
+            if elapsed != expected.len() as u64 {
+                let _ = write!(
+                    message,
+                    "\n  cycles: expected {}, got {}",
+                    expected.len(),
+                    elapsed
+                );
+            }
+            if let Some(i) =
+                (0..expected.len().max(log.len())).find(|&i| expected.get(i) != log.get(i))
+            {
+                let _ = write!(
+                    message,
+                    "\n  bus cycle {}: expected {}, got {}",
+                    i,
+                    BusCycleText(expected.get(i)),
+                    BusCycleText(log.get(i))
+                );
+            }
+        }
+        if !message.is_empty() {
+            bail!("{}:{}", self.name, message);
+        }
+        Ok(())
+    }
+
+    fn expected_state(&self) -> ExpectedState {
+        let state = &self.final_state;
+        ExpectedState {
+            a: Some(state.a),
+            x: Some(state.x),
+            y: Some(state.y),
+            pc: Some(state.pc),
+            sp: Some(state.s),
+            carry: Some(state.p & 0b0000_0001 != 0),
+            zero: Some(state.p & 0b0000_0010 != 0),
+            interrupt_disabled: Some(state.p & 0b0000_0100 != 0),
+            decimal: Some(state.p & 0b0000_1000 != 0),
+            overflow: Some(state.p & 0b0100_0000 != 0),
+            negative: Some(state.p & 0b1000_0000 != 0),
+            memory: state
+                .ram
+                .iter()
+                .map(|&(address, value)| (address, vec![value]))
+                .collect(),
+        }
+    }
+}
+
+struct BusCycleText<'a>(Option<&'a BusCycle>);
+
+impl fmt::Display for BusCycleText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(BusCycle {
+                access: BusAccess::Read(address),
+                value,
+            }) => write!(f, "read ${:02X} from ${:04X}", value, address),
+            Some(BusCycle {
+                access: BusAccess::Write(address),
+                value,
+            }) => write!(f, "write ${:02X} to ${:04X}", value, address),
+            None => write!(f, "nothing"),
+        }
+    }
+}
+
+/// Results of the test vectors of one opcode
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpcodeReport {
+    pub opcode: u8,
+    pub passed: usize,
+    pub failed: usize,
+    /// Why the first failing test failed
+    pub first_failure: Option<String>,
+}
+
+impl fmt::Display for OpcodeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = if self.failed == 0 { "PASS" } else { "FAIL" };
+        write!(
+            f,
+            "{:02X}  {}  {:>5} passed  {:>5} failed",
+            self.opcode, status, self.passed, self.failed
+        )?;
+        if let Some(failure) = &self.first_failure {
+            write!(f, "\n    {}", failure.replace('\n', "\n    "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Run every test of one opcode's file, `a9.json` for LDA immediate
+pub fn run_single_step_file(path: &Path, check_cycles: bool) -> Result<OpcodeReport> {
+    let opcode = path
+        .file_stem()
+        .and_then(|stem| u8::from_str_radix(&stem.to_string_lossy(), 16).ok())
+        .with_context(|| format!("{} isn't named after an opcode", path.display()))?;
+    let data = fs::read_to_string(path)?;
+    let tests: Vec<SingleStepTest> = serde_json::from_str(&data)
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+
+    let mut cpu = Box::<Cpu6502>::default();
+    let mut report = OpcodeReport {
+        opcode,
+        passed: 0,
+        failed: 0,
+        first_failure: None,
+    };
+    for test in &tests {
+        match test.run(&mut cpu, check_cycles) {
+            Ok(()) => report.passed += 1,
+            Err(error) => {
+                report.failed += 1;
+                report.first_failure.get_or_insert(format!("{:#}", error));
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Run the files of every opcode found in the directory, in opcode order
+pub fn run_single_step_tests(dir: &Path, check_cycles: bool) -> Result<Vec<OpcodeReport>> {
+    let mut paths: Vec<_> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "json"));
+    paths.sort();
+    paths
+        .iter()
+        .map(|path| run_single_step_file(path, check_cycles))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// LDA #$8E from the upstream a9.json
+    const LDA: &str = r#"{"name": "a9 8e 5c",
+        "initial": {"pc": 1234, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36, "ram": [[1234, 169], [1235, 142]]},
+        "final": {"pc": 1236, "s": 253, "a": 142, "x": 0, "y": 0, "p": 164, "ram": [[1234, 169], [1235, 142]]},
+        "cycles": [[1234, 169, "read"], [1235, 142, "read"]]}"#;
+
+    /// STA $2000 with the upper address range as plain RAM
+    const STA: &str = r#"{"name": "8d 00 20",
+        "initial": {"pc": 65530, "s": 253, "a": 66, "x": 0, "y": 0, "p": 36, "ram": [[65530, 141], [65531, 0], [65532, 32], [8192, 0]]},
+        "final": {"pc": 65533, "s": 253, "a": 66, "x": 0, "y": 0, "p": 36, "ram": [[65530, 141], [65531, 0], [65532, 32], [8192, 66]]},
+        "cycles": [[65530, 141, "read"], [65531, 0, "read"], [65532, 32, "read"], [8192, 66, "write"]]}"#;
+
+    /// A directory with a9.json, whose second vector expects the wrong result, and 8d.json
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("nes-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let wrong = LDA.replace(r#""a": 142"#, r#""a": 143"#);
+        fs::write(dir.join("a9.json"), format!("[{}, {}]", LDA, wrong)).unwrap();
+        fs::write(dir.join("8d.json"), format!("[{}]", STA)).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_final_state() {
+        let dir = test_dir("single-step");
+        let reports = run_single_step_tests(&dir, false).unwrap();
+        assert_eq!(
+            reports
+                .iter()
+                .map(|report| (report.opcode, report.passed, report.failed))
+                .collect::<Vec<_>>(),
+            vec![(0x8d, 1, 0), (0xa9, 1, 1)]
+        );
+        assert_eq!(
+            reports[1].first_failure.as_deref(),
+            Some("a9 8e 5c:\n  A: expected $8F, got $8E")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bus_cycles() {
+        // A store only writes its target, without reading it first
+        let dir = test_dir("single-step-cycles");
+        let reports = run_single_step_tests(&dir, true).unwrap();
+        assert_eq!((reports[0].passed, reports[0].failed), (1, 0));
+        assert_eq!(reports[0].first_failure, None);
+        assert_eq!((reports[1].passed, reports[1].failed), (1, 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_no_apu_on_flat_bus() {
+        // NOP with interrupts enabled, repeated for more cycles than an APU frame IRQ takes
+        let nop = r#"{"name": "ea",
+            "initial": {"pc": 1234, "s": 253, "a": 0, "x": 0, "y": 0, "p": 32, "ram": [[1234, 234]]},
+            "final": {"pc": 1235, "s": 253, "a": 0, "x": 0, "y": 0, "p": 32, "ram": [[1234, 234]]},
+            "cycles": []}"#;
+        let dir = std::env::temp_dir().join(format!("nes-single-step-irq-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ea.json");
+        fs::write(&path, format!("[{}]", vec![nop; 20_000].join(","))).unwrap();
+        let report = run_single_step_file(&path, false).unwrap();
+        assert_eq!((report.passed, report.failed), (20_000, 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
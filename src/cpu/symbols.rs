@@ -0,0 +1,477 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use crate::cartridge::{INES_HEADER_SIZE, PRG_ROM_BANK_SIZE};
+use crate::constant::PRG_RAM_ADDRESS;
+
+/// A label from a symbol file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    /// CPU address the label was assembled at, None for PRG ROM labels only known by offset
+    pub address: Option<u16>,
+    /// Offset in PRG ROM of labels in ROM, which tells apart labels of different banks
+    /// at the same address
+    pub prg_offset: Option<usize>,
+    /// Bytes the label covers, e.g. for tables
+    pub size: u16,
+}
+
+/// Labels loaded from ca65 debug info, FCEUX namelists and Mesen label files
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+    /// Index into `symbols` by CPU address and by PRG ROM offset
+    by_address: BTreeMap<usize, usize>,
+    by_prg_offset: BTreeMap<usize, usize>,
+    by_name: HashMap<String, Vec<usize>>,
+}
+
+impl SymbolTable {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    /// Add a label. The first one added wins when several start at the same place.
+    pub fn add(&mut self, symbol: Symbol) {
+        let index = self.symbols.len();
+        if let Some(address) = symbol.address {
+            self.by_address.entry(address as usize).or_insert(index);
+        }
+        if let Some(offset) = symbol.prg_offset {
+            self.by_prg_offset.entry(offset).or_insert(index);
+        }
+        self.by_name
+            .entry(symbol.name.clone())
+            .or_default()
+            .push(index);
+        self.symbols.push(symbol);
+    }
+
+    pub fn extend(&mut self, other: SymbolTable) {
+        for symbol in other.symbols {
+            self.add(symbol);
+        }
+    }
+
+    /// Every label with this name, one per bank it's defined in
+    pub fn named<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a Symbol> {
+        self.by_name
+            .get(name)
+            .into_iter()
+            .flatten()
+            .map(|&index| &self.symbols[index])
+    }
+
+    /// Label of `address` as `name` or `name+3` inside what a label covers. `prg_offset` is
+    /// where the address currently maps in PRG ROM, which picks the label of the mapped bank.
+    #[must_use]
+    pub fn label(&self, address: u16, prg_offset: Option<usize>) -> Option<String> {
+        if let Some(offset) = prg_offset {
+            if let Some(label) = covering(&self.symbols, &self.by_prg_offset, offset, |symbol| {
+                symbol.prg_offset
+            }) {
+                return Some(label);
+            }
+        }
+        covering(
+            &self.symbols,
+            &self.by_address,
+            address as usize,
+            |symbol| {
+                // With the bank known, a ROM label of some other bank doesn't apply
+                match (prg_offset, symbol.prg_offset) {
+                    (Some(_), Some(_)) => None,
+                    _ => symbol.address.map(usize::from),
+                }
+            },
+        )
+    }
+
+    /// Load a symbol file by its extension: ca65 `.dbg`, FCEUX `.nl` or Mesen `.mlb`
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read symbols from {}", path.display()))?;
+        let extension = path.extension().unwrap_or_default().to_string_lossy();
+        let table = match extension.to_ascii_lowercase().as_str() {
+            "dbg" => Self::parse_ca65_dbg(&text),
+            "mlb" => Self::parse_mesen_mlb(&text),
+            "nl" => {
+                // game.nes.ram.nl or game.nes.<bank in hex>.nl
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                let bank = match stem.rsplit('.').next() {
+                    Some("ram") => None,
+                    Some(bank) => Some(usize::from_str_radix(bank, 16).with_context(|| {
+                        format!("'{}' does not name a ram or bank namelist", path.display())
+                    })?),
+                    None => None,
+                };
+                Self::parse_fceux_nl(&text, bank)
+            }
+            _ => bail!("unknown symbol file format '{}'", path.display()),
+        };
+        table.with_context(|| format!("failed to load symbols from {}", path.display()))
+    }
+
+    /// Symbol files next to a ROM: `game.dbg`, `game.mlb` and FCEUX's `game.nes.*.nl`
+    pub fn discover(rom: &Path) -> Result<Self> {
+        let mut paths = vec![rom.with_extension("dbg"), rom.with_extension("mlb")];
+        let directory = match rom.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let prefix = format!("{}.", rom.file_name().unwrap_or_default().to_string_lossy());
+        if let Ok(entries) = fs::read_dir(&directory) {
+            let mut namelists: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| {
+                    let name = path.file_name().unwrap_or_default().to_string_lossy();
+                    name.starts_with(&prefix) && name.ends_with(".nl")
+                })
+                .collect();
+            namelists.sort();
+            paths.extend(namelists);
+        }
+        let mut table = Self::default();
+        for path in paths.iter().filter(|path| path.is_file()) {
+            table.extend(Self::load(path)?);
+        }
+        Ok(table)
+    }
+
+    /// ca65/ld65 debug info written with `--dbgfile`. Cheap local labels are named
+    /// `global@local`, as the assembler does.
+    ///
+    /// ```text
+    /// seg id=0,name="CODE",start=0x00C000,size=0x0012,addrsize=absolute,type=ro,oname="game.nes",ooffs=16
+    /// sym id=0,name="reset",addrsize=absolute,scope=0,def=3,ref=7,val=0xC000,seg=0,type=lab
+    /// ```
+    pub fn parse_ca65_dbg(text: &str) -> Result<Self> {
+        // start, PRG ROM offset of start
+        let mut segments: HashMap<u64, (u64, Option<usize>)> = HashMap::new();
+        let mut symbols = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let Some((kind, fields)) = line.split_once(char::is_whitespace) else {
+                continue;
+            };
+            let fields = parse_fields(fields);
+            let number = |key: &str| -> Result<Option<u64>> {
+                fields
+                    .get(key)
+                    .map(|value| parse_number(value))
+                    .transpose()
+                    .with_context(|| format!("line {}: bad {}", number + 1, key))
+            };
+            match kind {
+                "seg" => {
+                    let (Some(id), Some(start)) = (number("id")?, number("start")?) else {
+                        continue;
+                    };
+                    let header = match fields.get("oname") {
+                        Some(name) if name.to_ascii_lowercase().ends_with(".nes") => {
+                            INES_HEADER_SIZE as u64
+                        }
+                        _ => 0,
+                    };
+                    let offset = match (fields.get("type").map(String::as_str), number("ooffs")?) {
+                        (Some("ro"), Some(ooffs)) if ooffs >= header => {
+                            Some((ooffs - header) as usize)
+                        }
+                        _ => None,
+                    };
+                    segments.insert(id, (start, offset));
+                }
+                "sym" if fields.get("type").map(String::as_str) == Some("lab") => {
+                    let (Some(id), Some(value)) = (number("id")?, number("val")?) else {
+                        continue;
+                    };
+                    let name = fields.get("name").cloned().unwrap_or_default();
+                    let size = number("size")?.unwrap_or(1);
+                    symbols.push((id, name, value, size, number("seg")?, number("parent")?));
+                }
+                _ => {}
+            }
+        }
+
+        let names: HashMap<u64, String> = symbols
+            .iter()
+            .map(|(id, name, ..)| (*id, name.clone()))
+            .collect();
+        let mut table = Self::default();
+        for (_, name, value, size, segment, parent) in symbols {
+            let name = match parent.and_then(|parent| names.get(&parent)) {
+                Some(parent) => format!("{}{}", parent, name),
+                None => name,
+            };
+            let prg_offset = segment
+                .and_then(|segment| segments.get(&segment))
+                .and_then(|&(start, offset)| Some(offset? + value.checked_sub(start)? as usize));
+            table.add(Symbol {
+                name,
+                address: Some(value as u16),
+                prg_offset,
+                size: size.clamp(1, u16::MAX as u64) as u16,
+            });
+        }
+        Ok(table)
+    }
+
+    /// An FCEUX namelist: `$C000#reset#comment`, `$0300/10#buffer#` for $10 bytes.
+    /// `bank` is the 16KB PRG bank of a `game.nes.<bank>.nl`, None for `game.nes.ram.nl`.
+    pub fn parse_fceux_nl(text: &str, bank: Option<usize>) -> Result<Self> {
+        let mut table = Self::default();
+        for (number, line) in text.lines().enumerate() {
+            // Comments continue on lines starting with a backslash
+            let Some(line) = line.strip_prefix('$') else {
+                continue;
+            };
+            let mut fields = line.splitn(3, '#');
+            let (Some(location), Some(name)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            let (address, size) = match location.split_once('/') {
+                Some((address, size)) => (address, size),
+                None => (location, "1"),
+            };
+            let parse = |value: &str| {
+                u16::from_str_radix(value.trim(), 16)
+                    .with_context(|| format!("line {}: '{}' is not hex", number + 1, value))
+            };
+            let address = parse(address)?;
+            let name = name.trim();
+            if name.is_empty() {
+                continue;
+            }
+            table.add(Symbol {
+                name: name.to_string(),
+                address: Some(address),
+                prg_offset: bank
+                    .map(|bank| bank * PRG_ROM_BANK_SIZE + (address as usize % PRG_ROM_BANK_SIZE)),
+                size: parse(size)?.max(1),
+            });
+        }
+        Ok(table)
+    }
+
+    /// A Mesen label file: `P:1234:label:comment` for a PRG ROM offset, `R:0300:buffer` for
+    /// internal RAM, `S:`/`W:` for save or work RAM and `G:2000:PPUCTRL` for registers.
+    /// Ranges are written `R:0300-030F:buffer`, Mesen 2's long type names work too.
+    pub fn parse_mesen_mlb(text: &str) -> Result<Self> {
+        let mut table = Self::default();
+        for (number, line) in text.lines().enumerate() {
+            let mut fields = line.trim().splitn(4, ':');
+            let (Some(kind), Some(location), Some(name)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let name = name.trim();
+            if name.is_empty() {
+                continue;
+            }
+            let (start, end) = match location.split_once('-') {
+                Some((start, end)) => (start, end),
+                None => (location, location),
+            };
+            let parse = |value: &str| {
+                u32::from_str_radix(value.trim(), 16)
+                    .with_context(|| format!("line {}: '{}' is not hex", number + 1, value))
+            };
+            let (start, end) = (parse(start)?, parse(end)?);
+            let size = (end.saturating_sub(start) + 1).min(u16::MAX as u32) as u16;
+            let (address, prg_offset) = match kind {
+                "P" | "NesPrgRom" => (None, Some(start as usize)),
+                "R" | "NesInternalRam" | "G" | "NesMemory" => (Some(start as u16), None),
+                // Save and work RAM offsets start at $6000 on the CPU bus
+                "S" | "W" | "NesSaveRam" | "NesWorkRam" => {
+                    (Some(PRG_RAM_ADDRESS.wrapping_add(start as u16)), None)
+                }
+                // CHR and other memory the CPU can't see
+                _ => continue,
+            };
+            table.add(Symbol {
+                name: name.to_string(),
+                address,
+                prg_offset,
+                size,
+            });
+        }
+        Ok(table)
+    }
+}
+
+/// `name` or `name+n` of the label starting at or before `key` that covers it
+fn covering(
+    symbols: &[Symbol],
+    index: &BTreeMap<usize, usize>,
+    key: usize,
+    start: impl Fn(&Symbol) -> Option<usize>,
+) -> Option<String> {
+    let (_, &found) = index.range(..=key).next_back()?;
+    let symbol = &symbols[found];
+    match key - start(symbol)? {
+        0 => Some(symbol.name.clone()),
+        distance if distance < symbol.size as usize => {
+            Some(format!("{}+{}", symbol.name, distance))
+        }
+        _ => None,
+    }
+}
+
+/// `key=value,key="quoted, value"` of a ca65 debug info line
+fn parse_fields(text: &str) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        let Some((key, value)) = rest.split_once('=') else {
+            break;
+        };
+        let (value, next) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                let next = quoted[end..].trim_start_matches('"');
+                (&quoted[..end], next)
+            }
+            None => value.split_once(',').map_or((value, ""), |(v, n)| (v, n)),
+        };
+        fields.insert(key.trim().to_string(), value.to_string());
+        rest = next.trim_start_matches(',').trim();
+    }
+    fields
+}
+
+/// `0x8000` or decimal, as used in ca65 debug info
+fn parse_number(value: &str) -> Result<u64> {
+    Ok(match value.strip_prefix("0x") {
+        Some(digits) => u64::from_str_radix(digits, 16)?,
+        None => value.parse()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{trace_line, Expression};
+    use crate::test_util::{nes_with_source, STORE_LOOP_SOURCE};
+
+    /// ld65 debug info for STORE_LOOP_SOURCE, with a 4 byte buffer at $0300
+    const DBG: &str = r#"version major=2,minor=0
+seg id=0,name="CODE",start=0x008000,size=0x000F,addrsize=absolute,type=ro,oname="game.nes",ooffs=16
+seg id=1,name="BSS",start=0x000300,size=0x0004,addrsize=absolute,type=rw
+sym id=0,name="start",addrsize=absolute,scope=0,def=1,ref=9,val=0x8000,seg=0,type=lab
+sym id=1,name="loop",addrsize=absolute,scope=0,def=2,ref=3+4,val=0x8002,seg=0,type=lab
+sym id=2,name="store",addrsize=absolute,scope=0,def=5,ref=6,val=0x800B,seg=0,type=lab
+sym id=3,name="@done",addrsize=absolute,parent=2,scope=0,def=7,val=0x800E,seg=0,type=lab
+sym id=4,name="buffer",addrsize=absolute,size=4,scope=0,def=8,val=0x300,seg=1,type=lab
+sym id=5,name="COUNT",addrsize=zeropage,scope=0,def=9,val=0x3,type=equ
+"#;
+
+    fn parsed(symbols: &SymbolTable) -> Vec<(&str, Option<u16>, Option<usize>, u16)> {
+        symbols
+            .iter()
+            .map(|s| (s.name.as_str(), s.address, s.prg_offset, s.size))
+            .collect()
+    }
+
+    #[test]
+    fn test_ca65_dbg() {
+        let symbols = SymbolTable::parse_ca65_dbg(DBG).unwrap();
+        // Equates aren't labels, cheap locals are scoped to their parent
+        assert_eq!(
+            parsed(&symbols),
+            vec![
+                ("start", Some(0x8000), Some(0x0000), 1),
+                ("loop", Some(0x8002), Some(0x0002), 1),
+                ("store", Some(0x800b), Some(0x000b), 1),
+                ("store@done", Some(0x800e), Some(0x000e), 1),
+                ("buffer", Some(0x0300), None, 4),
+            ]
+        );
+        assert_eq!(symbols.label(0x0302, None).as_deref(), Some("buffer+2"));
+        assert_eq!(symbols.label(0x0304, None), None);
+    }
+
+    #[test]
+    fn test_mesen_mlb() {
+        let mlb = "P:000B:store:copies X\nR:0300-0303:buffer\nG:2000:PPUCTRL\nS:0010:save\nP:0020::comment only\nC:0000:tiles\n";
+        let symbols = SymbolTable::parse_mesen_mlb(mlb).unwrap();
+        assert_eq!(
+            parsed(&symbols),
+            vec![
+                ("store", None, Some(0x000b), 1),
+                ("buffer", Some(0x0300), None, 4),
+                ("PPUCTRL", Some(0x2000), None, 1),
+                ("save", Some(0x6010), None, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fceux_nl_banks() {
+        // The same address holds different labels in different banks
+        let mut banked =
+            SymbolTable::parse_fceux_nl("$8000#main#entry\n\\more\n$8010/4#table#\n", Some(0))
+                .unwrap();
+        banked.extend(SymbolTable::parse_fceux_nl("$8000#music_init#\n", Some(1)).unwrap());
+        banked.add(Symbol {
+            name: "frame_count".to_string(),
+            address: Some(0x0010),
+            prg_offset: None,
+            size: 1,
+        });
+        assert_eq!(banked.label(0x8000, Some(0x0000)).as_deref(), Some("main"));
+        assert_eq!(
+            banked.label(0x8000, Some(0x4000)).as_deref(),
+            Some("music_init")
+        );
+        assert_eq!(
+            banked.label(0x8013, Some(0x0013)).as_deref(),
+            Some("table+3")
+        );
+        assert_eq!(banked.label(0x8000, Some(0x8000)), None);
+        assert_eq!(banked.label(0x0010, None).as_deref(), Some("frame_count"));
+    }
+
+    #[test]
+    fn test_discover() {
+        // Symbol files next to the ROM are found by name
+        let dir = std::env::temp_dir().join(format!("nes-symbols-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom = dir.join("game.nes");
+        fs::write(dir.join("game.dbg"), DBG).unwrap();
+        fs::write(dir.join("game.nes.ram.nl"), "$0010#frame_count#\n").unwrap();
+        fs::write(dir.join("game.nes.1.nl"), "$C000#music_init#\n").unwrap();
+        fs::write(dir.join("other.nes.0.nl"), "$8000#other#\n").unwrap();
+        let found = SymbolTable::discover(&rom).unwrap();
+        assert_eq!(found.len(), 7);
+        assert!(found.named("other").next().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_labels_in_traces_and_expressions() {
+        let mut nes = nes_with_source(STORE_LOOP_SOURCE);
+        nes.cpu.symbols = SymbolTable::parse_ca65_dbg(DBG).unwrap();
+        nes.cpu.bounded_run(1).unwrap();
+        assert!(trace_line(&nes.cpu).starts_with("8002  20 0B 80  JSR store "));
+        // PRG labels follow the bank into its $C000 mirror
+        assert_eq!(nes.cpu.label(0xc00e).as_deref(), Some("store@done"));
+        assert_eq!(nes.cpu.symbol_address("buffer"), Some(0x0300));
+        let condition = Expression::parse_with_symbols("PC == loop", &nes.cpu.symbols).unwrap();
+        assert!(condition.is_true(&nes.expression_context()).unwrap());
+        assert!(Expression::parse_with_symbols("PC == nowhere", &nes.cpu.symbols).is_err());
+    }
+}
@@ -0,0 +1,252 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::cpu::address::AddressingMode::*;
+use crate::cpu::disassembler::{format_operand_with, instruction_length, is_official_opcode};
+use crate::cpu::opcode::{Operation, OPCODE_TABLE};
+use crate::cpu::Cpu6502;
+use crate::ppu::{DOTS_PER_SCANLINE, SCANLINES_PER_FRAME};
+
+// reference: https://www.nesdev.org/wiki/Emulator_tests (nestest.log)
+
+/// Where nestest starts in automation mode, without a PPU to drive its menu
+pub const NESTEST_START: u16 = 0xc000;
+
+/// One line of the nestest log format for the instruction at the program counter,
+/// before it executes:
+///
+/// ```text
+/// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+/// ```
+///
+/// The PPU position is derived from the CPU cycle count, three dots per cycle with rendering off.
+/// Operands are shown by the name of their label when symbols are loaded.
+#[must_use]
+pub fn trace_line(cpu: &Cpu6502) -> String {
+    let registers = &cpu.registers;
+    let pc = registers.pc;
+    let opcode = cpu.peek(pc);
+    let (operation, mode, ..) = OPCODE_TABLE[opcode as usize];
+    let bytes: Vec<u8> = (0..instruction_length(mode))
+        .map(|i| cpu.peek(pc.wrapping_add(i)))
+        .collect();
+    let operand = match bytes[1..] {
+        [low] => low as u16,
+        [low, high] => u16::from_le_bytes([low, high]),
+        _ => 0,
+    };
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+
+    let mnemonic = match operation {
+        // nestest calls it by its other name
+        Operation::ISC => "ISB".to_string(),
+        operation => format!("{:?}", operation),
+    };
+    let operand_text = format_operand_with(mode, pc, operand, |address| cpu.label(address));
+    let mut text = format!("{} {}", mnemonic, operand_text);
+    let read_u16 = |low: u16, high: u16| u16::from_le_bytes([cpu.peek(low), cpu.peek(high)]);
+    match mode {
+        ZeroPage => text += &format!(" = {:02X}", cpu.peek(operand)),
+        ZeroPageX | ZeroPageY => {
+            let index = if mode == ZeroPageX {
+                registers.x
+            } else {
+                registers.y
+            };
+            let address = (operand as u8).wrapping_add(index) as u16;
+            text += &format!(" @ {:02X} = {:02X}", address, cpu.peek(address));
+        }
+        Absolute if !matches!(operation, Operation::JMP | Operation::JSR) => {
+            text += &format!(" = {:02X}", cpu.peek(operand));
+        }
+        AbsoluteX | AbsoluteY => {
+            let index = if mode == AbsoluteX {
+                registers.x
+            } else {
+                registers.y
+            };
+            let address = operand.wrapping_add(index as u16);
+            text += &format!(" @ {:04X} = {:02X}", address, cpu.peek(address));
+        }
+        // The pointer's high byte doesn't carry into the next page
+        Indirect => {
+            let high = (operand & 0xff00) | (operand.wrapping_add(1) & 0x00ff);
+            text += &format!(" = {:04X}", read_u16(operand, high));
+        }
+        IndirectX => {
+            let pointer = (operand as u8).wrapping_add(registers.x);
+            let address = read_u16(pointer as u16, pointer.wrapping_add(1) as u16);
+            text += &format!(
+                " @ {:02X} = {:04X} = {:02X}",
+                pointer,
+                address,
+                cpu.peek(address)
+            );
+        }
+        IndirectY => {
+            let pointer = operand as u8;
+            let base = read_u16(pointer as u16, pointer.wrapping_add(1) as u16);
+            let address = base.wrapping_add(registers.y as u16);
+            text += &format!(
+                " = {:04X} @ {:04X} = {:02X}",
+                base,
+                address,
+                cpu.peek(address)
+            );
+        }
+        _ => {}
+    }
+
+    let dots = cpu.cycles * 3;
+    let scanline = dots / DOTS_PER_SCANLINE as u64 % SCANLINES_PER_FRAME as u64;
+    let dot = dots % DOTS_PER_SCANLINE as u64;
+    format!(
+        "{:04X}  {:<8} {}{:<31} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+        pc,
+        hex.join(" "),
+        if is_official_opcode(opcode) { ' ' } else { '*' },
+        text.trim_end(),
+        registers.a,
+        registers.x,
+        registers.y,
+        cpu.status_register_byte(true),
+        registers.sp,
+        scanline,
+        dot,
+        cpu.cycles
+    )
+}
+
+/// Destination of the trace written by the CPU before each instruction
+pub struct TraceLog {
+    writer: Box<dyn Write>,
+}
+
+impl fmt::Debug for TraceLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TraceLog").finish_non_exhaustive()
+    }
+}
+
+impl TraceLog {
+    #[must_use]
+    pub fn new(writer: Box<dyn Write>) -> Self {
+        Self { writer }
+    }
+
+    pub fn create(path: &Path) -> Result<Self> {
+        Ok(Self::new(Box::new(BufWriter::new(File::create(path)?))))
+    }
+
+    pub fn write_line(&mut self, line: &str) -> Result<()> {
+        writeln!(self.writer, "{}", line)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The first line where a trace stops matching a golden log
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// 1-based line number
+    pub line: usize,
+    pub expected: String,
+    /// `None` when the trace ended early
+    pub actual: Option<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "trace diverges at line {}", self.line)?;
+        writeln!(f, "expected: {}", self.expected)?;
+        match &self.actual {
+            Some(actual) => write!(f, "actual:   {}", actual),
+            None => write!(f, "actual:   <end of trace>"),
+        }
+    }
+}
+
+/// Compare a trace against a golden log, line by line, ignoring line endings.
+/// Lines the trace has past the end of the golden log aren't checked.
+#[must_use]
+pub fn first_divergence(golden: &str, trace: &[String]) -> Option<Divergence> {
+    golden
+        .lines()
+        .map(str::trim_end)
+        .enumerate()
+        .find_map(|(i, expected)| {
+            let actual = trace.get(i).map(|line| line.trim_end());
+            (actual != Some(expected)).then(|| Divergence {
+                line: i + 1,
+                expected: expected.to_string(),
+                actual: actual.map(str::to_string),
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::TestHarness;
+    use crate::test_util::nes_with_program;
+
+    /// The first lines of nestest.log
+    const GOLDEN: &str = "\
+C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10
+C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 36 CYC:12
+C5F9  86 10     STX $10 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 45 CYC:15
+C5FB  86 11     STX $11 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 54 CYC:18
+C5FD  20 2D C7  JSR $C72D                       A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 63 CYC:21
+C72D  EA        NOP                             A:00 X:00 Y:00 P:26 SP:FB PPU:  0, 81 CYC:27
+";
+
+    /// The code those lines run, in a 16KB PRG mirrored at $C000
+    fn nestest_prg() -> Vec<u8> {
+        let mut prg = vec![0u8; 0x730];
+        prg[0x000..0x003].copy_from_slice(&[0x4c, 0xf5, 0xc5]);
+        prg[0x5f5..0x600].copy_from_slice(&[
+            0xa2, 0x00, 0x86, 0x00, 0x86, 0x10, 0x86, 0x11, 0x20, 0x2d, 0xc7,
+        ]);
+        prg[0x72d] = 0xea;
+        prg
+    }
+
+    #[test]
+    fn test_matches_nestest_log() {
+        let mut nes = nes_with_program(&nestest_prg());
+        assert_eq!(nes.run_nestest(GOLDEN).unwrap(), None);
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let mut nes = nes_with_program(&nestest_prg());
+        let golden = GOLDEN.replace("PPU:  0, 45 CYC:15", "PPU:  0, 46 CYC:15");
+        let divergence = nes.run_nestest(&golden).unwrap().unwrap();
+        assert_eq!(divergence.line, 4);
+        assert!(divergence.actual.unwrap().ends_with("PPU:  0, 45 CYC:15"));
+    }
+
+    #[test]
+    fn test_effective_address_annotations() {
+        let harness = TestHarness::new()
+            .with_program(0xd959, &[0xb1, 0x89])
+            .with_memory(0x0089, &[0x00, 0x03])
+            .with_memory(0x0300, &[0x89])
+            .with_registers(|registers| registers.a = 0xff);
+        assert!(trace_line(&harness.cpu)
+            .starts_with("D959  B1 89     LDA ($89),Y = 0300 @ 0300 = 89  A:FF"));
+        let harness = TestHarness::new().with_program(0xc000, &[0x04, 0xa9]);
+        assert!(trace_line(&harness.cpu).starts_with("C000  04 A9    *NOP $A9 = 00    "));
+        let harness = TestHarness::new().with_program(0xc000, &[0x6c, 0xff, 0x02]);
+        assert!(trace_line(&harness.cpu).starts_with("C000  6C FF 02  JMP ($02FF) = 0000  "));
+    }
+}
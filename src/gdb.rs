@@ -0,0 +1,585 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use anyhow::{bail, Context, Result};
+
+use crate::cpu::{BreakReason, BusAccess, DebugStop, WatchKind};
+use crate::mem::Mem;
+use crate::nes::NesEmulator;
+
+// reference: https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+
+/// Instructions to run between checks for an interrupt from the client while continuing
+const INTERRUPT_POLL_INTERVAL: usize = 1024;
+
+/// Largest packet data in either direction, advertised to the client in `qSupported`
+const PACKET_SIZE: usize = 0x1000;
+
+/// GDB has no 6502 architecture, so the registers are described to it instead.
+/// `g` packets follow this order: A, X, Y, P, SP and PC in little endian.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.nes.cpu6502">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="x" bitsize="8" type="uint8"/>
+    <reg name="y" bitsize="8" type="uint8"/>
+    <reg name="p" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+/// Serve one GDB client on `127.0.0.1:port` until it detaches or kills the target
+pub fn run_gdb_server(nes: &mut NesEmulator, port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .with_context(|| format!("failed to listen on port {}", port))?;
+    println!("Waiting for GDB on {}", listener.local_addr()?);
+    let (stream, client) = listener.accept()?;
+    println!("GDB connected from {}", client);
+    GdbServer::new(nes, stream).serve()
+}
+
+/// GDB remote serial protocol stub driving the emulator for one client
+pub struct GdbServer<'a> {
+    nes: &'a mut NesEmulator,
+    stream: TcpStream,
+    /// Set by `QStartNoAckMode`, packets are no longer acknowledged
+    no_ack: bool,
+}
+
+impl<'a> GdbServer<'a> {
+    pub fn new(nes: &'a mut NesEmulator, stream: TcpStream) -> Self {
+        Self {
+            nes,
+            stream,
+            no_ack: false,
+        }
+    }
+
+    /// Answer packets until the client detaches, kills the target or disconnects
+    pub fn serve(&mut self) -> Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let Some(reply) = self.handle(&packet)? else {
+                return Ok(());
+            };
+            self.send(&reply)?;
+            match packet.as_slice() {
+                // The OK is still acknowledged, nothing after it
+                b"QStartNoAckMode" => self.no_ack = true,
+                [b'D', ..] => return Ok(()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let mut byte = [0];
+        loop {
+            return match self.stream.read(&mut byte) {
+                Ok(0) => Ok(None),
+                Ok(_) => Ok(Some(byte[0])),
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                Err(error) => Err(error.into()),
+            };
+        }
+    }
+
+    /// `$data#checksum`, acknowledged with `+` or `-`. Returns None once the client is gone.
+    fn read_packet(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            // Acks and interrupts between packets are skipped
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'$') => break,
+                    Some(_) => {}
+                }
+            }
+            let mut data = Vec::new();
+            let mut checksum = 0u8;
+            loop {
+                let Some(byte) = self.read_byte()? else {
+                    return Ok(None);
+                };
+                if byte == b'#' {
+                    break;
+                }
+                checksum = checksum.wrapping_add(byte);
+                data.push(byte);
+            }
+            let mut digits = [0; 2];
+            for digit in &mut digits {
+                let Some(byte) = self.read_byte()? else {
+                    return Ok(None);
+                };
+                *digit = byte;
+            }
+            let expected = std::str::from_utf8(&digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+            if self.no_ack {
+                return Ok(Some(data));
+            }
+            if expected == Some(checksum) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(data));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, reply: &str) -> Result<()> {
+        let checksum = reply.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        let packet = format!("${}#{:02x}", reply, checksum);
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                Some(b'-') => {}
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// The reply to a packet, None when the session is over
+    fn handle(&mut self, packet: &[u8]) -> Result<Option<String>> {
+        let packet = unescape(packet);
+        let Some((&command, args)) = packet.split_first() else {
+            return Ok(Some(String::new()));
+        };
+        let text = String::from_utf8_lossy(args);
+        let result = match command {
+            b'?' => Ok(format!("S{:02x}", SIGTRAP)),
+            b'g' => Ok(self.read_registers()),
+            b'G' => self.write_registers(&text),
+            b'p' => self.read_register(&text),
+            b'P' => self.write_register(&text),
+            b'm' => self.read_memory(&text),
+            b'M' => self.write_memory_hex(&text),
+            b'X' => self.write_memory_binary(args),
+            b's' => self.resume(&text, true),
+            b'c' => self.resume(&text, false),
+            b'Z' | b'z' => self.change_point(command == b'Z', &text),
+            b'H' | b'T' => Ok("OK".to_string()),
+            b'q' => Ok(self.query(&text)),
+            b'Q' if text == "StartNoAckMode" => Ok("OK".to_string()),
+            b'D' => Ok("OK".to_string()),
+            b'k' => return Ok(None),
+            _ => Ok(String::new()),
+        };
+        // Errors in a request are reported to the client, the session goes on
+        Ok(Some(result.unwrap_or_else(|_| "E01".to_string())))
+    }
+
+    fn query(&self, query: &str) -> String {
+        if let Some(annex) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            return read_annex(TARGET_XML, annex).unwrap_or_else(|_| "E00".to_string());
+        }
+        match query.split(':').next().unwrap_or_default() {
+            "Supported" => format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+",
+                PACKET_SIZE
+            ),
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    // a x y p sp pcl pch
+    fn read_registers(&self) -> String {
+        let cpu = &self.nes.cpu;
+        let registers = &cpu.registers;
+        let [pc_lo, pc_hi] = registers.pc.to_le_bytes();
+        let bytes = [
+            registers.a,
+            registers.x,
+            registers.y,
+            cpu.status_register_byte(true),
+            registers.sp,
+            pc_lo,
+            pc_hi,
+        ];
+        hex(&bytes)
+    }
+
+    fn write_registers(&mut self, text: &str) -> Result<String> {
+        let bytes = parse_hex_bytes(text)?;
+        let [a, x, y, p, sp, pc_lo, pc_hi] = bytes[..] else {
+            bail!("expected 7 register bytes, got {}", bytes.len());
+        };
+        let cpu = &mut self.nes.cpu;
+        cpu.registers.a = a;
+        cpu.registers.x = x;
+        cpu.registers.y = y;
+        cpu.set_status_register_from_byte(p);
+        cpu.registers.sp = sp;
+        cpu.registers.pc = u16::from_le_bytes([pc_lo, pc_hi]);
+        Ok("OK".to_string())
+    }
+
+    fn read_register(&self, text: &str) -> Result<String> {
+        let registers = parse_hex_bytes(&self.read_registers())?;
+        Ok(match usize::from_str_radix(text, 16)? {
+            number @ 0..=4 => hex(&registers[number..=number]),
+            5 => hex(&registers[5..]),
+            number => bail!("no register {}", number),
+        })
+    }
+
+    fn write_register(&mut self, text: &str) -> Result<String> {
+        let Some((number, value)) = text.split_once('=') else {
+            bail!("missing register value");
+        };
+        let mut registers = parse_hex_bytes(&self.read_registers())?;
+        let value = parse_hex_bytes(value)?;
+        let range = match usize::from_str_radix(number, 16)? {
+            number @ 0..=4 => number..number + 1,
+            5 => 5..7,
+            number => bail!("no register {}", number),
+        };
+        if value.len() != range.len() {
+            bail!("register {} takes {} bytes", number, range.len());
+        }
+        registers[range].copy_from_slice(&value);
+        self.write_registers(&hex(&registers))
+    }
+
+    fn read_memory(&self, text: &str) -> Result<String> {
+        let (address, length) = parse_address_length(text)?;
+        // Each byte is two hex digits in the reply
+        if length > PACKET_SIZE / 2 {
+            bail!("{} bytes don't fit in a reply", length);
+        }
+        let bytes: Vec<u8> = (0..length)
+            .map(|i| self.nes.cpu.peek(address.wrapping_add(i as u16)))
+            .collect();
+        Ok(hex(&bytes))
+    }
+
+    fn write_memory(&mut self, address: u16, bytes: &[u8]) -> Result<String> {
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.nes
+                .cpu
+                .mem_write(address.wrapping_add(i as u16), byte)?;
+        }
+        Ok("OK".to_string())
+    }
+
+    fn write_memory_hex(&mut self, text: &str) -> Result<String> {
+        let Some((target, data)) = text.split_once(':') else {
+            bail!("missing memory data");
+        };
+        let (address, length) = parse_address_length(target)?;
+        let bytes = parse_hex_bytes(data)?;
+        if bytes.len() != length {
+            bail!("expected {} bytes, got {}", length, bytes.len());
+        }
+        self.write_memory(address, &bytes)
+    }
+
+    fn write_memory_binary(&mut self, args: &[u8]) -> Result<String> {
+        let Some(colon) = args.iter().position(|&byte| byte == b':') else {
+            bail!("missing memory data");
+        };
+        let (address, length) = parse_address_length(&String::from_utf8_lossy(&args[..colon]))?;
+        let bytes = &args[colon + 1..];
+        if bytes.len() != length {
+            bail!("expected {} bytes, got {}", length, bytes.len());
+        }
+        self.write_memory(address, bytes)
+    }
+
+    /// `Z`/`z` type,addr,kind: 0 and 1 are breakpoints, 2 write, 3 read and 4 access watchpoints
+    fn change_point(&mut self, insert: bool, text: &str) -> Result<String> {
+        let mut fields = text.split(',');
+        let (Some(kind), Some(address), length) = (fields.next(), fields.next(), fields.next())
+        else {
+            bail!("malformed breakpoint packet");
+        };
+        let address = u16::from_str_radix(address, 16)?;
+        let length = u16::from_str_radix(length.unwrap_or("1"), 16)?.max(1);
+        let end = address.saturating_add(length - 1);
+        let debugger = &mut self.nes.cpu.debugger;
+        let watch_kind = match kind {
+            "0" | "1" => {
+                if insert {
+                    debugger.add_breakpoint(address);
+                } else if let Some(breakpoint) =
+                    debugger.breakpoints.iter().find(|b| b.address == address)
+                {
+                    debugger.remove(breakpoint.id);
+                }
+                return Ok("OK".to_string());
+            }
+            "2" => WatchKind::WRITE,
+            "3" => WatchKind::READ,
+            "4" => WatchKind::READ | WatchKind::WRITE,
+            _ => return Ok(String::new()),
+        };
+        if insert {
+            debugger.add_watchpoint(address, end, watch_kind);
+        } else if let Some(watchpoint) = debugger
+            .watchpoints
+            .iter()
+            .find(|w| w.start == address && w.end == end && w.kind == watch_kind)
+        {
+            debugger.remove(watchpoint.id);
+        }
+        Ok("OK".to_string())
+    }
+
+    /// `s [addr]` and `c [addr]`, answered with a stop reply once the CPU stops
+    fn resume(&mut self, text: &str, single_step: bool) -> Result<String> {
+        if !text.is_empty() {
+            self.nes.cpu.registers.pc = u16::from_str_radix(text, 16)?;
+        }
+        let mut steps = 0usize;
+        let stop = loop {
+            let stop = match self.nes.debug_step() {
+                Ok(stop) => stop,
+                Err(_) => return Ok(format!("S{:02x}", SIGILL)),
+            };
+            if stop != DebugStop::Done || single_step {
+                break stop;
+            }
+            steps += 1;
+            if steps.is_multiple_of(INTERRUPT_POLL_INTERVAL) && self.interrupted()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        };
+        Ok(self.stop_reply(stop))
+    }
+
+    /// Whether the client sent a break (0x03) while the CPU was running
+    fn interrupted(&mut self) -> Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(1) => Ok(byte[0] == 0x03),
+            Ok(_) => Ok(false),
+            Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    fn stop_reply(&self, stop: DebugStop) -> String {
+        let reason = match stop {
+            DebugStop::Break(BreakReason::Breakpoint { .. }) => "swbreak:;".to_string(),
+            DebugStop::Break(BreakReason::Access { id, cycle }) => {
+                let address = match cycle.access {
+                    BusAccess::Read(address) | BusAccess::Write(address) => address,
+                };
+                let watchpoint = self
+                    .nes
+                    .cpu
+                    .debugger
+                    .watchpoints
+                    .iter()
+                    .find(|w| w.id == id);
+                let kind = match watchpoint.map(|w| w.kind) {
+                    Some(kind) if kind.contains(WatchKind::READ | WatchKind::WRITE) => "awatch",
+                    _ if matches!(cycle.access, BusAccess::Read(_)) => "rwatch",
+                    _ => "watch",
+                };
+                format!("{}:{:x};", kind, address)
+            }
+            DebugStop::Done | DebugStop::Break(BreakReason::Execute { .. }) | DebugStop::Halted => {
+                String::new()
+            }
+        };
+        format!("T{:02x}{}", SIGTRAP, reason)
+    }
+}
+
+/// `offset,length` of a `qXfer` read, answered with `m` when more follows and `l` at the end
+fn read_annex(document: &str, annex: &str) -> Result<String> {
+    let Some((offset, length)) = annex.split_once(',') else {
+        bail!("malformed qXfer read");
+    };
+    let offset = usize::from_str_radix(offset, 16)?.min(document.len());
+    let length = usize::from_str_radix(length, 16)?;
+    let end = (offset + length).min(document.len());
+    let marker = if end < document.len() { 'm' } else { 'l' };
+    Ok(format!("{}{}", marker, &document[offset..end]))
+}
+
+fn parse_address_length(text: &str) -> Result<(u16, usize)> {
+    let Some((address, length)) = text.split_once(',') else {
+        bail!("missing length");
+    };
+    // Addresses past the CPU bus wrap around to it
+    let address = u32::from_str_radix(address, 16)? as u16;
+    Ok((address, usize::from_str_radix(length, 16)?))
+}
+
+fn parse_hex_bytes(text: &str) -> Result<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        bail!("odd number of hex digits");
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&text[i..i + 2], 16)?))
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unescape(packet: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(packet.len());
+    let mut bytes = packet.iter();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'}' => data.push(bytes.next().copied().unwrap_or_default() ^ 0x20),
+            _ => data.push(byte),
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::test_util::{nes_with_source, STORE_LOOP_SOURCE};
+
+    /// Serve STORE_LOOP_SOURCE to a client that sends the packets and detaches
+    fn session(requests: &[&str]) -> Vec<String> {
+        let mut nes = nes_with_source(STORE_LOOP_SOURCE);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut requests: Vec<Vec<u8>> = requests.iter().map(|r| r.as_bytes().to_vec()).collect();
+        requests.push(b"D".to_vec());
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let mut ack = true;
+            let mut replies = Vec::new();
+            for data in requests {
+                let checksum = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+                let mut packet = vec![b'$'];
+                packet.extend_from_slice(&data);
+                packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+                stream.write_all(&packet).unwrap();
+                let mut reply = Vec::new();
+                let mut byte = [0];
+                loop {
+                    stream.read_exact(&mut byte).unwrap();
+                    if byte[0] == b'#' {
+                        break;
+                    }
+                    reply.push(byte[0]);
+                }
+                let mut checksum = [0; 2];
+                stream.read_exact(&mut checksum).unwrap();
+                if ack {
+                    stream.write_all(b"+").unwrap();
+                }
+                if data == b"QStartNoAckMode" {
+                    ack = false;
+                }
+                // Acknowledged replies start with the + for the request
+                let reply = String::from_utf8(reply).unwrap();
+                replies.push(
+                    reply
+                        .trim_start_matches('+')
+                        .trim_start_matches('$')
+                        .to_string(),
+                );
+            }
+            replies
+        });
+        let (stream, _) = listener.accept().unwrap();
+        GdbServer::new(&mut nes, stream).serve().unwrap();
+        let mut replies = client.join().unwrap();
+        assert_eq!(replies.pop().as_deref(), Some("OK"));
+        replies
+    }
+
+    #[test]
+    fn test_handshake() {
+        let replies = session(&[
+            "qSupported:swbreak+",
+            "QStartNoAckMode",
+            "qXfer:features:read:target.xml:0,10",
+            "?",
+        ]);
+        assert_eq!(
+            replies,
+            vec![
+                "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+",
+                "OK",
+                "m<?xml version=\"1",
+                "S05",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_registers() {
+        let replies = session(&["g", "p1", "P1=07", "g"]);
+        assert_eq!(
+            replies,
+            vec!["00000024fd0080", "00", "OK", "00070024fd0080"]
+        );
+    }
+
+    #[test]
+    fn test_memory() {
+        // X takes binary data with } escapes
+        let replies = session(&[
+            "m8000,3",
+            "M0300,2:4142",
+            "m0300,2",
+            "X0310,2:}]A",
+            "m0310,2",
+        ]);
+        assert_eq!(replies, vec!["a20020", "OK", "4142", "OK", "7d41"]);
+    }
+
+    #[test]
+    fn test_memory_read_fits_packet() {
+        let replies = session(&["m0,800", "m0,801", "m0,ffffffffffff"]);
+        assert_eq!(replies[0].len(), PACKET_SIZE);
+        assert_eq!(replies[1..], ["E01", "E01"]);
+    }
+
+    #[test]
+    fn test_breakpoints_and_watchpoints() {
+        let replies = session(&["Z0,8005,1", "c", "z0,8005,1", "Z2,300,1", "c", "p1", "s"]);
+        assert_eq!(
+            replies,
+            vec![
+                "OK",
+                "T05swbreak:;",
+                "OK",
+                "OK",
+                "T05watch:300;",
+                "01",
+                "T05"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_watchpoint_ignores_stores() {
+        // Only the store touches $0300, so the session stops at the BRK instead
+        let replies = session(&["Z3,300,1", "c", "p1"]);
+        assert_eq!(replies, vec!["OK", "T05", "03"]);
+    }
+}
@@ -0,0 +1,207 @@
+use std::any::Any;
+
+use anyhow::Result;
+use bitflags::bitflags;
+
+use crate::input::InputDevice;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+bitflags! {
+
+   // Report order of the shift register, A is read first
+   // 7  bit  0
+   // ---- ----
+   // RLDU TSBA
+   #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+   pub struct Buttons: u8 {
+       const A      = 0b00000001;
+       const B      = 0b00000010;
+       const SELECT = 0b00000100;
+       const START  = 0b00001000;
+       const UP     = 0b00010000;
+       const DOWN   = 0b00100000;
+       const LEFT   = 0b01000000;
+       const RIGHT  = 0b10000000;
+   }
+}
+
+impl Buttons {
+    /// A d-pad can't press opposing directions, but a keyboard can, and some games
+    /// glitch out when they see both. Pressing both cancels them out.
+    #[must_use]
+    pub fn without_opposing_directions(self) -> Self {
+        let mut buttons = self;
+        if buttons.contains(Self::LEFT | Self::RIGHT) {
+            buttons.remove(Self::LEFT | Self::RIGHT);
+        }
+        if buttons.contains(Self::UP | Self::DOWN) {
+            buttons.remove(Self::UP | Self::DOWN);
+        }
+        buttons
+    }
+}
+
+/// The standard NES controller: a 4021 shift register latched by the strobe bit.
+/// While the strobe is high, the register is continuously reloaded and reads return A.
+/// reference: https://www.nesdev.org/wiki/Standard_controller
+#[derive(Debug, Clone, Default)]
+pub struct Controller {
+    pub buttons: Buttons,
+    pub strobe: bool,
+    pub shift_register: u8,
+}
+
+impl InputDevice for Controller {
+    fn write(&mut self, value: u8) {
+        self.strobe = value & 1 == 1;
+        if self.strobe {
+            self.shift_register = self.buttons.bits();
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        if self.strobe {
+            self.shift_register = self.buttons.bits();
+            return self.shift_register & 1;
+        }
+        let bit = self.shift_register & 1;
+        // Official controllers report 1 after all eight buttons have been read
+        self.shift_register = (self.shift_register >> 1) | 0x80;
+        bit
+    }
+
+    fn peek(&self) -> u8 {
+        if self.strobe {
+            self.buttons.bits() & 1
+        } else {
+            self.shift_register & 1
+        }
+    }
+
+    fn set_buttons(&mut self, buttons: Buttons) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.shift_register = buttons.bits();
+        }
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Savestate for Controller {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.buttons.bits());
+        state.write_bool(self.strobe);
+        state.write_u8(self.shift_register);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.buttons = Buttons::from_bits_retain(state.read_u8()?);
+        self.strobe = state.read_bool()?;
+        self.shift_register = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Cpu6502, TestHarness};
+    use crate::mem::Mem;
+
+    fn strobe(cpu: &mut Cpu6502) {
+        cpu.mem_write(0x4016, 0x01).unwrap();
+        cpu.mem_write(0x4016, 0x00).unwrap();
+    }
+
+    #[test]
+    fn test_shift_register() {
+        let mut harness = TestHarness::new().with_program(
+            0xc000,
+            &[
+                0xa9, 0x01, // LDA #$01
+                0x8d, 0x16, 0x40, // STA $4016
+                0xa9, 0x00, // LDA #$00
+                0x8d, 0x16, 0x40, // STA $4016
+                0xad, 0x16, 0x40, // LDA $4016
+            ],
+        );
+        let cpu = &mut harness.cpu;
+        cpu.input
+            .set_buttons(0, Buttons::A | Buttons::START | Buttons::RIGHT);
+        harness.run(5).unwrap();
+        // The high byte of the operand is still on the bus for the upper bits
+        assert_eq!(harness.cpu.registers.a, 0x41);
+
+        // B, Select, Start, Up, Down, Left, Right, then 1s once the register is empty
+        let cpu = &mut harness.cpu;
+        let bits: Vec<u8> = (0..9).map(|_| cpu.mem_read(0x4016).unwrap() & 1).collect();
+        assert_eq!(bits, vec![0, 0, 1, 0, 0, 0, 1, 1, 1]);
+        // Port 2 has nothing pressed
+        assert_eq!(cpu.mem_read(0x4017).unwrap() & 1, 0);
+    }
+
+    #[test]
+    fn test_strobe_high_reads_a() {
+        let mut cpu = Cpu6502::default();
+        cpu.input.set_buttons(0, Buttons::A);
+        cpu.mem_write(0x4016, 0x01).unwrap();
+        assert_eq!(cpu.mem_read(0x4016).unwrap() & 1, 1);
+        assert_eq!(cpu.mem_read(0x4016).unwrap() & 1, 1);
+
+        // Reads follow the live A button, and the buttons held when the strobe drops stay latched
+        cpu.input.set_buttons(0, Buttons::B);
+        assert_eq!(cpu.mem_read(0x4016).unwrap() & 1, 0);
+        cpu.mem_write(0x4016, 0x00).unwrap();
+        cpu.input.set_buttons(0, Buttons::A);
+        let bits: Vec<u8> = (0..2).map(|_| cpu.mem_read(0x4016).unwrap() & 1).collect();
+        assert_eq!(bits, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_opposing_directions() {
+        let mut cpu = Cpu6502::default();
+        cpu.input.prevent_opposing_directions = true;
+        cpu.input
+            .set_buttons(0, Buttons::LEFT | Buttons::RIGHT | Buttons::UP);
+        assert_eq!(
+            cpu.input.device_mut::<Controller>(0).unwrap().buttons,
+            Buttons::UP
+        );
+    }
+
+    #[test]
+    fn test_store_to_port_2() {
+        // $4017 writes go to the frame counter, the store doesn't read port 2 first
+        let mut harness = TestHarness::new().with_program(
+            0xc000,
+            &[
+                0xa9, 0x00, // LDA #$00
+                0x8d, 0x17, 0x40, // STA $4017
+            ],
+        );
+        harness.cpu.input.set_buttons(1, Buttons::A);
+        strobe(&mut harness.cpu);
+        harness.run(2).unwrap();
+        assert_eq!(harness.cpu.mem_read(0x4017).unwrap() & 1, 1);
+    }
+
+    #[test]
+    fn test_dmc_dma_drops_bit() {
+        let mut cpu = Cpu6502::default();
+        // A and Select pressed: 1, 0, 1, ...
+        cpu.input.set_buttons(0, Buttons::A | Buttons::SELECT);
+        strobe(&mut cpu);
+
+        cpu.mem_write(0x4013, 0x00).unwrap(); // 1 byte sample
+        cpu.mem_write(0x4015, 0x10).unwrap();
+        assert_eq!(cpu.mem_read(0x4016).unwrap() & 1, 1);
+
+        // The DMA halts the CPU on the $4016 read, which repeats and clocks out B
+        cpu.tick(1).unwrap();
+        assert!(cpu.apu.dmc.sample_buffer.is_some());
+        assert_eq!(cpu.mem_read(0x4016).unwrap() & 1, 1);
+    }
+}
@@ -0,0 +1,149 @@
+use std::any::Any;
+
+use anyhow::{bail, Result};
+
+use crate::input::InputDevice;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+pub const KEYBOARD_ROWS: usize = 9;
+pub const KEYBOARD_COLUMNS: usize = 2;
+/// Keys in each column of a row
+pub const KEYS_PER_COLUMN: u8 = 4;
+
+/// The Family BASIC keyboard on the Famicom expansion port.
+/// Writes to $4016 walk through a 9 row by 2 column matrix, and $4017 reports
+/// the 4 keys of the selected half row on D1-D4, 0 meaning pressed.
+/// reference: https://www.nesdev.org/wiki/Family_BASIC_Keyboard
+#[derive(Debug, Clone, Default)]
+pub struct FamilyBasicKeyboard {
+    /// Pressed keys, 4 bits for each row and column
+    pub matrix: [[u8; KEYBOARD_COLUMNS]; KEYBOARD_ROWS],
+    pub row: usize,
+    pub column: usize,
+    pub enabled: bool,
+}
+
+impl FamilyBasicKeyboard {
+    /// Press or release the key at `bit` (0-3) of a row (0-8) and column (0-1) of the matrix
+    pub fn set_key(&mut self, row: usize, column: usize, bit: u8, pressed: bool) -> Result<()> {
+        if row >= KEYBOARD_ROWS || column >= KEYBOARD_COLUMNS || bit >= KEYS_PER_COLUMN {
+            bail!(
+                "keyboard key {}/{}/{} doesn't exist, rows are 0-{}, columns 0-{} and bits 0-{}",
+                row,
+                column,
+                bit,
+                KEYBOARD_ROWS - 1,
+                KEYBOARD_COLUMNS - 1,
+                KEYS_PER_COLUMN - 1
+            );
+        }
+        let mask = 1 << bit;
+        if pressed {
+            self.matrix[row][column] |= mask;
+        } else {
+            self.matrix[row][column] &= !mask;
+        }
+        Ok(())
+    }
+}
+
+impl InputDevice for FamilyBasicKeyboard {
+    // 7  bit  0
+    // ---- ----
+    // xxxx xKCR
+    //       |||
+    //       ||+- Reset to row 0
+    //       |+-- Column select, leaving column 1 advances to the next row
+    //       +--- Enable the keyboard matrix
+    fn write(&mut self, value: u8) {
+        self.enabled = value & 0x04 != 0;
+        let column = ((value >> 1) & 1) as usize;
+        if self.enabled && self.column == 1 && column == 0 {
+            self.row += 1;
+        }
+        self.column = column;
+        if value & 1 == 1 {
+            self.row = 0;
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        self.peek()
+    }
+
+    fn peek(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        match self.matrix.get(self.row) {
+            Some(row) => !(row[self.column] << 1) & 0x1e,
+            None => 0x1e,
+        }
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Savestate for FamilyBasicKeyboard {
+    fn save_state(&self, state: &mut StateWriter) {
+        for row in &self.matrix {
+            state.write_bytes(row);
+        }
+        state.write_u8(self.row as u8);
+        state.write_u8(self.column as u8);
+        state.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        for row in self.matrix.iter_mut() {
+            state.read_bytes(row)?;
+        }
+        self.row = state.read_u8()? as usize;
+        self.column = state.read_u8()? as usize;
+        if self.column >= KEYBOARD_COLUMNS {
+            bail!("keyboard column {} doesn't exist", self.column);
+        }
+        self.enabled = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu6502;
+    use crate::input::InputDeviceKind;
+    use crate::mem::Mem;
+
+    #[test]
+    fn test_matrix_scan() {
+        let mut cpu = Cpu6502::default();
+        cpu.input.connect(InputDeviceKind::FamilyBasicKeyboard);
+        let keyboard = cpu.input.expansion_mut::<FamilyBasicKeyboard>().unwrap();
+        keyboard.set_key(0, 1, 2, true).unwrap();
+        keyboard.set_key(1, 0, 0, true).unwrap();
+
+        // Reset to row 0, then walk columns 0, 1 of row 0 and column 0 of row 1
+        cpu.mem_write(0x4016, 0x05).unwrap();
+        assert_eq!(cpu.mem_read(0x4017).unwrap() & 0x1e, 0x1e);
+        cpu.mem_write(0x4016, 0x06).unwrap();
+        assert_eq!(cpu.mem_read(0x4017).unwrap() & 0x1e, 0x16);
+        cpu.mem_write(0x4016, 0x04).unwrap();
+        assert_eq!(cpu.mem_read(0x4017).unwrap() & 0x1e, 0x1c);
+        // Disabled, the matrix doesn't drive the bus
+        cpu.mem_write(0x4016, 0x00).unwrap();
+        assert_eq!(cpu.mem_read(0x4017).unwrap() & 0x1e, 0x00);
+    }
+
+    #[test]
+    fn test_keys_outside_matrix() {
+        let mut keyboard = FamilyBasicKeyboard::default();
+        for (row, column, bit) in [(9, 0, 0), (0, 2, 0), (0, 0, 4), (0, 0, 8)] {
+            assert!(keyboard.set_key(row, column, bit, true).is_err());
+        }
+        keyboard.set_key(8, 1, 3, true).unwrap();
+        assert_eq!(keyboard.matrix[8][1], 0x08);
+    }
+}
@@ -0,0 +1,310 @@
+mod controller;
+mod keyboard;
+mod multitap;
+mod paddle;
+mod power_pad;
+mod zapper;
+
+pub use controller::*;
+pub use keyboard::*;
+pub use multitap::*;
+pub use paddle::*;
+pub use power_pad::*;
+pub use zapper::*;
+
+use std::any::Any;
+use std::fmt::Debug;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Error, Result};
+
+use crate::ppu::Ppu;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// A device plugged into one of the controller ports.
+/// Every port sees the value written to $4016 and is read serially through
+/// $4016 (port 1) or $4017 (port 2).
+/// reference: https://www.nesdev.org/wiki/Input_devices
+pub trait InputDevice: Debug + Savestate {
+    /// $4016 write: the low bit is the strobe/latch shared by both ports
+    fn write(&mut self, value: u8);
+    /// $4016/$4017 read: the device drives the low bits (D0-D4), the rest is open bus
+    fn read(&mut self) -> u8;
+    /// The value the next read would return, without clocking the device
+    fn peek(&self) -> u8;
+    /// Update the pressed buttons, for devices that have them
+    fn set_buttons(&mut self, _buttons: Buttons) {}
+    /// Look at the picture at the current beam position, for light guns
+    fn sense_light(&mut self, _ppu: &Ppu) {}
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+/// Nothing plugged in, every read returns 0
+#[derive(Debug, Clone, Default)]
+pub struct Unplugged;
+
+impl InputDevice for Unplugged {
+    fn write(&mut self, _value: u8) {}
+
+    fn read(&mut self) -> u8 {
+        0
+    }
+
+    fn peek(&self) -> u8 {
+        0
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// The input setups a session can be started with
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InputDeviceKind {
+    Controllers,
+    FourScore,
+    Hori,
+    Zapper,
+    ArkanoidPaddle,
+    PowerPad,
+    FamilyBasicKeyboard,
+}
+
+impl InputDeviceKind {
+    /// Map the NES 2.0 default expansion device field (header byte 15)
+    /// reference: https://www.nesdev.org/wiki/NES_2.0#Default_Expansion_Device
+    #[must_use]
+    pub fn from_expansion_device(device: u8) -> Option<Self> {
+        match device {
+            0x01 => Some(InputDeviceKind::Controllers),
+            0x02 => Some(InputDeviceKind::FourScore),
+            0x03 => Some(InputDeviceKind::Hori),
+            0x08 => Some(InputDeviceKind::Zapper),
+            0x0b | 0x0c => Some(InputDeviceKind::PowerPad),
+            0x0f => Some(InputDeviceKind::ArkanoidPaddle),
+            0x23 => Some(InputDeviceKind::FamilyBasicKeyboard),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for InputDeviceKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "controllers" | "standard" => Ok(InputDeviceKind::Controllers),
+            "fourscore" | "four-score" => Ok(InputDeviceKind::FourScore),
+            "hori" => Ok(InputDeviceKind::Hori),
+            "zapper" => Ok(InputDeviceKind::Zapper),
+            "arkanoid" | "paddle" => Ok(InputDeviceKind::ArkanoidPaddle),
+            "powerpad" | "power-pad" => Ok(InputDeviceKind::PowerPad),
+            "keyboard" => Ok(InputDeviceKind::FamilyBasicKeyboard),
+            _ => Err(anyhow!("unknown input device '{}'", s)),
+        }
+    }
+}
+
+/// The two controller ports and the Famicom expansion port
+#[derive(Debug)]
+pub struct InputPorts {
+    pub ports: [Box<dyn InputDevice>; 2],
+    /// Expansion port device, sees $4016 writes and shares $4017 reads with port 2
+    pub expansion: Box<dyn InputDevice>,
+    /// A four player adapter in front of both ports, hiding the plugged in devices
+    pub multitap: Option<Multitap>,
+    /// Cancel out Left+Right and Up+Down when buttons are set, on by default since games
+    /// can glitch on presses a real d-pad can't make
+    pub prevent_opposing_directions: bool,
+}
+
+impl Default for InputPorts {
+    fn default() -> Self {
+        Self {
+            ports: [
+                Box::new(Controller::default()),
+                Box::new(Controller::default()),
+            ],
+            expansion: Box::new(Unplugged),
+            multitap: None,
+            prevent_opposing_directions: true,
+        }
+    }
+}
+
+impl InputPorts {
+    pub fn plug(&mut self, port: usize, device: Box<dyn InputDevice>) -> Result<()> {
+        let Some(slot) = self.ports.get_mut(port) else {
+            bail!("there is no port {}, only 0 and 1", port);
+        };
+        *slot = device;
+        Ok(())
+    }
+
+    /// Replace every connected device with the given setup
+    pub fn connect(&mut self, kind: InputDeviceKind) {
+        *self = Self {
+            prevent_opposing_directions: self.prevent_opposing_directions,
+            ..Self::default()
+        };
+        match kind {
+            InputDeviceKind::Controllers => {}
+            InputDeviceKind::FourScore => self.set_multitap(Some(MultitapKind::FourScore)),
+            InputDeviceKind::Hori => self.set_multitap(Some(MultitapKind::Hori)),
+            InputDeviceKind::Zapper => self.ports[1] = Box::new(Zapper::default()),
+            InputDeviceKind::ArkanoidPaddle => self.ports[1] = Box::new(ArkanoidPaddle::default()),
+            InputDeviceKind::PowerPad => self.ports[1] = Box::new(PowerPad::default()),
+            InputDeviceKind::FamilyBasicKeyboard => {
+                self.expansion = Box::new(FamilyBasicKeyboard::default())
+            }
+        }
+    }
+
+    /// Typed access to the device in a port
+    pub fn device_mut<T: InputDevice + 'static>(&mut self, port: usize) -> Option<&mut T> {
+        self.ports.get_mut(port)?.as_any_mut().downcast_mut::<T>()
+    }
+
+    /// Typed access to the device on the expansion port
+    pub fn expansion_mut<T: InputDevice + 'static>(&mut self) -> Option<&mut T> {
+        self.expansion.as_any_mut().downcast_mut::<T>()
+    }
+
+    pub fn set_multitap(&mut self, kind: Option<MultitapKind>) {
+        self.multitap = kind.map(Multitap::new);
+    }
+
+    /// $4016 write
+    pub fn write(&mut self, value: u8) {
+        if let Some(multitap) = self.multitap.as_mut() {
+            return multitap.write(value);
+        }
+        for device in self.ports.iter_mut() {
+            device.write(value);
+        }
+        self.expansion.write(value);
+    }
+
+    /// $4016 (port 0) or $4017 (port 1) read, low 5 bits only
+    pub fn read(&mut self, port: usize) -> u8 {
+        if let Some(multitap) = self.multitap.as_mut() {
+            return multitap.read(port);
+        }
+        let mut value = self.ports[port].read();
+        if port == 1 {
+            value |= self.expansion.read();
+        }
+        value & 0x1f
+    }
+
+    pub fn peek(&self, port: usize) -> u8 {
+        if let Some(multitap) = self.multitap.as_ref() {
+            return multitap.peek(port);
+        }
+        let mut value = self.ports[port].peek();
+        if port == 1 {
+            value |= self.expansion.peek();
+        }
+        value & 0x1f
+    }
+
+    pub fn sense_light(&mut self, ppu: &Ppu) {
+        for device in self.ports.iter_mut() {
+            device.sense_light(ppu);
+        }
+    }
+
+    /// Set the buttons of player `port` (0-1, or 0-3 with a multitap)
+    pub fn set_buttons(&mut self, port: usize, buttons: Buttons) {
+        let buttons = if self.prevent_opposing_directions {
+            buttons.without_opposing_directions()
+        } else {
+            buttons
+        };
+        match self.multitap.as_mut() {
+            Some(multitap) => {
+                if let Some(player) = multitap.buttons.get_mut(port) {
+                    *player = buttons;
+                }
+            }
+            None => {
+                // Players 3 and 4 aren't connected without a multitap
+                if let Some(device) = self.ports.get_mut(port) {
+                    device.set_buttons(buttons);
+                }
+            }
+        }
+    }
+}
+
+impl Savestate for Unplugged {
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Savestate for InputPorts {
+    // Every device is stored in its own chunk, so loading into a different
+    // setup fails instead of reading another device's fields
+    fn save_state(&self, state: &mut StateWriter) {
+        for device in self.ports.iter().chain([&self.expansion]) {
+            let mut chunk = StateWriter::new();
+            device.save_state(&mut chunk);
+            state.write_chunk(&chunk.into_inner());
+        }
+        state.write_bool(self.multitap.is_some());
+        if let Some(multitap) = &self.multitap {
+            multitap.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        for device in self.ports.iter_mut().chain([&mut self.expansion]) {
+            let mut chunk = StateReader::new(state.read_chunk()?);
+            device.load_state(&mut chunk)?;
+            chunk.finish()?;
+        }
+        match (state.read_bool()?, self.multitap.as_mut()) {
+            (true, Some(multitap)) => multitap.load_state(state),
+            (false, None) => Ok(()),
+            _ => bail!("savestate was made with different input devices"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::nes::NesEmulator;
+    use crate::test_util::create_test_rom;
+
+    #[test]
+    fn test_default_expansion_device() {
+        // NES 2.0 header with the Arkanoid controller as the default device
+        let mut rom = create_test_rom(&[0xea]);
+        rom[7] |= 0x08;
+        rom[15] = 0x0f;
+        let mut nes = NesEmulator::default();
+        nes.insert_cartridge(Cartridge::from_bytes(&rom).unwrap())
+            .unwrap();
+        assert!(nes.cpu.input.device_mut::<ArkanoidPaddle>(1).is_some());
+
+        // The command line overrides it
+        nes.connect_input("zapper".parse().unwrap());
+        assert!(nes.cpu.input.device_mut::<Zapper>(1).is_some());
+    }
+
+    #[test]
+    fn test_ports() {
+        let mut input = InputPorts::default();
+        assert!(input.prevent_opposing_directions);
+        assert!(input.plug(2, Box::new(Unplugged)).is_err());
+        assert!(input.device_mut::<Controller>(2).is_none());
+        input.plug(0, Box::new(Zapper::default())).unwrap();
+        assert!(input.device_mut::<Zapper>(0).is_some());
+    }
+}
@@ -0,0 +1,202 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Error, Result};
+
+use crate::input::Buttons;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// Four player adapters. Both report 8 reads for the first controller on a port,
+/// 8 for the second one and an 8-bit signature identifying the adapter.
+/// reference: https://www.nesdev.org/wiki/Four_player_adapters
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MultitapKind {
+    /// NES Four Score / Satellite, reports on D0
+    FourScore,
+    /// Hori 4 Players Adapter for the Famicom expansion port, reports on D1
+    Hori,
+}
+
+impl MultitapKind {
+    /// The signature following the 16 button reads on $4016 and $4017, first read in bit 0.
+    /// The Four Score sets read 20 on $4016 and read 19 on $4017.
+    #[must_use]
+    pub fn signatures(&self) -> [u8; 2] {
+        match self {
+            MultitapKind::FourScore => [0x08, 0x04],
+            MultitapKind::Hori => [0x04, 0x08],
+        }
+    }
+
+    #[must_use]
+    fn data_bit(&self) -> u8 {
+        match self {
+            MultitapKind::FourScore => 0,
+            MultitapKind::Hori => 1,
+        }
+    }
+}
+
+impl FromStr for MultitapKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fourscore" | "four-score" => Ok(MultitapKind::FourScore),
+            "hori" => Ok(MultitapKind::Hori),
+            _ => Err(anyhow!("unknown multitap '{}'", s)),
+        }
+    }
+}
+
+/// A four player adapter taking over both controller ports.
+/// $4016 reads players 1 and 3, $4017 reads players 2 and 4.
+#[derive(Debug, Clone)]
+pub struct Multitap {
+    pub kind: MultitapKind,
+    pub buttons: [Buttons; 4],
+    pub strobe: bool,
+    pub shift_registers: [u32; 2],
+}
+
+impl Multitap {
+    pub fn new(kind: MultitapKind) -> Self {
+        Self {
+            kind,
+            buttons: [Buttons::empty(); 4],
+            strobe: false,
+            shift_registers: [0; 2],
+        }
+    }
+
+    fn reload(&mut self) {
+        let signatures = self.kind.signatures();
+        for (port, signature) in signatures.into_iter().enumerate() {
+            self.shift_registers[port] = self.buttons[port].bits() as u32
+                | (self.buttons[port + 2].bits() as u32) << 8
+                | (signature as u32) << 16;
+        }
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.strobe = value & 1 == 1;
+        if self.strobe {
+            self.reload();
+        }
+    }
+
+    pub fn read(&mut self, port: usize) -> u8 {
+        if self.strobe {
+            self.reload();
+        }
+        let bit = (self.shift_registers[port] & 1) as u8;
+        if !self.strobe {
+            // Like a single controller, 1s are reported once all 24 bits are read
+            self.shift_registers[port] = (self.shift_registers[port] >> 1) | 1 << 23;
+        }
+        bit << self.kind.data_bit()
+    }
+
+    #[must_use]
+    pub fn peek(&self, port: usize) -> u8 {
+        ((self.shift_registers[port] & 1) as u8) << self.kind.data_bit()
+    }
+}
+
+impl Savestate for Multitap {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.kind.data_bit());
+        for buttons in &self.buttons {
+            state.write_u8(buttons.bits());
+        }
+        state.write_bool(self.strobe);
+        for register in &self.shift_registers {
+            state.write_u32(*register);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        if state.read_u8()? != self.kind.data_bit() {
+            bail!("savestate was made with a different multitap");
+        }
+        for buttons in self.buttons.iter_mut() {
+            *buttons = Buttons::from_bits_retain(state.read_u8()?);
+        }
+        self.strobe = state.read_bool()?;
+        for register in self.shift_registers.iter_mut() {
+            *register = state.read_u32()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu6502;
+    use crate::mem::Mem;
+
+    fn strobe(cpu: &mut Cpu6502) {
+        cpu.mem_write(0x4016, 0x01).unwrap();
+        cpu.mem_write(0x4016, 0x00).unwrap();
+    }
+
+    /// 24 reads of D0, the first one in bit 0
+    fn read_bits(cpu: &mut Cpu6502, addr: u16) -> u32 {
+        (0..24).fold(0, |acc, i| {
+            acc | ((cpu.mem_read(addr).unwrap() & 1) as u32) << i
+        })
+    }
+
+    #[test]
+    fn test_four_score() {
+        let mut cpu = Cpu6502::default();
+        cpu.input.set_multitap(Some(MultitapKind::FourScore));
+        for (player, buttons) in [Buttons::A, Buttons::B, Buttons::START, Buttons::RIGHT]
+            .into_iter()
+            .enumerate()
+        {
+            cpu.input.set_buttons(player, buttons);
+        }
+        strobe(&mut cpu);
+        // Player 1, player 3, signature with read 20 set
+        assert_eq!(read_bits(&mut cpu, 0x4016), 0x08_08_01);
+        // Player 2, player 4, signature with read 19 set
+        assert_eq!(read_bits(&mut cpu, 0x4017), 0x04_80_02);
+        assert_eq!(cpu.mem_read(0x4016).unwrap() & 1, 1);
+    }
+
+    #[test]
+    fn test_hori() {
+        let mut cpu = Cpu6502::default();
+        cpu.input.set_multitap(Some(MultitapKind::Hori));
+        strobe(&mut cpu);
+        // D1 only, with the signatures of the Four Score swapped
+        let bits: Vec<u8> = (0..24)
+            .map(|_| cpu.mem_read(0x4016).unwrap() & 0x03)
+            .collect();
+        assert!(bits.iter().all(|&bit| bit & 1 == 0));
+        // Only read 19 of the signature is set
+        let signature: Vec<usize> = (16..24).filter(|&i| bits[i] == 0x02).collect();
+        assert_eq!(signature, vec![18]);
+    }
+
+    #[test]
+    fn test_only_four_players() {
+        let mut cpu = Cpu6502::default();
+        cpu.input.set_multitap(Some(MultitapKind::FourScore));
+        cpu.input.set_buttons(4, Buttons::A);
+        strobe(&mut cpu);
+        assert_eq!(read_bits(&mut cpu, 0x4016) & 0xffff, 0);
+        assert_eq!(read_bits(&mut cpu, 0x4017) & 0xffff, 0);
+    }
+
+    #[test]
+    fn test_parse_kind() {
+        assert_eq!(
+            "four-score".parse::<MultitapKind>().unwrap(),
+            MultitapKind::FourScore
+        );
+        assert_eq!("Hori".parse::<MultitapKind>().unwrap(), MultitapKind::Hori);
+        assert!("satellite".parse::<MultitapKind>().is_err());
+    }
+}
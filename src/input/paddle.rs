@@ -0,0 +1,113 @@
+use std::any::Any;
+
+use anyhow::Result;
+
+use crate::input::InputDevice;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// Smallest and largest potentiometer readings of the NES controller
+pub const PADDLE_MIN: u8 = 0x62;
+pub const PADDLE_MAX: u8 = 0xf2;
+
+/// The NES Arkanoid "Vaus" controller, usually plugged into port 2.
+/// The strobe latches the 8-bit potentiometer value, which is then read serially,
+/// most significant bit first and inverted, on D4. D3 is the fire button.
+/// reference: https://www.nesdev.org/wiki/Arkanoid_controller
+#[derive(Debug, Clone)]
+pub struct ArkanoidPaddle {
+    /// Knob position, PADDLE_MIN (left) to PADDLE_MAX (right)
+    pub position: u8,
+    pub button: bool,
+    pub strobe: bool,
+    pub shift_register: u8,
+}
+
+impl Default for ArkanoidPaddle {
+    fn default() -> Self {
+        Self {
+            position: PADDLE_MIN + (PADDLE_MAX - PADDLE_MIN) / 2,
+            button: false,
+            strobe: false,
+            shift_register: 0,
+        }
+    }
+}
+
+impl ArkanoidPaddle {
+    pub fn set_position(&mut self, position: u8) {
+        self.position = position.clamp(PADDLE_MIN, PADDLE_MAX);
+    }
+}
+
+impl InputDevice for ArkanoidPaddle {
+    fn write(&mut self, value: u8) {
+        self.strobe = value & 1 == 1;
+        if self.strobe {
+            self.shift_register = !self.position;
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        let value = self.peek();
+        if !self.strobe {
+            self.shift_register <<= 1;
+        }
+        value
+    }
+
+    fn peek(&self) -> u8 {
+        let button = if self.button { 0x08 } else { 0 };
+        let data = (self.shift_register >> 7) << 4;
+        button | data
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Savestate for ArkanoidPaddle {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.position);
+        state.write_bool(self.button);
+        state.write_bool(self.strobe);
+        state.write_u8(self.shift_register);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.position = state.read_u8()?;
+        self.button = state.read_bool()?;
+        self.strobe = state.read_bool()?;
+        self.shift_register = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu6502;
+    use crate::input::InputDeviceKind;
+    use crate::mem::Mem;
+
+    #[test]
+    fn test_position_and_button() {
+        let mut cpu = Cpu6502::default();
+        cpu.input.connect(InputDeviceKind::ArkanoidPaddle);
+        let paddle = cpu.input.device_mut::<ArkanoidPaddle>(1).unwrap();
+        paddle.set_position(0xa5);
+        paddle.button = true;
+        cpu.mem_write(0x4016, 0x01).unwrap();
+        cpu.mem_write(0x4016, 0x00).unwrap();
+
+        // The inverted position is shifted out MSB first on D4, the button is on D3
+        let reads: Vec<u8> = (0..8)
+            .map(|_| cpu.mem_read(0x4017).unwrap() & 0x18)
+            .collect();
+        assert!(reads.iter().all(|&value| value & 0x08 != 0));
+        let position = reads
+            .iter()
+            .fold(0u8, |acc, &value| acc << 1 | (value >> 4));
+        assert_eq!(!position, 0xa5);
+    }
+}
@@ -0,0 +1,133 @@
+use std::any::Any;
+
+use anyhow::{bail, Result};
+
+use crate::input::InputDevice;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+pub const POWER_PAD_BUTTONS: u8 = 12;
+/// Buttons reported on D3, in read order
+const D3_ORDER: [u8; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+/// Buttons reported on D4, in read order, followed by 1s
+const D4_ORDER: [u8; 4] = [4, 3, 12, 8];
+
+/// The Power Pad (Family Trainer) floor mat with 12 buttons, usually in port 2.
+/// Both halves of the button matrix are shifted out at the same time on D3 and D4.
+/// reference: https://www.nesdev.org/wiki/Power_Pad
+#[derive(Debug, Clone, Default)]
+pub struct PowerPad {
+    /// Bit n - 1 is set while button n (1-12) is pressed
+    pub pressed: u16,
+    pub strobe: bool,
+    pub shift_registers: [u8; 2],
+}
+
+impl PowerPad {
+    /// Press or release button 1-12
+    pub fn set_button(&mut self, button: u8, pressed: bool) -> Result<()> {
+        if !(1..=POWER_PAD_BUTTONS).contains(&button) {
+            bail!(
+                "Power Pad button {} doesn't exist, they are numbered 1-{}",
+                button,
+                POWER_PAD_BUTTONS
+            );
+        }
+        let mask = 1 << (button - 1);
+        if pressed {
+            self.pressed |= mask;
+        } else {
+            self.pressed &= !mask;
+        }
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn is_pressed(&self, button: u8) -> bool {
+        (1..=POWER_PAD_BUTTONS).contains(&button) && self.pressed & (1 << (button - 1)) != 0
+    }
+
+    fn latch(&mut self) {
+        let serialize = |order: &[u8]| {
+            order.iter().enumerate().fold(0u8, |bits, (i, &button)| {
+                bits | (self.is_pressed(button) as u8) << i
+            })
+        };
+        self.shift_registers = [serialize(&D3_ORDER), serialize(&D4_ORDER) | 0xf0];
+    }
+}
+
+impl InputDevice for PowerPad {
+    fn write(&mut self, value: u8) {
+        self.strobe = value & 1 == 1;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        let value = self.peek();
+        if !self.strobe {
+            for register in self.shift_registers.iter_mut() {
+                *register = (*register >> 1) | 0x80;
+            }
+        }
+        value
+    }
+
+    fn peek(&self) -> u8 {
+        ((self.shift_registers[0] & 1) << 3) | ((self.shift_registers[1] & 1) << 4)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Savestate for PowerPad {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.pressed);
+        state.write_bool(self.strobe);
+        state.write_bytes(&self.shift_registers);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.pressed = state.read_u16()?;
+        self.strobe = state.read_bool()?;
+        state.read_bytes(&mut self.shift_registers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu6502;
+    use crate::input::InputDeviceKind;
+    use crate::mem::Mem;
+
+    #[test]
+    fn test_matrix_order() {
+        let mut cpu = Cpu6502::default();
+        cpu.input.connect(InputDeviceKind::PowerPad);
+        let pad = cpu.input.device_mut::<PowerPad>(1).unwrap();
+        pad.set_button(1, true).unwrap();
+        pad.set_button(12, true).unwrap();
+        cpu.mem_write(0x4016, 0x01).unwrap();
+        cpu.mem_write(0x4016, 0x00).unwrap();
+
+        // D3 reads 2, 1, ... and D4 reads 4, 3, 12, 8, then 1s
+        let reads: Vec<u8> = (0..8)
+            .map(|_| cpu.mem_read(0x4017).unwrap() & 0x18)
+            .collect();
+        assert_eq!(reads, vec![0x00, 0x08, 0x10, 0x00, 0x10, 0x10, 0x10, 0x10]);
+    }
+
+    #[test]
+    fn test_button_range() {
+        let mut pad = PowerPad::default();
+        assert!(pad.set_button(0, true).is_err());
+        assert!(pad.set_button(13, true).is_err());
+        assert_eq!(pad.pressed, 0);
+        assert!(!pad.is_pressed(0));
+        assert!(!pad.is_pressed(16));
+    }
+}
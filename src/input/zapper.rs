@@ -0,0 +1,187 @@
+use std::any::Any;
+
+use anyhow::Result;
+
+use crate::input::InputDevice;
+use crate::ppu::{Ppu, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// Pixels around the aim point the photodiode can see
+const SENSOR_RADIUS: i32 = 2;
+/// Minimum brightness (0-255) that registers as light
+const LIGHT_THRESHOLD: u8 = 0x80;
+/// Number of scanlines the sensor keeps reporting light after the beam passed
+const LIGHT_PERSISTENCE: u16 = 20;
+
+/// The NES Zapper light gun, usually plugged into port 2.
+/// The photodiode only sees light for a short time after the beam drew a bright
+/// pixel under the aim point, which is how games find out where the gun points.
+///
+/// The sensor samples `Ppu::framebuffer`, which the PPU draws a dot at a time.
+/// reference: https://www.nesdev.org/wiki/Zapper
+#[derive(Debug, Clone, Default)]
+pub struct Zapper {
+    /// Screen coordinates the gun points at, None when aimed off screen
+    pub aim: Option<(u16, u16)>,
+    pub trigger: bool,
+    pub light_sensed: bool,
+}
+
+impl Zapper {
+    pub fn set_aim(&mut self, x: u16, y: u16) {
+        self.aim = if (x as usize) < SCREEN_WIDTH && (y as usize) < SCREEN_HEIGHT {
+            Some((x, y))
+        } else {
+            None
+        };
+    }
+
+    /// Whether the beam at its current position has recently lit a bright pixel under the aim point
+    fn detect_light(&self, ppu: &Ppu) -> bool {
+        let Some((aim_x, aim_y)) = self.aim else {
+            return false;
+        };
+        for dy in -SENSOR_RADIUS..=SENSOR_RADIUS {
+            for dx in -SENSOR_RADIUS..=SENSOR_RADIUS {
+                let (x, y) = (aim_x as i32 + dx, aim_y as i32 + dy);
+                if x < 0 || y < 0 || x >= SCREEN_WIDTH as i32 || y >= SCREEN_HEIGHT as i32 {
+                    continue;
+                }
+                let (x, y) = (x as u16, y as u16);
+                // Pixel x is output on dot x + 1
+                let drawn = ppu.scanline > y || (ppu.scanline == y && ppu.dot > x);
+                if drawn
+                    && ppu.scanline - y < LIGHT_PERSISTENCE
+                    && ppu.brightness(x as usize, y as usize) >= LIGHT_THRESHOLD
+                {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+impl InputDevice for Zapper {
+    fn write(&mut self, _value: u8) {}
+
+    // 7  bit  0
+    // ---- ----
+    // xxxT WxxS
+    //    | |  |
+    //    | |  +- Serial data (unused)
+    //    | +---- Light sensed (0: detected, 1: not detected)
+    //    +------ Trigger (0: released, 1: pulled)
+    fn read(&mut self) -> u8 {
+        self.peek()
+    }
+
+    fn peek(&self) -> u8 {
+        let light = if self.light_sensed { 0 } else { 0x08 };
+        let trigger = if self.trigger { 0x10 } else { 0 };
+        light | trigger
+    }
+
+    fn sense_light(&mut self, ppu: &Ppu) {
+        self.light_sensed = self.detect_light(ppu);
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Savestate for Zapper {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.aim.is_some());
+        let (x, y) = self.aim.unwrap_or_default();
+        state.write_u16(x);
+        state.write_u16(y);
+        state.write_bool(self.trigger);
+        state.write_bool(self.light_sensed);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        let aimed = state.read_bool()?;
+        let (x, y) = (state.read_u16()?, state.read_u16()?);
+        self.aim = aimed.then_some((x, y));
+        self.trigger = state.read_bool()?;
+        self.light_sensed = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nes::NesEmulator;
+    use crate::test_util::nes_with_white_tile;
+
+    /// A Zapper in port 2 and a white square at 96-111, 96-111
+    fn white_square() -> NesEmulator {
+        let mut nes = NesEmulator::default();
+        nes.connect_zapper();
+        for y in 96..112 {
+            for x in 96..112 {
+                let i = (y * 256 + x) * 3;
+                nes.cpu.ppu.framebuffer[i..i + 3].copy_from_slice(&[0xff, 0xff, 0xff]);
+            }
+        }
+        nes
+    }
+
+    /// Light and trigger bits seen with the beam at `scanline` and `dot`
+    fn read_at(nes: &mut NesEmulator, scanline: u16, dot: u16) -> u8 {
+        nes.cpu.ppu.scanline = scanline;
+        nes.cpu.ppu.dot = dot;
+        nes.cpu.input.sense_light(&nes.cpu.ppu);
+        nes.cpu.input.read(1) & 0x18
+    }
+
+    #[test]
+    fn test_light_follows_beam() {
+        let mut nes = white_square();
+        nes.set_zapper(100, 100, false).unwrap();
+        // The beam hasn't reached the target yet
+        assert_eq!(read_at(&mut nes, 50, 0), 0x08);
+        // Just drawn
+        assert_eq!(read_at(&mut nes, 100, 120), 0x00);
+        // The photodiode has decayed
+        assert_eq!(read_at(&mut nes, 200, 0), 0x08);
+    }
+
+    #[test]
+    fn test_dark_area_and_trigger() {
+        let mut nes = white_square();
+        nes.set_zapper(20, 20, true).unwrap();
+        assert_eq!(read_at(&mut nes, 30, 0), 0x18);
+    }
+
+    #[test]
+    fn test_aim_off_screen() {
+        let mut nes = white_square();
+        nes.set_zapper(300, 100, true).unwrap();
+        assert_eq!(nes.cpu.input.device_mut::<Zapper>(1).unwrap().aim, None);
+        assert_eq!(read_at(&mut nes, 100, 120), 0x18);
+        // Port 1 still has a controller
+        assert!(nes.cpu.input.device_mut::<Zapper>(0).is_none());
+    }
+
+    #[test]
+    fn test_light_from_rendered_picture() {
+        let mut nes = nes_with_white_tile();
+        nes.connect_zapper();
+        nes.set_zapper(100, 100, false).unwrap();
+        nes.run_frame().unwrap();
+        // Light is only seen after the beam drew the tile in this frame
+        let mut sensed = Vec::new();
+        for scanline in [50, 101, 200] {
+            while nes.cpu.ppu.scanline != scanline {
+                nes.step().unwrap();
+            }
+            nes.step().unwrap();
+            sensed.push(nes.cpu.input.device_mut::<Zapper>(1).unwrap().light_sensed);
+        }
+        assert_eq!(sensed, vec![false, true, false]);
+    }
+}
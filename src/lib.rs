@@ -0,0 +1,22 @@
+pub mod apu;
+pub mod audio;
+pub mod battery;
+pub mod cartridge;
+pub mod cli;
+pub mod constant;
+pub mod cpu;
+pub mod gdb;
+pub mod input;
+pub mod mem;
+pub mod movie;
+pub mod nes;
+pub mod ppu;
+pub mod region;
+pub mod repl;
+pub mod rewind;
+pub mod savestate;
+pub mod stack;
+pub mod test_rom;
+#[cfg(test)]
+mod test_util;
+pub mod util;
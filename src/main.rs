@@ -1,22 +1,205 @@
-mod cli;
-mod constant;
-mod cpu;
-mod mem;
-mod nes;
-mod ppu;
-mod stack;
-mod util;
-
-use crate::cpu::Cpu6502;
-
-fn main() {
-    let mut cpu = Cpu6502::default();
-    cpu.load_test_program(vec![0x00]).unwrap();
-    cpu.run().unwrap();
+use anyhow::{bail, Result};
+use structopt::StructOpt;
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use nes_emulator::{
+    cartridge::Cartridge,
+    cli::Cli,
+    cpu::{disassemble, run_single_step_tests, FunctionalTest, SymbolTable, TraceLog},
+    gdb::run_gdb_server,
+    movie::Movie,
+    nes::NesEmulator,
+    repl::DebuggerRepl,
+    rewind::DEFAULT_REWIND_BUDGET,
+    test_rom::{run_test_rom, run_test_rom_dir},
+};
+
+fn main() -> Result<()> {
+    let cli = Cli::from_args();
+    if cli.print_asm {
+        let data = fs::read(&cli.path)?;
+        let cartridge = Cartridge::from_bytes(&data).ok();
+        let lines = match &cartridge {
+            Some(cartridge) => cartridge.disassemble(),
+            None => disassemble(&data, cli.asm_origin),
+        };
+        let symbols = load_symbols(&cli.path, &cli.symbols)?;
+        let mut out = io::stdout().lock();
+        let mut offset = 0;
+        for line in lines {
+            let label = |address: u16| {
+                let prg_offset = cartridge
+                    .as_ref()
+                    .and_then(|cartridge| cartridge.prg_rom_offset(address, offset));
+                symbols.label(address, prg_offset)
+            };
+            if let Some(name) = label(line.address).filter(|name| !name.contains('+')) {
+                writeln!(out, "{}:", name)?;
+            }
+            writeln!(out, "{}", line.line_with(label))?;
+            offset += line.bytes.len();
+        }
+        return Ok(());
+    }
+
+    if cli.test_rom && cli.path.is_dir() {
+        let results = run_test_rom_dir(&cli.path, cli.frames)?;
+        let mut out = io::stdout().lock();
+        let mut passed = 0;
+        for (path, result) in &results {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            match result {
+                Ok(result) => {
+                    passed += result.passed() as usize;
+                    let line = match &result.reason {
+                        Some(reason) => reason.as_str(),
+                        None => result.text.lines().next().unwrap_or_default(),
+                    };
+                    writeln!(out, "{:<40} {:<10} {}", name, result.to_string(), line)?;
+                }
+                Err(error) => writeln!(out, "{:<40} {:<10} {:#}", name, "ERROR", error)?,
+            }
+        }
+        writeln!(out, "{}/{} passed", passed, results.len())?;
+        std::process::exit(if passed == results.len() { 0 } else { 1 });
+    }
+    if cli.test_rom {
+        let result = run_test_rom(&cli.path, cli.frames)?;
+        println!("{}", result.text.trim_end());
+        println!("{} after {} frames", result, result.frames);
+        if let Some(reason) = &result.reason {
+            println!("{}", reason);
+        }
+        std::process::exit(result.exit_code());
+    }
+    if cli.functional_test {
+        let test = FunctionalTest {
+            load_address: cli.load_address,
+            start: cli.start_pc,
+            success: cli.success_pc,
+            max_instructions: cli.max_instructions,
+        };
+        let result = test.run(&fs::read(&cli.path)?)?;
+        println!("{}", result);
+        if !result.passed() {
+            bail!("expected a trap at ${:04X}", cli.success_pc);
+        }
+        return Ok(());
+    }
+    if cli.single_step_tests {
+        let reports = run_single_step_tests(&cli.path, cli.check_cycles)?;
+        let mut out = io::stdout().lock();
+        for report in &reports {
+            writeln!(out, "{}", report)?;
+        }
+        let passed = reports.iter().filter(|report| report.failed == 0).count();
+        writeln!(out, "{}/{} opcodes pass", passed, reports.len())?;
+        return Ok(());
+    }
+
+    let mut nes = NesEmulator::default();
+    nes.cpu.debugger.verbose = cli.verbose;
+    nes.set_sample_rate(cli.sample_rate);
+    for channel in cli.mute {
+        nes.cpu.apu.mixer.set_muted(channel, true);
+    }
+    for channel in cli.solo {
+        nes.cpu.apu.mixer.set_soloed(channel, true);
+    }
+    nes.set_battery_saves(!cli.no_battery_save);
+    nes.load_rom(&cli.path)?;
+    nes.cpu.symbols = load_symbols(&cli.path, &cli.symbols)?;
+    if let Some(path) = &cli.trace {
+        nes.cpu.trace = Some(TraceLog::create(path)?);
+    }
+    if let Some(path) = &cli.nestest {
+        let golden = fs::read_to_string(path)?;
+        let result = nes.run_nestest(&golden);
+        if let Some(trace) = nes.cpu.trace.as_mut() {
+            trace.flush()?;
+        }
+        match result? {
+            Some(divergence) => bail!("{}", divergence),
+            None => println!("nestest matches all {} lines", golden.lines().count()),
+        }
+        return Ok(());
+    }
+    if let Some(kind) = cli.input {
+        nes.connect_input(kind);
+    }
+
+    if let Some(path) = &cli.load_state {
+        nes.load_state_file(path)?;
+    }
+    if cli.debug || cli.gdb.is_some() {
+        match cli.gdb {
+            Some(port) => run_gdb_server(&mut nes, port)?,
+            None => {
+                // Every frame, so `rewind` can step back one frame at a time
+                nes.enable_rewind(1, DEFAULT_REWIND_BUDGET);
+                DebuggerRepl::new(&mut nes).run(io::stdin().lock(), &mut io::stdout().lock())?
+            }
+        }
+        if let Some(trace) = nes.cpu.trace.as_mut() {
+            trace.flush()?;
+        }
+        return nes.flush_save();
+    }
+    if let Some(path) = &cli.play_movie {
+        nes.play_movie(Movie::load(path)?)?;
+    }
+    if cli.record_movie.is_some() {
+        nes.record_movie(true)?;
+    }
+
+    if let Some(path) = &cli.record_wav {
+        nes.start_recording(path, cli.stems)?;
+    }
+    if cli.play_movie.is_some() {
+        while nes.is_playing_movie() && nes.run_frame()? {}
+    } else {
+        for _ in 0..cli.frames {
+            if !nes.run_frame()? {
+                break;
+            }
+        }
+    }
+    nes.stop_recording()?;
+    nes.flush_save()?;
+    if let Some(trace) = nes.cpu.trace.as_mut() {
+        trace.flush()?;
+    }
+    if let Some(path) = &cli.save_state {
+        nes.save_state_file(path)?;
+    }
+
+    if let (Some(path), Some(mut movie)) = (&cli.record_movie, nes.stop_movie()) {
+        if let Some(name) = cli.path.file_stem() {
+            movie.rom_filename = name.to_string_lossy().into_owned();
+        }
+        movie.save(path)?;
+    }
+    Ok(())
+}
+
+/// The symbol files given with --symbols, or the ones next to the ROM
+fn load_symbols(rom: &Path, paths: &[PathBuf]) -> Result<SymbolTable> {
+    if paths.is_empty() {
+        return SymbolTable::discover(rom);
+    }
+    let mut symbols = SymbolTable::default();
+    for path in paths {
+        symbols.extend(SymbolTable::load(path)?);
+    }
+    Ok(symbols)
 }
 
+#[cfg(test)]
 mod tests {
-    use crate::{constant::ADDRESS_TEST_PROGRAM, cpu::Cpu6502, mem::Mem};
+    use nes_emulator::{constant::ADDRESS_TEST_PROGRAM, cpu::Cpu6502, mem::Mem};
 
     #[allow(unused)]
     fn create_test_cpu(program: Vec<u8>) -> Cpu6502 {
@@ -24,7 +207,7 @@ mod tests {
         let rom_address = ADDRESS_TEST_PROGRAM as usize;
         cpu.mapper[rom_address..(rom_address + program.len())].copy_from_slice(&program[..]);
         cpu.registers.pc = ADDRESS_TEST_PROGRAM;
-        return cpu;
+        cpu
     }
 
     #[test]
@@ -112,7 +295,7 @@ mod tests {
     fn test_bcc() {
         let mut cpu = self::create_test_cpu(vec![0x90, 0x09]);
         cpu.set_status_register_from_byte(0xf9);
-        assert_eq!(cpu.registers.carry, true);
+        assert!(cpu.registers.carry);
 
         let pc = cpu.registers.pc;
 
@@ -145,9 +328,10 @@ mod tests {
             0xf8, // SED
             0x08, // PHP
             0x68, // PLA
+            0x29, 0xef, // AND #$EF, PHP pushes the break flag
         ];
         let mut cpu = create_test_cpu(program);
-        cpu.bounded_run(9).unwrap();
+        cpu.bounded_run(10).unwrap();
         assert_eq!(cpu.registers.a, 111);
     }
 
@@ -160,7 +344,7 @@ mod tests {
         cpu.set_status_register_from_byte(0x24);
         cpu.bounded_run(2).unwrap();
         assert_eq!(cpu.registers.a, 0x2A);
-        assert_eq!(cpu.registers.carry, true);
+        assert!(cpu.registers.carry);
         assert_eq!(cpu.status_register_byte(true), 0x25);
     }
 
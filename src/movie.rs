@@ -0,0 +1,437 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bitflags::bitflags;
+
+use crate::input::Buttons;
+
+// reference: https://fceux.com/web/help/fm2.html
+
+pub const FM2_VERSION: u32 = 3;
+/// FCEUX version the written movies claim to come from
+pub const FM2_EMU_VERSION: u32 = 22020;
+/// Gamepad column letters, one per button from bit 7 down to bit 0
+const GAMEPAD_COLUMN: &[u8; 8] = b"RLDUTSBA";
+
+bitflags! {
+    /// The commands column of an input record
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct MovieCommand: u8 {
+        const SOFT_RESET = 0b00000001;
+        const POWER      = 0b00000010;
+        const FDS_INSERT = 0b00000100;
+        const FDS_SELECT = 0b00001000;
+        const VS_COIN    = 0b00010000;
+    }
+}
+
+/// The device recorded for a port, port0/port1 in the header
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PortDevice {
+    None,
+    Gamepad,
+    Zapper,
+}
+
+impl PortDevice {
+    fn from_id(id: u32) -> Result<Self> {
+        match id {
+            0 => Ok(PortDevice::None),
+            1 => Ok(PortDevice::Gamepad),
+            2 => Ok(PortDevice::Zapper),
+            _ => bail!("unsupported movie port device {}", id),
+        }
+    }
+
+    fn id(&self) -> u32 {
+        match self {
+            PortDevice::None => 0,
+            PortDevice::Gamepad => 1,
+            PortDevice::Zapper => 2,
+        }
+    }
+}
+
+/// The state of one port for one frame
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PortInput {
+    None,
+    Gamepad(Buttons),
+    Zapper { x: u16, y: u16, trigger: bool },
+}
+
+impl PortInput {
+    fn parse(device: PortDevice, column: &str) -> Result<Self> {
+        Ok(match device {
+            PortDevice::None => PortInput::None,
+            PortDevice::Gamepad => {
+                let column = column.as_bytes();
+                if column.len() != GAMEPAD_COLUMN.len() {
+                    bail!("gamepad column must be 8 characters wide");
+                }
+                let bits = column.iter().enumerate().fold(0u8, |bits, (i, &c)| {
+                    let pressed = c != b'.' && c != b' ';
+                    bits | (pressed as u8) << (7 - i)
+                });
+                PortInput::Gamepad(Buttons::from_bits_retain(bits))
+            }
+            PortDevice::Zapper => {
+                let values = column
+                    .split_whitespace()
+                    .map(|value| value.parse::<u16>())
+                    .collect::<Result<Vec<_>, _>>()?;
+                let [x, y, buttons, ..] = values[..] else {
+                    bail!("zapper column needs x, y and buttons");
+                };
+                PortInput::Zapper {
+                    x,
+                    y,
+                    trigger: buttons & 1 == 1,
+                }
+            }
+        })
+    }
+
+    fn write(&self, out: &mut String) {
+        match self {
+            PortInput::None => {}
+            PortInput::Gamepad(buttons) => {
+                for (i, &letter) in GAMEPAD_COLUMN.iter().enumerate() {
+                    let pressed = buttons.bits() & (1 << (7 - i)) != 0;
+                    out.push(if pressed { letter as char } else { '.' });
+                }
+            }
+            PortInput::Zapper { x, y, trigger } => {
+                let _ = write!(out, "{:3} {:3} {} 0 0", x, y, *trigger as u8);
+            }
+        }
+    }
+}
+
+/// One input record, applied at the start of a frame
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MovieFrame {
+    pub commands: MovieCommand,
+    /// Ports 1 and 2, or players 1-4 with a Four Score
+    pub ports: Vec<PortInput>,
+}
+
+/// An FCEUX text movie
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Movie {
+    pub emu_version: u32,
+    pub rerecord_count: u32,
+    pub pal: bool,
+    pub rom_filename: String,
+    /// MD5 of the ROM the movie was recorded with
+    pub rom_checksum: Option<[u8; 16]>,
+    pub guid: Option<String>,
+    pub fourscore: bool,
+    pub ports: [PortDevice; 2],
+    pub comments: Vec<String>,
+    pub subtitles: Vec<String>,
+    /// The movie starts from this savestate instead of power on
+    pub savestate: Option<Vec<u8>>,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Default for Movie {
+    fn default() -> Self {
+        Self {
+            emu_version: FM2_EMU_VERSION,
+            rerecord_count: 0,
+            pal: false,
+            rom_filename: String::new(),
+            rom_checksum: None,
+            guid: None,
+            fourscore: false,
+            ports: [PortDevice::Gamepad, PortDevice::Gamepad],
+            comments: Vec::new(),
+            subtitles: Vec::new(),
+            savestate: None,
+            frames: Vec::new(),
+        }
+    }
+}
+
+fn decode_base64(value: &str) -> Result<Vec<u8>> {
+    let Some(data) = value.strip_prefix("base64:") else {
+        bail!("only base64 encoded values are supported");
+    };
+    Ok(BASE64.decode(data)?)
+}
+
+impl Movie {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read movie {}", path.display()))?;
+        Self::parse(&text)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_fm2())?;
+        Ok(())
+    }
+
+    /// A fresh random-looking GUID for a new recording
+    #[must_use]
+    pub fn generate_guid() -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos())
+            .unwrap_or_default();
+        let hex = format!("{:032X}", md5::compute(nanos.to_le_bytes()));
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[0..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..32]
+        )
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut movie = Movie::default();
+        let mut version = None;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.starts_with('|') {
+                let frame = movie
+                    .parse_frame(line)
+                    .with_context(|| format!("line {}: bad input record", number + 1))?;
+                movie.frames.push(frame);
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            let flag = || value.trim() == "1";
+            let number = || {
+                value
+                    .trim()
+                    .parse::<u32>()
+                    .map_err(|_| anyhow!("line {}: {} is not a number", number + 1, key))
+            };
+            match key {
+                "version" => version = Some(number()?),
+                "emuVersion" => movie.emu_version = number()?,
+                "rerecordCount" => movie.rerecord_count = number()?,
+                "palFlag" => movie.pal = flag(),
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "romChecksum" => {
+                    let checksum = decode_base64(value)?;
+                    movie.rom_checksum = Some(
+                        checksum
+                            .try_into()
+                            .map_err(|_| anyhow!("romChecksum is not an MD5"))?,
+                    );
+                }
+                "guid" => movie.guid = Some(value.to_string()),
+                "fourscore" => movie.fourscore = flag(),
+                "port0" => movie.ports[0] = PortDevice::from_id(number()?)?,
+                "port1" => movie.ports[1] = PortDevice::from_id(number()?)?,
+                "comment" => movie.comments.push(value.to_string()),
+                "subtitle" => movie.subtitles.push(value.to_string()),
+                "savestate" => movie.savestate = Some(decode_base64(value)?),
+                "binary" if flag() => bail!("binary movies are not supported"),
+                // port2, FDS, NewPPU, microphone, length...
+                _ => {}
+            }
+        }
+        match version {
+            Some(FM2_VERSION) => Ok(movie),
+            Some(version) => bail!("unsupported movie version {}", version),
+            None => bail!("not an fm2 movie"),
+        }
+    }
+
+    fn port_devices(&self) -> Vec<PortDevice> {
+        if self.fourscore {
+            vec![PortDevice::Gamepad; 4]
+        } else {
+            self.ports.to_vec()
+        }
+    }
+
+    // |c|port0|port1|port2|
+    // |c|port0|port1|port2|port3|port2| with a Four Score
+    fn parse_frame(&self, line: &str) -> Result<MovieFrame> {
+        let mut columns = line.split('|').skip(1);
+        let commands = columns.next().unwrap_or("0").trim();
+        let commands = MovieCommand::from_bits_truncate(commands.parse()?);
+        let ports = self
+            .port_devices()
+            .into_iter()
+            .map(|device| {
+                let column = columns
+                    .next()
+                    .ok_or_else(|| anyhow!("missing port column"))?;
+                PortInput::parse(device, column)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(MovieFrame { commands, ports })
+    }
+
+    #[must_use]
+    pub fn to_fm2(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "version {}", FM2_VERSION);
+        let _ = writeln!(out, "emuVersion {}", self.emu_version);
+        let _ = writeln!(out, "rerecordCount {}", self.rerecord_count);
+        let _ = writeln!(out, "palFlag {}", self.pal as u8);
+        let _ = writeln!(out, "romFilename {}", self.rom_filename);
+        if let Some(checksum) = &self.rom_checksum {
+            let _ = writeln!(out, "romChecksum base64:{}", BASE64.encode(checksum));
+        }
+        if let Some(guid) = &self.guid {
+            let _ = writeln!(out, "guid {}", guid);
+        }
+        let _ = writeln!(out, "fourscore {}", self.fourscore as u8);
+        let _ = writeln!(out, "microphone 0");
+        let _ = writeln!(out, "port0 {}", self.ports[0].id());
+        let _ = writeln!(out, "port1 {}", self.ports[1].id());
+        let _ = writeln!(out, "port2 0");
+        let _ = writeln!(out, "FDS 0");
+        let _ = writeln!(out, "NewPPU 0");
+        for comment in &self.comments {
+            let _ = writeln!(out, "comment {}", comment);
+        }
+        for subtitle in &self.subtitles {
+            let _ = writeln!(out, "subtitle {}", subtitle);
+        }
+        if let Some(savestate) = &self.savestate {
+            let _ = writeln!(out, "savestate base64:{}", BASE64.encode(savestate));
+        }
+        for frame in &self.frames {
+            let _ = write!(out, "|{}|", frame.commands.bits());
+            for port in &frame.ports {
+                port.write(&mut out);
+                out.push('|');
+            }
+            // The expansion port column is always empty
+            out.push_str("|\n");
+        }
+        out
+    }
+}
+
+/// A movie being recorded or played back by the emulator
+#[derive(Debug)]
+pub enum MovieSession {
+    Recording {
+        movie: Movie,
+        /// Commands issued since the last frame, stored with the next record
+        pending: MovieCommand,
+    },
+    Playing {
+        movie: Movie,
+        frame: usize,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::nes::NesEmulator;
+    use crate::test_util::{create_test_rom, nes_with_program, PULSE_TONE_PROGRAM};
+
+    /// Counts the controller polls that see A held in $00
+    const POLL_PROGRAM: [u8; 23] = [
+        0xa9, 0x01, // LDA #$01
+        0x8d, 0x16, 0x40, // STA $4016
+        0xa9, 0x00, // LDA #$00
+        0x8d, 0x16, 0x40, // STA $4016
+        0xad, 0x16, 0x40, // LDA $4016
+        0x29, 0x01, // AND #$01
+        0x18, // CLC
+        0x65, 0x00, // ADC $00
+        0x85, 0x00, // STA $00
+        0x4c, 0x00, 0x80, // JMP $8000
+    ];
+
+    /// Record 12 frames of alternating input with a reset on frame 7
+    fn record(nes: &mut NesEmulator) -> Movie {
+        nes.record_movie(true).unwrap();
+        for frame in 0..12 {
+            let buttons = if frame % 3 == 0 {
+                Buttons::A
+            } else {
+                Buttons::RIGHT
+            };
+            nes.set_buttons(0, buttons);
+            if frame == 7 {
+                nes.reset().unwrap();
+            }
+            nes.run_frame().unwrap();
+        }
+        nes.stop_movie().unwrap()
+    }
+
+    #[test]
+    fn test_record_and_play_back() {
+        let mut nes = nes_with_program(&POLL_PROGRAM);
+        let movie = record(&mut nes);
+        let (ram, cycles) = (nes.cpu.mapper[0], nes.cpu.cycles);
+        assert_ne!(ram, 0);
+        assert_eq!(movie.frames.len(), 12);
+        assert_eq!(movie.frames[7].commands, MovieCommand::SOFT_RESET);
+
+        let mut nes = nes_with_program(&POLL_PROGRAM);
+        // Whatever was going on before, playback starts from power on
+        nes.cpu.mapper[0] = 0x55;
+        nes.play_movie(movie).unwrap();
+        while nes.is_playing_movie() {
+            nes.run_frame().unwrap();
+        }
+        assert_eq!((nes.cpu.mapper[0], nes.cpu.cycles), (ram, cycles));
+    }
+
+    #[test]
+    fn test_fm2_round_trip() {
+        let movie = record(&mut nes_with_program(&POLL_PROGRAM));
+        let text = movie.to_fm2();
+        assert!(text.starts_with("version 3\n"));
+        assert!(text.contains("\n|0|.......A|........||\n"));
+        assert!(text.contains("\n|1|R.......|........||\n"));
+        assert_eq!(Movie::parse(&text).unwrap().frames, movie.frames);
+    }
+
+    #[test]
+    fn test_rejects_other_rom() {
+        let movie = record(&mut nes_with_program(&POLL_PROGRAM));
+        let mut other = create_test_rom(&POLL_PROGRAM);
+        *other.last_mut().unwrap() ^= 0xff;
+        let mut nes = NesEmulator::default();
+        nes.insert_cartridge(Cartridge::from_bytes(&other).unwrap())
+            .unwrap();
+        assert!(nes.play_movie(movie).is_err());
+    }
+
+    #[test]
+    fn test_start_from_savestate() {
+        let mut nes = nes_with_program(&PULSE_TONE_PROGRAM);
+        nes.run_frame().unwrap();
+        nes.record_movie(false).unwrap();
+        for _ in 0..5 {
+            nes.set_buttons(0, Buttons::START);
+            nes.run_frame().unwrap();
+        }
+        let expected = nes.save_state().unwrap();
+        let movie = Movie::parse(&nes.stop_movie().unwrap().to_fm2()).unwrap();
+        assert!(movie.savestate.is_some());
+
+        nes.power_cycle().unwrap();
+        nes.play_movie(movie).unwrap();
+        while nes.is_playing_movie() {
+            nes.run_frame().unwrap();
+        }
+        assert_eq!(nes.save_state().unwrap(), expected);
+    }
+}
@@ -1,17 +1,621 @@
-use crate::cpu::Cpu6502;
-use crate::ppu::Ppu;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use crate::audio::{AudioPipeline, AudioRecorder, DEFAULT_SAMPLE_RATE};
+use crate::battery::{SaveFile, SaveMemory};
+use crate::cartridge::Cartridge;
+use crate::cpu::{
+    first_divergence, trace_line, Clocked, Cpu6502, DebugStop, Divergence, ExpressionContext,
+    NESTEST_START,
+};
+use crate::input::{Buttons, Controller, InputDeviceKind, MultitapKind, Unplugged, Zapper};
+use crate::movie::{Movie, MovieCommand, MovieFrame, MovieSession, PortDevice, PortInput};
+use crate::ppu::{Ppu, SCREEN_WIDTH};
+use crate::region::Region;
+use crate::rewind::RewindBuffer;
+use crate::savestate::{Savestate, StateReader, StateWriter, SAVESTATE_MAGIC, SAVESTATE_VERSION};
 
 // Main entry point for the NES emulator
-struct NesEmulator {
+pub struct NesEmulator {
     pub cpu: Box<Cpu6502>,
-    pub ppu: Box<Ppu>,
+    pub cartridge: Option<Cartridge>,
+    pub audio: AudioPipeline,
+    /// Feed the live output pipeline, which a frontend drains with `audio_samples`
+    pub audio_output: bool,
+    pub recorder: Option<AudioRecorder>,
+    pub movie: Option<MovieSession>,
+    pub rewind: Option<RewindBuffer>,
+    /// Where the battery-backed save memory of the cartridge is persisted
+    pub save_file: Option<SaveFile>,
+    /// Load and write `.sav` files for battery-backed cartridges
+    pub battery_saves: bool,
 }
 
 impl Default for NesEmulator {
     fn default() -> Self {
+        let cpu = Box::new(Cpu6502::default());
+        let clock_rate = cpu.apu.region.cpu_clock_rate();
         Self {
-            cpu: Box::new(Cpu6502::default()),
-            ppu: Box::new(Ppu::default()),
+            cpu,
+            cartridge: None,
+            audio: AudioPipeline::new(clock_rate, DEFAULT_SAMPLE_RATE),
+            audio_output: false,
+            recorder: None,
+            movie: None,
+            rewind: None,
+            save_file: None,
+            battery_saves: true,
+        }
+    }
+}
+
+impl NesEmulator {
+    /// Change the host sample rate of the live output
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        let clock_rate = self.cpu.apu.region.cpu_clock_rate();
+        self.audio = AudioPipeline::new(clock_rate, sample_rate);
+    }
+
+    /// Turn the live audio output on or off. While it is off, samples are only
+    /// produced for a running recording.
+    pub fn set_audio_output(&mut self, enabled: bool) {
+        self.audio_output = enabled;
+        if !enabled {
+            self.audio.take_samples();
+        }
+        self.update_sample_production();
+    }
+
+    /// The APU only produces samples while something consumes them
+    fn update_sample_production(&mut self) {
+        let enabled = self.audio_output || self.recorder.is_some();
+        self.cpu.apu.set_samples_enabled(enabled);
+    }
+
+    /// Replace the connected input devices
+    pub fn connect_input(&mut self, kind: InputDeviceKind) {
+        self.cpu.input.connect(kind);
+    }
+
+    /// Put a four player adapter in front of the controller ports, or remove it with None
+    pub fn set_multitap(&mut self, kind: Option<MultitapKind>) {
+        self.cpu.input.set_multitap(kind);
+    }
+
+    /// Set the buttons held on the controller in `port` (0 or 1, 0-3 with a multitap)
+    pub fn set_buttons(&mut self, port: usize, buttons: Buttons) {
+        self.cpu.input.set_buttons(port, buttons);
+    }
+
+    /// Cancel out Left+Right and Up+Down presses, which a real d-pad can't make
+    pub fn set_prevent_opposing_directions(&mut self, prevent: bool) {
+        self.cpu.input.prevent_opposing_directions = prevent;
+    }
+
+    /// Plug a Zapper into port 2
+    pub fn connect_zapper(&mut self) {
+        self.cpu.input.ports[1] = Box::new(Zapper::default());
+    }
+
+    /// Point the Zapper in port 2 at a screen coordinate and set its trigger.
+    /// Coordinates outside the 256x240 picture aim off screen.
+    pub fn set_zapper(&mut self, x: u16, y: u16, trigger: bool) -> Result<()> {
+        let Some(zapper) = self.cpu.input.device_mut::<Zapper>(1) else {
+            bail!("No Zapper connected to port 2");
+        };
+        zapper.set_aim(x, y);
+        zapper.trigger = trigger;
+        Ok(())
+    }
+
+    /// Turn `.sav` files off, e.g. for deterministic test runs
+    pub fn set_battery_saves(&mut self, enabled: bool) {
+        self.battery_saves = enabled;
+    }
+
+    /// Load a ROM and, for battery-backed cartridges, its `.sav` file next to it
+    pub fn load_rom(&mut self, path: &Path) -> Result<()> {
+        self.flush_save()?;
+        self.save_file = None;
+        let cartridge = Cartridge::load(path)?;
+        let battery = cartridge.battery;
+        self.insert_cartridge(cartridge)?;
+        if battery && self.battery_saves {
+            let save_file = SaveFile::for_rom(path);
+            save_file.load(self.save_memory())?;
+            self.save_file = Some(save_file);
+        }
+        Ok(())
+    }
+
+    /// The battery-backed memory of the cartridge: the mapper's EEPROM if it has one, PRG RAM otherwise
+    pub fn save_memory(&mut self) -> &mut dyn SaveMemory {
+        if self.cpu.eeprom.is_some() {
+            return self.cpu.eeprom.as_deref_mut().unwrap();
+        }
+        self.cpu.as_mut()
+    }
+
+    /// Write the save memory to the `.sav` file if it changed since the last flush
+    pub fn flush_save(&mut self) -> Result<()> {
+        if let Some(mut save_file) = self.save_file.take() {
+            let result = save_file.flush(self.save_memory());
+            self.save_file = Some(save_file);
+            result?;
+        }
+        Ok(())
+    }
+
+    pub fn insert_cartridge(&mut self, cartridge: Cartridge) -> Result<()> {
+        self.cpu.load_cartridge(&cartridge)?;
+        self.cpu.ppu.load_cartridge(&cartridge);
+        if let Some(kind) = InputDeviceKind::from_expansion_device(cartridge.expansion_device) {
+            self.connect_input(kind);
+        }
+        self.cartridge = Some(cartridge);
+        self.cpu.reset()
+    }
+
+    /// Press the reset button
+    pub fn reset(&mut self) -> Result<()> {
+        if let Some(MovieSession::Recording { pending, .. }) = self.movie.as_mut() {
+            *pending |= MovieCommand::SOFT_RESET;
+        }
+        self.cpu.reset()
+    }
+
+    /// Turn the console off and on again. Connected input devices and audio settings stay.
+    pub fn power_cycle(&mut self) -> Result<()> {
+        if let Some(MovieSession::Recording { pending, .. }) = self.movie.as_mut() {
+            *pending |= MovieCommand::POWER;
+        }
+        self.process_audio()?;
+        let Some(cartridge) = self.cartridge.as_ref() else {
+            bail!("No cartridge inserted");
+        };
+        let mut ppu = Box::new(Ppu::default());
+        ppu.load_cartridge(cartridge);
+        self.cpu.ppu = ppu;
+        self.cpu.load_cartridge(cartridge)?;
+        self.cpu.power_on()
+    }
+
+    /// Execute one CPU instruction, the PPU and APU run along with its cycles.
+    /// Returns false once the CPU stops.
+    pub fn step(&mut self) -> Result<bool> {
+        let cpu = self.cpu.as_mut();
+        cpu.input.sense_light(&cpu.ppu);
+        cpu.clocked()
+    }
+
+    /// Execute one instruction under the debugger: check the watchpoints against its accesses
+    /// and the breakpoints against the next instruction
+    pub fn debug_step(&mut self) -> Result<DebugStop> {
+        self.cpu.bus_log = self.cpu.debugger.watches_accesses().then(Vec::new);
+        let frame = self.cpu.ppu.frame;
+        let result = self.step();
+        let accesses = self.cpu.bus_log.take().unwrap_or_default();
+        if !result? {
+            return Ok(DebugStop::Halted);
+        }
+        // Snapshots for stepping back with `rewind` are taken at the start of each frame
+        if self.cpu.ppu.frame != frame {
+            self.capture_rewind()?;
+        }
+
+        // Conditions read the CPU the debugger lives in
+        let mut debugger = std::mem::take(&mut self.cpu.debugger);
+        let context = self.expression_context();
+        let access = debugger.check_accesses(&accesses, &context);
+        let execute = debugger.check_execute(&context);
+        self.cpu.debugger = debugger;
+        Ok(match access?.or(execute?) {
+            Some(reason) => DebugStop::Break(reason),
+            None => DebugStop::Done,
+        })
+    }
+
+    /// What debugger expressions see right now
+    #[must_use]
+    pub fn expression_context(&self) -> ExpressionContext<'_> {
+        ExpressionContext {
+            cpu: &self.cpu,
+            scanline: self.cpu.ppu.scanline,
+            dot: self.cpu.ppu.dot,
+            frame: self.cpu.ppu.frame,
+            access: None,
+        }
+    }
+
+    /// Run nestest in automation mode from $C000 for as many instructions as the golden log has
+    /// lines, and return the first line where the trace stops matching it
+    pub fn run_nestest(&mut self, golden: &str) -> Result<Option<Divergence>> {
+        self.cpu.registers.pc = NESTEST_START;
+        self.cpu.registers.sp = 0xfd;
+        self.cpu.set_status_register_from_byte(0x24);
+        self.cpu.cycles = 7;
+        self.cpu.ppu.scanline = 0;
+        self.cpu.ppu.dot = 21;
+
+        let mut trace = Vec::new();
+        for _ in 0..golden.lines().count() {
+            trace.push(trace_line(&self.cpu));
+            match self.step() {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(error) => {
+                    // A divergence before the CPU gave up explains it better than the error
+                    return match first_divergence(golden, &trace) {
+                        Some(divergence) if divergence.line <= trace.len() => Ok(Some(divergence)),
+                        _ => Err(error.context(format!("nestest stopped at line {}", trace.len()))),
+                    };
+                }
+            }
+        }
+        Ok(first_divergence(golden, &trace))
+    }
+
+    /// Run until the PPU finishes the current frame. Returns false if the CPU stopped first.
+    pub fn run_frame(&mut self) -> Result<bool> {
+        self.advance_movie()?;
+        let frame = self.cpu.ppu.frame;
+        while self.cpu.ppu.frame == frame {
+            if !self.step()? {
+                self.process_audio()?;
+                return Ok(false);
+            }
+        }
+        self.process_audio()?;
+        self.capture_rewind()?;
+        if let Some(mut save_file) = self.save_file.take() {
+            let result = save_file.tick(self.save_memory());
+            self.save_file = Some(save_file);
+            result?;
+        }
+        Ok(true)
+    }
+
+    /// Move the APU's samples into the live pipeline and the recorder, if one is running
+    fn process_audio(&mut self) -> Result<()> {
+        let samples = self.cpu.apu.take_samples();
+        if self.audio_output {
+            self.audio.push_samples(&samples);
+        }
+        if let Some(recorder) = self.recorder.as_mut() {
+            let stems = self.cpu.apu.take_stem_samples();
+            recorder.record(&samples, &stems)?;
+        }
+        Ok(())
+    }
+
+    /// Resample everything the APU produced since the last call and return it at the host rate
+    pub fn audio_samples(&mut self) -> Result<Vec<f32>> {
+        if !self.audio_output {
+            bail!("Audio output is off, turn it on with set_audio_output");
+        }
+        self.process_audio()?;
+        Ok(self.audio.take_samples())
+    }
+
+    /// Record the audio to a WAV file at the live output's sample rate.
+    /// With `stems`, every channel is also written to its own file next to it.
+    pub fn start_recording(&mut self, path: &Path, stems: bool) -> Result<()> {
+        self.stop_recording()?;
+        let clock_rate = self.cpu.apu.region.cpu_clock_rate();
+        let recorder = AudioRecorder::create(path, clock_rate, self.audio.sample_rate(), stems)?;
+        // Samples produced before the recording started belong to the live output only
+        self.process_audio()?;
+        self.cpu.apu.set_stems_enabled(stems);
+        self.recorder = Some(recorder);
+        self.update_sample_production();
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> Result<()> {
+        self.process_audio()?;
+        self.cpu.apu.set_stems_enabled(false);
+        let recorder = self.recorder.take();
+        self.update_sample_production();
+        if let Some(recorder) = recorder {
+            recorder.finish()?;
+        }
+        Ok(())
+    }
+
+    /// Snapshot the whole machine. Running from a loaded snapshot is exactly
+    /// the same as running on from the moment it was taken.
+    pub fn save_state(&mut self) -> Result<Vec<u8>> {
+        // Samples produced so far belong to the timeline before the snapshot
+        self.process_audio()?;
+        let mut state = StateWriter::new();
+        state.write_bytes(SAVESTATE_MAGIC);
+        state.write_u16(SAVESTATE_VERSION);
+        let checksum = self.cartridge.as_ref().map(Cartridge::checksum);
+        state.write_bytes(&checksum.unwrap_or_default());
+        self.cpu.save_state(&mut state);
+        self.cpu.ppu.save_state(&mut state);
+        Ok(state.into_inner())
+    }
+
+    /// Restore a snapshot taken by `save_state` with the same ROM inserted
+    pub fn load_state(&mut self, data: &[u8]) -> Result<()> {
+        let mut state = StateReader::new(data);
+        let mut magic = [0u8; 4];
+        state.read_bytes(&mut magic)?;
+        if &magic != SAVESTATE_MAGIC {
+            bail!("Not a savestate");
+        }
+        let version = state.read_u16()?;
+        if version != SAVESTATE_VERSION {
+            bail!(
+                "Savestate version {} is not supported, expected {}",
+                version,
+                SAVESTATE_VERSION
+            );
+        }
+        let mut checksum = [0u8; 16];
+        state.read_bytes(&mut checksum)?;
+        if checksum
+            != self
+                .cartridge
+                .as_ref()
+                .map(Cartridge::checksum)
+                .unwrap_or_default()
+        {
+            bail!("Savestate was made with a different ROM");
+        }
+
+        self.process_audio()?;
+        // A corrupt savestate is only noticed halfway through, go back to the machine as it was
+        let mut backup = StateWriter::new();
+        self.cpu.save_state(&mut backup);
+        self.cpu.ppu.save_state(&mut backup);
+        let result = self.load_machine(&mut state);
+        if result.is_err() {
+            let backup = backup.into_inner();
+            self.load_machine(&mut StateReader::new(&backup))?;
+        }
+        result
+    }
+
+    fn load_machine(&mut self, state: &mut StateReader) -> Result<()> {
+        self.cpu.load_state(state)?;
+        self.cpu.ppu.load_state(state)?;
+        state.finish()
+    }
+
+    pub fn save_state_file(&mut self, path: &Path) -> Result<()> {
+        let state = self.save_state()?;
+        fs::write(path, state)?;
+        Ok(())
+    }
+
+    pub fn load_state_file(&mut self, path: &Path) -> Result<()> {
+        let state = fs::read(path)?;
+        self.load_state(&state)
+    }
+
+    /// Keep a snapshot every `interval` frames for rewinding, using at most `budget` bytes
+    pub fn enable_rewind(&mut self, interval: u64, budget: usize) {
+        self.rewind = Some(RewindBuffer::new(interval, budget));
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    fn capture_rewind(&mut self) -> Result<()> {
+        let frame = self.cpu.ppu.frame;
+        if self
+            .rewind
+            .as_ref()
+            .is_some_and(|rewind| rewind.is_due(frame))
+        {
+            let state = self.save_state()?;
+            if let Some(rewind) = self.rewind.as_mut() {
+                rewind.push(frame, &state);
+            }
+        }
+        Ok(())
+    }
+
+    /// Go back to the newest snapshot at least `frames` frames in the past, or the oldest one kept.
+    /// Returns how many frames were actually rewound.
+    pub fn rewind(&mut self, frames: u64) -> Result<u64> {
+        let Some(rewind) = self.rewind.as_mut() else {
+            bail!("Rewind is not enabled");
+        };
+        let current = self.cpu.ppu.frame;
+        let Some((frame, state)) = rewind.restore(current.saturating_sub(frames))? else {
+            bail!("Nothing to rewind to");
+        };
+        self.load_state(&state)?;
+
+        let rewound = current - frame;
+        match self.movie.as_mut() {
+            // Recording carries on from the restored frame, like a rerecord
+            Some(MovieSession::Recording { movie, .. }) => {
+                let len = movie.frames.len().saturating_sub(rewound as usize);
+                movie.frames.truncate(len);
+                movie.rerecord_count += 1;
+            }
+            Some(MovieSession::Playing { frame, .. }) => {
+                *frame = frame.saturating_sub(rewound as usize);
+            }
+            None => {}
+        }
+        Ok(rewound)
+    }
+
+    /// Start recording the input of every frame into a movie, from power on.
+    /// Without `from_power_on` the movie embeds a savestate and starts from the current state.
+    pub fn record_movie(&mut self, from_power_on: bool) -> Result<()> {
+        let Some(cartridge) = self.cartridge.as_ref() else {
+            bail!("No cartridge inserted");
+        };
+        let multitap = self
+            .cpu
+            .input
+            .multitap
+            .as_ref()
+            .map(|multitap| multitap.kind);
+        let fourscore = multitap == Some(MultitapKind::FourScore);
+        let mut ports = [PortDevice::None; 2];
+        for (port, device) in ports.iter_mut().enumerate() {
+            if self.cpu.input.device_mut::<Controller>(port).is_some() || fourscore {
+                *device = PortDevice::Gamepad;
+            } else if self.cpu.input.device_mut::<Zapper>(port).is_some() {
+                *device = PortDevice::Zapper;
+            }
+        }
+        let mut movie = Movie {
+            pal: self.cpu.apu.region == Region::Pal,
+            rom_checksum: Some(cartridge.checksum()),
+            guid: Some(Movie::generate_guid()),
+            fourscore,
+            ports,
+            ..Movie::default()
+        };
+        self.movie = None;
+        if from_power_on {
+            self.power_cycle()?;
+        } else {
+            movie.savestate = Some(self.save_state()?);
+        }
+        self.movie = Some(MovieSession::Recording {
+            movie,
+            pending: MovieCommand::empty(),
+        });
+        Ok(())
+    }
+
+    /// Play a movie back from power on or its savestate, checking it was made with the loaded ROM
+    pub fn play_movie(&mut self, movie: Movie) -> Result<()> {
+        let Some(cartridge) = self.cartridge.as_ref() else {
+            bail!("No cartridge inserted");
+        };
+        if let Some(checksum) = movie.rom_checksum {
+            if checksum != cartridge.checksum() {
+                bail!(
+                    "Movie desync: it was recorded with '{}', which does not match the loaded ROM",
+                    movie.rom_filename
+                );
+            }
+        }
+        if movie.pal != (self.cpu.apu.region == Region::Pal) {
+            bail!("Movie desync: the movie was recorded for another region");
+        }
+
+        if movie.fourscore {
+            self.connect_input(InputDeviceKind::FourScore);
+        } else {
+            self.connect_input(InputDeviceKind::Controllers);
+            for (port, device) in movie.ports.iter().enumerate() {
+                match device {
+                    PortDevice::None => self.cpu.input.plug(port, Box::new(Unplugged))?,
+                    PortDevice::Gamepad => {}
+                    PortDevice::Zapper => self.cpu.input.plug(port, Box::new(Zapper::default()))?,
+                }
+            }
+        }
+        self.movie = None;
+        match &movie.savestate {
+            Some(savestate) => self.load_state(savestate)?,
+            None => self.power_cycle()?,
+        }
+        self.movie = Some(MovieSession::Playing { movie, frame: 0 });
+        Ok(())
+    }
+
+    /// True while a movie is being played back and has frames left
+    #[must_use]
+    pub fn is_playing_movie(&self) -> bool {
+        matches!(&self.movie, Some(MovieSession::Playing { movie, frame }) if *frame < movie.frames.len())
+    }
+
+    /// Stop recording or playing, returning the movie
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        self.movie.take().map(|session| match session {
+            MovieSession::Recording { movie, .. } => movie,
+            MovieSession::Playing { movie, .. } => movie,
+        })
+    }
+
+    /// Feed the next movie record into the console, or record the current input
+    fn advance_movie(&mut self) -> Result<()> {
+        match self.movie.as_mut() {
+            None => {}
+            Some(MovieSession::Recording { .. }) => {
+                let ports = self.capture_input();
+                if let Some(MovieSession::Recording { movie, pending }) = self.movie.as_mut() {
+                    movie.frames.push(MovieFrame {
+                        commands: std::mem::take(pending),
+                        ports,
+                    });
+                }
+            }
+            Some(MovieSession::Playing { movie, frame }) => {
+                let Some(record) = movie.frames.get(*frame).cloned() else {
+                    return Ok(());
+                };
+                *frame += 1;
+                if record.commands.contains(MovieCommand::POWER) {
+                    self.power_cycle()?;
+                } else if record.commands.contains(MovieCommand::SOFT_RESET) {
+                    self.cpu.reset()?;
+                }
+                self.apply_input(&record.ports);
+            }
+        }
+        Ok(())
+    }
+
+    fn capture_input(&mut self) -> Vec<PortInput> {
+        let input = &mut self.cpu.input;
+        if let Some(multitap) = input.multitap.as_ref() {
+            return multitap.buttons.map(PortInput::Gamepad).to_vec();
+        }
+        (0..2)
+            .map(|port| {
+                if let Some(controller) = input.device_mut::<Controller>(port) {
+                    PortInput::Gamepad(controller.buttons)
+                } else if let Some(zapper) = input.device_mut::<Zapper>(port) {
+                    let (x, y) = zapper.aim.unwrap_or((SCREEN_WIDTH as u16, 0));
+                    PortInput::Zapper {
+                        x,
+                        y,
+                        trigger: zapper.trigger,
+                    }
+                } else {
+                    PortInput::None
+                }
+            })
+            .collect()
+    }
+
+    fn apply_input(&mut self, ports: &[PortInput]) {
+        let input = &mut self.cpu.input;
+        for (port, state) in ports.iter().enumerate() {
+            match *state {
+                PortInput::None => {}
+                // Movies hold exactly what was pressed, so skip the opposing directions filter
+                PortInput::Gamepad(buttons) => match input.multitap.as_mut() {
+                    Some(multitap) => multitap.buttons[port] = buttons,
+                    None => {
+                        if let Some(device) = input.ports.get_mut(port) {
+                            device.set_buttons(buttons);
+                        }
+                    }
+                },
+                PortInput::Zapper { x, y, trigger } => {
+                    if let Some(zapper) = input.device_mut::<Zapper>(port) {
+                        zapper.set_aim(x, y);
+                        zapper.trigger = trigger;
+                    }
+                }
+            }
         }
     }
 }
@@ -1,58 +1,275 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
+mod palette;
 mod registers;
+mod render;
 
-use crate::{constant::MEMORY_MAX, mem::Mem};
+pub use palette::*;
 
-use self::registers::{PpuAddrRegister, PpuControlRegister, PpuMaskRegister};
+use crate::{
+    cartridge::{Cartridge, Mirroring},
+    constant::MEMORY_MAX,
+    mem::Mem,
+    savestate::{Savestate, StateReader, StateWriter},
+};
+
+use self::registers::{PpuControlRegister, PpuMaskRegister, ScrollRegister};
+use self::render::SpriteRow;
 
 /// # PPU Registers
 /// The PPU exposes eight memory-mapped registers to the CPU. These nominally sit at $2000 through $2007 in the CPU's address space, but because their addresses are incompletely decoded, they're mirrored in every 8 bytes from $2008 through $3FFF. For example, a write to $3456 is the same as a write to $2006.
 /// After power-on and reset, many of the PPU's registers are not immediately usable until enough time has passed. See PPU power up state and Init code for details.
 #[derive(Debug, Clone)]
-struct PpuRegister {
+pub struct PpuRegister {
     // $2000 - PPUCTRL controller
-    ppuctrl: PpuControlRegister,
+    pub ppuctrl: PpuControlRegister,
     // $2001 - PPUMASK mask register
-    ppumask: PpuMaskRegister,
+    pub ppumask: PpuMaskRegister,
     // $2002 - PPUSTATUS status
-    ppustatus: u8,
-    // $2003 - OAM data read/write
-    odmadata: u8,
-    // $2004 - OAM data read/write
-    oamdata: u8,
-    // $2005 - fine scroll position
-    ppuscroll: u8,
-    // $2006 - PPU read/write address (two writes: most significant byte, least significant byte)
-    ppuaddr: PpuAddrRegister,
-    // $2007 - PPU data read/write
-    ppudata: u8,
+    pub ppustatus: u8,
+    // $2003 - OAM address, where $2004 reads and writes
+    pub oamaddr: u8,
+    // $2005/$2006 - scroll position and VRAM address
+    pub scroll: ScrollRegister,
+    // $2007 - PPU data read buffer, reads below the palettes return the byte read before
+    pub ppudata: u8,
 }
 
 impl Default for PpuRegister {
     fn default() -> Self {
         Self {
-            ..Default::default()
+            ppuctrl: PpuControlRegister::new(),
+            ppumask: PpuMaskRegister::new(),
+            ppustatus: 0,
+            oamaddr: 0,
+            scroll: ScrollRegister::default(),
+            ppudata: 0,
         }
     }
 }
 
+pub const DOTS_PER_SCANLINE: u16 = 341;
+pub const SCANLINES_PER_FRAME: u16 = 262;
+pub const VBLANK_SCANLINE: u16 = 241;
+pub const PRE_RENDER_SCANLINE: u16 = 261;
+pub const VBLANK_FLAG: u8 = 0x80;
+pub const SPRITE_ZERO_HIT_FLAG: u8 = 0x40;
+pub const SPRITE_OVERFLOW_FLAG: u8 = 0x20;
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+pub const OAM_SIZE: usize = 256;
+/// Sprites shown on one scanline, the rest set the overflow flag
+pub const SPRITES_PER_SCANLINE: usize = 8;
+
 #[derive(Debug)]
 pub struct Ppu {
     pub registers: PpuRegister,
+    /// The PPU address space: pattern tables at $0000-$1FFF, nametables at $2000-$2FFF
+    /// and palettes at $3F00-$3F1F, stored at the address their mirrors fold to
     pub mapper: [u8; MEMORY_MAX], // 64KB
+    /// Object attribute memory: 64 sprites of 4 bytes each
+    pub oam: [u8; OAM_SIZE],
+    /// Beam position: scanline 0-261 (NTSC) and dot 0-340
+    pub scanline: u16,
+    pub dot: u16,
+    /// Number of frames completed since power on
+    pub frame: u64,
+    /// The picture output, 24-bit RGB in row order, drawn a dot at a time as the beam moves
+    pub framebuffer: Vec<u8>,
+    /// How the cartridge wires the four nametables to the 2KB of VRAM
+    pub mirroring: Mirroring,
+    /// The pattern tables are RAM on cartridges without CHR ROM
+    pub chr_ram: bool,
+    /// Set at the start of vblank with NMIs enabled, until the CPU takes the NMI
+    pub nmi: bool,
+    /// The PPU's own data bus latch, read back from write-only registers
+    pub open_bus: u8,
+    /// Sprites found on the current scanline
+    sprites: Vec<SpriteRow>,
 }
 
 impl Ppu {
-    fn write_to_ppuaddr(&mut self, value: u8) {
-        self.registers.ppuaddr.write(value);
+    /// Copy the cartridge's CHR ROM into the pattern tables at $0000-$1FFF and wire the nametables
+    pub fn load_cartridge(&mut self, cartridge: &Cartridge) {
+        let len = cartridge.chr_rom.len().min(0x2000);
+        self.mapper[..len].copy_from_slice(&cartridge.chr_rom[..len]);
+        self.chr_ram = cartridge.chr_rom.is_empty();
+        self.mirroring = cartridge.mirroring;
     }
 
-    fn write_to_ppuctrl(&mut self, value: u8) {
-        self.registers.ppuctrl.write(value);
+    #[inline]
+    #[must_use]
+    pub fn is_rendering_enabled(&self) -> bool {
+        self.registers
+            .ppumask
+            .intersects(PpuMaskRegister::SHOW_BG | PpuMaskRegister::SHOW_SPIRTES)
+    }
+
+    /// Advance the beam by one dot. The PPU runs three dots per CPU cycle.
+    /// reference: https://www.nesdev.org/wiki/PPU_frame_timing
+    pub fn tick(&mut self) {
+        // With rendering enabled, the pre-render line of odd frames is one dot shorter
+        if self.scanline == PRE_RENDER_SCANLINE
+            && self.dot == DOTS_PER_SCANLINE - 2
+            && self.frame % 2 == 1
+            && self.is_rendering_enabled()
+        {
+            self.dot = DOTS_PER_SCANLINE - 1;
+        }
+
+        self.dot += 1;
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline == SCANLINES_PER_FRAME {
+                self.scanline = 0;
+                self.frame += 1;
+            }
+        }
+
+        match (self.scanline, self.dot) {
+            (VBLANK_SCANLINE, 1) => {
+                self.registers.ppustatus |= VBLANK_FLAG;
+                if self.registers.ppuctrl.nmi_enabled() {
+                    self.nmi = true;
+                }
+            }
+            (PRE_RENDER_SCANLINE, 1) => {
+                self.registers.ppustatus &=
+                    !(VBLANK_FLAG | SPRITE_ZERO_HIT_FLAG | SPRITE_OVERFLOW_FLAG);
+            }
+            _ => {}
+        }
+        self.render_dot();
+    }
+
+    /// Perceived brightness (0-255) of the output pixel at x, y
+    #[must_use]
+    pub fn brightness(&self, x: usize, y: usize) -> u8 {
+        let i = (y * SCREEN_WIDTH + x) * 3;
+        let [r, g, b] = [
+            self.framebuffer[i] as u32,
+            self.framebuffer[i + 1] as u32,
+            self.framebuffer[i + 2] as u32,
+        ];
+        ((r * 299 + g * 587 + b * 114) / 1000) as u8
+    }
+
+    /// Where `addr` is stored in `mapper` after folding the nametable and palette mirrors
+    /// reference: https://www.nesdev.org/wiki/Mirroring#Nametable_Mirroring
+    fn vram_index(&self, addr: u16) -> usize {
+        let addr = addr & 0x3fff;
+        match addr {
+            0x0000..=0x1fff => addr as usize,
+            0x2000..=0x3eff => {
+                let table = (addr >> 10) & 0b11;
+                let table = match self.mirroring {
+                    Mirroring::Horizontal => table & 0b10,
+                    Mirroring::Vertical => table & 0b01,
+                    Mirroring::FourScreen => table,
+                };
+                (0x2000 | (table << 10) | (addr & 0x03ff)) as usize
+            }
+            _ => {
+                // The backdrop entries of the sprite palettes are the background ones
+                let mut index = addr & 0x1f;
+                if index & 0x13 == 0x10 {
+                    index &= !0x10;
+                }
+                (0x3f00 | index) as usize
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn vram_read(&self, addr: u16) -> u8 {
+        self.mapper[self.vram_index(addr)]
+    }
+
+    pub fn vram_write(&mut self, addr: u16, value: u8) {
+        if addr & 0x3fff < 0x2000 && !self.chr_ram {
+            return;
+        }
+        self.mapper[self.vram_index(addr)] = value;
+    }
+
+    /// CPU read of the register at $2000-$2007
+    pub fn read_register(&mut self, addr: u16) -> u8 {
+        let value = match addr & 0x0007 {
+            2 => {
+                let status = (self.registers.ppustatus & 0xe0) | (self.open_bus & 0x1f);
+                self.registers.ppustatus &= !VBLANK_FLAG;
+                self.registers.scroll.w = false;
+                status
+            }
+            4 => self.oam[self.registers.oamaddr as usize],
+            7 => {
+                let addr = self.registers.scroll.v & 0x3fff;
+                let value = if addr >= 0x3f00 {
+                    // Palette reads skip the buffer, which gets the nametable byte underneath
+                    self.registers.ppudata = self.vram_read(addr - 0x1000);
+                    (self.vram_read(addr) & 0x3f) | (self.open_bus & 0xc0)
+                } else {
+                    let buffered = self.vram_read(addr);
+                    std::mem::replace(&mut self.registers.ppudata, buffered)
+                };
+                let step = self.registers.ppuctrl.vram_increment();
+                self.registers.scroll.increment(step);
+                value
+            }
+            _ => self.open_bus,
+        };
+        self.open_bus = value;
+        value
+    }
+
+    /// The value a read of the register would return, without its side effects
+    #[must_use]
+    pub fn peek_register(&self, addr: u16) -> u8 {
+        match addr & 0x0007 {
+            2 => (self.registers.ppustatus & 0xe0) | (self.open_bus & 0x1f),
+            4 => self.oam[self.registers.oamaddr as usize],
+            7 => match self.registers.scroll.v & 0x3fff {
+                addr @ 0x3f00..=0x3fff => self.vram_read(addr) & 0x3f,
+                _ => self.registers.ppudata,
+            },
+            _ => self.open_bus,
+        }
+    }
+
+    /// CPU write of the register at $2000-$2007
+    pub fn write_register(&mut self, addr: u16, value: u8) {
+        self.open_bus = value;
+        match addr & 0x0007 {
+            0 => {
+                // Turning NMIs on during vblank raises one right away
+                let nmi_enabled = self.registers.ppuctrl.nmi_enabled();
+                self.registers.ppuctrl.write(value);
+                if !nmi_enabled
+                    && self.registers.ppuctrl.nmi_enabled()
+                    && self.registers.ppustatus & VBLANK_FLAG != 0
+                {
+                    self.nmi = true;
+                }
+                self.registers.scroll.write_nametable(value);
+            }
+            1 => self.registers.ppumask.write(value),
+            3 => self.registers.oamaddr = value,
+            4 => self.write_oam(value),
+            5 => self.registers.scroll.write_scroll(value),
+            6 => self.registers.scroll.write_addr(value),
+            7 => {
+                self.vram_write(self.registers.scroll.v, value);
+                let step = self.registers.ppuctrl.vram_increment();
+                self.registers.scroll.increment(step);
+            }
+            _ => {}
+        }
     }
 
-    fn write_to_ppumask(&mut self, value: u8) {
-        self.registers.ppumask.write(value);
+    /// $2004 write, also used by OAM DMA
+    pub fn write_oam(&mut self, value: u8) {
+        self.oam[self.registers.oamaddr as usize] = value;
+        self.registers.oamaddr = self.registers.oamaddr.wrapping_add(1);
     }
 }
 
@@ -61,25 +278,196 @@ impl Default for Ppu {
         Self {
             registers: PpuRegister::default(),
             mapper: [0u8; MEMORY_MAX],
+            oam: [0u8; OAM_SIZE],
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            framebuffer: vec![0u8; SCREEN_WIDTH * SCREEN_HEIGHT * 3],
+            mirroring: Mirroring::Horizontal,
+            chr_ram: true,
+            nmi: false,
+            open_bus: 0,
+            sprites: Vec::with_capacity(SPRITES_PER_SCANLINE),
         }
     }
 }
 
 impl Mem for Ppu {
-    // TODO @dromaz help to confirm if I can apply the same mirroring method for the PPU
-    fn mem_read(&self, addr: u16) -> Result<u8> {
-        match addr {
-            0x0000..=0x3fff => {
-                // Mask to zero out the highest two bits in a 16-bit address
-                let mirror_down_addr = addr & 0b00000111_11111111;
-                Ok(self.mapper[mirror_down_addr as usize])
-            }
-            _ => Ok(self.mapper[addr as usize]),
-        }
+    fn mem_read(&mut self, addr: u16) -> Result<u8> {
+        Ok(self.vram_read(addr))
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) -> Result<()> {
-        self.mapper[addr as usize] = data;
+        self.vram_write(addr, data);
+        Ok(())
+    }
+}
+
+impl Savestate for PpuRegister {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.ppuctrl.bits());
+        state.write_u8(self.ppumask.bits());
+        state.write_u8(self.ppustatus);
+        state.write_u8(self.oamaddr);
+        self.scroll.save_state(state);
+        state.write_u8(self.ppudata);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.ppuctrl = PpuControlRegister::from_bits_retain(state.read_u8()?);
+        self.ppumask = PpuMaskRegister::from_bits_retain(state.read_u8()?);
+        self.ppustatus = state.read_u8()?;
+        self.oamaddr = state.read_u8()?;
+        self.scroll.load_state(state)?;
+        self.ppudata = state.read_u8()?;
         Ok(())
     }
 }
+
+impl Savestate for Ppu {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.registers.save_state(state);
+        state.write_bytes(&self.mapper);
+        state.write_bytes(&self.oam);
+        state.write_u16(self.scanline);
+        state.write_u16(self.dot);
+        state.write_u64(self.frame);
+        state.write_bytes(&self.framebuffer);
+        state.write_bool(self.nmi);
+        state.write_u8(self.open_bus);
+        state.write_u8(self.sprites.len() as u8);
+        for sprite in &self.sprites {
+            sprite.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.registers.load_state(state)?;
+        state.read_bytes(&mut self.mapper)?;
+        state.read_bytes(&mut self.oam)?;
+        self.scanline = state.read_u16()?;
+        self.dot = state.read_u16()?;
+        self.frame = state.read_u64()?;
+        state.read_bytes(&mut self.framebuffer)?;
+        self.nmi = state.read_bool()?;
+        self.open_bus = state.read_u8()?;
+        let count = state.read_u8()? as usize;
+        if count > SPRITES_PER_SCANLINE {
+            bail!("{} sprites on a scanline, at most 8 fit", count);
+        }
+        self.sprites.clear();
+        for _ in 0..count {
+            let mut sprite = SpriteRow::default();
+            sprite.load_state(state)?;
+            self.sprites.push(sprite);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{assemble, StopReason, TestHarness};
+    use crate::test_util::nes_with_source;
+
+    #[test]
+    fn test_frame_timing() {
+        let mut ppu = Ppu::default();
+        let mut dots = 0;
+        while ppu.frame == 0 {
+            ppu.tick();
+            dots += 1;
+        }
+        assert_eq!(dots, 341 * 262);
+
+        // Odd frames skip a dot when rendering is enabled
+        ppu.write_register(0x2001, 0x08);
+        let mut dots = 0;
+        while ppu.frame == 1 {
+            ppu.tick();
+            dots += 1;
+        }
+        assert_eq!(dots, 341 * 262 - 1);
+    }
+
+    #[test]
+    fn test_vram_access() {
+        let mut ppu = Ppu::default();
+        ppu.write_register(0x2006, 0x24);
+        ppu.write_register(0x2006, 0x05);
+        ppu.write_register(0x2007, 0x66);
+        ppu.write_register(0x2007, 0x77);
+        // Horizontal mirroring: $2400 is the same nametable as $2000
+        ppu.write_register(0x2006, 0x20);
+        ppu.write_register(0x2006, 0x05);
+        // The first read only fills the buffer
+        ppu.read_register(0x2007);
+        assert_eq!(ppu.read_register(0x2007), 0x66);
+        assert_eq!(ppu.read_register(0x2007), 0x77);
+
+        // Palettes are read right away, $3F10 is the backdrop at $3F00
+        ppu.write_register(0x2006, 0x3f);
+        ppu.write_register(0x2006, 0x10);
+        ppu.write_register(0x2007, 0x21);
+        ppu.write_register(0x2006, 0x3f);
+        ppu.write_register(0x2006, 0x00);
+        assert_eq!(ppu.read_register(0x2007), 0x21);
+
+        // Going down a column with the increment of 32
+        ppu.write_register(0x2000, 0x04);
+        ppu.write_register(0x2006, 0x20);
+        ppu.write_register(0x2006, 0x00);
+        ppu.write_register(0x2007, 0x01);
+        ppu.write_register(0x2007, 0x02);
+        assert_eq!(ppu.vram_read(0x2020), 0x02);
+    }
+
+    #[test]
+    fn test_status_read() {
+        let mut ppu = Ppu::default();
+        while ppu.registers.ppustatus & VBLANK_FLAG == 0 {
+            ppu.tick();
+        }
+        assert_eq!((ppu.scanline, ppu.dot), (VBLANK_SCANLINE, 1));
+        ppu.write_register(0x2005, 0x10);
+        assert_eq!(ppu.read_register(0x2002), 0x90);
+        // Reading clears the flag and the write latch
+        assert_eq!(ppu.read_register(0x2002) & VBLANK_FLAG, 0);
+        assert!(!ppu.registers.scroll.w);
+    }
+
+    #[test]
+    fn test_nmi() {
+        let source = "
+            .org $8000
+            LDA #$80
+            STA $2000
+        loop:
+            JMP loop
+        nmi:
+            INC $10
+            RTI
+        ";
+        let nmi = assemble(source).unwrap().symbol("nmi").unwrap();
+        let mut nes = nes_with_source(source);
+        nes.cpu.mapper[0xfffa..0xfffc].copy_from_slice(&nmi.to_le_bytes());
+        for _ in 0..3 {
+            nes.run_frame().unwrap();
+        }
+        assert_eq!(nes.cpu.mapper[0x10], 3);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let page: Vec<u8> = (0..=255).collect();
+        let mut harness = TestHarness::new()
+            .with_source(".org $8000\nLDA #$02\nSTA $4014\nBRK")
+            .unwrap()
+            .with_memory(0x0200, &page);
+        assert_eq!(harness.run(10).unwrap(), StopReason::Break);
+        assert_eq!(harness.cpu.ppu.oam.to_vec(), page);
+        // The DMA starts on odd cycle 5 and waits one cycle to line up
+        assert_eq!(harness.cpu.cycles, 2 + 4 + 514);
+    }
+}
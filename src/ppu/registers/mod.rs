@@ -1,7 +1,7 @@
-mod ppuaddr;
 mod ppuctrl;
 mod ppumask;
+mod scroll;
 
-pub use ppuaddr::*;
 pub use ppuctrl::*;
 pub use ppumask::*;
+pub use scroll::*;
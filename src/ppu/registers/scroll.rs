@@ -0,0 +1,174 @@
+use anyhow::Result;
+
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// The internal registers behind PPUSCROLL ($2005) and PPUADDR ($2006).
+/// `v` is the current VRAM address, which rendering walks through, and `t` the one being
+/// written. Both hold a scroll position while rendering:
+///
+/// ```text
+/// yyy NN YYYYY XXXXX
+/// ||| || ||||| +++++-- coarse X scroll
+/// ||| || +++++-------- coarse Y scroll
+/// ||| ++-------------- nametable select
+/// +++----------------- fine Y scroll
+/// ```
+/// The two writes of $2005 and $2006 share the `w` latch.
+/// reference: https://www.nesdev.org/wiki/PPU_scrolling
+#[derive(Debug, Clone, Default)]
+pub struct ScrollRegister {
+    pub v: u16,
+    pub t: u16,
+    /// Fine X scroll, 0-7
+    pub fine_x: u8,
+    /// Set between the first and the second write
+    pub w: bool,
+}
+
+impl ScrollRegister {
+    /// $2000 write: the nametable select bits
+    pub fn write_nametable(&mut self, value: u8) {
+        self.t = (self.t & !0x0c00) | (((value & 0b11) as u16) << 10);
+    }
+
+    /// $2005 write: X scroll first, Y scroll second
+    pub fn write_scroll(&mut self, value: u8) {
+        if self.w {
+            self.t = (self.t & !0x73e0)
+                | (((value & 0x07) as u16) << 12)
+                | (((value & 0xf8) as u16) << 2);
+        } else {
+            self.t = (self.t & !0x001f) | (value >> 3) as u16;
+            self.fine_x = value & 0x07;
+        }
+        self.w = !self.w;
+    }
+
+    /// $2006 write: high byte first, the second write copies the address to `v`
+    pub fn write_addr(&mut self, value: u8) {
+        if self.w {
+            self.t = (self.t & 0xff00) | value as u16;
+            self.v = self.t;
+        } else {
+            self.t = (self.t & 0x00ff) | (((value & 0x3f) as u16) << 8);
+        }
+        self.w = !self.w;
+    }
+
+    /// $2007 access: step to the next byte, across or down
+    pub fn increment(&mut self, step: u16) {
+        self.v = self.v.wrapping_add(step) & 0x7fff;
+    }
+
+    /// Move to the next tile column, into the next nametable after column 31
+    pub fn increment_x(&mut self) {
+        if self.v & 0x001f == 31 {
+            self.v &= !0x001f;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    /// Move to the next pixel row. Row 29 is the last one of a nametable,
+    /// rows 30 and 31 wrap without switching to the next nametable.
+    pub fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03e0) >> 5;
+        match coarse_y {
+            29 => {
+                coarse_y = 0;
+                self.v ^= 0x0800;
+            }
+            31 => coarse_y = 0,
+            _ => coarse_y += 1,
+        }
+        self.v = (self.v & !0x03e0) | (coarse_y << 5);
+    }
+
+    /// Start a scanline at the X scroll in `t`
+    pub fn copy_horizontal(&mut self) {
+        self.v = (self.v & !0x041f) | (self.t & 0x041f);
+    }
+
+    /// Start a frame at the Y scroll in `t`
+    pub fn copy_vertical(&mut self) {
+        self.v = (self.v & !0x7be0) | (self.t & 0x7be0);
+    }
+
+    /// Address of the nametable entry of the tile at `v`
+    #[must_use]
+    pub fn tile_addr(&self) -> u16 {
+        0x2000 | (self.v & 0x0fff)
+    }
+
+    /// Address of the attribute byte covering the tile at `v`
+    #[must_use]
+    pub fn attribute_addr(&self) -> u16 {
+        0x23c0 | (self.v & 0x0c00) | ((self.v >> 4) & 0x38) | ((self.v >> 2) & 0x07)
+    }
+
+    #[must_use]
+    pub fn fine_y(&self) -> u16 {
+        (self.v >> 12) & 0x07
+    }
+}
+
+impl Savestate for ScrollRegister {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.v);
+        state.write_u16(self.t);
+        state.write_u8(self.fine_x);
+        state.write_bool(self.w);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.v = state.read_u16()?;
+        self.t = state.read_u16()?;
+        self.fine_x = state.read_u8()?;
+        self.w = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scroll_writes() {
+        // The example from the nesdev wiki: $2000, $2005 twice and $2006 twice
+        let mut scroll = ScrollRegister::default();
+        scroll.write_nametable(0x00);
+        scroll.write_scroll(0x7d);
+        assert_eq!((scroll.t, scroll.fine_x, scroll.w), (0x000f, 0x05, true));
+        scroll.write_scroll(0x5e);
+        assert_eq!((scroll.t, scroll.w), (0x616f, false));
+        scroll.write_addr(0x3d);
+        assert_eq!(scroll.t, 0x3d6f);
+        scroll.write_addr(0xf0);
+        assert_eq!((scroll.t, scroll.v), (0x3df0, 0x3df0));
+    }
+
+    #[test]
+    fn test_increments() {
+        let mut scroll = ScrollRegister {
+            v: 0x001f,
+            ..Default::default()
+        };
+        scroll.increment_x();
+        assert_eq!(scroll.v, 0x0400);
+
+        // Fine Y 7 of row 29 moves to the top of the nametable below
+        scroll.v = 0x73a0;
+        scroll.increment_y();
+        assert_eq!(scroll.v, 0x0800);
+        scroll.v = 0x73e0;
+        scroll.increment_y();
+        assert_eq!(scroll.v, 0x0000);
+    }
+}
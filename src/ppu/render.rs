@@ -0,0 +1,267 @@
+use anyhow::Result;
+
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+use super::registers::PpuMaskRegister;
+use super::{
+    Ppu, PRE_RENDER_SCANLINE, SCREEN_HEIGHT, SCREEN_WIDTH, SPRITES_PER_SCANLINE,
+    SPRITE_OVERFLOW_FLAG, SPRITE_ZERO_HIT_FLAG, SYSTEM_PALETTE,
+};
+
+/// The pixels of one sprite on the current scanline, fetched when the line starts
+#[derive(Debug, Clone, Default)]
+pub struct SpriteRow {
+    x: u8,
+    /// Low and high bit planes, already flipped horizontally if the sprite is
+    low: u8,
+    high: u8,
+    attributes: u8,
+    sprite_zero: bool,
+}
+
+impl SpriteRow {
+    /// Palette entry (1-3) at screen column `x`, 0 where the sprite is transparent or absent
+    fn pixel(&self, x: u16) -> u8 {
+        let Some(column) = x.checked_sub(self.x as u16).filter(|&column| column < 8) else {
+            return 0;
+        };
+        let bit = 7 - column;
+        (((self.high >> bit) & 1) << 1) | ((self.low >> bit) & 1)
+    }
+
+    fn palette(&self) -> u8 {
+        self.attributes & 0b11
+    }
+
+    fn behind_background(&self) -> bool {
+        self.attributes & 0x20 != 0
+    }
+}
+
+// The picture is drawn one pixel per dot on dots 1-256 of the visible scanlines. Instead of
+// the PPU's shift registers and prefetches, each pixel is looked up at `v`, which moves on a
+// tile once its last pixel is drawn. Sprites are found and fetched when a scanline starts.
+// reference: https://www.nesdev.org/wiki/PPU_rendering
+impl Ppu {
+    /// Do the rendering work of the dot the beam just moved to
+    pub(super) fn render_dot(&mut self) {
+        let visible = (self.scanline as usize) < SCREEN_HEIGHT;
+        if !visible && self.scanline != PRE_RENDER_SCANLINE {
+            return;
+        }
+        if visible && (1..=SCREEN_WIDTH as u16).contains(&self.dot) {
+            if self.dot == 1 {
+                self.evaluate_sprites();
+            }
+            self.draw_pixel(self.dot - 1);
+        }
+        if !self.is_rendering_enabled() {
+            return;
+        }
+        match self.dot {
+            256 => self.registers.scroll.increment_y(),
+            257 => self.registers.scroll.copy_horizontal(),
+            280..=304 if self.scanline == PRE_RENDER_SCANLINE => {
+                self.registers.scroll.copy_vertical()
+            }
+            _ => {}
+        }
+    }
+
+    /// Fetch the sprites on the current scanline, at most 8 of them in OAM order
+    fn evaluate_sprites(&mut self) {
+        self.sprites.clear();
+        if !self.is_rendering_enabled() {
+            return;
+        }
+        let height = self.registers.ppuctrl.spr_height() as i16;
+        for index in 0..self.oam.len() / 4 {
+            let [y, tile, attributes, x] = [0, 1, 2, 3].map(|i| self.oam[index * 4 + i]);
+            // Sprites are drawn one line below their Y coordinate
+            let row = self.scanline as i16 - y as i16 - 1;
+            if !(0..height).contains(&row) {
+                continue;
+            }
+            if self.sprites.len() == SPRITES_PER_SCANLINE {
+                self.registers.ppustatus |= SPRITE_OVERFLOW_FLAG;
+                break;
+            }
+            let row = if attributes & 0x80 != 0 {
+                height - 1 - row
+            } else {
+                row
+            } as u16;
+            let addr = if height == 16 {
+                // 8x16 sprites pick the pattern table with bit 0 of the tile number
+                let table = (tile as u16 & 1) * 0x1000;
+                table + ((tile & 0xfe) as u16 + row / 8) * 16 + row % 8
+            } else {
+                self.registers.ppuctrl.spr_select() + tile as u16 * 16 + row
+            };
+            let (mut low, mut high) = (self.vram_read(addr), self.vram_read(addr + 8));
+            if attributes & 0x40 != 0 {
+                low = low.reverse_bits();
+                high = high.reverse_bits();
+            }
+            self.sprites.push(SpriteRow {
+                x,
+                low,
+                high,
+                attributes,
+                sprite_zero: index == 0,
+            });
+        }
+    }
+
+    /// Palette (0-3) and entry (0-3) of the background pixel at `v`, then move `v` past it
+    fn background_pixel(&mut self, x: u16) -> (u8, u8) {
+        let scroll = &self.registers.scroll;
+        let column = (scroll.fine_x as u16 + x) % 8;
+        let tile = self.vram_read(scroll.tile_addr()) as u16;
+        // Each attribute byte covers 4x4 tiles, two bits for each 2x2 quadrant
+        let shift = ((scroll.v >> 4) & 0x04) | (scroll.v & 0x02);
+        let palette = (self.vram_read(scroll.attribute_addr()) >> shift) & 0b11;
+        let addr = self.registers.ppuctrl.bg_select() + tile * 16 + scroll.fine_y();
+        let bit = 7 - column;
+        let pixel =
+            (((self.vram_read(addr + 8) >> bit) & 1) << 1) | ((self.vram_read(addr) >> bit) & 1);
+        if column == 7 {
+            self.registers.scroll.increment_x();
+        }
+        (palette, pixel)
+    }
+
+    fn draw_pixel(&mut self, x: u16) {
+        let mask = self.registers.ppumask.clone();
+        let (background_palette, background) = match self.is_rendering_enabled() {
+            true => self.background_pixel(x),
+            false => (0, 0),
+        };
+        let background = match mask.contains(PpuMaskRegister::SHOW_BG)
+            && (x >= 8 || mask.contains(PpuMaskRegister::SHOW_BG_LEFTMOST))
+        {
+            true => background,
+            false => 0,
+        };
+        let show_sprites = mask.contains(PpuMaskRegister::SHOW_SPIRTES)
+            && (x >= 8 || mask.contains(PpuMaskRegister::SHOW_SPIRTE_LEFTMOST));
+        let sprite = self
+            .sprites
+            .iter()
+            .find(|sprite| show_sprites && sprite.pixel(x) != 0);
+
+        let addr = match sprite {
+            Some(sprite) => {
+                if sprite.sprite_zero && background != 0 && x != 255 {
+                    self.registers.ppustatus |= SPRITE_ZERO_HIT_FLAG;
+                }
+                match background != 0 && sprite.behind_background() {
+                    true => 0x3f00 + background_palette as u16 * 4 + background as u16,
+                    false => 0x3f10 + sprite.palette() as u16 * 4 + sprite.pixel(x) as u16,
+                }
+            }
+            None if background != 0 => 0x3f00 + background_palette as u16 * 4 + background as u16,
+            None => 0x3f00,
+        };
+        let mut color = self.vram_read(addr) & 0x3f;
+        if mask.contains(PpuMaskRegister::GREYSCALE) {
+            color &= 0x30;
+        }
+        let i = (self.scanline as usize * SCREEN_WIDTH + x as usize) * 3;
+        self.framebuffer[i..i + 3].copy_from_slice(&SYSTEM_PALETTE[color as usize]);
+    }
+}
+
+impl Savestate for SpriteRow {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.x);
+        state.write_u8(self.low);
+        state.write_u8(self.high);
+        state.write_u8(self.attributes);
+        state.write_bool(self.sprite_zero);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.x = state.read_u8()?;
+        self.low = state.read_u8()?;
+        self.high = state.read_u8()?;
+        self.attributes = state.read_u8()?;
+        self.sprite_zero = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::nes::NesEmulator;
+    use crate::test_util::nes_with_white_tile;
+
+    /// Run until the beam reaches `scanline`
+    fn run_to_scanline(nes: &mut NesEmulator, scanline: u16) {
+        while nes.cpu.ppu.scanline != scanline {
+            nes.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_background() {
+        let mut nes = nes_with_white_tile();
+        nes.run_frame().unwrap();
+        nes.run_frame().unwrap();
+        let ppu = &nes.cpu.ppu;
+        assert_eq!(ppu.brightness(96, 96), 0xff);
+        assert_eq!(ppu.brightness(103, 103), 0xff);
+        for (x, y) in [(95, 100), (104, 100), (100, 95), (100, 104)] {
+            assert!(ppu.brightness(x, y) < 0x10, "{}, {}", x, y);
+        }
+    }
+
+    #[test]
+    fn test_scroll() {
+        let mut nes = nes_with_white_tile();
+        nes.run_frame().unwrap();
+        // Scrolling 4 pixels right and 2 down moves the tile up and to the left
+        let ppu = &mut nes.cpu.ppu;
+        ppu.write_register(0x2005, 4);
+        ppu.write_register(0x2005, 2);
+        nes.run_frame().unwrap();
+        nes.run_frame().unwrap();
+        let ppu = &nes.cpu.ppu;
+        assert_eq!(ppu.brightness(92, 94), 0xff);
+        assert_eq!(ppu.brightness(99, 101), 0xff);
+        assert!(ppu.brightness(100, 100) < 0x10);
+        assert!(ppu.brightness(96, 102) < 0x10);
+    }
+
+    #[test]
+    fn test_sprites() {
+        let mut nes = nes_with_white_tile();
+        nes.run_frame().unwrap();
+        let ppu = &mut nes.cpu.ppu;
+        // Sprite 0 over the corner of the tile, and sprite 1 drawn flipped from a tile
+        // with only its right half set
+        ppu.oam[..8].copy_from_slice(&[99, 1, 0x00, 100, 149, 2, 0x40, 150]);
+        ppu.mapper[0x20..0x30].copy_from_slice(&[0x0f; 16]);
+        ppu.vram_write(0x3f13, 0x30);
+        ppu.write_register(0x2001, 0x1e);
+        run_to_scanline(&mut nes, 120);
+        assert_ne!(nes.cpu.ppu.registers.ppustatus & 0x40, 0);
+        run_to_scanline(&mut nes, 200);
+        let ppu = &nes.cpu.ppu;
+        assert_eq!(ppu.brightness(107, 107), 0xff);
+        assert!(ppu.brightness(108, 107) < 0x10);
+        assert_eq!(ppu.brightness(150, 150), 0xff);
+        assert!(ppu.brightness(154, 150) < 0x10);
+    }
+
+    #[test]
+    fn test_sprite_zero_needs_background() {
+        let mut nes = nes_with_white_tile();
+        nes.run_frame().unwrap();
+        let ppu = &mut nes.cpu.ppu;
+        ppu.oam[..4].copy_from_slice(&[49, 1, 0x00, 50]);
+        ppu.write_register(0x2001, 0x1e);
+        run_to_scanline(&mut nes, 200);
+        assert_eq!(nes.cpu.ppu.registers.ppustatus & 0x40, 0);
+    }
+}
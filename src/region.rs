@@ -0,0 +1,21 @@
+/// The TV system the console was built for.
+/// NTSC and PAL consoles run from different master clocks, so a few of the
+/// APU lookup tables (noise and DMC periods, frame counter steps) differ.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+}
+
+impl Region {
+    /// CPU clock rate in Hz
+    #[inline]
+    #[must_use]
+    pub const fn cpu_clock_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc => 1_789_773.0,
+            Region::Pal => 1_662_607.0,
+        }
+    }
+}
@@ -0,0 +1,886 @@
+use std::io::{BufRead, Write};
+
+use anyhow::{bail, Context, Result};
+
+use crate::cpu::{
+    call_stack, disassemble_one, Cpu6502, DebugStop, DisassembledInstruction, Expression, WatchKind,
+};
+use crate::nes::NesEmulator;
+use crate::util::parse_hex_u16;
+
+const OPCODE_JSR: u8 = 0x20;
+const OPCODE_RTS: u8 = 0x60;
+const OPCODE_RTI: u8 = 0x40;
+
+/// Instructions shown by `dis` when no count is given
+const DISASSEMBLY_LINES: usize = 10;
+/// Bytes shown by `mem` when no length is given
+const MEMORY_DUMP_LENGTH: u16 = 64;
+/// Instructions `continue`, `next` and `finish` run before giving the prompt back,
+/// so a program that never stops doesn't hang the debugger
+const RUN_LIMIT: usize = 1_000_000;
+
+const HELP: &str = "\
+step [n]              execute n instructions (s)
+next                  step over a JSR (n)
+finish                run until the current subroutine returns (out)
+continue [n]          run until a breakpoint or watchpoint, at most n instructions (c)
+break <addr> [if <cond>]
+                      stop before the instruction at addr (b)
+watch <start>[-<end>] [rwx] [if <cond>]
+                      stop on reads, writes or execution in a range (w)
+condition <id> [cond] change or remove the condition of a breakpoint or watchpoint
+ignore <id> <n>       let the next n hits of a breakpoint or watchpoint pass
+delete <id>           remove a breakpoint or watchpoint (d)
+enable <id>, disable <id>
+info                  list breakpoints and watchpoints with their hits (i)
+print <expr>          evaluate an expression (p)
+regs                  show the registers (r)
+set <reg|flag> <value>
+                      change A, X, Y, SP, PC, P or one of the flags N V D I Z C
+mem <addr> [len]      dump memory with ASCII (x)
+write <addr> <bytes>  write bytes to memory
+dis [addr] [n]        disassemble around PC or from addr (l)
+backtrace             show the subroutine calls on the stack (bt)
+rewind [frames]       go back to the snapshot at least that many frames ago
+help                  show this help (h)
+quit                  leave the debugger (q)
+An empty line repeats the last command. Addresses can be given by label.
+Running commands return to the prompt after 1000000 instructions by default.
+
+Expressions use registers A X Y SP P PC, flags N V D I Z C, SCANLINE, DOT, FRAME,
+CYCLES, and VALUE and ADDRESS of the access that hit a watchpoint. [addr] reads a
+byte, {addr} a word, labels are their address. A label spelled exactly like one of
+these, e.g. c or frame, hides it; C or FRAME still work. Operators: ! ~ - * / % + - << >> & ^ | == != < <= > >= && ||
+e.g. break $8005 if X == 2 && [$0300] & $80";
+
+/// Interactive debugger reading commands from `input` and answering on `output`
+pub struct DebuggerRepl<'a> {
+    nes: &'a mut NesEmulator,
+    last_command: Option<String>,
+}
+
+impl<'a> DebuggerRepl<'a> {
+    pub fn new(nes: &'a mut NesEmulator) -> Self {
+        Self {
+            nes,
+            last_command: None,
+        }
+    }
+
+    /// Read commands until `quit` or the end of the input
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, output: &mut W) -> Result<()> {
+        self.show_location(output)?;
+        let mut lines = input.lines();
+        loop {
+            match self.nes.cpu.label(self.nes.cpu.registers.pc) {
+                Some(label) => write!(output, "(nes {}) ", label)?,
+                None => write!(output, "(nes) ")?,
+            }
+            output.flush()?;
+            let Some(line) = lines.next() else {
+                writeln!(output)?;
+                return Ok(());
+            };
+            let line = line?;
+            let line = match line.trim() {
+                "" => match self.last_command.clone() {
+                    Some(last) => last,
+                    None => continue,
+                },
+                line => line.to_string(),
+            };
+            self.last_command = Some(line.clone());
+            match self.execute(&line, output) {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(error) => writeln!(output, "error: {:#}", error)?,
+            }
+        }
+    }
+
+    /// Run one command. Returns false when the debugger should exit.
+    pub fn execute<W: Write>(&mut self, line: &str, output: &mut W) -> Result<bool> {
+        let (line, condition) = match line.split_once(" if ") {
+            Some((line, condition)) => (line, Some(self.parse_expression(condition)?)),
+            None => (line, None),
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = words.split_first() else {
+            return Ok(true);
+        };
+        if condition.is_some() && !matches!(command, "break" | "b" | "watch" | "w") {
+            bail!("only break and watch take a condition");
+        }
+        match command {
+            "step" | "s" => {
+                let count = parse_count(args.first(), 1)?;
+                let stop = self.step(count)?;
+                self.report(stop, output)?;
+            }
+            "next" | "n" => {
+                let stop = self.step_over()?;
+                self.report_run(stop, RUN_LIMIT, output)?;
+            }
+            "finish" | "out" => {
+                let stop = self.step_out()?;
+                self.report_run(stop, RUN_LIMIT, output)?;
+            }
+            "continue" | "c" => {
+                let limit = parse_count(args.first(), RUN_LIMIT)?;
+                let stop = self.run_until(limit, |_, _| false)?;
+                self.report_run(stop, limit, output)?;
+            }
+            "break" | "b" => {
+                let address = parse_address(&self.nes.cpu, args.first())?;
+                let debugger = &mut self.nes.cpu.debugger;
+                let id = debugger.add_breakpoint(address);
+                debugger.set_condition(id, condition);
+                writeln!(output, "breakpoint {} at ${:04X}", id, address)?;
+            }
+            "watch" | "w" => {
+                let (start, end) = parse_range(&self.nes.cpu, args.first())?;
+                let kind = parse_watch_kind(args.get(1).copied())?;
+                let debugger = &mut self.nes.cpu.debugger;
+                let id = debugger.add_watchpoint(start, end, kind);
+                debugger.set_condition(id, condition);
+                writeln!(
+                    output,
+                    "watchpoint {} on ${:04X}-${:04X} {}",
+                    id,
+                    start.min(end),
+                    start.max(end),
+                    watch_kind_name(kind)
+                )?;
+            }
+            "delete" | "d" => {
+                let id = parse_count(args.first(), 0)?;
+                if !self.nes.cpu.debugger.remove(id) {
+                    bail!("no breakpoint or watchpoint {}", id);
+                }
+            }
+            "condition" => {
+                let id = parse_count(args.first(), 0)?;
+                let condition = match args.get(1..) {
+                    Some(rest) if !rest.is_empty() => Some(self.parse_expression(&rest.join(" "))?),
+                    _ => None,
+                };
+                if !self.nes.cpu.debugger.set_condition(id, condition) {
+                    bail!("no breakpoint or watchpoint {}", id);
+                }
+            }
+            "ignore" => {
+                let id = parse_count(args.first(), 0)?;
+                let count = parse_count(args.get(1), 0)? as u64;
+                if !self.nes.cpu.debugger.set_ignore(id, count) {
+                    bail!("no breakpoint or watchpoint {}", id);
+                }
+            }
+            "print" | "p" => {
+                let expression = self.parse_expression(&args.join(" "))?;
+                let value = expression.evaluate(&self.nes.expression_context())?;
+                writeln!(output, "{} = ${:X} ({})", expression, value, value)?;
+            }
+            "enable" | "disable" => {
+                let id = parse_count(args.first(), 0)?;
+                self.set_enabled(id, command == "enable")?;
+            }
+            "info" | "i" => self.show_points(output)?,
+            "regs" | "r" => writeln!(output, "{}", registers_line(&self.nes.cpu))?,
+            "set" => {
+                let (Some(name), Some(value)) = (args.first(), args.get(1)) else {
+                    bail!("usage: set <reg|flag> <value>");
+                };
+                set_register(&mut self.nes.cpu, name, value)?;
+                writeln!(output, "{}", registers_line(&self.nes.cpu))?;
+            }
+            "mem" | "x" => {
+                let address = parse_address(&self.nes.cpu, args.first())?;
+                let length = match args.get(1) {
+                    Some(length) => parse_count(Some(length), 0)? as u16,
+                    None => MEMORY_DUMP_LENGTH,
+                };
+                self.dump_memory(address, length, output)?;
+            }
+            "write" => {
+                let address = parse_address(&self.nes.cpu, args.first())?;
+                if args.len() < 2 {
+                    bail!("usage: write <addr> <bytes>");
+                }
+                for (i, byte) in args[1..].iter().enumerate() {
+                    let value = parse_byte(byte)?;
+                    self.nes.cpu.poke(address.wrapping_add(i as u16), value);
+                }
+            }
+            "dis" | "l" => {
+                let count = parse_count(args.get(1), DISASSEMBLY_LINES)?;
+                match args.first() {
+                    Some(address) => {
+                        let address = parse_address(&self.nes.cpu, Some(address))?;
+                        for line in disassemble_from(&self.nes.cpu, address, count) {
+                            self.write_disassembly(&line, output)?;
+                        }
+                    }
+                    None => {
+                        for line in disassemble_around(&self.nes.cpu, count) {
+                            self.write_disassembly(&line, output)?;
+                        }
+                    }
+                }
+            }
+            "backtrace" | "bt" => self.show_call_stack(output)?,
+            "rewind" => {
+                let frames = parse_count(args.first(), 1)? as u64;
+                let rewound = self.nes.rewind(frames)?;
+                writeln!(
+                    output,
+                    "rewound {} frames to frame {}",
+                    rewound, self.nes.cpu.ppu.frame
+                )?;
+                self.show_location(output)?;
+            }
+            "help" | "h" | "?" => writeln!(output, "{}", HELP)?,
+            "quit" | "q" => return Ok(false),
+            _ => bail!("unknown command '{}', try 'help'", command),
+        }
+        Ok(true)
+    }
+
+    /// Execute `count` instructions, stopping early on a breakpoint or watchpoint
+    pub fn step(&mut self, count: usize) -> Result<DebugStop> {
+        for _ in 0..count {
+            let stop = self.nes.debug_step()?;
+            if stop != DebugStop::Done {
+                return Ok(stop);
+            }
+        }
+        Ok(DebugStop::Done)
+    }
+
+    /// Run until `done` holds after an instruction, or a breakpoint or watchpoint hits.
+    /// `done` gets the opcode of the instruction that just executed.
+    /// None if `max_instructions` ran out first.
+    fn run_until(
+        &mut self,
+        max_instructions: usize,
+        mut done: impl FnMut(&mut Self, u8) -> bool,
+    ) -> Result<Option<DebugStop>> {
+        for _ in 0..max_instructions {
+            let opcode = self.nes.cpu.peek(self.nes.cpu.registers.pc);
+            let stop = self.nes.debug_step()?;
+            if stop != DebugStop::Done || done(self, opcode) {
+                return Ok(Some(stop));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Step, running a JSR until it returns to the next instruction.
+    /// None if it didn't return within the run limit.
+    pub fn step_over(&mut self) -> Result<Option<DebugStop>> {
+        let cpu = &self.nes.cpu;
+        if cpu.peek(cpu.registers.pc) != OPCODE_JSR {
+            return self.step(1).map(Some);
+        }
+        let (return_address, sp) = (cpu.registers.pc.wrapping_add(3), cpu.registers.sp);
+        self.run_until(RUN_LIMIT, |repl, _| {
+            let registers = &repl.nes.cpu.registers;
+            registers.pc == return_address && registers.sp == sp
+        })
+    }
+
+    /// Run until an RTS or RTI pops the stack above where it is now.
+    /// None if it didn't return within the run limit.
+    pub fn step_out(&mut self) -> Result<Option<DebugStop>> {
+        let sp = self.nes.cpu.registers.sp;
+        self.run_until(RUN_LIMIT, |repl, opcode| {
+            matches!(opcode, OPCODE_RTS | OPCODE_RTI) && repl.nes.cpu.registers.sp > sp
+        })
+    }
+
+    fn parse_expression(&self, source: &str) -> Result<Expression> {
+        Expression::parse_with_symbols(source, &self.nes.cpu.symbols)
+    }
+
+    // #0  $800E  store+3
+    // #1  $8002  main+2  JSR store
+    fn show_call_stack<W: Write>(&self, output: &mut W) -> Result<()> {
+        let cpu = &self.nes.cpu;
+        let name = |address: u16| {
+            cpu.label(address)
+                .unwrap_or_else(|| format!("${:04X}", address))
+        };
+        let pc = cpu.registers.pc;
+        writeln!(output, "#0  ${:04X}  {}", pc, name(pc))?;
+        for (depth, frame) in call_stack(cpu).iter().enumerate() {
+            writeln!(
+                output,
+                "#{}  ${:04X}  {}  JSR {}",
+                depth + 1,
+                frame.call_site,
+                name(frame.call_site),
+                name(frame.target)
+            )?;
+        }
+        Ok(())
+    }
+
+    fn set_enabled(&mut self, id: usize, enabled: bool) -> Result<()> {
+        let debugger = &mut self.nes.cpu.debugger;
+        if let Some(breakpoint) = debugger.breakpoints.iter_mut().find(|b| b.id == id) {
+            breakpoint.enabled = enabled;
+        } else if let Some(watchpoint) = debugger.watchpoints.iter_mut().find(|w| w.id == id) {
+            watchpoint.enabled = enabled;
+        } else {
+            bail!("no breakpoint or watchpoint {}", id);
+        }
+        Ok(())
+    }
+
+    fn report<W: Write>(&self, stop: DebugStop, output: &mut W) -> Result<()> {
+        match stop {
+            DebugStop::Done => {}
+            DebugStop::Break(reason) => writeln!(output, "stopped: {}", reason)?,
+            DebugStop::Halted => {
+                writeln!(output, "stopped: BRK at ${:04X}", self.nes.cpu.registers.pc)?
+            }
+        }
+        self.show_location(output)
+    }
+
+    /// Report a run that may have given up after `limit` instructions
+    fn report_run<W: Write>(
+        &self,
+        stop: Option<DebugStop>,
+        limit: usize,
+        output: &mut W,
+    ) -> Result<()> {
+        match stop {
+            Some(stop) => self.report(stop, output),
+            None => {
+                writeln!(output, "stopped: nothing hit in {} instructions", limit)?;
+                self.show_location(output)
+            }
+        }
+    }
+
+    fn show_location<W: Write>(&self, output: &mut W) -> Result<()> {
+        let line = disassemble_from(&self.nes.cpu, self.nes.cpu.registers.pc, 1).remove(0);
+        self.write_disassembly(&line, output)?;
+        writeln!(output, "{}", registers_line(&self.nes.cpu))?;
+        Ok(())
+    }
+
+    fn show_points<W: Write>(&self, output: &mut W) -> Result<()> {
+        let debugger = &self.nes.cpu.debugger;
+        if debugger.breakpoints.is_empty() && debugger.watchpoints.is_empty() {
+            writeln!(output, "no breakpoints or watchpoints")?;
+        }
+        for breakpoint in &debugger.breakpoints {
+            writeln!(
+                output,
+                "{:>3}  break  ${:04X}{}",
+                breakpoint.id,
+                breakpoint.address,
+                point_details(
+                    breakpoint.enabled,
+                    breakpoint.condition.as_ref(),
+                    breakpoint.hits,
+                    breakpoint.ignore
+                )
+            )?;
+        }
+        for watchpoint in &debugger.watchpoints {
+            writeln!(
+                output,
+                "{:>3}  watch  ${:04X}-${:04X} {}{}",
+                watchpoint.id,
+                watchpoint.start,
+                watchpoint.end,
+                watch_kind_name(watchpoint.kind),
+                point_details(
+                    watchpoint.enabled,
+                    watchpoint.condition.as_ref(),
+                    watchpoint.hits,
+                    watchpoint.ignore
+                )
+            )?;
+        }
+        Ok(())
+    }
+
+    // $0300  48 45 4C 4C 4F 00 00 00 00 00 00 00 00 00 00 00  HELLO...........
+    fn dump_memory<W: Write>(&self, address: u16, length: u16, output: &mut W) -> Result<()> {
+        let end = address as u32 + length as u32;
+        let mut row = address as u32;
+        while row < end {
+            let bytes: Vec<u8> = (row..end.min(row + 16))
+                .map(|addr| self.nes.cpu.peek(addr as u16))
+                .collect();
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let ascii: String = bytes
+                .iter()
+                .map(|&b| match b {
+                    0x20..=0x7e => b as char,
+                    _ => '.',
+                })
+                .collect();
+            writeln!(output, "${:04X}  {:<47}  {}", row, hex.join(" "), ascii)?;
+            row += 16;
+        }
+        Ok(())
+    }
+
+    fn write_disassembly<W: Write>(
+        &self,
+        line: &DisassembledInstruction,
+        output: &mut W,
+    ) -> Result<()> {
+        let cpu = &self.nes.cpu;
+        let marker = if line.address == cpu.registers.pc {
+            "=>"
+        } else if cpu
+            .debugger
+            .breakpoints
+            .iter()
+            .any(|breakpoint| breakpoint.enabled && breakpoint.address == line.address)
+        {
+            " *"
+        } else {
+            "  "
+        };
+        if let Some(label) = cpu.label(line.address).filter(|label| !label.contains('+')) {
+            writeln!(output, "   {}:", label)?;
+        }
+        writeln!(
+            output,
+            "{} {}",
+            marker,
+            line.line_with(|address| cpu.label(address))
+        )?;
+        Ok(())
+    }
+}
+
+/// Registers and flags in one line, flags that are set in upper case
+#[must_use]
+pub fn registers_line(cpu: &Cpu6502) -> String {
+    let registers = &cpu.registers;
+    let flags: String = [
+        ('n', registers.negative),
+        ('v', registers.overflow),
+        ('-', false),
+        ('-', false),
+        ('d', registers.decimal),
+        ('i', registers.interrupt_disabled),
+        ('z', registers.zero),
+        ('c', registers.carry),
+    ]
+    .iter()
+    .map(|&(flag, set)| if set { flag.to_ascii_uppercase() } else { flag })
+    .collect();
+    format!(
+        "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X} {} CYC:{}",
+        registers.a,
+        registers.x,
+        registers.y,
+        cpu.status_register_byte(true),
+        registers.sp,
+        registers.pc,
+        flags,
+        cpu.cycles
+    )
+}
+
+// "  if X == 2  hits 3  ignore next 1  (disabled)"
+fn point_details(enabled: bool, condition: Option<&Expression>, hits: u64, ignore: u64) -> String {
+    let mut details = String::new();
+    if let Some(condition) = condition {
+        details += &format!("  if {}", condition);
+    }
+    if hits > 0 {
+        details += &format!("  hits {}", hits);
+    }
+    if ignore > hits {
+        details += &format!("  ignore next {}", ignore - hits);
+    }
+    if !enabled {
+        details += "  (disabled)";
+    }
+    details
+}
+
+fn disassemble_from(cpu: &Cpu6502, address: u16, count: usize) -> Vec<DisassembledInstruction> {
+    let mut lines = Vec::with_capacity(count);
+    let mut address = address;
+    for _ in 0..count {
+        let bytes: Vec<u8> = (0..3).map(|i| cpu.peek(address.wrapping_add(i))).collect();
+        let line = disassemble_one(&bytes, address);
+        address = address.wrapping_add(line.bytes.len() as u16);
+        lines.push(line);
+    }
+    lines
+}
+
+/// Disassemble `count` instructions with PC in the middle. There is no telling where the
+/// instructions before PC begin, so they come from the nearest start that decodes into PC.
+fn disassemble_around(cpu: &Cpu6502, count: usize) -> Vec<DisassembledInstruction> {
+    let pc = cpu.registers.pc;
+    let before = count / 2;
+    let mut lines = Vec::new();
+    for distance in 1..=(before as u16 * 3) {
+        let start = pc.wrapping_sub(distance);
+        let mut address = start;
+        let mut decoded = Vec::new();
+        while address.wrapping_sub(start) < distance {
+            let line = disassemble_from(cpu, address, 1).remove(0);
+            address = address.wrapping_add(line.bytes.len() as u16);
+            decoded.push(line);
+        }
+        let aligned = address == pc && decoded.iter().all(|line| line.operation.is_some());
+        if aligned && decoded.len() > lines.len() {
+            lines = decoded;
+            if lines.len() >= before {
+                break;
+            }
+        }
+    }
+    let skip = lines.len().saturating_sub(before);
+    lines.drain(..skip);
+    let after = count - lines.len();
+    lines.extend(disassemble_from(cpu, pc, after));
+    lines
+}
+
+/// A label or a hex address
+fn parse_address(cpu: &Cpu6502, arg: Option<&&str>) -> Result<u16> {
+    let Some(arg) = arg else {
+        bail!("missing address");
+    };
+    match cpu.symbol_address(arg) {
+        Some(address) => Ok(address),
+        None => parse_hex_u16(arg),
+    }
+}
+
+/// Parse `$0300`, `$0300-$03ff` or labels, which cover their size
+fn parse_range(cpu: &Cpu6502, arg: Option<&&str>) -> Result<(u16, u16)> {
+    let Some(arg) = arg else {
+        bail!("missing address range");
+    };
+    match arg.split_once('-') {
+        Some((start, end)) => Ok((
+            parse_address(cpu, Some(&start))?,
+            parse_address(cpu, Some(&end))?,
+        )),
+        None => {
+            let address = parse_address(cpu, Some(arg))?;
+            // A label covers all of its bytes, e.g. a buffer from a .dbg or .mlb
+            let size = cpu.symbols.named(arg).map(|s| s.size).max().unwrap_or(1);
+            Ok((address, address.wrapping_add(size.max(1) - 1)))
+        }
+    }
+}
+
+fn parse_count(arg: Option<&&str>, default: usize) -> Result<usize> {
+    match arg {
+        Some(arg) => arg
+            .parse()
+            .with_context(|| format!("'{}' is not a number", arg)),
+        None => Ok(default),
+    }
+}
+
+fn parse_byte(arg: &str) -> Result<u8> {
+    let value = parse_hex_u16(arg)?;
+    u8::try_from(value).with_context(|| format!("'{}' does not fit in a byte", arg))
+}
+
+/// `r`, `w`, `x` or any combination, read and write when not given
+fn parse_watch_kind(arg: Option<&str>) -> Result<WatchKind> {
+    let Some(arg) = arg else {
+        return Ok(WatchKind::READ | WatchKind::WRITE);
+    };
+    let mut kind = WatchKind::empty();
+    for c in arg.chars() {
+        kind |= match c {
+            'r' => WatchKind::READ,
+            'w' => WatchKind::WRITE,
+            'x' => WatchKind::EXECUTE,
+            _ => bail!("'{}' is not a watch kind, use r, w and x", arg),
+        };
+    }
+    Ok(kind)
+}
+
+fn watch_kind_name(kind: WatchKind) -> String {
+    [
+        (WatchKind::READ, 'r'),
+        (WatchKind::WRITE, 'w'),
+        (WatchKind::EXECUTE, 'x'),
+    ]
+    .iter()
+    .filter(|(flag, _)| kind.contains(*flag))
+    .map(|&(_, c)| c)
+    .collect()
+}
+
+fn set_register(cpu: &mut Cpu6502, name: &str, value: &str) -> Result<()> {
+    let registers = &mut cpu.registers;
+    let flag = || -> Result<bool> {
+        match value {
+            "0" => Ok(false),
+            "1" => Ok(true),
+            _ => bail!("flags are 0 or 1"),
+        }
+    };
+    match name.to_ascii_lowercase().as_str() {
+        "a" => registers.a = parse_byte(value)?,
+        "x" => registers.x = parse_byte(value)?,
+        "y" => registers.y = parse_byte(value)?,
+        "sp" | "s" => registers.sp = parse_byte(value)?,
+        "pc" => registers.pc = parse_hex_u16(value)?,
+        "p" => cpu.set_status_register_from_byte(parse_byte(value)?),
+        "n" => registers.negative = flag()?,
+        "v" => registers.overflow = flag()?,
+        "d" => registers.decimal = flag()?,
+        "i" => registers.interrupt_disabled = flag()?,
+        "z" => registers.zero = flag()?,
+        "c" => registers.carry = flag()?,
+        _ => bail!("unknown register '{}'", name),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::SymbolTable;
+    use crate::test_util::{
+        nes_with_program, nes_with_source, PULSE_TONE_PROGRAM, STORE_LOOP_SOURCE,
+    };
+
+    /// Run the commands against STORE_LOOP_SOURCE and return the transcript
+    fn run_script(nes: &mut NesEmulator, commands: &[&str]) -> String {
+        let mut output = Vec::new();
+        DebuggerRepl::new(nes)
+            .run(commands.join("\n").as_bytes(), &mut output)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    /// Check that the lines appear in the transcript in this order
+    fn assert_in_order(output: &str, expected: &[&str]) {
+        let mut rest = output;
+        for line in expected {
+            let Some(position) = rest.find(line) else {
+                panic!("missing '{}' in:\n{}", line, output);
+            };
+            rest = &rest[position + line.len()..];
+        }
+    }
+
+    #[test]
+    fn test_next_stops_at_breakpoint() {
+        // Stepping over the first JSR, where the breakpoint stops it too
+        let mut nes = nes_with_source(STORE_LOOP_SOURCE);
+        let output = run_script(&mut nes, &["step", "break $8005", "next"]);
+        assert_in_order(
+            &output,
+            &[
+                "(nes) stopped: breakpoint 1 at $8005",
+                "=> $8005  E8        INX",
+                "A:00 X:00 Y:00 P:26 SP:FD PC:8005 nv--dIZc CYC:18",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut nes = nes_with_source(STORE_LOOP_SOURCE);
+        let output = run_script(&mut nes, &["watch $0300 w", "c", "c", "info"]);
+        assert_in_order(
+            &output,
+            &[
+                "(nes) stopped: watchpoint 1: write $0300 = $00",
+                "(nes) stopped: watchpoint 1: write $0300 = $01",
+                "A:00 X:01 Y:00 P:A4 SP:FB PC:800E Nv--dIzc",
+                "(nes)   1  watch  $0300-$0300 w",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_read_watchpoint_ignores_stores() {
+        // STX $0300 only writes, so the program runs to its BRK
+        let mut nes = nes_with_source(STORE_LOOP_SOURCE);
+        let output = run_script(&mut nes, &["watch $0300 r", "c"]);
+        assert_in_order(&output, &["(nes) stopped: BRK at $800A"]);
+    }
+
+    #[test]
+    fn test_step_and_repeat() {
+        // An empty line repeats the last command
+        let mut nes = nes_with_source(STORE_LOOP_SOURCE);
+        let output = run_script(&mut nes, &["step 2", "", "quit"]);
+        assert_in_order(
+            &output,
+            &["=> $800B  8E 00 03  STX $0300", "=> $8005  E8        INX"],
+        );
+    }
+
+    #[test]
+    fn test_finish() {
+        let mut nes = nes_with_source(STORE_LOOP_SOURCE);
+        let output = run_script(&mut nes, &["step 2", "finish"]);
+        assert_in_order(&output, &["=> $800B", "=> $8005  E8        INX"]);
+    }
+
+    #[test]
+    fn test_set_registers_and_next() {
+        // next is a single step outside a JSR and runs through one
+        let mut nes = nes_with_source(STORE_LOOP_SOURCE);
+        let output = run_script(&mut nes, &["set a 42", "set c 1", "next", "next"]);
+        assert_in_order(
+            &output,
+            &[
+                "A:42 X:00 Y:00 P:27 SP:FD PC:8002",
+                "A:42 X:00 Y:00 P:27 SP:FD PC:8005",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_memory_commands() {
+        let mut nes = nes_with_source(STORE_LOOP_SOURCE);
+        let output = run_script(
+            &mut nes,
+            &["write $0300 48 49", "mem $0300 4", "dis $8000 3"],
+        );
+        assert_in_order(
+            &output,
+            &[
+                "(nes) (nes) $0300  48 49 00 00                                      HI..",
+                "=> $8000  A2 00     LDX #$00",
+                "   $8002  20 0B 80  JSR $800B",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_continue_until_brk() {
+        let mut nes = nes_with_source(STORE_LOOP_SOURCE);
+        let output = run_script(&mut nes, &["bogus", "c", "quit"]);
+        assert_in_order(
+            &output,
+            &[
+                "(nes) error: unknown command 'bogus', try 'help'",
+                "(nes) stopped: BRK at $800A",
+            ],
+        );
+        assert_eq!(nes.cpu.registers.x, 3);
+    }
+
+    #[test]
+    fn test_continue_limit() {
+        // A program that never stops gives the prompt back
+        let mut nes = nes_with_source(".org $8000\nloop: JMP loop");
+        let output = run_script(&mut nes, &["continue 100", "c x", "quit"]);
+        assert_in_order(
+            &output,
+            &[
+                "(nes) stopped: nothing hit in 100 instructions",
+                "=> $8000  4C 00 80  JMP $8000",
+                "(nes) error: 'x' is not a number",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_conditions_and_hit_counts() {
+        let mut nes = nes_with_source(STORE_LOOP_SOURCE);
+        let output = run_script(
+            &mut nes,
+            &[
+                "break $800B",
+                "watch $0300 w",
+                "condition 1 A == 1",
+                "ignore 2 4",
+                "break $8005 if [$0300] == 2",
+                "info",
+                "print {$fffc} + X",
+                "step if X",
+                "continue",
+            ],
+        );
+        assert_in_order(
+            &output,
+            &[
+                "(nes)   1  break  $800B  if A == 1",
+                "  3  break  $8005  if [$0300] == 2",
+                "  2  watch  $0300-$0300 w  ignore next 4",
+                "(nes) {$fffc} + X = $8000 (32768)",
+                "(nes) error: only break and watch take a condition",
+                "(nes) stopped: breakpoint 3 at $8005",
+                "A:00 X:02 Y:00 P:A4 SP:FD PC:8005",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut nes = nes_with_source(STORE_LOOP_SOURCE);
+        let mut symbols = SymbolTable::parse_fceux_nl(
+            "$8000#start#\n$8002#loop#\n$800B#store#\n$800E#done#\n",
+            Some(0),
+        )
+        .unwrap();
+        symbols.extend(SymbolTable::parse_fceux_nl("$0300/4#buffer#\n", None).unwrap());
+        nes.cpu.symbols = symbols;
+        nes.cpu.bounded_run(1).unwrap();
+        let output = run_script(
+            &mut nes,
+            &[
+                "break store if buffer == $300 && X == 1",
+                "c",
+                "bt",
+                "print {start} + store",
+                "print done",
+                "watch buffer w",
+                "c",
+            ],
+        );
+        assert_in_order(
+            &output,
+            &[
+                "(nes loop) breakpoint 1 at $800B",
+                "   store:\n=> $800B  8E 00 03  STX buffer\n",
+                "(nes store) #0  $800B  store\n#1  $8002  loop  JSR store\n",
+                "{start} + store = $80AD (32941)",
+                "done = $800E (32782)",
+                "watchpoint 2 on $0300-$0303 w",
+                "stopped: watchpoint 2: write $0300 = $01",
+                "(nes done) ",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut nes = nes_with_program(&PULSE_TONE_PROGRAM);
+        let output = run_script(&mut nes, &["rewind"]);
+        assert!(output.contains("error: Rewind is not enabled"));
+
+        // Snapshots are taken as the debugger steps into each frame
+        nes.enable_rewind(1, usize::MAX);
+        let output = run_script(&mut nes, &["step 20000", "rewind 1", "regs"]);
+        assert_in_order(
+            &output,
+            &[
+                "rewound 1 frames to frame 1",
+                "=> $8014  4C 14 80  JMP $8014",
+            ],
+        );
+        assert_eq!(nes.cpu.ppu.frame, 1);
+    }
+}
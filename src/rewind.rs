@@ -0,0 +1,358 @@
+use std::collections::VecDeque;
+
+use anyhow::{bail, Result};
+
+/// A new keyframe is stored after this many deltas, so deltas stay small
+/// as the machine drifts away from the keyframe
+pub const KEYFRAME_INTERVAL: usize = 30;
+
+/// Memory budget of the rewind buffer the debugger steps back with
+pub const DEFAULT_REWIND_BUDGET: usize = 64 * 1024 * 1024;
+
+/// XOR `state` against `base` (missing base bytes count as 0) and run-length encode the result.
+/// Each run is a varint count of unchanged bytes, a varint count of changed bytes and the changed bytes.
+#[must_use]
+pub fn encode_delta(base: &[u8], state: &[u8]) -> Vec<u8> {
+    let xor = |i: usize| state[i] ^ base.get(i).copied().unwrap_or(0);
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < state.len() {
+        let start = i;
+        while i < state.len() && xor(i) == 0 {
+            i += 1;
+        }
+        write_varint(&mut out, i - start);
+        let start = i;
+        while i < state.len() && xor(i) != 0 {
+            i += 1;
+        }
+        write_varint(&mut out, i - start);
+        out.extend((start..i).map(xor));
+    }
+    out
+}
+
+/// Rebuild a `len` byte state from `base` and a delta made by `encode_delta`
+pub fn decode_delta(base: &[u8], delta: &[u8], len: usize) -> Result<Vec<u8>> {
+    let mut state: Vec<u8> = (0..len)
+        .map(|i| base.get(i).copied().unwrap_or(0))
+        .collect();
+    let mut pos = 0;
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos)?;
+        let changed = read_varint(delta, &mut pos)?;
+        if i + changed > len || pos + changed > delta.len() {
+            bail!("corrupt rewind delta");
+        }
+        for byte in &mut state[i..i + changed] {
+            *byte ^= delta[pos];
+            pos += 1;
+        }
+        i += changed;
+    }
+    Ok(state)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<usize> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let Some(&byte) = data.get(*pos) else {
+            bail!("corrupt rewind delta");
+        };
+        *pos += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+#[derive(Debug)]
+struct Snapshot {
+    /// PPU frame number the snapshot was taken at
+    frame: u64,
+    keyframe: bool,
+    len: usize,
+    /// A keyframe is encoded against zeros, every other snapshot against its keyframe
+    data: Vec<u8>,
+}
+
+/// Ring buffer of compressed savestates taken every `interval` frames.
+/// The oldest snapshots are dropped, a keyframe and its deltas at a time,
+/// once the buffer grows past its memory budget. When the newest keyframe group
+/// alone is over budget its oldest deltas go next, then the group itself.
+#[derive(Debug)]
+pub struct RewindBuffer {
+    pub interval: u64,
+    /// Maximum number of bytes held by the snapshots and the uncompressed keyframe
+    pub budget: usize,
+    snapshots: VecDeque<Snapshot>,
+    /// Uncompressed copy of the newest keyframe, the base for new deltas
+    keyframe: Vec<u8>,
+    deltas_since_keyframe: usize,
+    used: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(interval: u64, budget: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            budget,
+            snapshots: VecDeque::new(),
+            keyframe: Vec::new(),
+            deltas_since_keyframe: 0,
+            used: 0,
+        }
+    }
+
+    /// Whether a snapshot is due at this frame
+    #[must_use]
+    pub fn is_due(&self, frame: u64) -> bool {
+        match self.snapshots.back() {
+            Some(last) => frame >= last.frame + self.interval,
+            None => true,
+        }
+    }
+
+    /// Bytes held by the compressed snapshots and the uncompressed keyframe
+    #[must_use]
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// The frame of the oldest snapshot that can still be restored
+    #[must_use]
+    pub fn oldest_frame(&self) -> Option<u64> {
+        self.snapshots.front().map(|snapshot| snapshot.frame)
+    }
+
+    pub fn push(&mut self, frame: u64, state: &[u8]) {
+        let keyframe = self.snapshots.is_empty()
+            || self.deltas_since_keyframe >= KEYFRAME_INTERVAL
+            || self.keyframe.len() != state.len();
+        let data = if keyframe {
+            self.set_keyframe(state.to_vec());
+            self.deltas_since_keyframe = 0;
+            encode_delta(&[], state)
+        } else {
+            self.deltas_since_keyframe += 1;
+            encode_delta(&self.keyframe, state)
+        };
+        self.used += data.len();
+        self.snapshots.push_back(Snapshot {
+            frame,
+            keyframe,
+            len: state.len(),
+            data,
+        });
+        self.enforce_budget();
+    }
+
+    /// The uncompressed keyframe counts towards the budget too
+    fn set_keyframe(&mut self, keyframe: Vec<u8>) {
+        self.used = self.used - self.keyframe.len() + keyframe.len();
+        self.keyframe = keyframe;
+    }
+
+    fn enforce_budget(&mut self) {
+        while self.used > self.budget {
+            let groups = self.snapshots.iter().filter(|s| s.keyframe).count();
+            if groups <= 1 {
+                // The newest group alone is over budget, thin out its deltas after the keyframe
+                if self.snapshots.len() <= 1 {
+                    self.clear();
+                    return;
+                }
+                let snapshot = self.snapshots.remove(1).unwrap();
+                self.used -= snapshot.data.len();
+                continue;
+            }
+            while let Some(snapshot) = self.snapshots.pop_front() {
+                self.used -= snapshot.data.len();
+                if self.snapshots.front().is_none_or(|next| next.keyframe) {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Drop every snapshot newer than `frame` and return the newest remaining
+    /// snapshot at or before it, decompressed, with its frame number.
+    /// Going further back than the buffer reaches stops at the oldest snapshot.
+    pub fn restore(&mut self, frame: u64) -> Result<Option<(u64, Vec<u8>)>> {
+        let frame = frame.max(self.oldest_frame().unwrap_or_default());
+        while self.snapshots.back().is_some_and(|last| last.frame > frame) {
+            let snapshot = self.snapshots.pop_back().unwrap();
+            self.used -= snapshot.data.len();
+        }
+        let Some(index) = self.snapshots.len().checked_sub(1) else {
+            self.set_keyframe(Vec::new());
+            return Ok(None);
+        };
+        let Some(key_index) = (0..=index).rev().find(|&i| self.snapshots[i].keyframe) else {
+            bail!("rewind buffer lost its keyframe");
+        };
+        let key = &self.snapshots[key_index];
+        let keyframe = decode_delta(&[], &key.data, key.len)?;
+        let snapshot = &self.snapshots[index];
+        let state = if snapshot.keyframe {
+            keyframe.clone()
+        } else {
+            decode_delta(&keyframe, &snapshot.data, snapshot.len)?
+        };
+        self.deltas_since_keyframe = index - key_index;
+        let frame = snapshot.frame;
+        self.set_keyframe(keyframe);
+        Ok(Some((frame, state)))
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.keyframe.clear();
+        self.deltas_since_keyframe = 0;
+        self.used = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nes::NesEmulator;
+    use crate::test_util::{nes_with_program, PULSE_TONE_PROGRAM};
+
+    /// 40 frames with a snapshot every 4, and the savestate of every frame
+    fn rewindable() -> (NesEmulator, Vec<(u64, Vec<u8>)>) {
+        let mut nes = nes_with_program(&PULSE_TONE_PROGRAM);
+        nes.enable_rewind(4, usize::MAX);
+        let mut states = Vec::new();
+        for _ in 0..40 {
+            nes.run_frame().unwrap();
+            states.push((nes.cpu.ppu.frame, nes.save_state().unwrap()));
+        }
+        (nes, states)
+    }
+
+    fn state_at(states: &[(u64, Vec<u8>)], frame: u64) -> &[u8] {
+        &states.iter().find(|(f, _)| *f == frame).unwrap().1
+    }
+
+    #[test]
+    fn test_rewind_to_snapshot() {
+        let (mut nes, states) = rewindable();
+        let rewound = nes.rewind(10).unwrap();
+        assert!((10..14).contains(&rewound));
+        let frame = nes.cpu.ppu.frame;
+        assert_eq!(frame, 40 - rewound);
+        assert_eq!(nes.save_state().unwrap(), state_at(&states, frame));
+    }
+
+    #[test]
+    fn test_rewind_and_replay() {
+        let (mut nes, states) = rewindable();
+        nes.rewind(10).unwrap();
+        // Rewinding again goes further back, running on replays the same frames
+        assert!(nes.rewind(1).unwrap() >= 1);
+        let frame = nes.cpu.ppu.frame;
+        nes.run_frame().unwrap();
+        assert_eq!(nes.save_state().unwrap(), state_at(&states, frame + 1));
+    }
+
+    #[test]
+    fn test_delta_encoding() {
+        let (_, states) = rewindable();
+        // Deltas against a keyframe only hold what changed
+        let (base, state) = (&states[0].1, &states[1].1);
+        let delta = encode_delta(base, state);
+        assert!(delta.len() * 100 < state.len());
+        assert_eq!(&decode_delta(base, &delta, state.len()).unwrap(), state);
+        assert_eq!(
+            &decode_delta(&[], &encode_delta(&[], state), state.len()).unwrap(),
+            state
+        );
+    }
+
+    /// Bytes used by a buffer holding only the first frame's keyframe
+    fn keyframe_size() -> usize {
+        let mut nes = nes_with_program(&PULSE_TONE_PROGRAM);
+        nes.enable_rewind(1, usize::MAX);
+        nes.run_frame().unwrap();
+        nes.rewind.as_ref().unwrap().memory_used()
+    }
+
+    fn state_len() -> usize {
+        nes_with_program(&PULSE_TONE_PROGRAM)
+            .save_state()
+            .unwrap()
+            .len()
+    }
+
+    fn run_with_budget(frames: usize, budget: usize) -> NesEmulator {
+        let mut nes = nes_with_program(&PULSE_TONE_PROGRAM);
+        nes.enable_rewind(1, budget);
+        for _ in 0..frames {
+            nes.run_frame().unwrap();
+        }
+        nes
+    }
+
+    #[test]
+    fn test_budget_counts_uncompressed_keyframe() {
+        assert!(keyframe_size() > state_len());
+    }
+
+    #[test]
+    fn test_budget_drops_oldest_group() {
+        // Not enough for a second compressed keyframe
+        let compressed = keyframe_size() - state_len();
+        let budget = keyframe_size() + compressed / 2;
+        let mut nes = run_with_budget(100, budget);
+        let rewind = nes.rewind.as_ref().unwrap();
+        assert!(rewind.memory_used() <= budget);
+        assert!(rewind.oldest_frame().unwrap() > 1);
+        assert!(nes.rewind(1000).is_ok());
+    }
+
+    #[test]
+    fn test_budget_thins_out_newest_group() {
+        // Fewer frames than KEYFRAME_INTERVAL, so there is only one group
+        let budget = keyframe_size() + 512;
+        let mut nes = run_with_budget(20, budget);
+        let rewind = nes.rewind.as_ref().unwrap();
+        assert!(rewind.memory_used() <= budget);
+        assert!(rewind.len() < 20);
+        // The keyframe and the newest snapshot stay
+        assert_eq!(rewind.oldest_frame(), Some(1));
+        assert_eq!(nes.rewind(0).unwrap(), 0);
+        assert_eq!(nes.rewind(1000).unwrap(), 19);
+    }
+
+    #[test]
+    fn test_budget_below_one_state() {
+        let mut nes = run_with_budget(5, 1024);
+        let rewind = nes.rewind.as_ref().unwrap();
+        assert!(rewind.is_empty());
+        assert_eq!(rewind.memory_used(), 0);
+        assert!(nes.rewind(1).is_err());
+    }
+}
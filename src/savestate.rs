@@ -0,0 +1,237 @@
+use std::io::{Cursor, Read};
+
+use anyhow::{bail, Result};
+use byteorder::{LittleEndian, ReadBytesExt};
+
+/// "NES" followed by 'S'tate
+pub const SAVESTATE_MAGIC: &[u8; 4] = b"NESS";
+/// Bump whenever the layout of any component changes, older states are rejected
+pub const SAVESTATE_VERSION: u16 = 3;
+
+/// A component of the machine that can be written to and restored from a savestate.
+/// Only emulated state belongs in it, user settings like mixer mutes stay untouched.
+pub trait Savestate {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()>;
+}
+
+/// Little-endian serializer for savestates
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Raw bytes of a fixed size buffer, the reader has to know the length
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// A length-prefixed block of bytes
+    pub fn write_chunk(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.write_bytes(bytes);
+    }
+
+    pub fn write_option_u8(&mut self, value: Option<u8>) {
+        self.write_bool(value.is_some());
+        self.write_u8(value.unwrap_or_default());
+    }
+
+    pub fn write_option_u16(&mut self, value: Option<u16>) {
+        self.write_bool(value.is_some());
+        self.write_u16(value.unwrap_or_default());
+    }
+}
+
+/// Deserializer for savestates written by StateWriter
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    cursor: Cursor<&'a [u8]>,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            cursor: Cursor::new(data),
+        }
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.cursor.read_u8()?)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool> {
+        Ok(self.cursor.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16> {
+        Ok(self.cursor.read_u16::<LittleEndian>()?)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32> {
+        Ok(self.cursor.read_u32::<LittleEndian>()?)
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64> {
+        Ok(self.cursor.read_u64::<LittleEndian>()?)
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32> {
+        Ok(self.cursor.read_f32::<LittleEndian>()?)
+    }
+
+    pub fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<()> {
+        Ok(self.cursor.read_exact(buffer)?)
+    }
+
+    pub fn read_chunk(&mut self) -> Result<&'a [u8]> {
+        let len = self.read_u32()? as usize;
+        let start = self.cursor.position() as usize;
+        let data = *self.cursor.get_ref();
+        if start + len > data.len() {
+            bail!("savestate is truncated");
+        }
+        self.cursor.set_position((start + len) as u64);
+        Ok(&data[start..start + len])
+    }
+
+    pub fn read_option_u8(&mut self) -> Result<Option<u8>> {
+        let some = self.read_bool()?;
+        let value = self.read_u8()?;
+        Ok(some.then_some(value))
+    }
+
+    pub fn read_option_u16(&mut self) -> Result<Option<u16>> {
+        let some = self.read_bool()?;
+        let value = self.read_u16()?;
+        Ok(some.then_some(value))
+    }
+
+    /// Fail if anything was left unread, which means the layout doesn't match
+    pub fn finish(&self) -> Result<()> {
+        if (self.cursor.position() as usize) < self.cursor.get_ref().len() {
+            bail!("savestate has trailing data");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::Cartridge;
+    use crate::input::Buttons;
+    use crate::nes::NesEmulator;
+    use crate::test_util::{create_test_rom, nes_with_program, PULSE_TONE_PROGRAM};
+
+    /// A console in the middle of its fourth frame, with a savestate taken there
+    fn mid_frame() -> (NesEmulator, Vec<u8>) {
+        let mut nes = nes_with_program(&PULSE_TONE_PROGRAM);
+        for _ in 0..3 {
+            nes.run_frame().unwrap();
+        }
+        for _ in 0..1000 {
+            nes.step().unwrap();
+        }
+        let state = nes.save_state().unwrap();
+        (nes, state)
+    }
+
+    /// Run a few frames with input and snapshot the result
+    fn run(nes: &mut NesEmulator) -> Vec<u8> {
+        for _ in 0..5 {
+            nes.set_buttons(0, Buttons::START);
+            nes.run_frame().unwrap();
+        }
+        nes.save_state().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (mut nes, state) = mid_frame();
+        let expected = run(&mut nes);
+        nes.load_state(&state).unwrap();
+        assert_eq!(run(&mut nes), expected);
+    }
+
+    #[test]
+    fn test_fresh_emulator() {
+        let (mut nes, state) = mid_frame();
+        let expected = run(&mut nes);
+        let mut other = nes_with_program(&PULSE_TONE_PROGRAM);
+        other.load_state(&state).unwrap();
+        assert_eq!(run(&mut other), expected);
+    }
+
+    #[test]
+    fn test_rejects_other_version() {
+        let (mut nes, state) = mid_frame();
+        let mut bad = state.clone();
+        bad[4] = 0xff;
+        assert!(nes.load_state(&bad).is_err());
+    }
+
+    #[test]
+    fn test_rejects_truncated_state() {
+        let (mut nes, state) = mid_frame();
+        run(&mut nes);
+        let before = nes.save_state().unwrap();
+        // The CPU part is complete, the PPU part is cut short
+        assert!(nes.load_state(&state[..state.len() - 1]).is_err());
+        assert_eq!(nes.save_state().unwrap(), before);
+    }
+
+    #[test]
+    fn test_rejects_trailing_data() {
+        let (mut nes, mut state) = mid_frame();
+        run(&mut nes);
+        let before = nes.save_state().unwrap();
+        state.push(0);
+        assert!(nes.load_state(&state).is_err());
+        assert_eq!(nes.save_state().unwrap(), before);
+    }
+
+    #[test]
+    fn test_rejects_other_rom() {
+        let (_, state) = mid_frame();
+        let mut rom = create_test_rom(&PULSE_TONE_PROGRAM);
+        *rom.last_mut().unwrap() ^= 0xff;
+        let mut other = NesEmulator::default();
+        other
+            .insert_cartridge(Cartridge::from_bytes(&rom).unwrap())
+            .unwrap();
+        assert!(other.load_state(&state).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::constant::{PRG_RAM_ADDRESS, PRG_RAM_SIZE};
+use crate::cpu::BusAccess;
+use crate::nes::NesEmulator;
+
+// reference: https://www.nesdev.org/wiki/Emulator_tests (blargg's $6000 status protocol)
+//
+// Only NROM cartridges load, so the MMC1/MMC3 ROMs of blargg's suites don't run.
+// The result says why a ROM failed to load or timed out.
+
+/// Result code of the test, or one of the two in-progress values
+pub const TEST_ROM_STATUS: u16 = 0x6000;
+/// Marks the status and text as valid
+pub const TEST_ROM_SIGNATURE_ADDRESS: u16 = 0x6001;
+pub const TEST_ROM_SIGNATURE: [u8; 3] = [0xde, 0xb0, 0x61];
+/// Zero-terminated result text
+pub const TEST_ROM_TEXT: u16 = 0x6004;
+
+/// Status while the test is running
+pub const TEST_ROM_RUNNING: u8 = 0x80;
+/// Status asking for the reset button to be pressed after at least 100ms
+pub const TEST_ROM_NEEDS_RESET: u8 = 0x81;
+/// Frames to wait before pressing reset, a little over 100ms
+const RESET_DELAY_FRAMES: u64 = 8;
+/// Instructions watched after a timeout to find out what the ROM is waiting on
+const TIMEOUT_PROBE_INSTRUCTIONS: usize = 64;
+
+/// How a test ROM finished
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestRomResult {
+    /// The final status, 0 when the test passed. None if it didn't finish in time.
+    pub code: Option<u8>,
+    pub text: String,
+    pub frames: u64,
+    /// Why the ROM didn't report a result, for timeouts
+    pub reason: Option<String>,
+}
+
+impl TestRomResult {
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.code == Some(0)
+    }
+
+    /// Exit code of the runner: the status, 255 for a timeout
+    #[must_use]
+    pub fn exit_code(&self) -> i32 {
+        self.code.map_or(255, i32::from)
+    }
+}
+
+impl fmt::Display for TestRomResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code {
+            Some(0) => write!(f, "PASS"),
+            Some(code) => write!(f, "FAIL (${:02X})", code),
+            None => write!(f, "TIMEOUT"),
+        }
+    }
+}
+
+impl NesEmulator {
+    /// The status byte at $6000, once the ROM wrote the signature that makes it valid
+    #[must_use]
+    pub fn test_rom_status(&self) -> Option<u8> {
+        let signature = (0..3).map(|i| self.cpu.peek(TEST_ROM_SIGNATURE_ADDRESS + i));
+        signature
+            .eq(TEST_ROM_SIGNATURE)
+            .then(|| self.cpu.peek(TEST_ROM_STATUS))
+    }
+
+    /// The text the ROM wrote from $6004 on
+    #[must_use]
+    pub fn test_rom_text(&self) -> String {
+        let bytes: Vec<u8> = (TEST_ROM_TEXT..PRG_RAM_ADDRESS + PRG_RAM_SIZE as u16)
+            .map(|addr| self.cpu.peek(addr))
+            .take_while(|&byte| byte != 0)
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// Run the inserted test ROM until it reports a result or `max_frames` pass,
+    /// pressing reset whenever it asks for it
+    pub fn run_test_rom(&mut self, max_frames: u64) -> Result<TestRomResult> {
+        let mut reset_at = None;
+        let mut frames = 0;
+        while frames < max_frames {
+            let running = self.run_frame()?;
+            frames += 1;
+            match self.test_rom_status() {
+                None | Some(TEST_ROM_RUNNING) => {}
+                Some(TEST_ROM_NEEDS_RESET) => {
+                    if frames >= *reset_at.get_or_insert(frames + RESET_DELAY_FRAMES) {
+                        self.reset()?;
+                        reset_at = None;
+                    }
+                }
+                Some(code) => {
+                    return Ok(TestRomResult {
+                        code: Some(code),
+                        text: self.test_rom_text(),
+                        frames,
+                        reason: None,
+                    });
+                }
+            }
+            if !running {
+                break;
+            }
+        }
+        Ok(TestRomResult {
+            code: None,
+            text: self.test_rom_text(),
+            frames,
+            reason: Some(self.timeout_reason()?),
+        })
+    }
+
+    /// Explain a timeout: watch a few more instructions for reads of the PPU registers,
+    /// which a ROM polls while it waits for vblank or a sprite 0 hit that never comes
+    fn timeout_reason(&mut self) -> Result<String> {
+        self.cpu.bus_log = Some(Vec::new());
+        let mut result = Ok(true);
+        for _ in 0..TIMEOUT_PROBE_INSTRUCTIONS {
+            result = self.step();
+            if !matches!(result, Ok(true)) {
+                break;
+            }
+        }
+        let accesses = self.cpu.bus_log.take().unwrap_or_default();
+        result?;
+        let ppu_read = accesses.iter().find_map(|cycle| match cycle.access {
+            BusAccess::Read(addr @ 0x2000..=0x3fff) => Some(0x2000 | (addr & 0x0007)),
+            _ => None,
+        });
+        Ok(match (ppu_read, self.test_rom_status()) {
+            (Some(addr), _) => format!("waiting on PPU register ${:04X}", addr),
+            (None, None) => "never wrote the $6001 signature".to_string(),
+            (None, Some(_)) => "still running".to_string(),
+        })
+    }
+}
+
+/// Boot a test ROM on a fresh console without touching its `.sav` file and run it
+pub fn run_test_rom(path: &Path, max_frames: u64) -> Result<TestRomResult> {
+    let mut nes = NesEmulator::default();
+    nes.set_battery_saves(false);
+    nes.load_rom(path)?;
+    nes.run_test_rom(max_frames)
+}
+
+/// Run every `.nes` file of the directory, in name order
+pub fn run_test_rom_dir(
+    dir: &Path,
+    max_frames: u64,
+) -> Result<Vec<(PathBuf, Result<TestRomResult>)>> {
+    let mut paths: Vec<_> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "nes"));
+    paths.sort();
+    Ok(paths
+        .into_iter()
+        .map(|path| {
+            let result = run_test_rom(&path, max_frames);
+            (path, result)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::assemble;
+    use crate::test_util::{create_test_rom, nes_with_source};
+
+    /// Asks for a reset once, then reports `code` with a text
+    fn reporting_source(code: u8) -> String {
+        let store = |address: u16, bytes: &[u8]| -> String {
+            bytes
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| format!("LDA #${:02X}\nSTA ${:04X}\n", byte, address as usize + i))
+                .collect()
+        };
+        format!(
+            ".org $8000\n{}{}LDA $6010\nBNE reported\nINC $6010\n{}wait: JMP wait\nreported:\n{}{}JMP wait\n",
+            store(TEST_ROM_STATUS, &[TEST_ROM_RUNNING]),
+            store(TEST_ROM_SIGNATURE_ADDRESS, &TEST_ROM_SIGNATURE),
+            store(TEST_ROM_STATUS, &[TEST_ROM_NEEDS_RESET]),
+            store(TEST_ROM_TEXT, b"Failed #2\ndetails\0"),
+            store(TEST_ROM_STATUS, &[code]),
+        )
+    }
+
+    #[test]
+    fn test_reported_failure() {
+        let mut nes = nes_with_source(&reporting_source(0x02));
+        let result = nes.run_test_rom(60).unwrap();
+        assert_eq!(result.code, Some(0x02));
+        assert_eq!(result.text, "Failed #2\ndetails");
+        assert_eq!(result.to_string(), "FAIL ($02)");
+        assert_eq!(result.exit_code(), 2);
+        // The reset request delays the result
+        assert!(result.frames > RESET_DELAY_FRAMES);
+    }
+
+    #[test]
+    fn test_directory_summary() {
+        // A ROM that never reports times out, files that aren't ROMs are skipped
+        let dir = std::env::temp_dir().join(format!("nes-test-roms-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, source) in [
+            ("01-pass.nes", reporting_source(0x00)),
+            ("02-fail.nes", reporting_source(0x02)),
+            ("03-hang.nes", ".org $8000\nloop: JMP loop".to_string()),
+        ] {
+            let rom = create_test_rom(&assemble(&source).unwrap().bytes);
+            fs::write(dir.join(name), rom).unwrap();
+        }
+        fs::write(dir.join("notes.txt"), "not a ROM").unwrap();
+        let results = run_test_rom_dir(&dir, 30).unwrap();
+        let summary: Vec<String> = results
+            .iter()
+            .map(|(_, result)| result.as_ref().unwrap().to_string())
+            .collect();
+        assert_eq!(summary, vec!["PASS", "FAIL ($02)", "TIMEOUT"]);
+        let reason = results[2].1.as_ref().unwrap().reason.as_deref();
+        assert_eq!(reason, Some("never wrote the $6001 signature"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_timeout_on_ppu_status() {
+        // With rendering off a sprite 0 hit never comes
+        let mut nes = nes_with_source(".org $8000\nhit: BIT $2002\nBVC hit");
+        let result = nes.run_test_rom(2).unwrap();
+        assert_eq!(result.code, None);
+        assert_eq!(
+            result.reason.as_deref(),
+            Some("waiting on PPU register $2002")
+        );
+    }
+
+    #[test]
+    fn test_unsupported_mapper() {
+        let path = std::env::temp_dir().join(format!("nes-test-mmc1-{}.nes", std::process::id()));
+        let mut rom = create_test_rom(&[0xea]);
+        rom[6] |= 0x10;
+        fs::write(&path, rom).unwrap();
+        let error = run_test_rom(&path, 1).unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "unsupported mapper 1, only NROM (mapper 0) is emulated"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+use crate::cartridge::Cartridge;
+use crate::cpu::{assemble, StopReason, TestHarness};
+use crate::nes::NesEmulator;
+
+/// Plays a constant pulse 1 tone forever
+pub const PULSE_TONE_PROGRAM: [u8; 23] = [
+    0xa9, 0x01, // LDA #$01
+    0x8d, 0x15, 0x40, // STA $4015
+    0xa9, 0xbf, // LDA #$BF
+    0x8d, 0x00, 0x40, // STA $4000
+    0xa9, 0xfd, // LDA #$FD
+    0x8d, 0x02, 0x40, // STA $4002
+    0xa9, 0x00, // LDA #$00
+    0x8d, 0x03, 0x40, // STA $4003
+    0x4c, 0x14, 0x80, // JMP $8014
+];
+
+/// Calls `store` for X = 0, 1 and 2, then stops at the BRK at $800A
+pub const STORE_LOOP_SOURCE: &str = "
+    .org $8000
+    LDX #$00
+loop:
+    JSR store
+    INX
+    CPX #$03
+    BNE loop
+    BRK
+store:
+    STX $0300
+    RTS
+";
+
+/// Run the source on a bare CPU with `memory` poked in first, until it reaches a BRK
+pub fn run_to_break(source: &str, memory: &[(u16, &[u8])]) -> TestHarness {
+    let mut harness = TestHarness::new().with_source(source).unwrap();
+    for &(address, bytes) in memory {
+        harness = harness.with_memory(address, bytes);
+    }
+    assert_eq!(harness.run(100).unwrap(), StopReason::Break);
+    harness
+}
+
+/// Waits for vblank and shows tile 1 at column 12, row 12 of the first nametable,
+/// over pixels 96-103 of lines 96-103, in white on black
+pub const WHITE_TILE_SOURCE: &str = "
+    .org $8000
+vblank:
+    BIT $2002
+    BPL vblank
+    LDA #$3F
+    STA $2006
+    LDA #$00
+    STA $2006
+    LDA #$0F
+    STA $2007
+    STA $2007
+    STA $2007
+    LDA #$30
+    STA $2007
+    LDA #$21
+    STA $2006
+    LDA #$8C
+    STA $2006
+    LDA #$01
+    STA $2007
+    LDA #$00
+    STA $2005
+    STA $2005
+    LDA #$0A
+    STA $2001
+loop:
+    JMP loop
+";
+
+/// Build an NROM image with the program at $8000 and the reset vector pointing to it
+pub fn create_test_rom(program: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0x4e, 0x45, 0x53, 0x1a, 0x01, 0x01, 0x00, 0x00];
+    rom.resize(16, 0);
+    let mut prg = vec![0u8; 0x4000];
+    prg[..program.len()].copy_from_slice(program);
+    // Reset vector at $FFFC, mirrored from $BFFC
+    prg[0x3ffc] = 0x00;
+    prg[0x3ffd] = 0x80;
+    rom.extend(prg);
+    rom.extend(vec![0u8; 0x2000]);
+    rom
+}
+
+/// A console with an NROM cartridge running the program from $8000
+pub fn nes_with_program(program: &[u8]) -> NesEmulator {
+    let mut nes = NesEmulator::default();
+    nes.insert_cartridge(Cartridge::from_bytes(&create_test_rom(program)).unwrap())
+        .unwrap();
+    nes
+}
+
+/// A console running the assembled source, which has to start at $8000
+pub fn nes_with_source(source: &str) -> NesEmulator {
+    nes_with_program(&assemble(source).unwrap().bytes)
+}
+
+/// A console running WHITE_TILE_SOURCE, with every pixel of tile 1 in color 3
+pub fn nes_with_white_tile() -> NesEmulator {
+    let mut rom = create_test_rom(&assemble(WHITE_TILE_SOURCE).unwrap().bytes);
+    // CHR ROM follows the header and the 16KB PRG ROM
+    let tile = 16 + 0x4000 + 16;
+    rom[tile..tile + 16].fill(0xff);
+    let mut nes = NesEmulator::default();
+    nes.insert_cartridge(Cartridge::from_bytes(&rom).unwrap())
+        .unwrap();
+    nes
+}
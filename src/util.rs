@@ -1,3 +1,14 @@
+use anyhow::{Context, Result};
+
 pub fn get_bit(x: u8, i: u8) -> u8 {
-    return (x >> i) & 1;
+    (x >> i) & 1
+}
+
+/// Parse an address written as `$C000`, `0xC000` or `C000`
+pub fn parse_hex_u16(s: &str) -> Result<u16> {
+    let digits = s
+        .strip_prefix('$')
+        .or_else(|| s.strip_prefix("0x"))
+        .unwrap_or(s);
+    u16::from_str_radix(digits, 16).with_context(|| format!("'{}' is not a hex address", s))
 }